@@ -0,0 +1,296 @@
+//! Animated GIF and PNG files, which keep their frames when optimized or converted to animated WebP.
+//!
+//! The frames are composited by the decoders, so each one is a full canvas, and encoded by libwebp, which keeps
+//! only what changes from one frame to the next. Animated PNGs keep their format when optimized by oxipng, which
+//! leaves their animation chunks in place.
+
+use gif::{DecodeOptions, Repeat};
+use image::codecs::gif::GifDecoder;
+use image::codecs::png::PngDecoder;
+use image::{AnimationDecoder, Frames, RgbaImage};
+use oxipng::StripChunks;
+use std::error::Error;
+use std::io::Cursor;
+
+/// Browsers play the frames of shorter delays at 100 ms, as most GIFs expect
+const MIN_FRAME_DELAY_MS: u32 = 20;
+const DEFAULT_FRAME_DELAY_MS: u32 = 100;
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+
+/// The frames of an animation, with how many times it is played, 0 meaning forever
+pub struct Animation {
+    pub width: u32,
+    pub height: u32,
+    pub loop_count: u32,
+    pub frames: Vec<AnimationFrame>,
+}
+
+/// A frame composited on the full canvas, and how long it is shown
+pub struct AnimationFrame {
+    pub image: RgbaImage,
+    pub delay_ms: u32,
+}
+
+impl Animation {
+    fn from_frames(frames: Frames<'_>, loop_count: u32) -> Result<Self, Box<dyn Error>> {
+        let frames = frames
+            .map(|frame| {
+                let frame = frame?;
+                let (numerator, denominator) = frame.delay().numer_denom_ms();
+                let delay_ms = numerator / denominator.max(1);
+                Ok(AnimationFrame {
+                    image: frame.into_buffer(),
+                    delay_ms: match delay_ms < MIN_FRAME_DELAY_MS {
+                        true => DEFAULT_FRAME_DELAY_MS,
+                        false => delay_ms,
+                    },
+                })
+            })
+            .collect::<Result<Vec<_>, image::ImageError>>()?;
+        let (width, height) = frames
+            .first()
+            .map(|frame| frame.image.dimensions())
+            .ok_or("Animation without any frame")?;
+
+        Ok(Self {
+            width,
+            height,
+            loop_count,
+            frames,
+        })
+    }
+}
+
+/// Decodes the frames of a GIF, a static GIF giving a single frame
+pub fn decode_gif_animation(buffer: &[u8]) -> Result<Animation, Box<dyn Error>> {
+    let decoder = GifDecoder::new(Cursor::new(buffer))?;
+    Animation::from_frames(decoder.into_frames(), gif_loop_count(buffer)?)
+}
+
+/// Decodes the frames of an animated PNG
+pub fn decode_apng_animation(buffer: &[u8]) -> Result<Animation, Box<dyn Error>> {
+    let loop_count = apng_control(buffer).ok_or("Not an animated PNG")?.1;
+    let decoder = PngDecoder::new(Cursor::new(buffer))?.apng()?;
+    Animation::from_frames(decoder.into_frames(), loop_count)
+}
+
+/// Whether `buffer` is an animated PNG of several frames, which has an animation control chunk before its image data
+pub fn is_apng(buffer: &[u8]) -> bool {
+    apng_control(buffer).is_some_and(|(frames, _)| frames > 1)
+}
+
+/// Optimizes an animated PNG losslessly, keeping all its frames
+pub fn optimize_apng(buffer: &[u8], keep_metadata: bool) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut options = oxipng::Options::from_preset(2);
+    // The safe chunks are the ones needed to display the image, including the animation ones
+    options.strip = match keep_metadata {
+        true => StripChunks::None,
+        false => StripChunks::Safe,
+    };
+    Ok(oxipng::optimize_from_memory(buffer, &options)?)
+}
+
+/// Number of frames and of plays of an animated PNG, from its acTL chunk
+fn apng_control(buffer: &[u8]) -> Option<(u32, u32)> {
+    if !buffer.starts_with(&PNG_SIGNATURE) {
+        return None;
+    }
+
+    let mut position = PNG_SIGNATURE.len();
+    while let Some(header) = buffer.get(position..position + 8) {
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        match &header[4..8] {
+            b"acTL" => {
+                let data = buffer.get(position + 8..position + 16)?;
+                let frames = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+                let plays = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+                return Some((frames, plays));
+            }
+            b"IDAT" | b"IEND" => return None,
+            _ => {}
+        }
+        // Chunk length, type, data and CRC
+        position += 12 + length;
+    }
+
+    None
+}
+
+/// Number of times the GIF is played. Its loop count is the number of repetitions after the first play.
+fn gif_loop_count(buffer: &[u8]) -> Result<u32, Box<dyn Error>> {
+    let mut decoder = DecodeOptions::new().read_info(Cursor::new(buffer))?;
+    // The loop count comes before the first frame
+    decoder.next_frame_info()?;
+    Ok(match decoder.repeat() {
+        Repeat::Infinite => 0,
+        Repeat::Finite(repetitions) => repetitions as u32 + 1,
+    })
+}
+
+/// Encodes the frames as an animated WebP, lossless or at `quality`, with the `effort` of the encoder or its default
+pub fn encode_webp_animation(
+    animation: &Animation,
+    quality: u32,
+    lossless: bool,
+    effort: Option<u8>,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut config = webp::WebPConfig::new().map_err(|_| "Error initializing the WebP encoder")?;
+    config.lossless = lossless as i32;
+    config.quality = quality as f32;
+    if let Some(effort) = effort {
+        config.method = effort as i32;
+    }
+
+    let mut encoder = webp::AnimEncoder::new(animation.width, animation.height, &config);
+    encoder.set_loop_count(animation.loop_count as i32);
+    let mut timestamp_ms = 0;
+    for frame in &animation.frames {
+        encoder.add_frame(webp::AnimFrame::from_rgba(
+            &frame.image,
+            animation.width,
+            animation.height,
+            timestamp_ms as i32,
+        ));
+        timestamp_ms += frame.delay_ms;
+    }
+    let encoded = encoder.try_encode().map_err(|e| format!("{e:?}"))?;
+    Ok(encoded.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::codecs::gif::GifEncoder;
+    use image::{Delay, Frame, Rgba};
+
+    /// A GIF of `colors.len()` frames, each filled with one color
+    fn write_gif(colors: &[[u8; 4]], delay_ms: u32, repeat: Option<image::codecs::gif::Repeat>) -> Vec<u8> {
+        let mut output = Vec::new();
+        {
+            let mut encoder = GifEncoder::new(&mut output);
+            if let Some(repeat) = repeat {
+                encoder.set_repeat(repeat).unwrap();
+            }
+            let frames = colors.iter().map(|&color| {
+                let image = RgbaImage::from_pixel(16, 8, Rgba(color));
+                Frame::from_parts(image, 0, 0, Delay::from_numer_denom_ms(delay_ms, 1))
+            });
+            encoder.encode_frames(frames).unwrap();
+        }
+        output
+    }
+
+    /// An animated PNG of `colors.len()` frames, each filled with one color
+    fn write_apng(colors: &[[u8; 4]], delay_ms: u16, plays: u32) -> Vec<u8> {
+        let mut output = Vec::new();
+        {
+            let mut encoder = png::Encoder::new(&mut output, 16, 8);
+            encoder.set_color(png::ColorType::Rgba);
+            encoder.set_animated(colors.len() as u32, plays).unwrap();
+            encoder.set_frame_delay(delay_ms, 1000).unwrap();
+            let mut writer = encoder.write_header().unwrap();
+            for color in colors {
+                let pixels: Vec<u8> = color.repeat(16 * 8);
+                writer.write_image_data(&pixels).unwrap();
+            }
+            writer.finish().unwrap();
+        }
+        output
+    }
+
+    /// The loop count of the ANIM chunk of a WebP file
+    fn webp_loop_count(buffer: &[u8]) -> u16 {
+        let anim = buffer.windows(4).position(|chunk| chunk == b"ANIM").unwrap();
+        u16::from_le_bytes([buffer[anim + 12], buffer[anim + 13]])
+    }
+
+    #[test]
+    fn test_decode_gif_animation() {
+        let colors = [[255, 0, 0, 255], [0, 255, 0, 255], [0, 0, 255, 255]];
+        let input = write_gif(&colors, 250, Some(image::codecs::gif::Repeat::Infinite));
+        let animation = decode_gif_animation(&input).unwrap();
+        assert_eq!((animation.width, animation.height), (16, 8));
+        assert_eq!(animation.loop_count, 0);
+        assert_eq!(animation.frames.len(), 3);
+        for (frame, color) in animation.frames.iter().zip(colors) {
+            assert_eq!(frame.delay_ms, 250);
+            assert_eq!(frame.image.get_pixel(0, 0).0, color);
+        }
+
+        // Played once without a loop count, three times with two repetitions
+        let input = write_gif(&colors, 0, None);
+        let animation = decode_gif_animation(&input).unwrap();
+        assert_eq!(animation.loop_count, 1);
+        assert!(animation
+            .frames
+            .iter()
+            .all(|frame| frame.delay_ms == DEFAULT_FRAME_DELAY_MS));
+        let input = write_gif(&colors, 50, Some(image::codecs::gif::Repeat::Finite(2)));
+        assert_eq!(decode_gif_animation(&input).unwrap().loop_count, 3);
+
+        assert!(decode_gif_animation(b"GIF89a").is_err());
+    }
+
+    #[test]
+    fn test_encode_webp_animation() {
+        let colors = [[255, 0, 0, 255], [0, 255, 0, 255], [0, 0, 255, 255]];
+        let input = write_gif(&colors, 250, Some(image::codecs::gif::Repeat::Finite(1)));
+        let animation = decode_gif_animation(&input).unwrap();
+
+        let output = encode_webp_animation(&animation, 80, true, None).unwrap();
+        assert_eq!(webp_loop_count(&output), 2);
+        let decoder = image::codecs::webp::WebPDecoder::new(Cursor::new(&output)).unwrap();
+        assert!(decoder.has_animation());
+        let frames: Vec<Frame> = decoder.into_frames().map(Result::unwrap).collect();
+        assert_eq!(frames.len(), 3);
+        for (frame, color) in frames.iter().zip(colors) {
+            assert_eq!(frame.buffer().get_pixel(8, 4).0, color);
+        }
+        // The duration of the last frame is up to libwebp
+        assert_eq!(frames[0].delay().numer_denom_ms(), (250, 1));
+        assert_eq!(frames[1].delay().numer_denom_ms(), (250, 1));
+    }
+
+    #[test]
+    fn test_is_apng() {
+        let colors = [[255, 0, 0, 255], [0, 255, 0, 255]];
+        assert!(is_apng(&write_apng(&colors, 100, 0)));
+        // A single frame is displayed like any PNG
+        assert!(!is_apng(&write_apng(&colors[..1], 100, 0)));
+        assert!(!is_apng(&std::fs::read("samples/p0.png").unwrap()));
+        assert!(!is_apng(&std::fs::read("samples/j0.JPG").unwrap()));
+        assert!(!is_apng(&PNG_SIGNATURE));
+    }
+
+    #[test]
+    fn test_decode_apng_animation() {
+        let colors = [[255, 0, 0, 255], [0, 255, 0, 255], [0, 0, 255, 128]];
+        let animation = decode_apng_animation(&write_apng(&colors, 40, 3)).unwrap();
+        assert_eq!((animation.width, animation.height), (16, 8));
+        assert_eq!(animation.loop_count, 3);
+        assert_eq!(animation.frames.len(), 3);
+        for (frame, color) in animation.frames.iter().zip(colors) {
+            assert_eq!(frame.delay_ms, 40);
+            assert_eq!(frame.image.get_pixel(15, 7).0, color);
+        }
+
+        assert!(decode_apng_animation(&std::fs::read("samples/p0.png").unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_optimize_apng() {
+        let colors = [[255, 0, 0, 255], [0, 255, 0, 255], [0, 0, 255, 255]];
+        let input = write_apng(&colors, 40, 0);
+        let output = optimize_apng(&input, false).unwrap();
+        assert!(is_apng(&output));
+        let animation = decode_apng_animation(&output).unwrap();
+        assert_eq!(animation.loop_count, 0);
+        let output_colors: Vec<[u8; 4]> = animation
+            .frames
+            .iter()
+            .map(|frame| frame.image.get_pixel(0, 0).0)
+            .collect();
+        assert_eq!(output_colors, colors);
+    }
+}
@@ -0,0 +1,249 @@
+use crate::options::OverwritePolicy;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process;
+use std::sync::Mutex;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+/// ZIP archive collecting all the compressed outputs.
+/// Entries are streamed to a temporary file as soon as each file is done, and the archive is moved to its final
+/// path by `finish`, following the overwrite policy. The temporary file is next to the archive, so that the move is
+/// a rename on the same file system, unless `with_temp_dir` puts it elsewhere.
+pub struct ZipOutput {
+    path: PathBuf,
+    temp_path: PathBuf,
+    overwrite_policy: OverwritePolicy,
+    writer: Mutex<Option<ZipWriter<File>>>,
+}
+
+impl ZipOutput {
+    pub fn new(path: PathBuf, overwrite_policy: OverwritePolicy) -> Result<Self, String> {
+        // The archive is a single file, so the question of `Ask` is up to the caller
+        if matches!(overwrite_policy, OverwritePolicy::Never | OverwritePolicy::Ask) && path.exists() {
+            return Err(format!(
+                "Output archive '{}' already exists, skipped due overwrite policy",
+                path.display()
+            ));
+        }
+
+        let mut temp_name = path.file_name().map(OsString::from).unwrap_or_default();
+        temp_name.push(".part");
+        let temp_path = path.with_file_name(temp_name);
+
+        Ok(Self {
+            path,
+            temp_path,
+            overwrite_policy,
+            writer: Mutex::new(None),
+        })
+    }
+
+    /// Streams the archive to a temporary file in `temp_dir` instead of the folder of the archive
+    pub fn with_temp_dir(mut self, temp_dir: &Path) -> Self {
+        if let Some(temp_name) = self.temp_path.file_name() {
+            self.temp_path = temp_dir.join(temp_name);
+        }
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Adds a file to the archive, creating it on the first call
+    pub fn add_file(&self, entry_path: &Path, data: &[u8]) -> Result<(), String> {
+        let mut writer = self
+            .writer
+            .lock()
+            .map_err(|_| "Error locking output archive".to_string())?;
+        if writer.is_none() {
+            if let Some(parent) = self.path.parent().filter(|p| !p.as_os_str().is_empty()) {
+                fs::create_dir_all(parent).map_err(|_| "Error creating output directory".to_string())?;
+            }
+            let file = File::create(&self.temp_path).map_err(|_| "Error creating output archive".to_string())?;
+            *writer = Some(ZipWriter::new(file));
+        }
+        let Some(writer) = writer.as_mut() else {
+            return Err("Error creating output archive".to_string());
+        };
+
+        // Images are already compressed, deflating them again would only waste time
+        let options = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+        writer
+            .start_file(entry_name(entry_path), options)
+            .map_err(|e| format!("Error adding file to output archive: {e}"))?;
+        writer
+            .write_all(data)
+            .map_err(|_| "Error writing output archive".to_string())
+    }
+
+    /// Completes the archive and moves it to its final path.
+    /// Returns `false` if the existing archive was kept because of the overwrite policy.
+    pub fn finish(&self) -> Result<bool, String> {
+        let mut writer = self
+            .writer
+            .lock()
+            .map_err(|_| "Error locking output archive".to_string())?;
+        let Some(writer) = writer.take() else {
+            return Ok(false);
+        };
+        writer
+            .finish()
+            .map_err(|e| format!("Error completing output archive: {e}"))?;
+
+        if self.overwrite_policy.compares_size() {
+            if let (Ok(existing), Ok(new)) = (self.path.metadata(), self.temp_path.metadata()) {
+                if self.overwrite_policy.keeps_existing(existing.len(), new.len()) {
+                    let _ = fs::remove_file(&self.temp_path);
+                    return Ok(false);
+                }
+            }
+        }
+
+        move_file(&self.temp_path, &self.path).map_err(|_| "Error writing output archive".to_string())?;
+        Ok(true)
+    }
+}
+
+/// Renames `from`, or copies it when the rename fails, e.g. across file systems
+fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    if fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    fs::copy(from, to)?;
+    fs::remove_file(from)
+}
+
+/// Fails with a clear message if temporary files can't be created in `temp_dir`
+pub fn check_temp_dir(temp_dir: &Path) -> Result<(), String> {
+    let probe = temp_dir.join(format!(".caesium-clt-{}.tmp", process::id()));
+    File::create(&probe)
+        .and_then(|_| fs::remove_file(&probe))
+        .map_err(|e| format!("Temporary directory '{}' is not writable: {e}", temp_dir.display()))
+}
+
+/// Archive entry names always use forward slashes, whatever the platform
+fn entry_name(path: &Path) -> String {
+    path.components()
+        .filter_map(|c| match c {
+            Component::Normal(part) => Some(part.to_string_lossy()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use tempfile::tempdir;
+    use zip::ZipArchive;
+
+    fn read_entries(path: &Path) -> Vec<(String, Vec<u8>)> {
+        let mut archive = ZipArchive::new(File::open(path).unwrap()).unwrap();
+        (0..archive.len())
+            .map(|i| {
+                let mut entry = archive.by_index(i).unwrap();
+                let mut data = Vec::new();
+                entry.read_to_end(&mut data).unwrap();
+                (entry.name().to_string(), data)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_entry_name() {
+        assert_eq!(entry_name(Path::new("image.jpg")), "image.jpg");
+        assert_eq!(entry_name(Path::new("a/b/image.jpg")), "a/b/image.jpg");
+        assert_eq!(entry_name(Path::new("./a/image.jpg")), "a/image.jpg");
+    }
+
+    #[test]
+    fn test_zip_output() {
+        let temp_dir = tempdir().unwrap();
+        let archive_path = temp_dir.path().join("nested/output.zip");
+
+        let zip_output = ZipOutput::new(archive_path.clone(), OverwritePolicy::All).unwrap();
+        zip_output.add_file(Path::new("a.jpg"), b"first").unwrap();
+        zip_output.add_file(Path::new("sub/b.png"), b"second").unwrap();
+        assert!(zip_output.add_file(Path::new("a.jpg"), b"duplicate").is_err());
+        assert!(zip_output.finish().unwrap());
+
+        let entries = read_entries(&archive_path);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0], ("a.jpg".to_string(), b"first".to_vec()));
+        assert_eq!(entries[1], ("sub/b.png".to_string(), b"second".to_vec()));
+        assert!(!temp_dir.path().join("nested/output.zip.part").exists());
+    }
+
+    #[test]
+    fn test_zip_output_overwrite_policy() {
+        let temp_dir = tempdir().unwrap();
+        let archive_path = temp_dir.path().join("output.zip");
+        fs::write(&archive_path, b"existing").unwrap();
+
+        assert!(ZipOutput::new(archive_path.clone(), OverwritePolicy::Never).is_err());
+        assert!(ZipOutput::new(archive_path.clone(), OverwritePolicy::Ask).is_err());
+
+        // The existing file is smaller than any archive, so it is kept
+        let zip_output = ZipOutput::new(archive_path.clone(), OverwritePolicy::Bigger).unwrap();
+        zip_output.add_file(Path::new("a.jpg"), b"data").unwrap();
+        assert!(!zip_output.finish().unwrap());
+        assert_eq!(fs::read(&archive_path).unwrap(), b"existing");
+
+        let zip_output = ZipOutput::new(archive_path.clone(), OverwritePolicy::All).unwrap();
+        zip_output.add_file(Path::new("a.jpg"), b"data").unwrap();
+        assert!(zip_output.finish().unwrap());
+        assert_eq!(read_entries(&archive_path).len(), 1);
+    }
+
+    #[test]
+    fn test_zip_output_temp_dir() {
+        let temp_dir = tempdir().unwrap();
+        let custom_temp_dir = tempdir().unwrap();
+        let archive_path = temp_dir.path().join("nested/output.zip");
+        check_temp_dir(custom_temp_dir.path()).unwrap();
+        assert_eq!(fs::read_dir(custom_temp_dir.path()).unwrap().count(), 0);
+
+        let zip_output = ZipOutput::new(archive_path.clone(), OverwritePolicy::All)
+            .unwrap()
+            .with_temp_dir(custom_temp_dir.path());
+        zip_output.add_file(Path::new("a.jpg"), b"first").unwrap();
+        assert!(custom_temp_dir.path().join("output.zip.part").exists());
+        assert!(!temp_dir.path().join("nested/output.zip.part").exists());
+
+        assert!(zip_output.finish().unwrap());
+        assert_eq!(
+            read_entries(&archive_path),
+            vec![("a.jpg".to_string(), b"first".to_vec())]
+        );
+        assert!(!custom_temp_dir.path().join("output.zip.part").exists());
+
+        assert!(check_temp_dir(&temp_dir.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn test_move_file() {
+        let temp_dir = tempdir().unwrap();
+        let from = temp_dir.path().join("from");
+        fs::write(&from, b"data").unwrap();
+        move_file(&from, &temp_dir.path().join("to")).unwrap();
+        assert!(!from.exists());
+        assert_eq!(fs::read(temp_dir.path().join("to")).unwrap(), b"data");
+        assert!(move_file(&from, &temp_dir.path().join("again")).is_err());
+    }
+
+    #[test]
+    fn test_zip_output_without_files() {
+        let temp_dir = tempdir().unwrap();
+        let archive_path = temp_dir.path().join("output.zip");
+
+        let zip_output = ZipOutput::new(archive_path.clone(), OverwritePolicy::All).unwrap();
+        assert!(!zip_output.finish().unwrap());
+        assert!(!archive_path.exists());
+    }
+}
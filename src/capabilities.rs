@@ -0,0 +1,145 @@
+use std::fmt;
+use std::thread::available_parallelism;
+
+/// Encoders used by libcaesium for each output format
+const ENCODERS: &[(&str, &str)] = &[
+    ("JPEG", "mozjpeg"),
+    ("PNG", "oxipng/imagequant"),
+    ("WebP", "libwebp"),
+    ("GIF", "gifsicle"),
+    ("TIFF", "image-rs"),
+];
+
+/// Encoders and CPU features of this host, to explain throughput differences between machines.
+/// Whatever cannot be detected is left empty.
+#[derive(Debug)]
+pub struct Capabilities {
+    pub version: &'static str,
+    pub arch: &'static str,
+    pub processors: Option<usize>,
+    pub encoders: &'static [(&'static str, &'static str)],
+    /// SIMD extensions detected on the CPU, the widest first. `None` on architectures that are not probed
+    pub simd_features: Option<Vec<&'static str>>,
+}
+
+impl Capabilities {
+    /// Instruction set picked at runtime by the SIMD code of mozjpeg
+    pub fn mozjpeg_simd(&self) -> Option<&'static str> {
+        let features = self.simd_features.as_ref()?;
+        ["AVX2", "SSE2", "NEON"]
+            .into_iter()
+            .find(|feature| features.contains(feature))
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let processors = self
+            .processors
+            .map_or_else(|| "unknown".to_string(), |processors| processors.to_string());
+        writeln!(
+            f,
+            "caesium-clt {} ({}, {} processors)",
+            self.version, self.arch, processors
+        )?;
+        let encoders: Vec<String> = self
+            .encoders
+            .iter()
+            .map(|(format, encoder)| format!("{format} {encoder}"))
+            .collect();
+        writeln!(f, "Encoders: {}", encoders.join(", "))?;
+        match &self.simd_features {
+            Some(features) if !features.is_empty() => writeln!(f, "CPU features: {}", features.join(", "))?,
+            Some(_) => writeln!(f, "CPU features: none")?,
+            None => writeln!(f, "CPU features: unknown")?,
+        }
+        write!(f, "mozjpeg SIMD: {}", self.mozjpeg_simd().unwrap_or("none"))
+    }
+}
+
+pub fn probe_capabilities() -> Capabilities {
+    Capabilities {
+        version: env!("CARGO_PKG_VERSION"),
+        arch: std::env::consts::ARCH,
+        processors: available_parallelism().ok().map(|processors| processors.get()),
+        encoders: ENCODERS,
+        simd_features: detect_simd_features(),
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn detect_simd_features() -> Option<Vec<&'static str>> {
+    let features = [
+        ("AVX-512", is_x86_feature_detected!("avx512f")),
+        ("AVX2", is_x86_feature_detected!("avx2")),
+        ("AVX", is_x86_feature_detected!("avx")),
+        ("SSE4.2", is_x86_feature_detected!("sse4.2")),
+        ("SSE4.1", is_x86_feature_detected!("sse4.1")),
+        ("SSSE3", is_x86_feature_detected!("ssse3")),
+        ("SSE2", is_x86_feature_detected!("sse2")),
+    ];
+    Some(
+        features
+            .into_iter()
+            .filter(|(_, detected)| *detected)
+            .map(|(name, _)| name)
+            .collect(),
+    )
+}
+
+#[cfg(target_arch = "aarch64")]
+fn detect_simd_features() -> Option<Vec<&'static str>> {
+    let features = [
+        ("SVE", std::arch::is_aarch64_feature_detected!("sve")),
+        ("NEON", std::arch::is_aarch64_feature_detected!("neon")),
+    ];
+    Some(
+        features
+            .into_iter()
+            .filter(|(_, detected)| *detected)
+            .map(|(name, _)| name)
+            .collect(),
+    )
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+fn detect_simd_features() -> Option<Vec<&'static str>> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_probe_capabilities() {
+        let capabilities = probe_capabilities();
+        assert_eq!(capabilities.version, env!("CARGO_PKG_VERSION"));
+        assert!(!capabilities.arch.is_empty());
+        assert!(capabilities.encoders.iter().any(|(format, _)| *format == "JPEG"));
+        if cfg!(target_arch = "x86_64") {
+            // SSE2 is part of the x86_64 baseline
+            assert!(capabilities.simd_features.as_ref().unwrap().contains(&"SSE2"));
+            assert!(capabilities.mozjpeg_simd().is_some());
+        }
+
+        let report = capabilities.to_string();
+        assert!(report.starts_with(&format!("caesium-clt {}", env!("CARGO_PKG_VERSION"))));
+        assert!(report.contains("Encoders: JPEG mozjpeg"));
+        assert!(report.contains("mozjpeg SIMD: "));
+    }
+
+    #[test]
+    fn test_mozjpeg_simd() {
+        let mut capabilities = probe_capabilities();
+        capabilities.simd_features = Some(vec!["AVX2", "AVX", "SSE2"]);
+        assert_eq!(capabilities.mozjpeg_simd(), Some("AVX2"));
+        capabilities.simd_features = Some(vec!["SSE4.1", "SSE2"]);
+        assert_eq!(capabilities.mozjpeg_simd(), Some("SSE2"));
+        capabilities.simd_features = Some(vec![]);
+        assert_eq!(capabilities.mozjpeg_simd(), None);
+        capabilities.simd_features = None;
+        assert_eq!(capabilities.mozjpeg_simd(), None);
+        assert!(capabilities.to_string().contains("CPU features: unknown"));
+    }
+}
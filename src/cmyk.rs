@@ -0,0 +1,161 @@
+//! Conversion of the CMYK and YCCK JPEGs produced by print workflows to RGB.
+//!
+//! The encoders can't take four channel pixels, and decoding them as RGB inverts the colors, so they are converted
+//! to RGB first, following the Adobe APP14 marker when there is one.
+
+use std::error::Error;
+use std::io::Cursor;
+
+const JPEG_APP14: u8 = 0xEE;
+const JPEG_SOS: u8 = 0xDA;
+const JPEG_EOI: u8 = 0xD9;
+
+/// How the four channels of a JPEG are stored
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CmykEncoding {
+    /// Plain CMYK, without an Adobe marker: 0 means no ink
+    Cmyk,
+    /// CMYK written by Adobe applications, stored inverted: 255 means no ink
+    AdobeCmyk,
+    /// Inverted CMYK whose first three channels are stored as YCbCr (Adobe transform 2)
+    Ycck,
+}
+
+/// How the channels of `buffer` are stored, `None` when it is not a four channel JPEG
+pub fn detect_cmyk_jpeg(buffer: &[u8]) -> Option<CmykEncoding> {
+    if !buffer.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+
+    let mut adobe_transform = None;
+    let mut position = 2;
+    while position + 4 <= buffer.len() && buffer[position] == 0xFF {
+        let marker = buffer[position + 1];
+        // Start of scan or end of image: the frame header should have been found already
+        if marker == JPEG_SOS || marker == JPEG_EOI {
+            return None;
+        }
+        let length = u16::from_be_bytes([buffer[position + 2], buffer[position + 3]]) as usize;
+        let segment = buffer.get(position + 4..position + 2 + length)?;
+        if marker == JPEG_APP14 && segment.len() >= 12 && segment.starts_with(b"Adobe") {
+            adobe_transform = Some(segment[11]);
+        }
+        if is_start_of_frame(marker) {
+            // Precision, height and width come before the number of components
+            return match (segment.get(5)?, adobe_transform) {
+                (4, None) => Some(CmykEncoding::Cmyk),
+                (4, Some(2)) => Some(CmykEncoding::Ycck),
+                (4, Some(_)) => Some(CmykEncoding::AdobeCmyk),
+                _ => None,
+            };
+        }
+        position += 2 + length;
+    }
+
+    None
+}
+
+/// Decodes a four channel JPEG and returns its pixels converted to RGB, encoded as a PNG without any metadata
+pub fn cmyk_jpeg_to_png(buffer: &[u8], encoding: CmykEncoding) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut decoder = jpeg_decoder::Decoder::new(Cursor::new(buffer));
+    // The channels are converted here, as the decoder guesses the encoding differently
+    decoder.set_color_transform(jpeg_decoder::ColorTransform::None);
+    let samples = decoder.decode()?;
+    let info = decoder.info().ok_or("Missing JPEG header")?;
+    if info.pixel_format != jpeg_decoder::PixelFormat::CMYK32 {
+        return Err("Not a CMYK JPEG".into());
+    }
+
+    let pixels = samples
+        .chunks_exact(4)
+        .flat_map(|pixel| cmyk_to_rgb([pixel[0], pixel[1], pixel[2], pixel[3]], encoding))
+        .collect();
+    let image =
+        image::RgbImage::from_raw(info.width as u32, info.height as u32, pixels).ok_or("Truncated CMYK pixels")?;
+    let mut output = Vec::new();
+    image.write_to(&mut Cursor::new(&mut output), image::ImageFormat::Png)?;
+    Ok(output)
+}
+
+fn cmyk_to_rgb(pixel: [u8; 4], encoding: CmykEncoding) -> [u8; 3] {
+    let [c, m, y, k] = match encoding {
+        CmykEncoding::Cmyk => pixel.map(|ink| 255 - ink),
+        CmykEncoding::AdobeCmyk => pixel,
+        CmykEncoding::Ycck => {
+            let [r, g, b] = ycbcr_to_rgb(pixel[0], pixel[1], pixel[2]);
+            [255 - r, 255 - g, 255 - b, pixel[3]]
+        }
+    };
+    // Channels without ink are 255 by now
+    [c, m, y].map(|channel| (channel as u16 * k as u16 / 255) as u8)
+}
+
+fn ycbcr_to_rgb(y: u8, cb: u8, cr: u8) -> [u8; 3] {
+    let (y, cb, cr) = (y as f32, cb as f32 - 128.0, cr as f32 - 128.0);
+    [y + 1.402 * cr, y - 0.344_136 * cb - 0.714_136 * cr, y + 1.772 * cb]
+        .map(|channel| channel.round().clamp(0.0, 255.0) as u8)
+}
+
+fn is_start_of_frame(marker: u8) -> bool {
+    // 0xC4, 0xC8 and 0xCC share the range but are the Huffman tables, a reserved marker and the arithmetic coding
+    (0xC0..=0xCF).contains(&marker) && !matches!(marker, 0xC4 | 0xC8 | 0xCC)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURE: &str = "tests/fixtures/cmyk.jpg";
+
+    #[test]
+    fn test_detect_cmyk_jpeg() {
+        let fixture = std::fs::read(FIXTURE).unwrap();
+        assert_eq!(detect_cmyk_jpeg(&fixture), Some(CmykEncoding::AdobeCmyk));
+
+        // Without its Adobe marker, or with the YCCK transform
+        let without_marker = [&fixture[..2], &fixture[18..]].concat();
+        assert_eq!(detect_cmyk_jpeg(&without_marker), Some(CmykEncoding::Cmyk));
+        let mut ycck = fixture.clone();
+        ycck[17] = 2;
+        assert_eq!(detect_cmyk_jpeg(&ycck), Some(CmykEncoding::Ycck));
+
+        assert_eq!(detect_cmyk_jpeg(&std::fs::read("samples/j0.JPG").unwrap()), None);
+        assert_eq!(detect_cmyk_jpeg(&std::fs::read("samples/p0.png").unwrap()), None);
+        assert_eq!(detect_cmyk_jpeg(&fixture[..20]), None);
+    }
+
+    #[test]
+    fn test_cmyk_to_rgb() {
+        // Red is full magenta and yellow ink
+        assert_eq!(cmyk_to_rgb([0, 255, 255, 0], CmykEncoding::Cmyk), [255, 0, 0]);
+        assert_eq!(cmyk_to_rgb([255, 0, 0, 255], CmykEncoding::AdobeCmyk), [255, 0, 0]);
+        assert_eq!(cmyk_to_rgb([0, 0, 0, 255], CmykEncoding::Cmyk), [0, 0, 0]);
+        assert_eq!(cmyk_to_rgb([255, 255, 255, 0], CmykEncoding::AdobeCmyk), [0, 0, 0]);
+        // The YCbCr channels hold the ink of the first three channels, black being no ink
+        assert_eq!(cmyk_to_rgb([0, 128, 128, 255], CmykEncoding::Ycck), [255, 255, 255]);
+        assert_eq!(cmyk_to_rgb([255, 128, 128, 255], CmykEncoding::Ycck), [0, 0, 0]);
+    }
+
+    #[test]
+    fn test_cmyk_jpeg_to_png() {
+        let fixture = std::fs::read(FIXTURE).unwrap();
+        let png = cmyk_jpeg_to_png(&fixture, CmykEncoding::AdobeCmyk).unwrap();
+        let image = image::load_from_memory(&png).unwrap();
+        assert_eq!(image.color(), image::ColorType::Rgb8);
+        // Red and green quadrants over blue and black ones, give or take the rounding of the IDCT
+        let image = image.to_rgb8();
+        let is_near = |x: u32, y: u32, expected: [u8; 3]| {
+            let pixel = image.get_pixel(x, y).0;
+            pixel
+                .iter()
+                .zip(expected)
+                .all(|(&channel, expected)| channel.abs_diff(expected) <= 2)
+        };
+        assert!(is_near(0, 0, [255, 0, 0]));
+        assert!(is_near(31, 0, [0, 255, 0]));
+        assert!(is_near(0, 31, [0, 0, 255]));
+        assert!(is_near(31, 31, [0, 0, 0]));
+
+        assert!(cmyk_jpeg_to_png(&std::fs::read("samples/j0.JPG").unwrap(), CmykEncoding::Cmyk).is_err());
+    }
+}
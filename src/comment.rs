@@ -0,0 +1,309 @@
+//! Writing text metadata, a comment and the software that produced the file, in JPEG (COM), PNG (tEXt) and WebP
+//! (XMP) files.
+
+use crate::dpi::{png_chunk, png_chunks, PNG_SIGNATURE};
+use std::error::Error;
+
+const JPEG_COM: u8 = 0xFE;
+const JPEG_MAX_SEGMENT_LENGTH: usize = 0xFFFF - 2;
+const WEBP_VP8X_XMP: u8 = 0x04;
+const WEBP_VP8X_ALPHA: u8 = 0x10;
+
+/// Text fields written by `add_text_metadata`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TextMetadata {
+    /// Name and version of the software, e.g. `caesium-clt 1.0.0`
+    pub software: Option<String>,
+    pub comment: Option<String>,
+}
+
+impl TextMetadata {
+    pub fn is_empty(&self) -> bool {
+        self.software.is_none() && self.comment.is_none()
+    }
+}
+
+/// Whether `add_text_metadata` can write in this format
+pub fn supports_text_metadata(mime_type: Option<&str>) -> bool {
+    matches!(mime_type, Some("image/jpeg" | "image/png" | "image/webp"))
+}
+
+/// Returns a copy of the image with the text fields added to its metadata.
+/// The other metadata and the pixels are left untouched.
+pub fn add_text_metadata(buffer: &[u8], text: &TextMetadata) -> Result<Vec<u8>, Box<dyn Error>> {
+    match infer::get(buffer).map(|t| t.mime_type()) {
+        Some("image/jpeg") => add_jpeg_comments(buffer, text),
+        Some("image/png") => add_png_text(buffer, text),
+        Some("image/webp") => add_webp_xmp(buffer, text),
+        _ => Err("Unsupported format for text metadata".into()),
+    }
+}
+
+/// Adds one COM segment per field, after the APPn segments so that JFIF and Exif stay first
+fn add_jpeg_comments(buffer: &[u8], text: &TextMetadata) -> Result<Vec<u8>, Box<dyn Error>> {
+    if !buffer.starts_with(&[0xFF, 0xD8]) {
+        return Err("Invalid JPEG data".into());
+    }
+
+    let mut position = 2;
+    while buffer.get(position) == Some(&0xFF) && buffer.get(position + 1).is_some_and(|m| (0xE0..=0xEF).contains(m)) {
+        let length = buffer
+            .get(position + 2..position + 4)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]) as usize)
+            .ok_or("Truncated JPEG segment")?;
+        position += 2 + length;
+    }
+    if position > buffer.len() {
+        return Err("Truncated JPEG segment".into());
+    }
+
+    let software = text.software.as_ref().map(|software| format!("Software: {software}"));
+    let mut segments = vec![];
+    for comment in software.iter().chain(&text.comment) {
+        if comment.len() > JPEG_MAX_SEGMENT_LENGTH - 2 {
+            return Err("Comment too long for a JPEG segment".into());
+        }
+        segments.extend_from_slice(&[0xFF, JPEG_COM]);
+        segments.extend_from_slice(&((comment.len() + 2) as u16).to_be_bytes());
+        segments.extend_from_slice(comment.as_bytes());
+    }
+
+    Ok([&buffer[..position], &segments, &buffer[position..]].concat())
+}
+
+/// Adds a `Software` and a `Comment` text chunk before the image data.
+/// Text that is not ASCII goes in an iTXt chunk, as tEXt is limited to Latin-1.
+fn add_png_text(buffer: &[u8], text: &TextMetadata) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut text_chunks = vec![];
+    for (keyword, value) in [("Software", &text.software), ("Comment", &text.comment)] {
+        let Some(value) = value else {
+            continue;
+        };
+        let chunk = if value.is_ascii() {
+            png_chunk(b"tEXt", &[keyword.as_bytes(), b"\0", value.as_bytes()].concat())
+        } else {
+            // Uncompressed, without language tag nor translated keyword
+            png_chunk(b"iTXt", &[keyword.as_bytes(), b"\0\0\0\0\0", value.as_bytes()].concat())
+        };
+        text_chunks.extend_from_slice(&chunk);
+    }
+
+    let mut output = Vec::with_capacity(buffer.len() + text_chunks.len());
+    output.extend_from_slice(PNG_SIGNATURE);
+    let mut inserted = false;
+    for (start, end, kind) in png_chunks(buffer)? {
+        if kind == b"IDAT" && !inserted {
+            output.extend_from_slice(&text_chunks);
+            inserted = true;
+        }
+        output.extend_from_slice(&buffer[start..end]);
+    }
+
+    if !inserted {
+        return Err("PNG without image data".into());
+    }
+    Ok(output)
+}
+
+/// Start, end and FourCC of every chunk of the RIFF container
+pub(crate) fn webp_chunks(buffer: &[u8]) -> Result<Vec<(usize, usize, &[u8])>, Box<dyn Error>> {
+    if buffer.len() < 12 || &buffer[0..4] != b"RIFF" || &buffer[8..12] != b"WEBP" {
+        return Err("Invalid WebP data".into());
+    }
+
+    let mut chunks = vec![];
+    let mut position = 12;
+    while position + 8 <= buffer.len() {
+        let size = u32::from_le_bytes(buffer[position + 4..position + 8].try_into()?) as usize;
+        // Chunks are padded to an even size
+        let end = position + 8 + size + size % 2;
+        if end > buffer.len() {
+            return Err("Truncated WebP chunk".into());
+        }
+        chunks.push((position, end, &buffer[position..position + 4]));
+        position = end;
+    }
+
+    Ok(chunks)
+}
+
+/// Canvas size and alpha of a simple (lossy or lossless) WebP, read from its image chunk
+fn webp_canvas(buffer: &[u8], start: usize, kind: &[u8]) -> Result<(u32, u32, bool), Box<dyn Error>> {
+    let data = &buffer[start + 8..];
+    match kind {
+        b"VP8 " if data.len() >= 10 && data[3..6] == [0x9D, 0x01, 0x2A] => {
+            let width = u16::from_le_bytes([data[6], data[7]]) & 0x3FFF;
+            let height = u16::from_le_bytes([data[8], data[9]]) & 0x3FFF;
+            Ok((width as u32, height as u32, false))
+        }
+        b"VP8L" if data.len() >= 5 && data[0] == 0x2F => {
+            let bits = u32::from_le_bytes([data[1], data[2], data[3], data[4]]);
+            Ok(((bits & 0x3FFF) + 1, ((bits >> 14) & 0x3FFF) + 1, (bits >> 28) & 1 == 1))
+        }
+        _ => Err("Invalid WebP image data".into()),
+    }
+}
+
+/// Writes the fields in an XMP chunk, replacing the existing one.
+/// A simple WebP is converted to the extended format, the only one that can hold metadata.
+fn add_webp_xmp(buffer: &[u8], text: &TextMetadata) -> Result<Vec<u8>, Box<dyn Error>> {
+    let chunks = webp_chunks(buffer)?;
+    let Some(&(first_start, first_end, first_kind)) = chunks.first() else {
+        return Err("WebP without image data".into());
+    };
+
+    let mut output = Vec::with_capacity(buffer.len() + 1024);
+    output.extend_from_slice(&buffer[..12]);
+    if first_kind == b"VP8X" {
+        output.extend_from_slice(&buffer[first_start..first_end]);
+        output[20] |= WEBP_VP8X_XMP;
+    } else {
+        let (width, height, alpha) = webp_canvas(buffer, first_start, first_kind)?;
+        let mut data = vec![WEBP_VP8X_XMP | if alpha { WEBP_VP8X_ALPHA } else { 0 }, 0, 0, 0];
+        data.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+        data.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+        output.extend_from_slice(&webp_chunk(b"VP8X", &data));
+        output.extend_from_slice(&buffer[first_start..first_end]);
+    }
+    for &(start, end, kind) in &chunks[1..] {
+        if kind != b"XMP " {
+            output.extend_from_slice(&buffer[start..end]);
+        }
+    }
+    output.extend_from_slice(&webp_chunk(b"XMP ", xmp_packet(text).as_bytes()));
+
+    let riff_size = u32::try_from(output.len() - 8)?;
+    output[4..8].copy_from_slice(&riff_size.to_le_bytes());
+    Ok(output)
+}
+
+fn webp_chunk(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut chunk = Vec::with_capacity(data.len() + 9);
+    chunk.extend_from_slice(kind);
+    chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    chunk.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        chunk.push(0);
+    }
+    chunk
+}
+
+/// Software as `xmp:CreatorTool` and comment as `dc:description`
+fn xmp_packet(text: &TextMetadata) -> String {
+    let mut properties = String::new();
+    if let Some(software) = &text.software {
+        properties.push_str(&format!("<xmp:CreatorTool>{}</xmp:CreatorTool>", escape_xml(software)));
+    }
+    if let Some(comment) = &text.comment {
+        properties.push_str(&format!(
+            "<dc:description><rdf:Alt><rdf:li xml:lang=\"x-default\">{}</rdf:li></rdf:Alt></dc:description>",
+            escape_xml(comment)
+        ));
+    }
+
+    format!(
+        "<?xpacket begin=\"\u{FEFF}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>\
+         <x:xmpmeta xmlns:x=\"adobe:ns:meta/\">\
+         <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\
+         <rdf:Description rdf:about=\"\" xmlns:xmp=\"http://ns.adobe.com/xap/1.0/\" \
+         xmlns:dc=\"http://purl.org/dc/elements/1.1/\">{properties}</rdf:Description>\
+         </rdf:RDF></x:xmpmeta><?xpacket end=\"w\"?>"
+    )
+}
+
+fn escape_xml(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageFormat, RgbImage};
+    use std::io::Cursor;
+
+    fn encode(format: ImageFormat) -> Vec<u8> {
+        let mut bytes = vec![];
+        RgbImage::from_fn(16, 8, |x, y| image::Rgb([x as u8 * 16, y as u8 * 32, 128]))
+            .write_to(&mut Cursor::new(&mut bytes), format)
+            .unwrap();
+        bytes
+    }
+
+    fn contains(buffer: &[u8], text: &[u8]) -> bool {
+        buffer.windows(text.len()).any(|window| window == text)
+    }
+
+    fn test_text() -> TextMetadata {
+        TextMetadata {
+            software: Some("caesium-clt 1.0.0".to_string()),
+            comment: Some("batch 2024-06".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_add_text_metadata() {
+        for format in [ImageFormat::Jpeg, ImageFormat::Png, ImageFormat::WebP] {
+            let output = add_text_metadata(&encode(format), &test_text()).unwrap();
+            assert!(contains(&output, b"caesium-clt 1.0.0"), "{format:?}");
+            assert!(contains(&output, b"batch 2024-06"), "{format:?}");
+
+            // The image is still valid and unchanged
+            let decoded = image::load_from_memory(&output).unwrap();
+            assert_eq!((decoded.width(), decoded.height()), (16, 8), "{format:?}");
+        }
+    }
+
+    #[test]
+    fn test_jpeg_comment_segments() {
+        let jpeg = encode(ImageFormat::Jpeg);
+        let output = add_text_metadata(&jpeg, &test_text()).unwrap();
+        assert!(contains(&output, b"\xFF\xFE\x00\x1DSoftware: caesium-clt 1.0.0"));
+        assert!(contains(&output, b"\xFF\xFE\x00\x0Fbatch 2024-06"));
+        assert_eq!(output.len(), jpeg.len() + 31 + 17);
+    }
+
+    #[test]
+    fn test_png_text_chunks() {
+        let text = TextMetadata {
+            software: None,
+            comment: Some("lot n°2".to_string()),
+        };
+        let output = add_text_metadata(&encode(ImageFormat::Png), &text).unwrap();
+        let chunks = png_chunks(&output).unwrap();
+        let kinds: Vec<&[u8]> = chunks.iter().map(|(_, _, kind)| *kind).collect();
+        let itxt = kinds.iter().position(|kind| *kind == b"iTXt").unwrap();
+        assert!(itxt < kinds.iter().position(|kind| *kind == b"IDAT").unwrap());
+        assert!(!kinds.contains(&&b"tEXt"[..]));
+        assert!(contains(&output, "Comment\0\0\0\0\0lot n°2".as_bytes()));
+    }
+
+    #[test]
+    fn test_webp_xmp_replaces_previous() {
+        let first = add_text_metadata(&encode(ImageFormat::WebP), &test_text()).unwrap();
+        let text = TextMetadata {
+            software: None,
+            comment: Some("<second>".to_string()),
+        };
+        let output = add_text_metadata(&first, &text).unwrap();
+        let chunks = webp_chunks(&output).unwrap();
+        assert_eq!(chunks.iter().filter(|(_, _, kind)| *kind == b"XMP ").count(), 1);
+        assert_eq!(chunks[0].2, b"VP8X");
+        assert!(contains(&output, b"&lt;second&gt;"));
+        assert!(!contains(&output, b"batch 2024-06"));
+        assert_eq!(
+            u32::from_le_bytes(output[4..8].try_into().unwrap()) as usize,
+            output.len() - 8
+        );
+    }
+
+    #[test]
+    fn test_unsupported_formats() {
+        assert!(add_text_metadata(&encode(ImageFormat::Gif), &test_text()).is_err());
+        assert!(!supports_text_metadata(Some("image/tiff")));
+        assert!(supports_text_metadata(Some("image/webp")));
+    }
+}
@@ -1,20 +1,50 @@
-use crate::options::{MinSavingsThreshold, OutputFormat, OverwritePolicy};
+use crate::animation::{
+    decode_apng_animation, decode_gif_animation, encode_webp_animation, is_apng, optimize_apng, Animation,
+};
+use crate::archive::ZipOutput;
+use crate::cmyk::{cmyk_jpeg_to_png, detect_cmyk_jpeg};
+use crate::comment::{add_text_metadata, supports_text_metadata, webp_chunks, TextMetadata};
+use crate::dpi::{read_dpi, set_dpi, supports_dpi};
+use crate::gif_palette::{gif_colors, optimize_gif};
+use crate::heic::{decode_heic, is_heic};
+use crate::legacy_formats::{detect_legacy_format, legacy_to_png, LegacyFormat};
+use crate::memory_budget::{estimate_decode_memory, MemoryBudget};
+use crate::metadata::{extract_exif, extract_icc_profile, insert_exif, insert_icc_profile, strip_exif_tags};
+use crate::options::{
+    CollisionPolicy, ExtensionCase, FitMode, Flip, Gravity, MinSavingsThreshold, NumberFormat, OutputFormat,
+    OverwritePolicy, ResizeFilter, TiffCompression,
+};
+use crate::pdf::{is_pdf, recompress_pdf_images};
+use crate::quality_rules::{quality_for, QualityRule};
+use crate::raw_preview::{extract_raw_preview, is_raw_file};
+use crate::rename_map::RenameMap;
+use crate::similarity::{decode_luma, ssim_against};
+use crate::svg::{is_svg, minify_svg, svg_precision};
+use crate::tiff_pages::{recompress_tiff_pages, tiff_page_count};
+use crate::tiff_stream::TiffStream;
 use serde::Serialize;
+use std::borrow::Cow;
 // use crate::scan_files::get_file_mime_type;
 use caesium::parameters::{CSParameters, ChromaSubsampling};
 use caesium::{compress_in_memory, compress_to_size_in_memory, convert_in_memory, SupportedFileTypes};
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use rayon::iter::ParallelIterator;
+use image::imageops::FilterType;
+use log::{debug, trace};
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
 use rayon::prelude::IntoParallelRefIterator;
+use std::cell::OnceCell;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::ffi::OsString;
 use std::fmt::Display;
 use std::fs::{File, FileTimes, Metadata};
-use std::io::{Read, Write};
+use std::io::{BufWriter, Cursor, Read, Write};
 #[cfg(target_os = "windows")]
 use std::os::windows::fs::FileTimesExt;
-use std::path::{absolute, Path, PathBuf};
-use std::{fs, io};
+use std::path::{absolute, Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::{fs, io, thread};
 
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "lowercase")]
@@ -33,6 +63,38 @@ impl Display for CompressionStatus {
         }
     }
 }
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionErrorKind {
+    /// The input file could not be read
+    Read,
+    /// The input file looks like a supported image but its content could not be decoded
+    Decode,
+    /// The image could not be compressed or converted
+    Encode,
+    /// The output file or directory could not be written
+    Write,
+    /// The input file is not a supported image format
+    Unsupported,
+    /// The compression did not complete in the allowed time
+    Timeout,
+    /// Any other filesystem error
+    Io,
+    /// Another input is written to the same output path
+    Collision,
+}
+
+impl CompressionErrorKind {
+    /// I/O failures may be transient (e.g. on network shares), unlike errors caused by the file content
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            CompressionErrorKind::Read | CompressionErrorKind::Write | CompressionErrorKind::Io
+        )
+    }
+}
+
 #[derive(Debug, Serialize)]
 pub struct CompressionResult {
     pub original_path: String,
@@ -40,1070 +102,6937 @@ pub struct CompressionResult {
     pub original_size: u64,
     pub compressed_size: u64,
     pub status: CompressionStatus,
+    pub error_kind: Option<CompressionErrorKind>,
     pub message: String,
 }
 
+#[derive(Clone)]
 pub struct CompressionOptions {
     pub quality: Option<u32>,
-    pub max_size: Option<usize>,
+    /// Qualities replacing `quality` for the files matching a glob, relative to `base_path`. The first matching rule
+    /// wins.
+    pub quality_rules: Vec<QualityRule>,
+    /// Qualities replacing `quality` for the outputs of a format. The quality rules take precedence.
+    pub format_qualities: Vec<(OutputFormat, u32)>,
+    pub max_size: Option<u64>,
+    pub target_ssim: Option<f64>,
+    /// Bits per pixel of the output, reached by searching the quality of each file
+    pub bpp_target: Option<f64>,
+    /// Maximum size of all the outputs together, shared between the files as their `max_size`
+    pub total_budget: Option<u64>,
+    pub max_iterations: u32,
+    /// Percentage under `max_size` at which the search stops. When this or `max_size_iterations` is set, the quality
+    /// is searched here instead of by libcaesium.
+    pub max_size_tolerance: Option<f64>,
+    /// Maximum number of encodings tried per file with `max_size`
+    pub max_size_iterations: Option<u32>,
     pub lossless: bool,
+    /// Re-encode each file losslessly in its own format, keeping the quality of lossy JPEG files. Lossy WebP files are
+    /// skipped, as they can't be re-encoded without changing their pixels.
+    pub optimize: bool,
+    /// Near-lossless preprocessing level of the WebP outputs [0-100], lower values give smaller files
+    pub webp_near_lossless: Option<u8>,
+    /// Effort of the WebP encoder [0-6], higher values give smaller files. libwebp's default when not set.
+    pub webp_effort: Option<u8>,
     pub exif: bool,
     pub png_opt_level: u8,
     pub zopfli: bool,
+    /// Speed of the AVIF encoder [1-10], lower values give smaller files
+    pub avif_speed: u8,
+    /// Compression used inside the TIFF outputs
+    pub tiff_compression: TiffCompression,
+    /// Compress the largest JPEG preview embedded in the RAW files, see `raw_preview::RAW_EXTENSIONS`
+    pub raw_preview: bool,
     pub width: Option<u32>,
     pub height: Option<u32>,
     pub long_edge: Option<u32>,
     pub short_edge: Option<u32>,
+    pub fit: Option<(u32, u32)>,
+    /// How the image is fitted to the box of `width` and `height`
+    pub fit_mode: Option<FitMode>,
+    /// Color around the image with `FitMode::Pad`, as RGBA
+    pub pad_color: [u8; 4],
+    /// Percentage of the input dimensions the output is scaled down to
+    pub scale: Option<f64>,
+    /// Resolution written in the output metadata, in dots per inch
+    pub dpi: Option<u32>,
+    /// Resize to keep the physical size of the input at `dpi`
+    pub resample_dpi: bool,
+    /// Text written in the output metadata, kept even when the other metadata is stripped
+    pub comment: Option<String>,
+    /// Write the name and version of caesium-clt in the output metadata
+    pub software_tag: bool,
     pub output_folder: Option<PathBuf>,
+    pub output_zip: Option<Arc<ZipOutput>>,
     pub same_folder_as_input: bool,
     pub base_path: PathBuf,
     pub suffix: Option<String>,
     pub overwrite_policy: OverwritePolicy,
+    pub overwrite_prompt: Option<Arc<OverwritePrompt>>,
+    pub collision_policy: CollisionPolicy,
+    pub trash_original: bool,
+    pub dedupe: bool,
+    /// Skip the inputs whose output is at least as recent as them
+    pub newer: bool,
+    /// Read the outputs back to check that they match what has been written
+    pub verify: bool,
+    pub retries: u32,
     pub format: OutputFormat,
+    pub extension_case: ExtensionCase,
+    /// Per input format conversions, used instead of `format` when not empty
+    pub format_conversions: Vec<(OutputFormat, OutputFormat)>,
     pub keep_dates: bool,
     pub keep_structure: bool,
+    /// Names the outputs with sequential numbers, in sorted input order, instead of the input names
+    pub flatten: Option<NumberFormat>,
+    /// Unix permissions of the written files
+    pub file_mode: Option<u32>,
+    /// Unix permissions of the created output directories
+    pub dir_mode: Option<u32>,
     pub jpeg_chroma_subsampling: ChromaSubsampling,
     pub jpeg_baseline: bool,
     pub no_upscale: bool,
+    /// Filter of the resizing. libcaesium only resizes with Lanczos3, the other filters are applied before encoding.
+    pub resize_filter: ResizeFilter,
+    /// Clockwise rotation in degrees, applied to the pixels before resizing
+    pub rotate: Option<u16>,
+    /// Applied to the pixels after the rotation
+    pub flip: Option<Flip>,
+    /// Size of the region kept after the flip, before resizing
+    pub crop: Option<(u32, u32)>,
+    /// Where the `crop` region is placed in the image
+    pub gravity: Gravity,
     pub strip_icc: bool,
+    pub strip_tags: Vec<String>,
     pub min_savings: Option<MinSavingsThreshold>,
+    pub timeout: Option<Duration>,
+    /// When the file being compressed times out, set from `timeout` as its compression starts. Checked between the
+    /// steps of the compression, which stops at the next one once it has passed.
+    pub deadline: Option<Instant>,
+    /// Stop compressing as soon as a file fails, the remaining ones are skipped
+    pub fail_fast: bool,
+    /// Shared by all the workers to cap the memory of the images decoded at the same time
+    pub memory_budget: Option<Arc<MemoryBudget>>,
+    /// Output names replacing the ones derived from the inputs, see `load_rename_map`
+    pub rename_map: Option<Arc<RenameMap>>,
+    /// Outputs written for each input instead of a single one, each overriding some of these options
+    pub variants: Vec<Variant>,
 }
 
-const MAX_FILE_SIZE: u64 = 500 * 1024 * 1024;
-
-pub fn start_compression(
-    input_files: &[PathBuf],
-    options: &CompressionOptions,
-    multi_progress: &MultiProgress,
-    progress_bar: &ProgressBar,
-    dry_run: bool,
-) -> Vec<CompressionResult> {
-    input_files
-        .par_iter()
-        .map(|input_file| {
-            let spinner = multi_progress.add(ProgressBar::new_spinner());
-            spinner.set_style(
-                ProgressStyle::default_spinner()
-                    .tick_strings(&["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏"])
-                    .template("{spinner:.cyan} {msg}")
-                    .unwrap_or(ProgressStyle::default_spinner()),
-            );
-            spinner.set_message(format!("{}", input_file.display()));
-            spinner.enable_steady_tick(std::time::Duration::from_millis(100));
-
-            let result = perform_compression(input_file, options, dry_run);
-
-            spinner.finish_and_clear();
-            progress_bar.inc(1);
-            result
-        })
-        .collect()
+/// One of the outputs written for each input, see `CompressionOptions::variants`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Variant {
+    /// Appended to the output file name, after the suffix of the options
+    pub suffix: String,
+    pub quality: Option<u32>,
+    /// Replaces the other resize options, and is never upscaled
+    pub long_edge: Option<u32>,
+    /// Replaces the other resize options, and is never upscaled
+    pub width: Option<u32>,
 }
 
-fn perform_compression(input_file: &PathBuf, options: &CompressionOptions, dry_run: bool) -> CompressionResult {
-    let mut compression_result = CompressionResult {
-        original_path: input_file.display().to_string(),
-        output_path: String::new(),
-        original_size: 0,
-        compressed_size: 0,
-        status: CompressionStatus::Error,
-        message: String::new(),
-    };
+impl Variant {
+    /// The options writing this variant. Its quality replaces the quality rules and the qualities of the formats.
+    fn apply(&self, options: &CompressionOptions) -> CompressionOptions {
+        let mut variant_options = CompressionOptions {
+            quality: self.quality.or(options.quality),
+            quality_rules: match self.quality {
+                Some(_) => vec![],
+                None => options.quality_rules.clone(),
+            },
+            format_qualities: match self.quality {
+                Some(_) => vec![],
+                None => options.format_qualities.clone(),
+            },
+            suffix: Some(format!(
+                "{}{}",
+                options.suffix.as_deref().unwrap_or_default(),
+                self.suffix
+            )),
+            variants: vec![],
+            ..options.clone()
+        };
+        if self.long_edge.is_some() || self.width.is_some() {
+            variant_options.width = self.width;
+            variant_options.height = None;
+            variant_options.long_edge = self.long_edge;
+            variant_options.short_edge = None;
+            variant_options.fit = None;
+            variant_options.fit_mode = None;
+            variant_options.scale = None;
+            variant_options.resample_dpi = false;
+            variant_options.no_upscale = true;
+        }
+        variant_options
+    }
+}
 
-    let input_file_metadata = match input_file.metadata() {
-        Ok(metadata) => metadata,
-        Err(_) => {
-            compression_result.message = "Error reading file metadata".to_string();
-            return compression_result;
+/// Same defaults as the command line
+impl Default for CompressionOptions {
+    fn default() -> Self {
+        Self {
+            quality: None,
+            quality_rules: vec![],
+            format_qualities: vec![],
+            max_size: None,
+            target_ssim: None,
+            bpp_target: None,
+            total_budget: None,
+            max_iterations: 8,
+            max_size_tolerance: None,
+            max_size_iterations: None,
+            lossless: false,
+            optimize: false,
+            webp_near_lossless: None,
+            webp_effort: None,
+            exif: false,
+            png_opt_level: 3,
+            zopfli: false,
+            avif_speed: 6,
+            tiff_compression: TiffCompression::Deflate,
+            raw_preview: false,
+            width: None,
+            height: None,
+            long_edge: None,
+            short_edge: None,
+            fit: None,
+            fit_mode: None,
+            pad_color: [0, 0, 0, 255],
+            scale: None,
+            dpi: None,
+            resample_dpi: false,
+            comment: None,
+            software_tag: false,
+            output_folder: None,
+            output_zip: None,
+            same_folder_as_input: false,
+            base_path: PathBuf::new(),
+            suffix: None,
+            overwrite_policy: OverwritePolicy::All,
+            overwrite_prompt: None,
+            collision_policy: CollisionPolicy::Rename,
+            trash_original: false,
+            dedupe: false,
+            newer: false,
+            verify: false,
+            retries: 0,
+            format: OutputFormat::Original,
+            extension_case: ExtensionCase::Lower,
+            format_conversions: vec![],
+            keep_dates: false,
+            keep_structure: false,
+            flatten: None,
+            file_mode: None,
+            dir_mode: None,
+            jpeg_chroma_subsampling: ChromaSubsampling::Auto,
+            jpeg_baseline: false,
+            no_upscale: false,
+            resize_filter: ResizeFilter::Lanczos3,
+            rotate: None,
+            flip: None,
+            crop: None,
+            gravity: Gravity::Center,
+            strip_icc: false,
+            strip_tags: vec![],
+            min_savings: None,
+            timeout: None,
+            deadline: None,
+            fail_fast: false,
+            memory_budget: None,
+            rename_map: None,
+            variants: vec![],
         }
-    };
+    }
+}
 
-    let original_file_size = input_file_metadata.len();
+const MAX_FILE_SIZE: u64 = 500 * 1024 * 1024;
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(100);
+const DEFAULT_QUALITY: u32 = 80;
 
-    if original_file_size > MAX_FILE_SIZE {
-        compression_result.message = "File exceeds 500Mb, skipping.".to_string();
-        compression_result.status = CompressionStatus::Skipped;
-        return compression_result;
-    }
+/// Progress notifications sent while compressing
+pub enum ProgressEvent<'a> {
+    /// The file is about to be compressed
+    Started { path: &'a Path },
+    /// The file has been processed, whatever the outcome
+    Finished {
+        path: &'a Path,
+        result: &'a CompressionResult,
+        /// Sizes of all the files processed so far, this one included
+        totals: &'a RunningTotals,
+    },
+}
 
-    compression_result.original_size = original_file_size;
+/// Running sum of the original and compressed sizes, and count of the failed files, updated by the workers as each
+/// file finishes. Once every file is processed, it matches the sum over the returned results.
+#[derive(Default, Debug)]
+pub struct RunningTotals {
+    original_size: AtomicU64,
+    compressed_size: AtomicU64,
+    errors: AtomicU64,
+}
 
-    let output_full_path = match setup_output_path(input_file, options, &mut compression_result, dry_run) {
-        Some(path) => path,
-        None => {
-            compression_result.message = "Error setting up output path".to_string();
-            return compression_result;
+impl RunningTotals {
+    fn add(&self, result: &CompressionResult) {
+        self.original_size.fetch_add(result.original_size, Ordering::Relaxed);
+        self.compressed_size
+            .fetch_add(result.compressed_size, Ordering::Relaxed);
+        if matches!(result.status, CompressionStatus::Error) {
+            self.errors.fetch_add(1, Ordering::Relaxed);
         }
-    };
-    compression_result.output_path = output_full_path.display().to_string();
+    }
 
-    if skip_due_to_overwrite_policy(options, &output_full_path, original_file_size, &mut compression_result) {
-        return compression_result;
+    pub fn original_size(&self) -> u64 {
+        self.original_size.load(Ordering::Relaxed)
     }
 
-    if dry_run {
-        compression_result.status = CompressionStatus::Success;
-        compression_result.compressed_size = original_file_size;
-        return compression_result;
+    pub fn compressed_size(&self) -> u64 {
+        self.compressed_size.load(Ordering::Relaxed)
     }
 
-    let compressed_image = match perform_image_compression(input_file, options, &mut compression_result) {
-        Some(image) => image,
-        None => return compression_result,
-    };
+    pub fn errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+}
 
-    let output_file_size = compressed_image.len() as u64;
+/// Receives the progress events.
+/// It is called from the worker threads, but never concurrently: events are delivered one at a time.
+pub type ProgressCallback<'a> = &'a mut (dyn FnMut(ProgressEvent) + Send);
 
-    if skip_due_to_insufficient_savings(
-        options.min_savings,
-        original_file_size,
-        output_file_size,
-        &mut compression_result,
-    ) {
-        return compression_result;
+/// Answer to the question asked before overwriting an existing output with `OverwritePolicy::Ask`
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum OverwriteAnswer {
+    Yes,
+    No,
+    /// Overwrite this file and all the following ones without asking
+    All,
+    /// Keep this file and skip all the remaining ones
+    Quit,
+}
+
+impl OverwriteAnswer {
+    /// Reads a `y/N/a(ll)/q(uit)` reply, anything else meaning no
+    pub fn parse(reply: &str) -> Self {
+        match reply.trim().to_lowercase().as_str() {
+            "y" | "yes" => OverwriteAnswer::Yes,
+            "a" | "all" => OverwriteAnswer::All,
+            "q" | "quit" => OverwriteAnswer::Quit,
+            _ => OverwriteAnswer::No,
+        }
     }
+}
 
-    if skip_due_to_bigger_policy(
-        options,
-        &output_full_path,
-        output_file_size,
-        original_file_size,
-        &mut compression_result,
-    ) {
-        return compression_result;
+/// Asks before overwriting existing outputs with `OverwritePolicy::Ask`.
+/// Questions are asked one at a time, even from several worker threads, and the answers `All` and `Quit`
+/// apply to the rest of the run.
+pub struct OverwritePrompt {
+    ask: Box<dyn Fn(&Path) -> OverwriteAnswer + Send + Sync>,
+    remembered: Mutex<Option<OverwriteAnswer>>,
+}
+
+impl OverwritePrompt {
+    pub fn new(ask: impl Fn(&Path) -> OverwriteAnswer + Send + Sync + 'static) -> Self {
+        Self {
+            ask: Box::new(ask),
+            remembered: Mutex::new(None),
+        }
     }
 
-    if let Err(msg) = write_compressed_file(&output_full_path, &compressed_image, options, &input_file_metadata) {
-        compression_result.message = msg;
-        return compression_result;
+    /// Whether `path` can be overwritten: returns `Yes`, `No` or `Quit`, only asking when no previous answer applies
+    pub fn confirm(&self, path: &Path) -> OverwriteAnswer {
+        // The lock is held while asking, so that the other workers wait for the answer
+        let mut remembered = self.remembered.lock().unwrap_or_else(|e| e.into_inner());
+        match *remembered {
+            Some(OverwriteAnswer::All) => return OverwriteAnswer::Yes,
+            Some(OverwriteAnswer::Quit) => return OverwriteAnswer::Quit,
+            _ => {}
+        }
+
+        match (self.ask)(path) {
+            OverwriteAnswer::All => {
+                *remembered = Some(OverwriteAnswer::All);
+                OverwriteAnswer::Yes
+            }
+            OverwriteAnswer::Quit => {
+                *remembered = Some(OverwriteAnswer::Quit);
+                OverwriteAnswer::Quit
+            }
+            answer => answer,
+        }
     }
 
-    compression_result.status = CompressionStatus::Success;
-    compression_result.compressed_size = output_file_size;
-    compression_result
+    pub fn is_aborted(&self) -> bool {
+        *self.remembered.lock().unwrap_or_else(|e| e.into_inner()) == Some(OverwriteAnswer::Quit)
+    }
 }
 
-fn is_resize_needed(options: &CompressionOptions) -> bool {
-    options.width.is_some() || options.height.is_some() || options.long_edge.is_some() || options.short_edge.is_some()
+/// Compresses the given files in parallel, without any terminal output.
+/// Results are returned in the same order as `input_files`.
+pub fn compress_files(
+    input_files: &[PathBuf],
+    options: &CompressionOptions,
+    on_progress: Option<ProgressCallback>,
+) -> Vec<CompressionResult> {
+    start_compression(input_files, options, false, on_progress)
 }
 
-fn setup_output_path(
-    input_file: &Path,
+/// Same as `compress_files`. With `dry_run`, only the output paths are computed and nothing is written.
+/// With variants, the results of each variant follow the ones of the previous variant.
+pub fn start_compression(
+    input_files: &[PathBuf],
     options: &CompressionOptions,
-    compression_result: &mut CompressionResult,
     dry_run: bool,
-) -> Option<PathBuf> {
-    let output_directory = determine_output_directory(input_file, options, compression_result)?;
-    let (output_directory, filename) = compute_output_full_path(
-        output_directory,
-        input_file,
-        &options.base_path,
-        options.keep_structure,
-        options.suffix.as_ref().unwrap_or(&String::new()).as_ref(),
-        options.format,
-        options.same_folder_as_input || output_directory == options.base_path,
-    )?;
+    on_progress: Option<ProgressCallback>,
+) -> Vec<CompressionResult> {
+    let on_progress = on_progress.map(Mutex::new);
+    let notify = |event: ProgressEvent| {
+        if let Some(callback) = &on_progress {
+            // A callback that panicked keeps receiving the following events
+            let mut callback = callback.lock().unwrap_or_else(|e| e.into_inner());
+            (*callback)(event);
+        }
+    };
 
-    if dry_run {
-        return Some(output_directory.join(filename));
-    }
+    let totals = RunningTotals::default();
+    let results = compress_batch(input_files, options, dry_run, &notify, &totals);
 
-    if !output_directory.exists() && fs::create_dir_all(&output_directory).is_err() {
-        compression_result.message = "Error creating output directory".to_string();
-        return None;
+    // Directory times can only be restored once every file has been written into them
+    if options.keep_dates && options.keep_structure && !options.same_folder_as_input && !dry_run {
+        if let Some(output_folder) = &options.output_folder {
+            preserve_directory_times(&results, output_folder);
+        }
     }
 
-    Some(output_directory.join(filename))
+    results
 }
 
-fn determine_output_directory<'a>(
-    input_file: &'a Path,
-    options: &'a CompressionOptions,
-    compression_result: &mut CompressionResult,
-) -> Option<&'a Path> {
-    if options.same_folder_as_input {
-        match input_file.parent() {
-            Some(p) => Some(p),
-            None => {
-                compression_result.message = "Error getting parent directory".to_string();
-                None
-            }
-        }
+/// Compresses every input once with `options`, or once per variant. All the variants of an input are written by the
+/// same task, which reads and decodes the input once for all of them, see `SharedInput`.
+fn compress_batch(
+    input_files: &[PathBuf],
+    options: &CompressionOptions,
+    dry_run: bool,
+    notify: &(dyn Fn(ProgressEvent) + Sync),
+    totals: &RunningTotals,
+) -> Vec<CompressionResult> {
+    let variant_options: Vec<CompressionOptions> = if options.variants.is_empty() {
+        vec![options.clone()]
     } else {
-        match options.output_folder.as_ref() {
-            Some(p) => Some(p),
-            None => {
-                compression_result.message = "Error getting output directory".to_string();
-                None
+        options.variants.iter().map(|variant| variant.apply(options)).collect()
+    };
+    let budgets = options
+        .total_budget
+        .map(|total_budget| allocate_budget(input_files, total_budget));
+    let budgets = budgets.as_ref();
+    let compress = |input_file: &PathBuf,
+                    options: &CompressionOptions,
+                    collision: Option<&Collision>,
+                    shared_input: Option<&SharedInput>| {
+        notify(ProgressEvent::Started { path: input_file });
+        let file_options = budget_options(input_file, options, budgets);
+        // Files already being compressed when another one fails still finish
+        let result = if options.fail_fast && totals.errors() > 0 {
+            aborted_result(input_file, "Aborted after an error")
+        } else {
+            retry_with_backoff(options.retries, RETRY_BASE_DELAY, || {
+                perform_compression(input_file, &file_options, collision, shared_input, dry_run)
+            })
+        };
+        totals.add(&result);
+        notify(ProgressEvent::Finished {
+            path: input_file,
+            result: &result,
+            totals,
+        });
+        result
+    };
+
+    debug!("Compressing {} files", input_files.len());
+    let duplicate_of = if options.dedupe {
+        find_duplicates(input_files)
+    } else {
+        vec![None; input_files.len()]
+    };
+    debug!(
+        "{} duplicates found",
+        duplicate_of.iter().filter(|duplicate| duplicate.is_some()).count()
+    );
+    // The output paths depend on the suffix of each variant
+    let collisions: Vec<Vec<Option<Collision>>> = variant_options
+        .iter()
+        .map(|options| match &options.flatten {
+            Some(number_format) => number_outputs(input_files, options, number_format),
+            None => resolve_collisions(input_files, options),
+        })
+        .collect();
+
+    // Duplicates are handled once the file they share their content with has been compressed
+    let unique_results: Vec<Option<Vec<CompressionResult>>> = (0..input_files.len())
+        .into_par_iter()
+        .map(|index| {
+            duplicate_of[index].is_none().then(|| {
+                let shared_input = (variant_options.len() > 1).then(SharedInput::default);
+                variant_options
+                    .iter()
+                    .enumerate()
+                    .map(|(variant, options)| {
+                        let collision = collisions[variant][index].as_ref();
+                        compress(&input_files[index], options, collision, shared_input.as_ref())
+                    })
+                    .collect()
+            })
+        })
+        .collect();
+    let duplicate_results: Vec<Option<Vec<CompressionResult>>> = (0..input_files.len())
+        .into_par_iter()
+        .map(|index| {
+            let original = duplicate_of[index]?;
+            let input_file = &input_files[index];
+            let original_file = input_files[original].as_path();
+            let original_results = unique_results[original].as_deref().unwrap_or_default();
+            let shared_input = (variant_options.len() > 1).then(SharedInput::default);
+            let mut results = vec![];
+            for (variant, options) in variant_options.iter().enumerate() {
+                let collision = collisions[variant][index].as_ref();
+                let file_options = budget_options(input_file, options, budgets);
+                results.push(match original_results.get(variant) {
+                    Some(original_result)
+                        if can_reuse_output(
+                            input_file,
+                            &file_options,
+                            original_file,
+                            &budget_options(original_file, options, budgets),
+                            original_result,
+                        ) =>
+                    {
+                        notify(ProgressEvent::Started { path: input_file });
+                        let result =
+                            reuse_duplicate_output(input_file, original_result, &file_options, collision, dry_run);
+                        totals.add(&result);
+                        notify(ProgressEvent::Finished {
+                            path: input_file,
+                            result: &result,
+                            totals,
+                        });
+                        result
+                    }
+                    _ => compress(input_file, options, collision, shared_input.as_ref()),
+                });
             }
+            Some(results)
+        })
+        .collect();
+
+    // Grouped by variant, in the input order
+    let mut results_by_variant: Vec<Vec<CompressionResult>> = vec![vec![]; variant_options.len()];
+    let input_results = unique_results
+        .into_iter()
+        .zip(duplicate_results)
+        .filter_map(|(unique_result, duplicate_result)| unique_result.or(duplicate_result));
+    for results in input_results {
+        for (variant_results, result) in results_by_variant.iter_mut().zip(results) {
+            variant_results.push(result);
         }
     }
+    results_by_variant.into_iter().flatten().collect()
 }
 
-fn skip_due_to_overwrite_policy(
-    options: &CompressionOptions,
-    output_path: &Path,
-    original_size: u64,
-    compression_result: &mut CompressionResult,
-) -> bool {
-    if options.overwrite_policy == OverwritePolicy::Never && output_path.exists() {
-        compression_result.status = CompressionStatus::Skipped;
-        compression_result.compressed_size = original_size;
-        compression_result.message = "File already exists, skipped due overwrite policy".to_string();
-        return true;
+/// The options of a file with its share of the total budget
+fn budget_options<'a>(
+    input_file: &Path,
+    options: &'a CompressionOptions,
+    budgets: Option<&HashMap<&Path, u64>>,
+) -> Cow<'a, CompressionOptions> {
+    match budgets.and_then(|budgets| budgets.get(input_file)) {
+        Some(&max_size) => Cow::Owned(CompressionOptions {
+            max_size: Some(max_size),
+            ..options.clone()
+        }),
+        None => Cow::Borrowed(options),
     }
-
-    false
 }
 
-fn get_file_mime_type_from_buffer(buffer: &[u8]) -> Option<String> {
-    match infer::get(buffer) {
-        Some(v) => Option::from(v.mime_type().to_string()),
-        None => None,
-    }
+/// An input written to several variants. It is read once, and decoded once for the variants that resize it, instead of
+/// once per variant.
+#[derive(Default)]
+struct SharedInput {
+    buffer: OnceCell<Vec<u8>>,
+    /// `None` when the image can't be decoded here, the variants are then compressed from the buffer as usual
+    pixels: OnceCell<Option<image::DynamicImage>>,
 }
 
-fn perform_image_compression(
-    input_file: &PathBuf,
-    options: &CompressionOptions,
-    compression_result: &mut CompressionResult,
-) -> Option<Vec<u8>> {
-    let input_file_buffer = match read_file_to_vec(input_file) {
-        Ok(b) => b,
-        Err(_) => {
-            compression_result.message = "Error reading input file".to_string();
-            return None;
-        }
-    };
-
-    let mut compression_parameters = match build_compression_parameters(options, &input_file_buffer) {
-        Ok(p) => p,
-        Err(e) => {
-            compression_result.message = format!("Error building compression parameters: {e}");
-            return None;
+impl SharedInput {
+    /// The content of `input_file`, read on first use. Errors are not kept, so that a retry reads the file again.
+    fn read(&self, input_file: &PathBuf) -> io::Result<Vec<u8>> {
+        if let Some(buffer) = self.buffer.get() {
+            return Ok(buffer.clone());
         }
-    };
+        let buffer = read_file_to_vec(input_file)?;
+        Ok(self.buffer.get_or_init(|| buffer).clone())
+    }
 
-    let compression_result_data = match (options.max_size, options.format) {
-        (Some(max_size), format) if format != OutputFormat::Original => {
-            let converted_image = convert_in_memory(
-                input_file_buffer,
-                &compression_parameters,
-                map_supported_formats(format),
-            )
-            .ok()?;
-            compress_to_size_in_memory(converted_image, &mut compression_parameters, max_size, true)
-        }
-        (Some(max_size), _) => {
-            compress_to_size_in_memory(input_file_buffer, &mut compression_parameters, max_size, true)
-        }
-        (None, format) if format != OutputFormat::Original => convert_in_memory(
-            input_file_buffer,
-            &compression_parameters,
-            map_supported_formats(format),
-        ),
-        _ => compress_in_memory(input_file_buffer, &compression_parameters),
-    };
+    /// The oriented pixels of `buffer`, the content of the input, decoded on first use
+    fn pixels(&self, buffer: &[u8]) -> Option<&image::DynamicImage> {
+        self.pixels.get_or_init(|| decode_oriented(buffer).ok()).as_ref()
+    }
+}
 
-    match compression_result_data {
-        Ok(compressed_image) => Some(compressed_image),
-        Err(e) => {
-            compression_result.message = format!("Error compressing file: {e}");
-            None
-        }
+/// Result of a file skipped without being read
+fn aborted_result(input_file: &Path, reason: &str) -> CompressionResult {
+    CompressionResult {
+        original_path: input_file.display().to_string(),
+        output_path: String::new(),
+        original_size: 0,
+        compressed_size: 0,
+        status: CompressionStatus::Skipped,
+        error_kind: None,
+        message: reason.to_string(),
     }
 }
 
-fn skip_due_to_insufficient_savings(
-    min_savings: Option<MinSavingsThreshold>,
-    original_size: u64,
-    compressed_size: u64,
-    compression_result: &mut CompressionResult,
-) -> bool {
-    let Some(threshold) = min_savings else {
-        return false;
-    };
+/// Output an input would be written to, as computed by `plan_outputs`
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlannedOutput {
+    pub input_path: PathBuf,
+    /// `None` when the output can't be computed, `message` tells why
+    pub output_path: Option<PathBuf>,
+    /// Format of the output, `None` when the content of the input is not a supported image
+    pub format: Option<OutputFormat>,
+    /// Quality setting applied to the input, e.g. `quality 80` or `lossless`
+    pub compression: String,
+    pub message: String,
+}
 
-    if original_size == 0 {
-        return false;
+/// Resolves the output of each input the same way `start_compression` does, without reading the pixels or writing
+/// anything.
+pub fn plan_outputs(input_files: &[PathBuf], options: &CompressionOptions) -> Vec<PlannedOutput> {
+    if !options.variants.is_empty() {
+        return options
+            .variants
+            .iter()
+            .flat_map(|variant| plan_outputs(input_files, &variant.apply(options)))
+            .collect();
     }
 
-    let actual_savings = original_size.saturating_sub(compressed_size);
+    let collisions = match &options.flatten {
+        Some(number_format) => number_outputs(input_files, options, number_format),
+        None => resolve_collisions(input_files, options),
+    };
 
-    match threshold {
-        MinSavingsThreshold::Percentage(percent) => {
-            let savings_percent = (actual_savings as f64 / original_size as f64) * 100.0;
-
-            if savings_percent < percent {
-                compression_result.status = CompressionStatus::Skipped;
-                compression_result.compressed_size = original_size;
-                compression_result.message =
-                    format!("Insufficient savings: {savings_percent:.2}% < {percent:.2}%, skipped",);
-                true
+    input_files
+        .iter()
+        .zip(&collisions)
+        .map(|(input_file, collision)| {
+            let options = options_for_file(input_file, options);
+            let mut compression_result = CompressionResult {
+                original_path: input_file.display().to_string(),
+                output_path: String::new(),
+                original_size: 0,
+                compressed_size: 0,
+                status: CompressionStatus::Error,
+                error_kind: None,
+                message: String::new(),
+            };
+            let output_path = if input_file.is_file() {
+                setup_output_path(input_file, &options, &mut compression_result, true)
+                    .and_then(|path| apply_collision(path, collision.as_ref(), &mut compression_result))
+                    .map(|path| match &options.output_zip {
+                        Some(zip_output) => zip_output.path().join(path),
+                        None => path,
+                    })
             } else {
-                false
+                compression_result.message = "File not found".to_string();
+                None
+            };
+            let content_format = infer::get_from_path(input_file)
+                .ok()
+                .flatten()
+                .and_then(|file_type| format_from_mime_type(file_type.mime_type()));
+            let format = match options.format {
+                OutputFormat::Original => content_format,
+                format => content_format.map(|_| format),
+            };
+
+            PlannedOutput {
+                input_path: input_file.clone(),
+                output_path,
+                format,
+                compression: describe_compression(&options),
+                message: compression_result.message,
             }
+        })
+        .collect()
+}
+
+/// Inputs that their own output replaces: with `same_folder_as_input`, an output is written beside its input when a
+/// suffix, a variant or a format change gives it another name, and over it otherwise.
+/// Names differing only by case count as the same file, as they are on case-insensitive file systems.
+pub fn in_place_inputs(input_files: &[PathBuf], options: &CompressionOptions) -> Vec<PathBuf> {
+    if !options.same_folder_as_input || options.output_zip.is_some() {
+        return vec![];
+    }
+
+    plan_outputs(input_files, options)
+        .into_iter()
+        .filter(|planned_output| {
+            planned_output
+                .output_path
+                .as_ref()
+                .is_some_and(|output_path| output_path.as_os_str().eq_ignore_ascii_case(&planned_output.input_path))
+        })
+        .map(|planned_output| planned_output.input_path)
+        .collect()
+}
+
+fn describe_compression(options: &CompressionOptions) -> String {
+    if options.lossless {
+        "lossless".to_string()
+    } else if options.optimize {
+        "optimize".to_string()
+    } else if let Some(max_size) = options.max_size {
+        format!("max size {max_size} bytes")
+    } else if let Some(target_ssim) = options.target_ssim {
+        format!("target SSIM {target_ssim}")
+    } else if let Some(bpp_target) = options.bpp_target {
+        format!("target {bpp_target} bpp")
+    } else if let Some(total_budget) = options.total_budget {
+        format!("total budget {total_budget} bytes")
+    } else {
+        format!("quality {}", options.quality.unwrap_or(DEFAULT_QUALITY))
+    }
+}
+
+/// How an input whose output path is already used by another input is handled.
+/// The sequential names of `flatten` are applied the same way.
+#[derive(Clone, Debug, PartialEq)]
+enum Collision {
+    /// Write to this path instead
+    Rename(PathBuf),
+    /// Fail, the output belongs to this other input
+    Error(PathBuf),
+}
+
+/// Replaces the output file names with sequential numbers, following the sorted order of the inputs.
+/// The planned output keeps its folder and extension, so the output format is honored.
+/// Missing inputs and the ones without an output path don't take a number.
+fn number_outputs(
+    input_files: &[PathBuf],
+    options: &CompressionOptions,
+    number_format: &NumberFormat,
+) -> Vec<Option<Collision>> {
+    let mut sorted_indexes: Vec<usize> = (0..input_files.len()).collect();
+    sorted_indexes.sort_by(|&a, &b| input_files[a].cmp(&input_files[b]));
+
+    let mut renames = vec![None; input_files.len()];
+    let mut number = 0;
+    for index in sorted_indexes {
+        let input_file = &input_files[index];
+        if !input_file.is_file() {
+            continue;
         }
-        MinSavingsThreshold::Bytes(min_bytes) => {
-            if actual_savings < min_bytes {
-                compression_result.status = CompressionStatus::Skipped;
-                compression_result.compressed_size = original_size;
-                compression_result.message = format!(
-                    "Insufficient savings: {} < {}, skipped",
-                    bytesize::ByteSize::b(actual_savings),
-                    bytesize::ByteSize::b(min_bytes)
-                );
-                true
-            } else {
-                false
-            }
+        let mut compression_result = CompressionResult {
+            original_path: String::new(),
+            output_path: String::new(),
+            original_size: 0,
+            compressed_size: 0,
+            status: CompressionStatus::Error,
+            error_kind: None,
+            message: String::new(),
+        };
+        let Some(planned_path) = setup_output_path(
+            input_file,
+            &options_for_file(input_file, options),
+            &mut compression_result,
+            true,
+        ) else {
+            continue;
+        };
+
+        number += 1;
+        let mut file_name = OsString::from(number_format.format(number));
+        file_name.push(options.suffix.as_deref().unwrap_or_default());
+        if let Some(extension) = planned_path.extension() {
+            file_name.push(".");
+            file_name.push(extension);
         }
+        renames[index] = Some(Collision::Rename(planned_path.with_file_name(file_name)));
     }
+
+    renames
 }
 
-fn skip_due_to_bigger_policy(
-    options: &CompressionOptions,
-    output_path: &Path,
-    output_size: u64,
-    original_size: u64,
-    compression_result: &mut CompressionResult,
-) -> bool {
-    if output_path.exists() && options.overwrite_policy == OverwritePolicy::Bigger {
-        match output_path.metadata() {
-            Ok(existing_metadata) => {
-                if existing_metadata.len() <= output_size {
-                    compression_result.status = CompressionStatus::Skipped;
-                    compression_result.compressed_size = original_size;
-                    compression_result.message = "File already exists, skipped due overwrite policy".to_string();
-                    return true;
-                }
+/// Finds the inputs that would be written to the same output path as a previous input, following the collision
+/// policy. The same input listed twice is not a collision.
+fn resolve_collisions(input_files: &[PathBuf], options: &CompressionOptions) -> Vec<Option<Collision>> {
+    let mut collisions = vec![None; input_files.len()];
+    if options.collision_policy == CollisionPolicy::Overwrite {
+        return collisions;
+    }
+
+    let planned_paths: Vec<Option<PathBuf>> = input_files
+        .iter()
+        .map(|input_file| {
+            let mut compression_result = CompressionResult {
+                original_path: String::new(),
+                output_path: String::new(),
+                original_size: 0,
+                compressed_size: 0,
+                status: CompressionStatus::Error,
+                error_kind: None,
+                message: String::new(),
+            };
+            setup_output_path(
+                input_file,
+                &options_for_file(input_file, options),
+                &mut compression_result,
+                true,
+            )
+        })
+        .collect();
+
+    let mut taken_paths: HashSet<PathBuf> = planned_paths.iter().flatten().cloned().collect();
+    let mut owners: HashMap<&PathBuf, &PathBuf> = HashMap::new();
+    for (index, (input_file, planned_path)) in input_files.iter().zip(&planned_paths).enumerate() {
+        let Some(planned_path) = planned_path else {
+            continue;
+        };
+        match owners.get(planned_path) {
+            None => {
+                owners.insert(planned_path, input_file);
             }
-            Err(_) => {
-                compression_result.message = "Error reading existing file metadata".to_string();
-                return false;
+            Some(&owner) if owner == input_file => {}
+            Some(&owner) => {
+                collisions[index] = Some(match options.collision_policy {
+                    CollisionPolicy::Error => Collision::Error(owner.clone()),
+                    _ => Collision::Rename(disambiguate_path(planned_path, &mut taken_paths)),
+                });
             }
         }
     }
 
-    false
+    collisions
 }
 
-fn write_compressed_file(
-    output_path: &PathBuf,
-    compressed_image: &[u8],
-    options: &CompressionOptions,
-    input_file_metadata: &Metadata,
-) -> Result<(), String> {
-    let mut output_file = File::create(output_path).map_err(|_| "Error creating output file".to_string())?;
-
-    output_file
-        .write_all(compressed_image)
-        .map_err(|_| "Error writing output file".to_string())?;
+/// Appends the first counter giving a path not used yet, e.g. `photo_1.jpg`
+fn disambiguate_path(path: &Path, taken_paths: &mut HashSet<PathBuf>) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path
+        .extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
 
-    if options.keep_dates {
-        preserve_file_times(&output_file, input_file_metadata)
-            .map_err(|_| "Error preserving file times".to_string())?;
+    let mut counter = 1;
+    loop {
+        let candidate = path.with_file_name(format!("{stem}_{counter}{extension}"));
+        if taken_paths.insert(candidate.clone()) {
+            return candidate;
+        }
+        counter += 1;
     }
+}
 
-    Ok(())
+fn apply_collision(
+    output_path: PathBuf,
+    collision: Option<&Collision>,
+    compression_result: &mut CompressionResult,
+) -> Option<PathBuf> {
+    match collision {
+        None => Some(output_path),
+        // The renamed path was planned before the format of `--format auto` was known, so it takes its extension
+        Some(Collision::Rename(path)) => Some(match output_path.extension() {
+            Some(extension) => path.with_extension(extension),
+            None => path.clone(),
+        }),
+        Some(Collision::Error(owner)) => {
+            compression_result.error_kind = Some(CompressionErrorKind::Collision);
+            compression_result.message = format!("Output path already used by {}", owner.display());
+            None
+        }
+    }
 }
 
-fn build_compression_parameters(options: &CompressionOptions, buffer: &[u8]) -> Result<CSParameters, Box<dyn Error>> {
-    let mut parameters = CSParameters::new();
-    let quality = options.quality.unwrap_or(80);
+/// Shares `total_budget` bytes between the inputs proportionally to their number of pixels, as the maximum size of
+/// each output. Inputs whose dimensions can't be read count as the average of the others.
+fn allocate_budget(input_files: &[PathBuf], total_budget: u64) -> HashMap<&Path, u64> {
+    let pixels: Vec<Option<u64>> = input_files
+        .par_iter()
+        .map(|input_file| {
+            imagesize::size(input_file)
+                .ok()
+                .map(|size| (size.width as u64 * size.height as u64).max(1))
+        })
+        .collect();
+    let known: Vec<u64> = pixels.iter().flatten().copied().collect();
+    let average = known
+        .iter()
+        .sum::<u64>()
+        .checked_div(known.len() as u64)
+        .unwrap_or(1)
+        .max(1);
+    let weights: Vec<u128> = pixels.iter().map(|pixels| pixels.unwrap_or(average) as u128).collect();
+    let total_weight = weights.iter().sum::<u128>().max(1);
 
-    parameters.jpeg.quality = quality;
-    parameters.png.quality = quality;
-    parameters.webp.quality = quality;
-    parameters.gif.quality = if options.lossless {
-        100
-    } else if quality == 0 {
-        1
-    } else {
-        quality
-    };
-    parameters.jpeg.preserve_icc = !options.strip_icc;
+    input_files
+        .iter()
+        .zip(weights)
+        .map(|(input_file, weight)| {
+            let share = total_budget as u128 * weight / total_weight;
+            (input_file.as_path(), share as u64)
+        })
+        .collect()
+}
 
-    parameters.jpeg.optimize = options.lossless;
-    parameters.png.optimize = options.lossless;
-    parameters.webp.lossless = options.lossless;
+/// For each input, the index of the first input with the same content, if any.
+/// Only files sharing their size with another input are hashed.
+fn find_duplicates(input_files: &[PathBuf]) -> Vec<Option<usize>> {
+    let mut files_by_size: HashMap<u64, Vec<usize>> = HashMap::new();
+    for (index, input_file) in input_files.iter().enumerate() {
+        if let Ok(metadata) = input_file.metadata() {
+            files_by_size.entry(metadata.len()).or_default().push(index);
+        }
+    }
 
-    parameters.keep_metadata = options.exif;
+    let candidates: Vec<usize> = files_by_size
+        .into_values()
+        .filter(|indexes| indexes.len() > 1)
+        .flatten()
+        .collect();
+    let mut hashes: Vec<(usize, blake3::Hash)> = candidates
+        .par_iter()
+        .filter_map(|&index| hash_file(&input_files[index]).ok().map(|hash| (index, hash)))
+        .collect();
+    hashes.sort_unstable_by_key(|(index, _)| *index);
 
-    parameters.jpeg.chroma_subsampling = options.jpeg_chroma_subsampling;
-    parameters.jpeg.progressive = !options.jpeg_baseline;
+    let mut duplicate_of = vec![None; input_files.len()];
+    let mut first_seen: HashMap<blake3::Hash, usize> = HashMap::new();
+    for (index, hash) in hashes {
+        match first_seen.get(&hash) {
+            Some(&original) => duplicate_of[index] = Some(original),
+            None => {
+                first_seen.insert(hash, index);
+            }
+        }
+    }
 
-    parameters.png.optimization_level = options.png_opt_level;
-    parameters.png.force_zopfli = options.zopfli;
+    duplicate_of
+}
 
-    let needs_resize = is_resize_needed(options);
-    if needs_resize {
-        let mime_type = get_file_mime_type_from_buffer(buffer);
-        build_resize_parameters(options, &mut parameters, buffer, mime_type)?;
-    }
+fn hash_file(path: &Path) -> io::Result<blake3::Hash> {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_reader(File::open(path)?)?;
+    Ok(hasher.finalize())
+}
 
-    Ok(parameters)
+/// A duplicate gets a copy of the original output only if it was written to disk, with the same quality, format and
+/// maximum size as the duplicate would get, e.g. not when `--quality-for` or `--total-budget` give them different ones.
+/// Archives and in-place writes that must trash the input go through the normal compression instead.
+fn can_reuse_output(
+    input_file: &Path,
+    options: &CompressionOptions,
+    original_file: &Path,
+    original_options: &CompressionOptions,
+    original_result: &CompressionResult,
+) -> bool {
+    let file_options = options_for_file(input_file, options);
+    let original_options = options_for_file(original_file, original_options);
+    matches!(original_result.status, CompressionStatus::Success)
+        && options.output_zip.is_none()
+        && !(options.trash_original && options.same_folder_as_input && options.suffix.is_none())
+        && file_options.quality == original_options.quality
+        && file_options.format == original_options.format
+        && file_options.max_size == original_options.max_size
+        && input_file.is_file()
 }
 
-fn compute_output_full_path(
-    output_directory: &Path,
-    input_file_path: &Path,
-    base_directory: &PathBuf,
-    keep_structure: bool,
-    suffix: &str,
-    format: OutputFormat,
-    same_folder_as_input: bool,
-) -> Option<(PathBuf, OsString)> {
-    let extension = match format {
-        OutputFormat::Jpeg => "jpg".into(),
-        OutputFormat::Png => "png".into(),
-        OutputFormat::Webp => "webp".into(),
-        OutputFormat::Tiff => "tiff".into(),
-        OutputFormat::Gif => "gif".into(),
-        OutputFormat::Original => input_file_path.extension().unwrap_or_default().to_os_string(),
+fn reuse_duplicate_output(
+    input_file: &Path,
+    original_result: &CompressionResult,
+    options: &CompressionOptions,
+    collision: Option<&Collision>,
+    dry_run: bool,
+) -> CompressionResult {
+    let options: &CompressionOptions = &options_for_file(input_file, options);
+    let mut compression_result = CompressionResult {
+        original_path: input_file.display().to_string(),
+        output_path: String::new(),
+        original_size: original_result.original_size,
+        compressed_size: original_result.original_size,
+        status: CompressionStatus::Error,
+        error_kind: None,
+        message: String::new(),
     };
 
-    let base_name = input_file_path.file_stem().unwrap_or_default().to_os_string();
-    let mut output_file_name = base_name;
-    output_file_name.push(suffix);
-    if !extension.is_empty() {
-        output_file_name.push(".");
-        output_file_name.push(extension);
-    }
+    let output_full_path = match setup_output_path(input_file, options, &mut compression_result, dry_run) {
+        Some(path) => path,
+        None => {
+            compression_result.error_kind.get_or_insert(CompressionErrorKind::Io);
+            if compression_result.message.is_empty() {
+                compression_result.message = "Error setting up output path".to_string();
+            }
+            return compression_result;
+        }
+    };
+    let Some(output_full_path) = apply_collision(output_full_path, collision, &mut compression_result) else {
+        return compression_result;
+    };
+    compression_result.output_path = output_full_path.display().to_string();
 
-    if keep_structure {
-        let parent = input_file_path.parent()?;
-        if !parent.exists() {
-            return None;
+    // The same file passed twice ends up in the same output, which is already written
+    let original_output = PathBuf::from(&original_result.output_path);
+    if !dry_run && output_full_path != original_output {
+        if skip_due_to_overwrite_policy(
+            options,
+            &output_full_path,
+            original_result.original_size,
+            &mut compression_result,
+        ) || skip_due_to_size_policy(
+            options,
+            &output_full_path,
+            original_result.compressed_size,
+            original_result.original_size,
+            &mut compression_result,
+        ) {
+            return compression_result;
         }
-        let parent = match absolute(parent) {
-            Ok(p) => p,
-            Err(_) => return None,
-        };
 
-        if same_folder_as_input {
-            return Some((parent.clone(), output_file_name));
+        if let Err(msg) = copy_duplicate_output(input_file, &original_output, &output_full_path, options) {
+            compression_result.error_kind = Some(CompressionErrorKind::Write);
+            compression_result.message = msg;
+            return compression_result;
         }
-        let output_path_prefix = if !base_directory.clone().into_os_string().is_empty() {
-            match parent.strip_prefix(base_directory) {
-                Ok(p) => p.to_path_buf(),
-                Err(_) => return None,
-            }
-        } else {
-            let prefix = parent.display().to_string().replace(":", "");
-            PathBuf::from(prefix)
-        };
-        let full_output_directory = output_directory.join(output_path_prefix);
-        Some((full_output_directory, output_file_name))
-    } else {
-        Some((PathBuf::from(output_directory), output_file_name))
     }
+
+    compression_result.status = CompressionStatus::Skipped;
+    compression_result.message = format!("Duplicate of {}", original_result.original_path);
+    compression_result
 }
 
-fn build_resize_parameters(
+fn copy_duplicate_output(
+    input_file: &Path,
+    original_output: &Path,
+    output_path: &Path,
     options: &CompressionOptions,
-    parameters: &mut CSParameters,
-    buffer: &[u8],
-    mime_type: Option<String>,
-) -> Result<(), Box<dyn Error>> {
-    let (width, height) = get_real_resolution(buffer, mime_type, options.exif)?;
-
-    if options.width.is_some() || options.height.is_some() {
-        parameters.width = options.width.unwrap_or(0);
-        parameters.height = options.height.unwrap_or(0);
-    } else if options.long_edge.is_some() {
-        let long_edge = options.long_edge.unwrap_or(0);
-        if width > height {
-            parameters.width = long_edge;
-        } else {
-            parameters.height = long_edge;
-        }
-    } else if options.short_edge.is_some() {
-        let short_edge = options.short_edge.unwrap_or(0);
-        if width < height {
-            parameters.width = short_edge;
-        } else {
-            parameters.height = short_edge;
-        }
+) -> Result<(), String> {
+    let input_file_metadata = input_file
+        .metadata()
+        .map_err(|_| "Error reading file metadata".to_string())?;
+    fs::copy(original_output, output_path).map_err(|_| "Error writing output file".to_string())?;
+    if options.verify {
+        let original_data = fs::read(original_output).map_err(|_| "Error reading output file".to_string())?;
+        verify_output(output_path, &original_data)?;
+    }
+    if let Some(mode) = options.file_mode {
+        set_mode(output_path, mode).map_err(|_| "Error setting file permissions".to_string())?;
     }
 
-    if options.no_upscale && (parameters.width >= width as u32 || parameters.height >= height as u32) {
-        parameters.width = 0;
-        parameters.height = 0;
+    if options.keep_dates {
+        let output_file = fs::OpenOptions::new()
+            .write(true)
+            .open(output_path)
+            .map_err(|_| "Error preserving file times".to_string())?;
+        preserve_file_times(&output_file, &input_file_metadata)
+            .map_err(|_| "Error preserving file times".to_string())?;
     }
 
     Ok(())
 }
 
-fn get_real_resolution(
-    buffer: &[u8],
-    mime_type: Option<String>,
-    keep_metadata: bool,
-) -> Result<(usize, usize), Box<dyn Error>> {
-    let resolution = imagesize::blob_size(buffer)?;
-    let mut orientation = 1;
-    let mime = mime_type.unwrap_or("".to_string());
-    if mime == "image/jpeg" && keep_metadata {
-        if let Ok(e) = exif::Reader::new().read_from_container(&mut std::io::Cursor::new(buffer)) {
-            let exif_field = match e.get_field(exif::Tag::Orientation, exif::In::PRIMARY) {
-                Some(f) => f,
-                None => return Ok((resolution.width, resolution.height)),
-            };
-            orientation = exif_field.value.get_uint(0).unwrap_or(1);
+/// Copies the source directory times to the output directories created for `--keep-structure`.
+/// The output folder itself is left untouched.
+fn preserve_directory_times(results: &[CompressionResult], output_folder: &Path) {
+    let mut directories: HashMap<PathBuf, PathBuf> = HashMap::new();
+    for result in results.iter().filter(|r| !r.output_path.is_empty()) {
+        let (Some(mut output_directory), Some(mut input_directory)) = (
+            Path::new(&result.output_path).parent(),
+            Path::new(&result.original_path).parent(),
+        ) else {
+            continue;
         };
+
+        while output_directory != output_folder && output_directory.starts_with(output_folder) {
+            if directories.contains_key(output_directory) {
+                break;
+            }
+            directories.insert(output_directory.to_path_buf(), input_directory.to_path_buf());
+            match (output_directory.parent(), input_directory.parent()) {
+                (Some(o), Some(i)) => (output_directory, input_directory) = (o, i),
+                _ => break,
+            }
+        }
     }
-    let (width, height) = match orientation {
-        5..=8 => (resolution.height, resolution.width),
-        _ => (resolution.width, resolution.height),
-    };
 
-    Ok((width, height))
+    for (output_directory, input_directory) in directories {
+        if let (Ok(metadata), Ok(directory)) = (input_directory.metadata(), open_directory(&output_directory)) {
+            let _ = preserve_file_times(&directory, &metadata);
+        }
+    }
 }
 
-fn preserve_file_times(output_file: &File, original_file_metadata: &Metadata) -> io::Result<()> {
-    let (last_modification_time, last_access_time) =
-        (original_file_metadata.modified()?, original_file_metadata.accessed()?);
-    #[cfg(target_os = "windows")]
-    {
-        let creation_time = original_file_metadata.created()?;
+#[cfg(not(target_os = "windows"))]
+fn open_directory(path: &Path) -> io::Result<File> {
+    File::open(path)
+}
 
-        output_file.set_times(
-            FileTimes::new()
-                .set_modified(last_modification_time)
-                .set_accessed(last_access_time)
-                .set_created(creation_time),
-        )?;
+#[cfg(target_os = "windows")]
+fn open_directory(path: &Path) -> io::Result<File> {
+    use std::os::windows::fs::OpenOptionsExt;
+    const FILE_FLAG_BACKUP_SEMANTICS: u32 = 0x02000000;
+
+    fs::OpenOptions::new()
+        .write(true)
+        .custom_flags(FILE_FLAG_BACKUP_SEMANTICS)
+        .open(path)
+}
+
+/// Runs `attempt` again up to `retries` times while it fails with a retryable error,
+/// doubling the wait between attempts starting from `base_delay`.
+fn retry_with_backoff<F>(retries: u32, base_delay: Duration, mut attempt: F) -> CompressionResult
+where
+    F: FnMut() -> CompressionResult,
+{
+    let mut result = attempt();
+    for retry in 0..retries {
+        let retryable = matches!(result.status, CompressionStatus::Error)
+            && result.error_kind.is_some_and(|kind| kind.is_retryable());
+        if !retryable {
+            break;
+        }
+        thread::sleep(base_delay.saturating_mul(2u32.saturating_pow(retry)));
+        result = attempt();
     }
 
-    #[cfg(not(target_os = "windows"))]
+    result
+}
+
+/// Fails the file with a `Timeout` error once its deadline has passed, see `CompressionOptions::deadline`. The
+/// compression runs in the thread of the file, so a slow file stops at the next step instead of holding on to a
+/// thread and its memory.
+fn is_past_deadline(options: &CompressionOptions, compression_result: &mut CompressionResult) -> bool {
+    let (Some(deadline), Some(timeout)) = (options.deadline, options.timeout) else {
+        return false;
+    };
+    if Instant::now() <= deadline {
+        return false;
+    }
+
+    compression_result.status = CompressionStatus::Error;
+    compression_result.error_kind = Some(CompressionErrorKind::Timeout);
+    compression_result.message = format!("Compression timed out after {}s", timeout.as_secs_f64());
+    true
+}
+
+fn perform_compression(
+    input_file: &PathBuf,
+    options: &CompressionOptions,
+    collision: Option<&Collision>,
+    shared_input: Option<&SharedInput>,
+    dry_run: bool,
+) -> CompressionResult {
+    let options = options_for_file(input_file, options);
+    let options: &CompressionOptions = &match options.timeout {
+        Some(timeout) => Cow::Owned(CompressionOptions {
+            deadline: Some(Instant::now() + timeout),
+            ..options.into_owned()
+        }),
+        None => options,
+    };
+    let mut compression_result = CompressionResult {
+        original_path: input_file.display().to_string(),
+        output_path: String::new(),
+        original_size: 0,
+        compressed_size: 0,
+        status: CompressionStatus::Error,
+        error_kind: None,
+        message: String::new(),
+    };
+
+    if options
+        .overwrite_prompt
+        .as_ref()
+        .is_some_and(|prompt| prompt.is_aborted())
     {
-        output_file.set_times(
-            FileTimes::new()
-                .set_modified(last_modification_time)
-                .set_accessed(last_access_time),
-        )?;
+        compression_result.status = CompressionStatus::Skipped;
+        compression_result.message = "Aborted by user".to_string();
+        return compression_result;
+    }
+
+    let input_file_metadata = match input_file.metadata() {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            compression_result.error_kind = Some(CompressionErrorKind::Read);
+            compression_result.message = if e.kind() == io::ErrorKind::NotFound {
+                "File not found".to_string()
+            } else {
+                "Error reading file metadata".to_string()
+            };
+            return compression_result;
+        }
+    };
+
+    let original_file_size = input_file_metadata.len();
+    // Streamed files are never read at once, so they are not limited in size
+    let tiff_stream = plan_tiff_stream(input_file, options);
+
+    if original_file_size > MAX_FILE_SIZE && tiff_stream.is_none() {
+        compression_result.message = "File exceeds 500Mb, skipping.".to_string();
+        compression_result.status = CompressionStatus::Skipped;
+        return compression_result;
+    }
+
+    if original_file_size == 0 {
+        compression_result.message = "Empty file, skipping.".to_string();
+        compression_result.status = CompressionStatus::Skipped;
+        return compression_result;
+    }
+
+    compression_result.original_size = original_file_size;
+
+    // The format of an automatic output is only known once the candidates are compressed
+    let options: &CompressionOptions = &match options.format {
+        OutputFormat::Auto if !dry_run => match resolve_auto_format(input_file, options) {
+            Ok(format) => Cow::Owned(CompressionOptions {
+                format,
+                ..options.clone()
+            }),
+            Err(_) => {
+                compression_result.error_kind = Some(CompressionErrorKind::Read);
+                compression_result.message = "Error reading input file".to_string();
+                return compression_result;
+            }
+        },
+        _ => Cow::Borrowed(options),
+    };
+
+    let output_full_path = match setup_output_path(input_file, options, &mut compression_result, dry_run) {
+        Some(path) => path,
+        None => {
+            compression_result.error_kind.get_or_insert(CompressionErrorKind::Io);
+            if compression_result.message.is_empty() {
+                compression_result.message = "Error setting up output path".to_string();
+            }
+            return compression_result;
+        }
+    };
+    let Some(output_full_path) = apply_collision(output_full_path, collision, &mut compression_result) else {
+        return compression_result;
+    };
+    compression_result.output_path = match &options.output_zip {
+        Some(zip_output) => zip_output.path().join(&output_full_path).display().to_string(),
+        None => output_full_path.display().to_string(),
+    };
+
+    if skip_if_up_to_date(
+        options,
+        input_file,
+        &input_file_metadata,
+        &output_full_path,
+        &mut compression_result,
+    ) {
+        return compression_result;
+    }
+
+    // Nothing is overwritten by a dry run, so there is nothing to confirm either
+    let asks_overwrite = matches!(options.overwrite_policy, OverwritePolicy::Ask);
+    if !(dry_run && asks_overwrite)
+        && skip_due_to_overwrite_policy(options, &output_full_path, original_file_size, &mut compression_result)
+    {
+        return compression_result;
+    }
+
+    if dry_run {
+        compression_result.status = CompressionStatus::Success;
+        compression_result.compressed_size = original_file_size;
+        return compression_result;
+    }
+
+    // Streamed straight to the output file, the recompressed image is never held in memory either
+    if let Some(tiff_stream) = &tiff_stream {
+        let _permit = options
+            .memory_budget
+            .as_ref()
+            .map(|budget| budget.acquire(tiff_stream.band_memory()));
+        return perform_tiff_stream_compression(
+            input_file,
+            tiff_stream,
+            options,
+            &output_full_path,
+            &input_file_metadata,
+            compression_result,
+        );
+    }
+
+    let compressed_image = {
+        let _permit = options
+            .memory_budget
+            .as_ref()
+            .map(|budget| budget.acquire(estimate_decode_memory(input_file)));
+        match perform_image_compression(input_file, options, shared_input, &mut compression_result) {
+            Some(image) => image,
+            None => return compression_result,
+        }
+    };
+
+    let output_file_size = compressed_image.len() as u64;
+
+    if skip_due_to_insufficient_savings(
+        options.min_savings,
+        original_file_size,
+        output_file_size,
+        &mut compression_result,
+    ) {
+        return compression_result;
+    }
+
+    if skip_due_to_size_policy(
+        options,
+        &output_full_path,
+        output_file_size,
+        original_file_size,
+        &mut compression_result,
+    ) {
+        return compression_result;
+    }
+
+    if is_past_deadline(options, &mut compression_result) {
+        return compression_result;
+    }
+
+    if options.trash_original && options.output_zip.is_none() && is_in_place_write(input_file, &output_full_path) {
+        if let Err(e) = trash::delete(input_file) {
+            compression_result.error_kind = Some(CompressionErrorKind::Io);
+            compression_result.message = format!("Error moving original file to trash: {e}");
+            return compression_result;
+        }
+    }
+
+    let write_result = match &options.output_zip {
+        Some(zip_output) => zip_output.add_file(&output_full_path, &compressed_image),
+        None => write_compressed_file(&output_full_path, &compressed_image, options, &input_file_metadata),
+    };
+    let write_result = match write_result {
+        Ok(()) if options.verify && options.output_zip.is_none() => verify_output(&output_full_path, &compressed_image),
+        write_result => write_result,
+    };
+    if let Err(msg) = write_result {
+        compression_result.error_kind = Some(CompressionErrorKind::Write);
+        compression_result.message = msg;
+        return compression_result;
+    }
+
+    compression_result.status = CompressionStatus::Success;
+    compression_result.compressed_size = output_file_size;
+    compression_result
+}
+
+/// Reads `output_path` back and compares it with the data that has been written, e.g. to detect a faulty disk
+fn verify_output(output_path: &Path, expected_data: &[u8]) -> Result<(), String> {
+    let written_data = fs::read(output_path).map_err(|_| "Error reading output file back".to_string())?;
+    if written_data != expected_data {
+        return Err("Verification failed, the written file differs from the compressed image".to_string());
+    }
+
+    Ok(())
+}
+
+/// Whether writing to `output_path` replaces `input_file` itself
+fn is_in_place_write(input_file: &Path, output_path: &Path) -> bool {
+    match (fs::canonicalize(input_file), fs::canonicalize(output_path)) {
+        (Ok(input), Ok(output)) => input == output,
+        _ => false,
+    }
+}
+
+fn is_resize_needed(options: &CompressionOptions) -> bool {
+    options.width.is_some()
+        || options.height.is_some()
+        || options.long_edge.is_some()
+        || options.short_edge.is_some()
+        || options.fit.is_some()
+        || options.scale.is_some()
+        || (options.resample_dpi && options.dpi.is_some())
+}
+
+/// Whether the pixels are rotated, flipped or cropped before being encoded
+fn is_transform_needed(options: &CompressionOptions) -> bool {
+    options.rotate.is_some() || options.flip.is_some() || options.crop.is_some()
+}
+
+/// Whether the resizing options change the dimensions of the image. They don't when it already fits a --fit box, or is
+/// smaller than the target with --no-upscale.
+fn resizes_image(options: &CompressionOptions, buffer: &[u8], mime_type: Option<String>) -> bool {
+    // The box is always filled
+    if matches!(options.fit_mode, Some(FitMode::Cover | FitMode::Pad)) {
+        return true;
+    }
+    let mut parameters = CSParameters::new();
+    match build_resize_parameters(options, &mut parameters, buffer, mime_type) {
+        Ok(()) => parameters.width != 0 || parameters.height != 0,
+        Err(_) => true,
+    }
+}
+
+fn without_resize(options: &CompressionOptions) -> CompressionOptions {
+    CompressionOptions {
+        width: None,
+        height: None,
+        long_edge: None,
+        short_edge: None,
+        fit: None,
+        fit_mode: None,
+        scale: None,
+        resample_dpi: false,
+        ..options.clone()
+    }
+}
+
+fn setup_output_path(
+    input_file: &Path,
+    options: &CompressionOptions,
+    compression_result: &mut CompressionResult,
+    dry_run: bool,
+) -> Option<PathBuf> {
+    let output_directory = determine_output_directory(input_file, options, compression_result)?;
+    // The encoder follows the content of the file, so does the extension of a misnamed file
+    let decoded_format = match options.format {
+        OutputFormat::Original => decoded_output_format(input_file, options),
+        _ => None,
+    };
+    let misnamed_format = match (options.format, decoded_format) {
+        (OutputFormat::Original, None) => detect_misnamed_format(input_file),
+        _ => None,
+    };
+    let format = decoded_format.or(misnamed_format).unwrap_or(options.format);
+    let named_input_file = renamed_input_file(input_file, options);
+    let Some((output_directory, filename)) = compute_output_full_path(
+        output_directory,
+        &named_input_file,
+        &options.base_path,
+        options.keep_structure,
+        options.suffix.as_ref().unwrap_or(&String::new()).as_ref(),
+        format,
+        options.extension_case,
+        options.same_folder_as_input || output_directory == options.base_path,
+    ) else {
+        if options.keep_structure {
+            compression_result.message = format!(
+                "Unable to keep the folder structure: {} is not inside the base folder {}",
+                input_file.display(),
+                options.base_path.display()
+            );
+        }
+        return None;
+    };
+
+    if let Some(format) = misnamed_format {
+        compression_result.message = format!(
+            "Detected {} content, saved with the .{} extension instead of .{}",
+            format!("{format:?}").to_uppercase(),
+            Path::new(&filename).extension().unwrap_or_default().to_string_lossy(),
+            input_file.extension().unwrap_or_default().to_string_lossy()
+        );
+    }
+
+    // Archive entries are relative paths, nothing is created on disk
+    if dry_run || options.output_zip.is_some() {
+        return Some(output_directory.join(filename));
+    }
+
+    if !output_directory.exists() && create_output_directory(&output_directory, options.dir_mode).is_err() {
+        compression_result.error_kind = Some(CompressionErrorKind::Write);
+        compression_result.message = "Error creating output directory".to_string();
+        return None;
+    }
+
+    Some(output_directory.join(filename))
+}
+
+/// The input path with the file name given by the rename map, if listed there, from which the output name is derived
+fn renamed_input_file<'a>(input_file: &'a Path, options: &CompressionOptions) -> Cow<'a, Path> {
+    let name = options
+        .rename_map
+        .as_ref()
+        .and_then(|rename_map| rename_map.get(&fs::canonicalize(input_file).ok()?));
+    match name {
+        Some(name) => {
+            let mut file_name = OsString::from(name);
+            if let Some(extension) = input_file.extension() {
+                file_name.push(".");
+                file_name.push(extension);
+            }
+            Cow::Owned(input_file.with_file_name(file_name))
+        }
+        None => Cow::Borrowed(input_file),
+    }
+}
+
+/// Creates `directory` and its missing parents, giving the created ones the `mode` permissions
+fn create_output_directory(directory: &Path, mode: Option<u32>) -> io::Result<()> {
+    let mut created = vec![];
+    let mut current = Some(directory);
+    while let Some(path) = current.filter(|p| !p.as_os_str().is_empty() && !p.exists()) {
+        created.push(path);
+        current = path.parent();
+    }
+
+    fs::create_dir_all(directory)?;
+    if let Some(mode) = mode {
+        for path in created {
+            set_mode(path, mode)?;
+        }
     }
 
-    Ok(())
-}
-fn map_supported_formats(format: OutputFormat) -> SupportedFileTypes {
-    match format {
-        OutputFormat::Jpeg => SupportedFileTypes::Jpeg,
-        OutputFormat::Png => SupportedFileTypes::Png,
-        OutputFormat::Gif => SupportedFileTypes::Gif,
-        OutputFormat::Webp => SupportedFileTypes::WebP,
-        OutputFormat::Tiff => SupportedFileTypes::Tiff,
-        _ => SupportedFileTypes::Unkn,
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+/// Permissions are Unix only, the command line warns when they are requested elsewhere
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) -> io::Result<()> {
+    Ok(())
+}
+
+fn determine_output_directory<'a>(
+    input_file: &'a Path,
+    options: &'a CompressionOptions,
+    compression_result: &mut CompressionResult,
+) -> Option<&'a Path> {
+    if options.output_zip.is_some() {
+        Some(Path::new(""))
+    } else if options.same_folder_as_input {
+        match input_file.parent() {
+            Some(p) => Some(p),
+            None => {
+                compression_result.message = "Error getting parent directory".to_string();
+                None
+            }
+        }
+    } else {
+        match options.output_folder.as_ref() {
+            Some(p) => Some(p),
+            None => {
+                compression_result.message = "Error getting output directory".to_string();
+                None
+            }
+        }
+    }
+}
+
+/// With `newer`, an existing output modified at the same time as its input or later is up to date.
+/// Equal times are what `keep_dates` gives, so a kept date still detects a modified input.
+/// Only metadata is read, and in-place writes are never considered up to date.
+fn skip_if_up_to_date(
+    options: &CompressionOptions,
+    input_file: &Path,
+    input_file_metadata: &Metadata,
+    output_path: &Path,
+    compression_result: &mut CompressionResult,
+) -> bool {
+    if !options.newer || options.output_zip.is_some() || is_in_place_write(input_file, output_path) {
+        return false;
+    }
+
+    let output_time = output_path.metadata().and_then(|metadata| metadata.modified());
+    match (input_file_metadata.modified(), output_time) {
+        (Ok(input_time), Ok(output_time)) if output_time >= input_time => {
+            compression_result.status = CompressionStatus::Skipped;
+            compression_result.compressed_size = input_file_metadata.len();
+            compression_result.message = "Up to date".to_string();
+            true
+        }
+        _ => false,
+    }
+}
+
+fn skip_due_to_overwrite_policy(
+    options: &CompressionOptions,
+    output_path: &Path,
+    original_size: u64,
+    compression_result: &mut CompressionResult,
+) -> bool {
+    // With an archive the policy applies to the archive itself
+    if options.output_zip.is_some() {
+        return false;
+    }
+
+    let skip_reason = match options.overwrite_policy {
+        OverwritePolicy::Never if output_path.exists() => {
+            Some("File already exists, skipped due overwrite policy 'never'")
+        }
+        // Without a prompt, e.g. when not attached to a terminal, nothing is overwritten
+        OverwritePolicy::Ask if output_path.exists() => {
+            match options
+                .overwrite_prompt
+                .as_ref()
+                .map(|prompt| prompt.confirm(output_path))
+            {
+                Some(OverwriteAnswer::Yes | OverwriteAnswer::All) => None,
+                Some(OverwriteAnswer::Quit) => Some("Aborted by user"),
+                Some(OverwriteAnswer::No) => Some("File already exists, overwrite declined"),
+                None => Some("File already exists, skipped due overwrite policy 'ask' without a terminal"),
+            }
+        }
+        _ => None,
+    };
+
+    match skip_reason {
+        Some(reason) => {
+            compression_result.status = CompressionStatus::Skipped;
+            compression_result.compressed_size = original_size;
+            compression_result.message = reason.to_string();
+            true
+        }
+        None => false,
+    }
+}
+
+/// Checks that the image data reaches the end marker of its format, e.g. after an interrupted transfer
+fn is_truncated(buffer: &[u8], mime_type: Option<&str>) -> bool {
+    match mime_type {
+        // Markers can't appear in the entropy-coded data, so the image ends after its last scan
+        Some("image/jpeg") => match rfind(buffer, &[0xFF, 0xDA]) {
+            Some(last_scan) => rfind(&buffer[last_scan..], &[0xFF, 0xD9]).is_none(),
+            None => true,
+        },
+        Some("image/png") => rfind(buffer, b"IEND").is_none(),
+        Some("image/webp") => match buffer.get(4..8) {
+            Some(riff_size) => {
+                u32::from_le_bytes([riff_size[0], riff_size[1], riff_size[2], riff_size[3]]) as usize + 8 > buffer.len()
+            }
+            None => true,
+        },
+        _ => false,
+    }
+}
+
+fn rfind(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).rposition(|window| window == needle)
+}
+
+fn get_file_mime_type_from_buffer(buffer: &[u8]) -> Option<String> {
+    match infer::get(buffer) {
+        Some(v) => Option::from(v.mime_type().to_string()),
+        None => None,
+    }
+}
+
+fn is_supported_mime_type(mime_type: Option<&str>) -> bool {
+    matches!(
+        mime_type,
+        Some("image/jpeg" | "image/png" | "image/webp" | "image/gif" | "image/tiff")
+    )
+}
+
+fn perform_image_compression(
+    input_file: &PathBuf,
+    options: &CompressionOptions,
+    shared_input: Option<&SharedInput>,
+    compression_result: &mut CompressionResult,
+) -> Option<Vec<u8>> {
+    let read_start = Instant::now();
+    let input_file_buffer = match shared_input.map_or_else(|| read_file_to_vec(input_file), |s| s.read(input_file)) {
+        Ok(b) => b,
+        Err(_) => {
+            compression_result.error_kind = Some(CompressionErrorKind::Read);
+            compression_result.message = "Error reading input file".to_string();
+            return None;
+        }
+    };
+    trace!(
+        "{}: read {} bytes in {:?}",
+        input_file.display(),
+        input_file_buffer.len(),
+        read_start.elapsed()
+    );
+    if is_past_deadline(options, compression_result) {
+        return None;
+    }
+
+    let compression_start = Instant::now();
+    let shared_pixels = shared_input
+        .filter(|_| resizes_shared_pixels(&input_file_buffer, options))
+        .and_then(|shared_input| shared_input.pixels(&input_file_buffer));
+    let compressed_image = if options.raw_preview && is_raw_file(input_file) {
+        compress_raw_preview(&input_file_buffer, options, compression_result)
+    } else if let Some(pixels) = shared_pixels {
+        compress_shared_pixels(pixels, &input_file_buffer, options, compression_result)
+    } else {
+        compress_buffer(input_file_buffer, options, compression_result)
+    };
+    debug!(
+        "{}: decoded and encoded in {:?}",
+        input_file.display(),
+        compression_start.elapsed()
+    );
+    compressed_image
+}
+
+/// Whether a variant is resized from the pixels decoded once for all the variants of the input. Images the variant
+/// leaves as they are, and the inputs needing their own decoding, e.g. animations or CMYK JPEGs, are compressed from
+/// the buffer as usual.
+fn resizes_shared_pixels(buffer: &[u8], options: &CompressionOptions) -> bool {
+    let mime_type = get_file_mime_type_from_buffer(buffer);
+    matches!(mime_type.as_deref(), Some("image/jpeg" | "image/png" | "image/webp"))
+        && is_resize_needed(options)
+        && !is_truncated(buffer, mime_type.as_deref())
+        && !is_apng(buffer)
+        && detect_cmyk_jpeg(buffer).is_none()
+        && resizes_image(options, buffer, mime_type)
+}
+
+/// Resizes `pixels`, decoded from `input_file_buffer`, for a variant and encodes them like the transformed images of
+/// `compress_buffer`
+fn compress_shared_pixels(
+    pixels: &image::DynamicImage,
+    input_file_buffer: &[u8],
+    options: &CompressionOptions,
+    compression_result: &mut CompressionResult,
+) -> Option<Vec<u8>> {
+    let mime_type = get_file_mime_type_from_buffer(input_file_buffer);
+    let resized_image = match transform_pixels(pixels.clone(), input_file_buffer, options, true) {
+        Ok(resized_image) => resized_image,
+        Err(e) => {
+            compression_result.error_kind = Some(CompressionErrorKind::Decode);
+            compression_result.message = format!("Error resizing the image: {e}");
+            return None;
+        }
+    };
+    encode_transformed(
+        input_file_buffer,
+        resized_image,
+        mime_type.as_deref(),
+        options,
+        true,
+        compression_result,
+    )
+}
+
+/// The band by band recompression of `input_file`, used to recompress a TIFF to TIFF when decoding the whole image
+/// would not fit in the memory budget. Options needing the full image, or its metadata, use the full decode instead.
+fn plan_tiff_stream(input_file: &Path, options: &CompressionOptions) -> Option<TiffStream> {
+    let memory_budget = options.memory_budget.as_ref()?;
+    // The output is streamed to a file, so it can't go into an archive or be compared with the image in memory
+    if options.output_zip.is_some() || options.verify {
+        return None;
+    }
+    if !matches!(options.format, OutputFormat::Original | OutputFormat::Tiff)
+        || (options.raw_preview && is_raw_file(input_file))
+        || is_resize_needed(options)
+        || is_transform_needed(options)
+        || options.exif
+        || options.max_size.is_some()
+        || options.target_ssim.is_some()
+        || options.bpp_target.is_some()
+        || estimate_decode_memory(input_file) <= memory_budget.limit()
+    {
+        return None;
+    }
+    let file_type = infer::get_from_path(input_file).ok().flatten()?;
+    if file_type.mime_type() != "image/tiff" {
+        return None;
+    }
+
+    TiffStream::open(input_file)
+}
+
+/// Recompresses `input_file` band by band into a temporary file next to the output, which replaces the output once
+/// the savings and overwrite checks pass
+fn perform_tiff_stream_compression(
+    input_file: &Path,
+    tiff_stream: &TiffStream,
+    options: &CompressionOptions,
+    output_path: &Path,
+    input_file_metadata: &Metadata,
+    mut compression_result: CompressionResult,
+) -> CompressionResult {
+    let mut part_name = output_path.file_name().map(OsString::from).unwrap_or_default();
+    part_name.push(".part");
+    let part_path = output_path.with_file_name(part_name);
+    let fail = |mut compression_result: CompressionResult, error_kind: CompressionErrorKind, message: String| {
+        let _ = fs::remove_file(&part_path);
+        compression_result.error_kind = Some(error_kind);
+        compression_result.message = message;
+        compression_result
+    };
+
+    let mut part_file = match File::create(&part_path) {
+        Ok(file) => BufWriter::new(file),
+        Err(_) => {
+            let message = "Error creating output file".to_string();
+            return fail(compression_result, CompressionErrorKind::Write, message);
+        }
+    };
+    let compression_start = Instant::now();
+    if let Err(e) = tiff_stream.recompress(input_file, options.tiff_compression, options.dpi, &mut part_file) {
+        let message = format!("Error compressing file: {e}");
+        return fail(compression_result, CompressionErrorKind::Encode, message);
+    }
+    debug!(
+        "{}: recompressed {} in {:?}",
+        input_file.display(),
+        tiff_stream.describe(),
+        compression_start.elapsed()
+    );
+    let part_file = match part_file.into_inner() {
+        Ok(file) => file,
+        Err(_) => {
+            let message = "Error writing output file".to_string();
+            return fail(compression_result, CompressionErrorKind::Write, message);
+        }
+    };
+    let output_file_size = part_file.metadata().map(|metadata| metadata.len()).unwrap_or_default();
+
+    let original_file_size = compression_result.original_size;
+    if skip_due_to_insufficient_savings(
+        options.min_savings,
+        original_file_size,
+        output_file_size,
+        &mut compression_result,
+    ) || skip_due_to_size_policy(
+        options,
+        output_path,
+        output_file_size,
+        original_file_size,
+        &mut compression_result,
+    ) || is_past_deadline(options, &mut compression_result)
+    {
+        let _ = fs::remove_file(&part_path);
+        return compression_result;
+    }
+
+    if options.trash_original && is_in_place_write(input_file, output_path) {
+        if let Err(e) = trash::delete(input_file) {
+            let message = format!("Error moving original file to trash: {e}");
+            return fail(compression_result, CompressionErrorKind::Io, message);
+        }
+    }
+
+    // The renamed file keeps its permissions and dates
+    let write_result = finish_output_file(&part_path, &part_file, options, input_file_metadata)
+        .and_then(|_| fs::rename(&part_path, output_path).map_err(|_| "Error writing output file".to_string()));
+    if let Err(message) = write_result {
+        return fail(compression_result, CompressionErrorKind::Write, message);
+    }
+
+    compression_result.status = CompressionStatus::Success;
+    compression_result.compressed_size = output_file_size;
+    compression_result.message = format!("Recompressed band by band ({})", tiff_stream.describe());
+    compression_result
+}
+
+/// Compresses an image read from `input` and writes the result to `output`, without touching the filesystem
+pub fn compress_stream<R: Read, W: Write>(
+    input: &mut R,
+    output: &mut W,
+    options: &CompressionOptions,
+) -> Result<(), String> {
+    let mut input_buffer = Vec::new();
+    input
+        .read_to_end(&mut input_buffer)
+        .map_err(|_| "Error reading input".to_string())?;
+
+    let mut compression_result = CompressionResult {
+        original_path: String::from("-"),
+        output_path: String::from("-"),
+        original_size: input_buffer.len() as u64,
+        compressed_size: 0,
+        status: CompressionStatus::Error,
+        error_kind: None,
+        message: String::new(),
+    };
+    let options = match options.format {
+        OutputFormat::Auto => Cow::Owned(CompressionOptions {
+            format: auto_format(&input_buffer, options),
+            ..options.clone()
+        }),
+        _ => Cow::Borrowed(options),
+    };
+    // A single image gets the whole budget
+    let options = match options.total_budget {
+        Some(total_budget) => Cow::Owned(CompressionOptions {
+            max_size: Some(total_budget),
+            ..options.into_owned()
+        }),
+        None => options,
+    };
+    let compressed_image =
+        compress_buffer(input_buffer, &options, &mut compression_result).ok_or(compression_result.message)?;
+
+    output
+        .write_all(&compressed_image)
+        .and_then(|_| output.flush())
+        .map_err(|_| "Error writing output".to_string())
+}
+
+fn compress_buffer(
+    input_file_buffer: Vec<u8>,
+    options: &CompressionOptions,
+    compression_result: &mut CompressionResult,
+) -> Option<Vec<u8>> {
+    // The quality searches check it again before each encoding, see `check_deadline`
+    if is_past_deadline(options, compression_result) {
+        return None;
+    }
+    if is_svg(&input_file_buffer) {
+        return compress_svg(&input_file_buffer, options, compression_result);
+    }
+    if is_pdf(&input_file_buffer) {
+        return compress_pdf(&input_file_buffer, options, compression_result);
+    }
+    if is_heic(&input_file_buffer) {
+        return compress_heic(&input_file_buffer, options, compression_result);
+    }
+    if let Some(format) = detect_legacy_format(&input_file_buffer) {
+        return compress_legacy_format(&input_file_buffer, format, options, compression_result);
+    }
+
+    let mime_type = get_file_mime_type_from_buffer(&input_file_buffer);
+    if !is_supported_mime_type(mime_type.as_deref()) {
+        compression_result.error_kind = Some(CompressionErrorKind::Unsupported);
+        compression_result.message = "Unsupported file type".to_string();
+        return None;
+    }
+
+    if imagesize::blob_size(&input_file_buffer).is_err() || is_truncated(&input_file_buffer, mime_type.as_deref()) {
+        compression_result.error_kind = Some(CompressionErrorKind::Decode);
+        compression_result.message = "Corrupt or truncated image".to_string();
+        return None;
+    }
+
+    // Images the resizing options leave as they are, e.g. smaller ones with --no-upscale, are handled as if no resizing
+    // was requested, so that the formats which can't be resized are still compressed
+    let resize_options = if is_resize_needed(options) && !resizes_image(options, &input_file_buffer, mime_type.clone())
+    {
+        Cow::Owned(without_resize(options))
+    } else {
+        Cow::Borrowed(options)
+    };
+    let options: &CompressionOptions = &resize_options;
+
+    // libcaesium only keeps the first page of a TIFF
+    if mime_type.as_deref() == Some("image/tiff") && tiff_page_count(&input_file_buffer) > 1 {
+        return compress_tiff_pages(&input_file_buffer, options, compression_result);
+    }
+
+    // CMYK pixels are converted to RGB and encoded as a JPEG again, unless they are recompressed losslessly
+    let keeps_cmyk = (options.lossless || options.optimize) && options.format == OutputFormat::Original;
+    let (input_file_buffer, options) = match detect_cmyk_jpeg(&input_file_buffer).filter(|_| !keeps_cmyk) {
+        Some(encoding) => match cmyk_jpeg_to_png(&input_file_buffer, encoding) {
+            Ok(rgb_image) => {
+                let format = match options.format {
+                    OutputFormat::Original => OutputFormat::Jpeg,
+                    format => format,
+                };
+                (
+                    rgb_image,
+                    Cow::Owned(CompressionOptions {
+                        format,
+                        ..options.clone()
+                    }),
+                )
+            }
+            Err(e) => {
+                compression_result.error_kind = Some(CompressionErrorKind::Decode);
+                compression_result.message = format!("Error converting the CMYK image to RGB: {e}");
+                return None;
+            }
+        },
+        None => (input_file_buffer, Cow::Borrowed(options)),
+    };
+    let options: &CompressionOptions = &options;
+
+    // The transformed pixels are then encoded like the original image
+    let fills_box = matches!(options.fit_mode, Some(FitMode::Cover | FitMode::Pad));
+    if fills_box && mime_type.as_deref() == Some("image/gif") {
+        compression_result.error_kind = Some(CompressionErrorKind::Unsupported);
+        compression_result.message = "GIF files can't be resized with --fit cover or pad".to_string();
+        return None;
+    }
+    let resizes_here = (options.resize_filter != ResizeFilter::Lanczos3 || fills_box)
+        && is_resize_needed(options)
+        && mime_type.as_deref() != Some("image/gif");
+    if is_transform_needed(options) || resizes_here {
+        if mime_type.as_deref() == Some("image/gif") {
+            compression_result.error_kind = Some(CompressionErrorKind::Unsupported);
+            compression_result.message = "GIF files can't be rotated, flipped or cropped".to_string();
+            return None;
+        }
+        let transformed_image = match transform_image(&input_file_buffer, options, resizes_here) {
+            Ok(transformed_image) => transformed_image,
+            Err(e) => {
+                compression_result.error_kind = Some(CompressionErrorKind::Decode);
+                compression_result.message = format!("Error transforming the image: {e}");
+                return None;
+            }
+        };
+        return encode_transformed(
+            &input_file_buffer,
+            transformed_image,
+            mime_type.as_deref(),
+            options,
+            resizes_here,
+            compression_result,
+        );
+    }
+
+    if options.optimize && mime_type.as_deref() == Some("image/webp") && !is_lossless_webp(&input_file_buffer) {
+        compression_result.status = CompressionStatus::Skipped;
+        compression_result.message = "Lossy WebP can't be optimized without changing its pixels".to_string();
+        return None;
+    }
+
+    // Only recompressing an existing JPEG can be lossless
+    if options.lossless && options.format == OutputFormat::Jpeg {
+        compression_result.error_kind = Some(CompressionErrorKind::Unsupported);
+        compression_result.message = "JPEG has no lossless mode, can't convert losslessly".to_string();
+        return None;
+    }
+
+    // Animated PNGs keep their frames, which the PNG and WebP encoders of libcaesium would flatten
+    if mime_type.as_deref() == Some("image/png") && is_apng(&input_file_buffer) {
+        match options.format {
+            OutputFormat::Webp => {
+                return match decode_apng_animation(&input_file_buffer) {
+                    Ok(animation) => compress_animation(&animation, options, compression_result),
+                    Err(e) => {
+                        compression_result.error_kind = Some(CompressionErrorKind::Decode);
+                        compression_result.message = format!("Error decoding PNG frames: {e}");
+                        None
+                    }
+                };
+            }
+            OutputFormat::Original | OutputFormat::Png => {
+                if let Some(message) = unsupported_for_animation(options) {
+                    compression_result.error_kind = Some(CompressionErrorKind::Unsupported);
+                    compression_result.message = message.to_string();
+                    return None;
+                }
+                return match optimize_apng(&input_file_buffer, options.exif) {
+                    Ok(compressed_image) => finalize_output(compressed_image, options, compression_result),
+                    Err(e) => {
+                        compression_result.error_kind = Some(CompressionErrorKind::Encode);
+                        compression_result.message = format!("Error optimizing animated PNG: {e}");
+                        None
+                    }
+                };
+            }
+            // The other formats only get the first frame
+            _ => {}
+        }
+    }
+
+    // Converting an animated GIF keeps its frames, which the WebP encoder of libcaesium would flatten
+    if mime_type.as_deref() == Some("image/gif") && options.format == OutputFormat::Webp {
+        let animation = match decode_gif_animation(&input_file_buffer) {
+            Ok(animation) => animation,
+            Err(e) => {
+                compression_result.error_kind = Some(CompressionErrorKind::Decode);
+                compression_result.message = format!("Error decoding GIF frames: {e}");
+                return None;
+            }
+        };
+        if animation.frames.len() > 1 {
+            return compress_animation(&animation, options, compression_result);
+        }
+    }
+
+    // libcaesium only compresses GIF files from a path, their palettes are rewritten here instead
+    if mime_type.as_deref() == Some("image/gif") && matches!(options.format, OutputFormat::Original | OutputFormat::Gif)
+    {
+        let unsupported = if is_resize_needed(options) {
+            Some("GIF files can't be resized")
+        } else if options.max_size.is_some() || options.target_ssim.is_some() || options.bpp_target.is_some() {
+            Some("GIF files only support a fixed quality")
+        } else {
+            None
+        };
+        if let Some(message) = unsupported {
+            compression_result.error_kind = Some(CompressionErrorKind::Unsupported);
+            compression_result.message = message.to_string();
+            return None;
+        }
+        let lossless = options.lossless || options.optimize;
+        let max_colors = gif_colors(if lossless {
+            100
+        } else {
+            options.quality.unwrap_or(DEFAULT_QUALITY)
+        });
+        return match optimize_gif(&input_file_buffer, max_colors) {
+            Ok(compressed_image) => finalize_output(compressed_image, options, compression_result),
+            Err(e) => {
+                compression_result.error_kind = Some(CompressionErrorKind::Decode);
+                compression_result.message = format!("Error optimizing GIF: {e}");
+                None
+            }
+        };
+    }
+
+    // libcaesium can't write these formats, they have their own encoders
+    let own_encoder: Option<(&str, ImageEncoder)> = match options.format {
+        OutputFormat::Avif => Some(("AVIF", encode_avif as ImageEncoder)),
+        OutputFormat::Jxl => Some(("JPEG XL", encode_jxl as ImageEncoder)),
+        _ => None,
+    };
+    if let Some((name, encode)) = own_encoder {
+        let unsupported = if options.format == OutputFormat::Jxl && !cfg!(feature = "jxl") {
+            Some("JPEG XL support is not included in this build, it needs the `jxl` feature".to_string())
+        } else if options.lossless && options.format == OutputFormat::Avif {
+            Some("AVIF is only encoded lossy, can't convert losslessly".to_string())
+        } else if options.max_size.is_some() || options.target_ssim.is_some() || options.bpp_target.is_some() {
+            Some(format!("{name} outputs only support a fixed quality"))
+        } else {
+            None
+        };
+        if let Some(message) = unsupported {
+            compression_result.error_kind = Some(CompressionErrorKind::Unsupported);
+            compression_result.message = message;
+            return None;
+        }
+        return match encode(&input_file_buffer, options) {
+            Ok(compressed_image) => finalize_output(compressed_image, options, compression_result),
+            Err(e) => {
+                compression_result.error_kind = Some(CompressionErrorKind::Encode);
+                compression_result.message = format!("Error encoding {name}: {e}");
+                None
+            }
+        };
+    }
+
+    if let Some(target) = options.target_ssim {
+        return match compress_to_target_ssim(&input_file_buffer, options, target) {
+            Ok((compressed_image, quality, ssim)) => {
+                compression_result.message = format!("Quality {quality} (SSIM {ssim:.4})");
+                finalize_output(compressed_image, options, compression_result)
+            }
+            Err(_) if is_past_deadline(options, compression_result) => None,
+            Err(e) => {
+                compression_result.error_kind = Some(CompressionErrorKind::Encode);
+                compression_result.message = format!("Error compressing file: {e}");
+                None
+            }
+        };
+    }
+
+    if let Some(target) = options.bpp_target {
+        return match compress_to_target_bpp(&input_file_buffer, options, target) {
+            Ok((compressed_image, quality, bpp)) => {
+                compression_result.message = format!("Quality {quality} ({bpp:.2} bpp)");
+                finalize_output(compressed_image, options, compression_result)
+            }
+            Err(_) if is_past_deadline(options, compression_result) => None,
+            Err(e) => {
+                compression_result.error_kind = Some(CompressionErrorKind::Encode);
+                compression_result.message = format!("Error compressing file: {e}");
+                None
+            }
+        };
+    }
+
+    if let (Some(max_size), true) = (
+        options.max_size,
+        options.max_size_tolerance.is_some() || options.max_size_iterations.is_some(),
+    ) {
+        return match compress_to_max_size(&input_file_buffer, options, max_size) {
+            Ok((compressed_image, quality)) => {
+                compression_result.message = format!("Quality {quality}");
+                finalize_output(compressed_image, options, compression_result)
+            }
+            Err(_) if is_past_deadline(options, compression_result) => None,
+            Err(e) => {
+                compression_result.error_kind = Some(CompressionErrorKind::Encode);
+                compression_result.message = format!("Error compressing file: {e}");
+                None
+            }
+        };
+    }
+
+    let webp_encoded_here = options.lossless || options.webp_near_lossless.is_some() || options.webp_effort.is_some();
+    if options.format == OutputFormat::Webp && options.max_size.is_none() && webp_encoded_here {
+        return match encode_webp(&input_file_buffer, options) {
+            Ok(compressed_image) => finalize_output(compressed_image, options, compression_result),
+            Err(e) => {
+                compression_result.error_kind = Some(CompressionErrorKind::Encode);
+                compression_result.message = format!("Error encoding WebP: {e}");
+                None
+            }
+        };
+    }
+
+    let mut compression_parameters = match build_compression_parameters(options, &input_file_buffer) {
+        Ok(p) => p,
+        Err(e) => {
+            compression_result.error_kind = Some(CompressionErrorKind::Decode);
+            compression_result.message = format!("Error building compression parameters: {e}");
+            return None;
+        }
+    };
+
+    let compression_result_data = match (options.max_size, options.format) {
+        (Some(max_size), format) if format != OutputFormat::Original => convert_in_memory(
+            input_file_buffer,
+            &compression_parameters,
+            map_supported_formats(format),
+        )
+        .and_then(|converted_image| {
+            compress_to_size_in_memory(
+                converted_image,
+                &mut compression_parameters,
+                libcaesium_size(max_size),
+                true,
+            )
+        }),
+        (Some(max_size), _) => compress_to_size_in_memory(
+            input_file_buffer,
+            &mut compression_parameters,
+            libcaesium_size(max_size),
+            true,
+        ),
+        (None, format) if format != OutputFormat::Original => convert_in_memory(
+            input_file_buffer,
+            &compression_parameters,
+            map_supported_formats(format),
+        ),
+        _ => compress_in_memory(input_file_buffer, &compression_parameters),
+    };
+
+    let mut compressed_image = match compression_result_data {
+        Ok(compressed_image) => compressed_image,
+        Err(e) => {
+            compression_result.error_kind = Some(CompressionErrorKind::Encode);
+            compression_result.message = format!("Error compressing file: {e}");
+            return None;
+        }
+    };
+
+    if (options.webp_near_lossless.is_some() || options.webp_effort.is_some())
+        && get_file_mime_type_from_buffer(&compressed_image).as_deref() == Some("image/webp")
+    {
+        compressed_image = match encode_webp_advanced(&compressed_image, options) {
+            Ok(image) => image,
+            Err(e) => {
+                compression_result.error_kind = Some(CompressionErrorKind::Encode);
+                compression_result.message = format!("Error encoding WebP: {e}");
+                return None;
+            }
+        };
+    }
+
+    finalize_output(compressed_image, options, compression_result)
+}
+
+/// Encodes the pixels transformed by `transform_pixels`, in the format of the original image unless the options ask for
+/// another one, and copies the JPEG metadata of the original image
+fn encode_transformed(
+    input_file_buffer: &[u8],
+    transformed_image: Vec<u8>,
+    mime_type: Option<&str>,
+    options: &CompressionOptions,
+    resized: bool,
+    compression_result: &mut CompressionResult,
+) -> Option<Vec<u8>> {
+    let format = match options.format {
+        OutputFormat::Original => mime_type
+            .and_then(format_from_mime_type)
+            .unwrap_or(OutputFormat::Original),
+        format => format,
+    };
+    // The pixels of a JPEG have changed, so they are encoded again at the highest quality instead
+    let reencodes_jpeg = options.lossless && format == OutputFormat::Jpeg && mime_type == Some("image/jpeg");
+    let options_left = if resized {
+        without_resize(options)
+    } else {
+        options.clone()
+    };
+    let transformed_options = CompressionOptions {
+        format,
+        rotate: None,
+        flip: None,
+        crop: None,
+        lossless: options.lossless && !reencodes_jpeg,
+        quality: if reencodes_jpeg { Some(100) } else { options.quality },
+        ..options_left
+    };
+    let compressed_image = compress_buffer(transformed_image, &transformed_options, compression_result)?;
+    copy_jpeg_metadata(input_file_buffer, compressed_image, options, compression_result)
+}
+
+/// Encodes the frames of an animation as an animated WebP, at the quality of the options or lossless
+fn compress_animation(
+    animation: &Animation,
+    options: &CompressionOptions,
+    compression_result: &mut CompressionResult,
+) -> Option<Vec<u8>> {
+    if let Some(message) = unsupported_for_animation(options) {
+        compression_result.error_kind = Some(CompressionErrorKind::Unsupported);
+        compression_result.message = message.to_string();
+        return None;
+    }
+
+    let quality = options.quality.unwrap_or(DEFAULT_QUALITY);
+    match encode_webp_animation(animation, quality, options.lossless, options.webp_effort) {
+        Ok(compressed_image) => finalize_output(compressed_image, options, compression_result),
+        Err(e) => {
+            compression_result.error_kind = Some(CompressionErrorKind::Encode);
+            compression_result.message = format!("Error encoding animated WebP: {e}");
+            None
+        }
+    }
+}
+
+/// Why the options can't be applied to an animation, whose frames are never resized nor compressed to a target
+fn unsupported_for_animation(options: &CompressionOptions) -> Option<&'static str> {
+    if is_resize_needed(options) {
+        Some("Animations can't be resized")
+    } else if options.max_size.is_some() || options.target_ssim.is_some() || options.bpp_target.is_some() {
+        Some("Animations only support a fixed quality")
+    } else {
+        None
+    }
+}
+
+/// HEIC can't be written: the decoded pixels are compressed to JPEG, or to the selected format, like any other image.
+/// With `exif`, the EXIF data of the photo is copied to JPEG outputs, without the orientation already applied.
+fn compress_heic(
+    buffer: &[u8],
+    options: &CompressionOptions,
+    compression_result: &mut CompressionResult,
+) -> Option<Vec<u8>> {
+    let decoded = match decode_heic(buffer) {
+        Ok(decoded) => decoded,
+        Err(e) => {
+            compression_result.error_kind = Some(match cfg!(feature = "heic") {
+                true => CompressionErrorKind::Decode,
+                false => CompressionErrorKind::Unsupported,
+            });
+            compression_result.message = format!("Error decoding HEIC: {e}");
+            return None;
+        }
+    };
+    let options = CompressionOptions {
+        format: default_format(options.format, OutputFormat::Jpeg),
+        ..options.clone()
+    };
+    let compressed_image = compress_buffer(decoded.png, &options, compression_result)?;
+
+    let is_jpeg = get_file_mime_type_from_buffer(&compressed_image).as_deref() == Some("image/jpeg");
+    let Some(exif) = decoded.exif.filter(|_| options.exif && is_jpeg) else {
+        return Some(compressed_image);
+    };
+    let mut stripped_tags = options.strip_tags.clone();
+    stripped_tags.push("Orientation".to_string());
+    match insert_exif(&compressed_image, &exif).and_then(|image| strip_exif_tags(&image, &stripped_tags)) {
+        Ok(image) => Some(image),
+        Err(e) => {
+            compression_result.error_kind = Some(CompressionErrorKind::Encode);
+            compression_result.message = format!("Error copying the EXIF data: {e}");
+            None
+        }
+    }
+}
+
+/// RAW files are not compressed themselves: their largest JPEG preview is, as a JPEG unless another format is selected
+fn compress_raw_preview(
+    buffer: &[u8],
+    options: &CompressionOptions,
+    compression_result: &mut CompressionResult,
+) -> Option<Vec<u8>> {
+    let preview = match extract_raw_preview(buffer) {
+        Ok(preview) => preview,
+        Err(e) => {
+            compression_result.error_kind = Some(CompressionErrorKind::Decode);
+            compression_result.message = format!("Error extracting the RAW preview: {e}");
+            return None;
+        }
+    };
+    let options = CompressionOptions {
+        format: default_format(options.format, OutputFormat::Jpeg),
+        ..options.clone()
+    };
+    compress_buffer(preview, &options, compression_result)
+}
+
+/// SVG files are minified as text, they can't be converted to raster formats nor transformed
+fn compress_svg(
+    buffer: &[u8],
+    options: &CompressionOptions,
+    compression_result: &mut CompressionResult,
+) -> Option<Vec<u8>> {
+    let unsupported = if options.format != OutputFormat::Original {
+        Some("SVG files can't be converted to other formats")
+    } else if is_resize_needed(options) || is_transform_needed(options) {
+        Some("SVG files can't be resized, rotated, flipped or cropped")
+    } else if options.max_size.is_some() || options.target_ssim.is_some() || options.bpp_target.is_some() {
+        Some("SVG files only support a fixed quality")
+    } else {
+        None
+    };
+    if let Some(message) = unsupported {
+        compression_result.error_kind = Some(CompressionErrorKind::Unsupported);
+        compression_result.message = message.to_string();
+        return None;
+    }
+
+    // Lossless minification keeps the value of every number
+    let precision = match options.lossless || options.optimize {
+        true => None,
+        false => Some(svg_precision(options.quality.unwrap_or(DEFAULT_QUALITY))),
+    };
+    match minify_svg(buffer, precision, options.exif) {
+        Ok(minified) => match minified.len() < buffer.len() {
+            true => finalize_output(minified, options, compression_result),
+            false => finalize_output(buffer.to_vec(), options, compression_result),
+        },
+        Err(e) => {
+            compression_result.error_kind = Some(CompressionErrorKind::Decode);
+            compression_result.message = format!("Error minifying SVG: {e}");
+            None
+        }
+    }
+}
+
+/// PDF files keep their pages, only their JPEG and Flate images are recompressed, each one replaced when smaller
+fn compress_pdf(
+    buffer: &[u8],
+    options: &CompressionOptions,
+    compression_result: &mut CompressionResult,
+) -> Option<Vec<u8>> {
+    let unsupported = if options.format != OutputFormat::Original {
+        Some("PDF files can't be converted to other formats")
+    } else if is_resize_needed(options) || is_transform_needed(options) {
+        Some("PDF files can't be resized, rotated, flipped or cropped")
+    } else if options.max_size.is_some() || options.target_ssim.is_some() || options.bpp_target.is_some() {
+        Some("PDF files only support a fixed quality")
+    } else {
+        None
+    };
+    if let Some(message) = unsupported {
+        compression_result.error_kind = Some(CompressionErrorKind::Unsupported);
+        compression_result.message = message.to_string();
+        return None;
+    }
+
+    // The images are recompressed without any metadata, which the document doesn't read
+    let image_options = CompressionOptions {
+        exif: false,
+        strip_tags: vec![],
+        dpi: None,
+        comment: None,
+        software_tag: false,
+        ..options.clone()
+    };
+    let lossless = options.lossless || options.optimize;
+    let recompression = recompress_pdf_images(buffer, lossless, |image| {
+        let mut image_result = CompressionResult {
+            original_path: compression_result.original_path.clone(),
+            output_path: String::new(),
+            original_size: image.len() as u64,
+            compressed_size: 0,
+            status: CompressionStatus::Error,
+            error_kind: None,
+            message: String::new(),
+        };
+        // Flate images are given as PNG files, converted to JPEG
+        let format = match infer::image::is_jpeg(image) {
+            true => OutputFormat::Original,
+            false => OutputFormat::Jpeg,
+        };
+        let options = CompressionOptions {
+            format,
+            ..image_options.clone()
+        };
+        compress_buffer(image.to_vec(), &options, &mut image_result)
+    });
+
+    match recompression {
+        Ok(recompression) => {
+            compression_result.message = format!(
+                "Recompressed {} of {} images",
+                recompression.recompressed, recompression.images
+            );
+            Some(recompression.output)
+        }
+        Err(e) => {
+            compression_result.error_kind = Some(CompressionErrorKind::Decode);
+            compression_result.message = format!("Error reading PDF: {e}");
+            None
+        }
+    }
+}
+
+/// Multi-page TIFF files keep all their pages, recompressed losslessly. They are skipped rather than losing pages
+/// when converted to another format or transformed.
+fn compress_tiff_pages(
+    buffer: &[u8],
+    options: &CompressionOptions,
+    compression_result: &mut CompressionResult,
+) -> Option<Vec<u8>> {
+    let pages = tiff_page_count(buffer);
+    let skipped = if !matches!(options.format, OutputFormat::Original | OutputFormat::Tiff) {
+        Some(format!(
+            "Multi-page TIFF ({pages} pages) can't be converted without dropping pages, skipping."
+        ))
+    } else if is_resize_needed(options) || is_transform_needed(options) {
+        Some(format!(
+            "Multi-page TIFF ({pages} pages) can't be resized, rotated, flipped or cropped, skipping."
+        ))
+    } else if options.max_size.is_some() || options.target_ssim.is_some() || options.bpp_target.is_some() {
+        Some(format!(
+            "Multi-page TIFF ({pages} pages) only supports lossless compression, skipping."
+        ))
+    } else {
+        None
+    };
+    if let Some(message) = skipped {
+        compression_result.status = CompressionStatus::Skipped;
+        compression_result.message = message;
+        return None;
+    }
+
+    match recompress_tiff_pages(buffer, options.tiff_compression) {
+        Ok(compressed_image) => {
+            compression_result.message = format!("Recompressed {pages} pages");
+            finalize_output(compressed_image, options, compression_result)
+        }
+        Err(e) => {
+            compression_result.status = CompressionStatus::Skipped;
+            compression_result.message =
+                format!("Multi-page TIFF ({pages} pages) can't be recompressed ({e}), skipping.");
+            None
+        }
+    }
+}
+
+/// BMP, TGA and ICO can't be written either, their pixels are compressed as a PNG unless another format is selected
+fn compress_legacy_format(
+    buffer: &[u8],
+    format: LegacyFormat,
+    options: &CompressionOptions,
+    compression_result: &mut CompressionResult,
+) -> Option<Vec<u8>> {
+    let png = match legacy_to_png(buffer, format) {
+        Ok(png) => png,
+        Err(e) => {
+            compression_result.error_kind = Some(CompressionErrorKind::Decode);
+            compression_result.message = format!("Error decoding {}: {e}", format!("{format:?}").to_uppercase());
+            return None;
+        }
+    };
+    let options = CompressionOptions {
+        format: default_format(options.format, OutputFormat::Png),
+        ..options.clone()
+    };
+    compress_buffer(png, &options, compression_result)
+}
+
+/// Format of `input_file` with `--format auto`, see `auto_format`. RAW previews stay JPEG files.
+fn resolve_auto_format(input_file: &PathBuf, options: &CompressionOptions) -> io::Result<OutputFormat> {
+    if options.raw_preview && is_raw_file(input_file) {
+        return Ok(OutputFormat::Jpeg);
+    }
+    Ok(auto_format(&read_file_to_vec(input_file)?, options))
+}
+
+/// The format giving the smallest output among the ones suited to the image: JPEG or WebP for opaque images, PNG or
+/// WebP for transparent ones and lossless compression, GIF or WebP for GIF files. SVG, PDF and multi-page TIFF files
+/// keep their format. When no candidate can be compressed, the first one is used to report the error.
+fn auto_format(buffer: &[u8], options: &CompressionOptions) -> OutputFormat {
+    let mime_type = get_file_mime_type_from_buffer(buffer);
+    if is_svg(buffer) || is_pdf(buffer) || (mime_type.as_deref() == Some("image/tiff") && tiff_page_count(buffer) > 1) {
+        return OutputFormat::Original;
+    }
+
+    let candidates = if mime_type.as_deref() == Some("image/gif") {
+        [OutputFormat::Gif, OutputFormat::Webp]
+    } else if options.lossless || has_alpha(buffer) {
+        [OutputFormat::Png, OutputFormat::Webp]
+    } else {
+        [OutputFormat::Jpeg, OutputFormat::Webp]
+    };
+    candidates
+        .into_iter()
+        .filter_map(|format| {
+            let candidate_options = CompressionOptions {
+                format,
+                ..options.clone()
+            };
+            let mut candidate_result = CompressionResult {
+                original_path: String::new(),
+                output_path: String::new(),
+                original_size: buffer.len() as u64,
+                compressed_size: 0,
+                status: CompressionStatus::Error,
+                error_kind: None,
+                message: String::new(),
+            };
+            let output = compress_buffer(buffer.to_vec(), &candidate_options, &mut candidate_result)?;
+            debug!("auto format: {format:?} is {} bytes", output.len());
+            Some((format, output.len()))
+        })
+        .min_by_key(|(_, size)| *size)
+        .map_or(candidates[0], |(format, _)| format)
+}
+
+/// Whether the pixels of the image have an alpha channel, from its header. `false` for the formats the image crate
+/// can't read.
+fn has_alpha(buffer: &[u8]) -> bool {
+    use image::ImageDecoder;
+
+    image::ImageReader::new(Cursor::new(buffer))
+        .with_guessed_format()
+        .ok()
+        .and_then(|reader| reader.into_decoder().ok())
+        .is_some_and(|decoder| decoder.color_type().has_alpha())
+}
+
+/// The selected format, or `default` for the inputs whose format can't be written
+fn default_format(format: OutputFormat, default: OutputFormat) -> OutputFormat {
+    match format {
+        OutputFormat::Original => default,
+        format => format,
+    }
+}
+
+/// Copies the EXIF data, with `exif`, and the ICC profile, unless `strip_icc`, of a JPEG to the JPEG encoded from its
+/// transformed pixels, as `transform_image` doesn't keep them. The orientation is left out, it has been applied.
+fn copy_jpeg_metadata(
+    source: &[u8],
+    compressed_image: Vec<u8>,
+    options: &CompressionOptions,
+    compression_result: &mut CompressionResult,
+) -> Option<Vec<u8>> {
+    let is_jpeg = |buffer: &[u8]| get_file_mime_type_from_buffer(buffer).as_deref() == Some("image/jpeg");
+    if !is_jpeg(source) || !is_jpeg(&compressed_image) {
+        return Some(compressed_image);
+    }
+    let exif = extract_exif(source).ok().flatten().filter(|_| options.exif);
+    let icc_profile = extract_icc_profile(source)
+        .ok()
+        .flatten()
+        .filter(|_| !options.strip_icc);
+
+    let mut stripped_tags = options.strip_tags.clone();
+    stripped_tags.push("Orientation".to_string());
+    let with_exif = match exif {
+        Some(exif) => insert_exif(&compressed_image, &exif).and_then(|image| strip_exif_tags(&image, &stripped_tags)),
+        None => Ok(compressed_image),
+    };
+    let with_metadata = with_exif.and_then(|image| match icc_profile {
+        Some(icc_profile) => insert_icc_profile(&image, &icc_profile),
+        None => Ok(image),
+    });
+    match with_metadata {
+        Ok(image) => Some(image),
+        Err(e) => {
+            compression_result.error_kind = Some(CompressionErrorKind::Encode);
+            compression_result.message = format!("Error copying the metadata: {e}");
+            None
+        }
+    }
+}
+
+/// Applies the EXIF orientation of the image, then the clockwise `rotate` angle, the `flip` and the `crop` of
+/// `options`, then its resizing options with their filter when `resize` is set, and returns the pixels encoded as a
+/// PNG, without any metadata
+fn transform_image(buffer: &[u8], options: &CompressionOptions, resize: bool) -> Result<Vec<u8>, Box<dyn Error>> {
+    transform_pixels(decode_oriented(buffer)?, buffer, options, resize)
+}
+
+/// Decodes the pixels of `buffer`, turned upright following its EXIF orientation
+fn decode_oriented(buffer: &[u8]) -> Result<image::DynamicImage, Box<dyn Error>> {
+    use image::ImageDecoder;
+
+    let mut decoder = image::ImageReader::new(Cursor::new(buffer))
+        .with_guessed_format()?
+        .into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let mut decoded = image::DynamicImage::from_decoder(decoder)?;
+    decoded.apply_orientation(orientation);
+    Ok(decoded)
+}
+
+/// Transforms the pixels decoded from `buffer` as `transform_image` does
+fn transform_pixels(
+    decoded: image::DynamicImage,
+    buffer: &[u8],
+    options: &CompressionOptions,
+    resize: bool,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let rotated = match options.rotate {
+        Some(90) => decoded.rotate90(),
+        Some(180) => decoded.rotate180(),
+        Some(270) => decoded.rotate270(),
+        _ => decoded,
+    };
+    let flipped = match options.flip {
+        Some(Flip::Horizontal) => rotated.fliph(),
+        Some(Flip::Vertical) => rotated.flipv(),
+        None => rotated,
+    };
+    let cropped = match options.crop {
+        Some((crop_width, crop_height)) => {
+            let (width, height) = (crop_width.min(flipped.width()), crop_height.min(flipped.height()));
+            let (x, y) = crop_origin(flipped.width(), flipped.height(), width, height, options.gravity);
+            flipped.crop_imm(x, y, width, height)
+        }
+        None => flipped,
+    };
+    let (width, height) = (cropped.width(), cropped.height());
+    let fit_box = options.width.zip(options.height).filter(|_| resize);
+    let transformed = match (options.fit_mode, fit_box) {
+        (Some(FitMode::Cover), Some((box_width, box_height))) => fit_cover(cropped, box_width, box_height, options),
+        (Some(FitMode::Pad), Some((box_width, box_height))) => fit_pad(cropped, box_width, box_height, options),
+        _ => match resize.then(|| resize_dimensions(options, width, height, || read_dpi(buffer))) {
+            Some((0, 0)) | None => cropped,
+            Some((resized_width, resized_height)) => {
+                // A side left to 0 keeps the aspect ratio, as with libcaesium
+                let keep_ratio = |side: u32, other: u32, resized_other: u32| {
+                    ((side as f64 * resized_other as f64 / other as f64).round() as u32).max(1)
+                };
+                let resized_width = match resized_width {
+                    0 => keep_ratio(width, height, resized_height),
+                    resized_width => resized_width,
+                };
+                let resized_height = match resized_height {
+                    0 => keep_ratio(height, width, resized_width),
+                    resized_height => resized_height,
+                };
+                cropped.resize_exact(resized_width, resized_height, filter_type(options.resize_filter))
+            }
+        },
+    };
+
+    let mut output = Vec::new();
+    transformed.write_to(&mut Cursor::new(&mut output), image::ImageFormat::Png)?;
+    Ok(output)
+}
+
+/// Scales the image to cover the `box_width`x`box_height` box, cropping what goes beyond it away from the gravity. With
+/// `no_upscale`, a smaller image is only cropped to the aspect ratio of the box.
+fn fit_cover(
+    image: image::DynamicImage,
+    box_width: u32,
+    box_height: u32,
+    options: &CompressionOptions,
+) -> image::DynamicImage {
+    let (width, height) = (image.width(), image.height());
+    // The largest region of the image with the aspect ratio of the box
+    let (region_width, region_height) = if width as u64 * box_height as u64 > height as u64 * box_width as u64 {
+        let region_width = (height as f64 * box_width as f64 / box_height as f64).round() as u32;
+        (region_width.clamp(1, width), height)
+    } else {
+        let region_height = (width as f64 * box_height as f64 / box_width as f64).round() as u32;
+        (width, region_height.clamp(1, height))
+    };
+    let (x, y) = crop_origin(width, height, region_width, region_height, options.gravity);
+    let region = image.crop_imm(x, y, region_width, region_height);
+    if options.no_upscale && region_width <= box_width {
+        return region;
+    }
+    region.resize_exact(box_width, box_height, filter_type(options.resize_filter))
+}
+
+/// Scales the image to fit within the `box_width`x`box_height` box, placed by the gravity, and fills the rest of the box
+/// with the pad color. With `no_upscale`, a smaller image keeps its size.
+fn fit_pad(
+    image: image::DynamicImage,
+    box_width: u32,
+    box_height: u32,
+    options: &CompressionOptions,
+) -> image::DynamicImage {
+    let (width, height) = (image.width(), image.height());
+    let mut scale = f64::min(box_width as f64 / width as f64, box_height as f64 / height as f64);
+    if options.no_upscale {
+        scale = scale.min(1.0);
+    }
+    let inner_width = ((width as f64 * scale).round() as u32).clamp(1, box_width);
+    let inner_height = ((height as f64 * scale).round() as u32).clamp(1, box_height);
+    let inner = if (inner_width, inner_height) == (width, height) {
+        image
+    } else {
+        image.resize_exact(inner_width, inner_height, filter_type(options.resize_filter))
+    };
+
+    let has_alpha = inner.color().has_alpha() || options.pad_color[3] < 255;
+    let mut canvas = image::RgbaImage::from_pixel(box_width, box_height, image::Rgba(options.pad_color));
+    let (x, y) = crop_origin(box_width, box_height, inner_width, inner_height, options.gravity);
+    image::imageops::overlay(&mut canvas, &inner.to_rgba8(), x as i64, y as i64);
+    let padded = image::DynamicImage::ImageRgba8(canvas);
+    if has_alpha {
+        padded
+    } else {
+        image::DynamicImage::ImageRgb8(padded.to_rgb8())
+    }
+}
+
+/// Encodes an image in a format libcaesium can't write, with the options of the file
+type ImageEncoder = fn(&[u8], &CompressionOptions) -> Result<Vec<u8>, Box<dyn Error>>;
+
+/// Decodes the image resized by libcaesium, through a lossless PNG, for the encoders libcaesium doesn't have
+fn decode_resized(buffer: &[u8], options: &CompressionOptions) -> Result<image::DynamicImage, Box<dyn Error>> {
+    let png_options = CompressionOptions {
+        lossless: true,
+        format: OutputFormat::Png,
+        png_opt_level: 0,
+        zopfli: false,
+        ..options.clone()
+    };
+    let parameters = build_compression_parameters(&png_options, buffer)?;
+    let png = convert_in_memory(buffer.to_vec(), &parameters, SupportedFileTypes::Png)?;
+    Ok(image::load_from_memory_with_format(&png, image::ImageFormat::Png)?)
+}
+
+/// Encodes the image as AVIF with the quality and speed of `options`. Metadata is not kept.
+fn encode_avif(buffer: &[u8], options: &CompressionOptions) -> Result<Vec<u8>, Box<dyn Error>> {
+    let image = decode_resized(buffer, options)?;
+    let quality = options.quality.unwrap_or(DEFAULT_QUALITY).clamp(1, 100) as u8;
+    let mut output = Vec::new();
+    let encoder = image::codecs::avif::AvifEncoder::new_with_speed_quality(&mut output, options.avif_speed, quality);
+    image.write_with_encoder(encoder)?;
+    Ok(output)
+}
+
+/// Encodes the image as JPEG XL with the quality of `options`, or losslessly. A JPEG that is not resized is
+/// transcoded reversibly, so that the original file can be rebuilt bit for bit, metadata included. Otherwise
+/// metadata is not kept.
+#[cfg(feature = "jxl")]
+fn encode_jxl(buffer: &[u8], options: &CompressionOptions) -> Result<Vec<u8>, Box<dyn Error>> {
+    let is_jpeg = get_file_mime_type_from_buffer(buffer).as_deref() == Some("image/jpeg");
+    if options.lossless && is_jpeg && !is_resize_needed(options) {
+        let mut encoder = jpegxl_rs::encoder_builder().build()?;
+        return Ok(encoder.encode_jpeg(buffer)?.data);
+    }
+
+    let image = decode_resized(buffer, options)?;
+    let has_alpha = image.color().has_alpha();
+    let mut encoder = jpegxl_rs::encoder_builder()
+        .lossless(options.lossless)
+        .uses_original_profile(options.lossless)
+        .quality(jxl_distance(options.quality.unwrap_or(DEFAULT_QUALITY)))
+        .has_alpha(has_alpha)
+        .build()?;
+    let (width, height) = (image.width(), image.height());
+    let encoded: jpegxl_rs::encode::EncoderResult<u8> = if has_alpha {
+        encoder.encode::<u8, u8>(image.to_rgba8().as_raw(), width, height)?
+    } else {
+        encoder.encode::<u8, u8>(image.to_rgb8().as_raw(), width, height)?
+    };
+    Ok(encoded.data)
+}
+
+/// Encodes the image as JPEG XL
+#[cfg(not(feature = "jxl"))]
+fn encode_jxl(_buffer: &[u8], _options: &CompressionOptions) -> Result<Vec<u8>, Box<dyn Error>> {
+    Err("JPEG XL support is not included in this build, it needs the `jxl` feature".into())
+}
+
+/// Butteraugli distance of the JPEG XL encoder matching a JPEG-like quality, as libjxl maps them: 1.0 at quality 90,
+/// which is visually lossless, up to 25 at quality 0
+#[cfg(feature = "jxl")]
+fn jxl_distance(quality: u32) -> f32 {
+    let quality = quality.min(100) as f32;
+    if quality >= 100.0 {
+        0.0
+    } else if quality >= 30.0 {
+        0.1 + (100.0 - quality) * 0.09
+    } else {
+        53.0 / 3000.0 * quality * quality - 23.0 / 20.0 * quality + 25.0
+    }
+}
+
+/// Whether a WebP is encoded losslessly, the only kind `optimize` can re-encode without changing its pixels
+fn is_lossless_webp(buffer: &[u8]) -> bool {
+    webp_chunks(buffer).is_ok_and(|chunks| chunks.iter().any(|(_, _, kind)| *kind == b"VP8L"))
+}
+
+/// Re-encodes a lossless WebP with the settings of libwebp that libcaesium doesn't expose: the near-lossless
+/// preprocessing, `level` 100 being plain lossless, and the effort of the encoder. The resize and conversion have
+/// already been applied by libcaesium to the lossless input.
+fn encode_webp_advanced(lossless_webp: &[u8], options: &CompressionOptions) -> Result<Vec<u8>, Box<dyn Error>> {
+    let image = image::load_from_memory_with_format(lossless_webp, image::ImageFormat::WebP)?;
+    encode_webp_image(&image, options)
+}
+
+/// Encodes an image converted to WebP from its decoded pixels, when it is lossless, near-lossless or has an effort.
+/// The lossless WebP conversion of libcaesium doesn't keep the exact pixels of the input.
+fn encode_webp(buffer: &[u8], options: &CompressionOptions) -> Result<Vec<u8>, Box<dyn Error>> {
+    let image = decode_resized(buffer, options)?;
+    encode_webp_image(&image, options)
+}
+
+/// Encodes the pixels with the WebP settings of `options`. Lossless modes keep the color of transparent pixels too.
+fn encode_webp_image(image: &image::DynamicImage, options: &CompressionOptions) -> Result<Vec<u8>, Box<dyn Error>> {
+    // The encoder only takes 8-bit RGB and RGBA pixels
+    let image = if image.color().has_alpha() {
+        image::DynamicImage::ImageRgba8(image.to_rgba8())
+    } else {
+        image::DynamicImage::ImageRgb8(image.to_rgb8())
+    };
+    let encoder = webp::Encoder::from_image(&image)?;
+    let mut config = webp::WebPConfig::new().map_err(|_| "Error initializing the WebP encoder")?;
+    if let Some(level) = options.webp_near_lossless {
+        config.lossless = 1;
+        config.exact = 1;
+        config.near_lossless = level as i32;
+    } else if options.lossless || options.optimize {
+        config.lossless = 1;
+        config.exact = 1;
+    } else {
+        config.quality = options.quality.unwrap_or(DEFAULT_QUALITY) as f32;
+    }
+    if let Some(effort) = options.webp_effort {
+        config.method = effort as i32;
+    }
+    let encoded = encoder.encode_advanced(&config).map_err(|e| format!("{e:?}"))?;
+    Ok(encoded.to_vec())
+}
+
+/// Applies the metadata changes made after encoding: EXIF tags removal, resolution and text fields
+fn finalize_output(
+    mut compressed_image: Vec<u8>,
+    options: &CompressionOptions,
+    compression_result: &mut CompressionResult,
+) -> Option<Vec<u8>> {
+    let output_mime_type = get_file_mime_type_from_buffer(&compressed_image);
+
+    if options.exif && !options.strip_tags.is_empty() && output_mime_type.as_deref() == Some("image/jpeg") {
+        compressed_image = match strip_exif_tags(&compressed_image, &options.strip_tags) {
+            Ok(stripped_image) => stripped_image,
+            Err(e) => {
+                compression_result.error_kind = Some(CompressionErrorKind::Encode);
+                compression_result.message = format!("Error stripping EXIF tags: {e}");
+                return None;
+            }
+        };
+    }
+
+    // WebP and GIF have no standard resolution field, they are left as is
+    if let Some(dpi) = options.dpi.filter(|_| supports_dpi(output_mime_type.as_deref())) {
+        compressed_image = match set_dpi(&compressed_image, dpi) {
+            Ok(image) => image,
+            Err(e) => {
+                compression_result.error_kind = Some(CompressionErrorKind::Encode);
+                compression_result.message = format!("Error setting DPI: {e}");
+                return None;
+            }
+        };
+    }
+
+    let text_metadata = TextMetadata {
+        software: options
+            .software_tag
+            .then(|| format!("caesium-clt {}", env!("CARGO_PKG_VERSION"))),
+        comment: options.comment.clone(),
+    };
+    if !text_metadata.is_empty() && supports_text_metadata(output_mime_type.as_deref()) {
+        compressed_image = match add_text_metadata(&compressed_image, &text_metadata) {
+            Ok(image) => image,
+            Err(e) => {
+                compression_result.error_kind = Some(CompressionErrorKind::Encode);
+                compression_result.message = format!("Error writing the comment: {e}");
+                return None;
+            }
+        };
+    }
+
+    Some(compressed_image)
+}
+
+/// Binary searches the lowest quality whose output reaches `target` SSIM, encoding at most `max_iterations` times.
+/// Falls back to quality 100 when no tried quality reaches the target.
+/// Returns the compressed image with the chosen quality and its SSIM.
+fn compress_to_target_ssim(
+    input_file_buffer: &[u8],
+    options: &CompressionOptions,
+    target: f64,
+) -> Result<(Vec<u8>, u32, f64), Box<dyn Error>> {
+    let original = decode_luma(input_file_buffer)?;
+    let encode = |quality: u32| -> Result<(Vec<u8>, f64), Box<dyn Error>> {
+        let compressed_image = encode_with_quality(input_file_buffer, options, quality)?;
+        let ssim = ssim_against(&original, &compressed_image)?;
+        Ok((compressed_image, ssim))
+    };
+
+    let (mut low, mut high) = (1, 100);
+    let mut best = None;
+    for _ in 0..options.max_iterations.max(1) {
+        if low > high {
+            break;
+        }
+        let quality = (low + high) / 2;
+        let (compressed_image, ssim) = encode(quality)?;
+        if ssim >= target {
+            best = Some((compressed_image, quality, ssim));
+            high = quality - 1;
+        } else {
+            low = quality + 1;
+        }
+    }
+
+    match best {
+        Some(best) => Ok(best),
+        None => {
+            let (compressed_image, ssim) = encode(100)?;
+            Ok((compressed_image, 100, ssim))
+        }
+    }
+}
+
+/// Binary searches the quality whose output is the closest to `target` bits per pixel, encoding at most
+/// `max_iterations` times. The pixels are the ones of the output, so resizing is taken into account.
+/// Returns the compressed image with the chosen quality and its bits per pixel.
+fn compress_to_target_bpp(
+    input_file_buffer: &[u8],
+    options: &CompressionOptions,
+    target: f64,
+) -> Result<(Vec<u8>, u32, f64), Box<dyn Error>> {
+    let encode = |quality: u32| -> Result<(Vec<u8>, f64), Box<dyn Error>> {
+        let compressed_image = encode_with_quality(input_file_buffer, options, quality)?;
+        let size = imagesize::blob_size(&compressed_image)?;
+        let pixels = (size.width * size.height).max(1);
+        let bpp = compressed_image.len() as f64 * 8.0 / pixels as f64;
+        Ok((compressed_image, bpp))
+    };
+
+    let (mut low, mut high) = (1, 100);
+    let mut best: Option<(Vec<u8>, u32, f64)> = None;
+    for _ in 0..options.max_iterations.max(1) {
+        if low > high {
+            break;
+        }
+        let quality = (low + high) / 2;
+        let (compressed_image, bpp) = encode(quality)?;
+        if best
+            .as_ref()
+            .is_none_or(|(_, _, best_bpp)| (bpp - target).abs() < (best_bpp - target).abs())
+        {
+            best = Some((compressed_image, quality, bpp));
+        }
+        if bpp > target {
+            high = quality - 1;
+        } else {
+            low = quality + 1;
+        }
+    }
+
+    best.ok_or_else(|| "No quality tried".into())
+}
+
+/// `max_size` as libcaesium takes it. A size past `usize::MAX` can't be reached on this platform anyway.
+fn libcaesium_size(max_size: u64) -> usize {
+    usize::try_from(max_size).unwrap_or(usize::MAX)
+}
+
+/// Binary searches the highest quality whose output fits in `max_size` bytes, encoding at most
+/// `max_size_iterations` times (8 by default). The search stops as soon as an output is at most `max_size_tolerance`
+/// percent under `max_size`. Falls back to the smallest output when none fits.
+/// Returns the compressed image with the chosen quality.
+fn compress_to_max_size(
+    input_file_buffer: &[u8],
+    options: &CompressionOptions,
+    max_size: u64,
+) -> Result<(Vec<u8>, u32), Box<dyn Error>> {
+    let tolerance = options.max_size_tolerance.unwrap_or(0.0);
+    let good_enough = max_size as f64 * (1.0 - tolerance / 100.0);
+
+    let (mut low, mut high) = (1, 100);
+    let mut best: Option<(Vec<u8>, u32)> = None;
+    let mut smallest: Option<(Vec<u8>, u32)> = None;
+    for _ in 0..options.max_size_iterations.unwrap_or(8).max(1) {
+        if low > high {
+            break;
+        }
+        let quality = (low + high) / 2;
+        let compressed_image = encode_with_quality(input_file_buffer, options, quality)?;
+        let size = compressed_image.len() as u64;
+        if size <= max_size {
+            best = Some((compressed_image, quality));
+            if size as f64 >= good_enough {
+                break;
+            }
+            low = quality + 1;
+        } else {
+            if smallest.as_ref().is_none_or(|(image, _)| size < image.len() as u64) {
+                smallest = Some((compressed_image, quality));
+            }
+            high = quality - 1;
+        }
+    }
+
+    best.or(smallest).ok_or_else(|| "No quality tried".into())
+}
+
+/// Fails once the deadline of the file has passed, so that a quality search stops between two encodings. The error
+/// is reported as a timeout by `is_past_deadline`.
+fn check_deadline(options: &CompressionOptions) -> Result<(), Box<dyn Error>> {
+    match options.deadline {
+        Some(deadline) if Instant::now() > deadline => Err("Compression timed out".into()),
+        _ => Ok(()),
+    }
+}
+
+/// Encodes the image in the output format of `options` with `quality` instead of its own
+fn encode_with_quality(
+    input_file_buffer: &[u8],
+    options: &CompressionOptions,
+    quality: u32,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    check_deadline(options)?;
+    let quality_options = CompressionOptions {
+        quality: Some(quality),
+        ..options.clone()
+    };
+    let parameters = build_compression_parameters(&quality_options, input_file_buffer)?;
+    let compressed_image = if options.format != OutputFormat::Original {
+        convert_in_memory(
+            input_file_buffer.to_vec(),
+            &parameters,
+            map_supported_formats(options.format),
+        )?
+    } else {
+        compress_in_memory(input_file_buffer.to_vec(), &parameters)?
+    };
+    Ok(compressed_image)
+}
+
+/// Percentage of the original size saved by compression; negative when the output is bigger
+pub fn savings_percent(original_size: u64, compressed_size: u64) -> f64 {
+    if original_size == 0 {
+        return 0.0;
+    }
+
+    ((original_size as i64 - compressed_size as i64) as f64 / original_size as f64) * 100.0
+}
+
+fn skip_due_to_insufficient_savings(
+    min_savings: Option<MinSavingsThreshold>,
+    original_size: u64,
+    compressed_size: u64,
+    compression_result: &mut CompressionResult,
+) -> bool {
+    let Some(threshold) = min_savings else {
+        return false;
+    };
+
+    if original_size == 0 {
+        return false;
+    }
+
+    let actual_savings = original_size.saturating_sub(compressed_size);
+
+    match threshold {
+        MinSavingsThreshold::Percentage(percent) => {
+            let savings_percent = savings_percent(original_size, compressed_size);
+
+            if savings_percent < percent {
+                compression_result.status = CompressionStatus::Skipped;
+                compression_result.compressed_size = original_size;
+                compression_result.message =
+                    format!("Insufficient savings: {savings_percent:.2}% < {percent:.2}%, skipped",);
+                true
+            } else {
+                false
+            }
+        }
+        MinSavingsThreshold::Bytes(min_bytes) => {
+            if actual_savings < min_bytes {
+                compression_result.status = CompressionStatus::Skipped;
+                compression_result.compressed_size = original_size;
+                compression_result.message = format!(
+                    "Insufficient savings: {} < {}, skipped",
+                    bytesize::ByteSize::b(actual_savings),
+                    bytesize::ByteSize::b(min_bytes)
+                );
+                true
+            } else {
+                false
+            }
+        }
+    }
+}
+
+fn skip_due_to_size_policy(
+    options: &CompressionOptions,
+    output_path: &Path,
+    output_size: u64,
+    original_size: u64,
+    compression_result: &mut CompressionResult,
+) -> bool {
+    if options.output_zip.is_some() || !options.overwrite_policy.compares_size() {
+        return false;
+    }
+
+    match output_path.metadata() {
+        Ok(existing_metadata) => {
+            let existing_size = existing_metadata.len();
+            if options.overwrite_policy.keeps_existing(existing_size, output_size) {
+                let (comparison, policy) = match options.overwrite_policy {
+                    OverwritePolicy::Smaller => ("not smaller", "smaller"),
+                    _ => ("not bigger", "bigger"),
+                };
+                compression_result.status = CompressionStatus::Skipped;
+                compression_result.compressed_size = original_size;
+                compression_result.message = format!(
+                    "Existing file is {comparison} than the new output ({} vs {}), skipped due overwrite policy '{policy}'",
+                    bytesize::ByteSize::b(existing_size),
+                    bytesize::ByteSize::b(output_size)
+                );
+                return true;
+            }
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(_) => {
+            compression_result.message = "Error reading existing file metadata".to_string();
+        }
+    }
+
+    false
+}
+
+fn write_compressed_file(
+    output_path: &PathBuf,
+    compressed_image: &[u8],
+    options: &CompressionOptions,
+    input_file_metadata: &Metadata,
+) -> Result<(), String> {
+    let mut output_file = File::create(output_path).map_err(|_| "Error creating output file".to_string())?;
+
+    output_file
+        .write_all(compressed_image)
+        .map_err(|_| "Error writing output file".to_string())?;
+    finish_output_file(output_path, &output_file, options, input_file_metadata)
+}
+
+/// Sets the permissions and the dates of a written output file
+fn finish_output_file(
+    output_path: &Path,
+    output_file: &File,
+    options: &CompressionOptions,
+    input_file_metadata: &Metadata,
+) -> Result<(), String> {
+    if let Some(mode) = options.file_mode {
+        set_mode(output_path, mode).map_err(|_| "Error setting file permissions".to_string())?;
+    }
+
+    if options.keep_dates {
+        preserve_file_times(output_file, input_file_metadata).map_err(|_| "Error preserving file times".to_string())?;
+    }
+
+    Ok(())
+}
+
+fn build_compression_parameters(options: &CompressionOptions, buffer: &[u8]) -> Result<CSParameters, Box<dyn Error>> {
+    let mut parameters = CSParameters::new();
+    let quality = options.quality.unwrap_or(DEFAULT_QUALITY);
+
+    parameters.jpeg.quality = quality;
+    parameters.png.quality = quality;
+    parameters.webp.quality = quality;
+    let lossless = options.lossless || options.optimize;
+    parameters.gif.quality = if lossless {
+        100
+    } else if quality == 0 {
+        1
+    } else {
+        quality
+    };
+    parameters.jpeg.preserve_icc = !options.strip_icc;
+
+    parameters.jpeg.optimize = lossless;
+    parameters.png.optimize = lossless;
+    // Near-lossless and the effort start from the exact pixels, see `encode_webp_advanced`
+    parameters.webp.lossless = lossless || options.webp_near_lossless.is_some() || options.webp_effort.is_some();
+
+    parameters.keep_metadata = options.exif;
+
+    parameters.jpeg.chroma_subsampling = options.jpeg_chroma_subsampling;
+    parameters.jpeg.progressive = !options.jpeg_baseline;
+
+    parameters.png.optimization_level = options.png_opt_level;
+    parameters.png.force_zopfli = options.zopfli;
+
+    parameters.tiff.algorithm = match options.tiff_compression {
+        TiffCompression::None => caesium::parameters::TiffCompression::Uncompressed,
+        TiffCompression::Lzw => caesium::parameters::TiffCompression::Lzw,
+        TiffCompression::Deflate => caesium::parameters::TiffCompression::Deflate,
+    };
+
+    let needs_resize = is_resize_needed(options);
+    if needs_resize {
+        let mime_type = get_file_mime_type_from_buffer(buffer);
+        build_resize_parameters(options, &mut parameters, buffer, mime_type)?;
+    }
+
+    Ok(parameters)
+}
+
+/// Extension of the files written in `format`, `None` for the original format
+fn output_extension(format: OutputFormat) -> Option<&'static str> {
+    match format {
+        OutputFormat::Jpeg => Some("jpg"),
+        OutputFormat::Png => Some("png"),
+        OutputFormat::Webp => Some("webp"),
+        OutputFormat::Tiff => Some("tiff"),
+        OutputFormat::Gif => Some("gif"),
+        OutputFormat::Avif => Some("avif"),
+        OutputFormat::Jxl => Some("jxl"),
+        // Only known when compressing, see `auto_format`. Planned outputs and collisions use WebP.
+        OutputFormat::Auto => Some("webp"),
+        OutputFormat::Original => None,
+    }
+}
+
+/// Format of the content of `input_file` when its extension names another one, e.g. a PNG saved as `photo.jpg`.
+/// Files without an extension or that can't be read are not considered misnamed.
+fn detect_misnamed_format(input_file: &Path) -> Option<OutputFormat> {
+    let extension = input_file.extension()?.to_str()?.to_lowercase();
+    let detected_format = infer::get_from_path(input_file)
+        .ok()
+        .flatten()
+        .and_then(|file_type| format_from_mime_type(file_type.mime_type()))?;
+    let matches_extension = match detected_format {
+        OutputFormat::Jpeg => matches!(extension.as_str(), "jpg" | "jpeg" | "jpe" | "jfif"),
+        OutputFormat::Png => extension == "png",
+        OutputFormat::Webp => extension == "webp",
+        OutputFormat::Tiff => matches!(extension.as_str(), "tif" | "tiff"),
+        OutputFormat::Gif => extension == "gif",
+        OutputFormat::Avif => extension == "avif",
+        OutputFormat::Jxl => extension == "jxl",
+        OutputFormat::Auto | OutputFormat::Original => true,
+    };
+
+    (!matches_extension).then_some(detected_format)
+}
+
+fn compute_output_full_path(
+    output_directory: &Path,
+    input_file_path: &Path,
+    base_directory: &PathBuf,
+    keep_structure: bool,
+    suffix: &str,
+    format: OutputFormat,
+    extension_case: ExtensionCase,
+    same_folder_as_input: bool,
+) -> Option<(PathBuf, OsString)> {
+    let extension: OsString = match output_extension(format) {
+        Some(extension) => extension.into(),
+        None => input_file_path.extension().unwrap_or_default().to_os_string(),
+    };
+    let extension = match extension_case {
+        ExtensionCase::Lower => extension.to_ascii_lowercase(),
+        ExtensionCase::Upper => extension.to_ascii_uppercase(),
+        ExtensionCase::Preserve => extension,
+    };
+
+    let base_name = input_file_path.file_stem().unwrap_or_default().to_os_string();
+    let mut output_file_name = base_name;
+    output_file_name.push(suffix);
+    if !extension.is_empty() {
+        output_file_name.push(".");
+        output_file_name.push(extension);
+    }
+
+    if keep_structure {
+        let parent = input_file_path.parent()?;
+        if !parent.exists() {
+            return None;
+        }
+        let parent = match absolute(parent) {
+            Ok(p) => p,
+            Err(_) => return None,
+        };
+
+        if same_folder_as_input {
+            return Some((parent.clone(), output_file_name));
+        }
+        let output_path_prefix = if !base_directory.clone().into_os_string().is_empty() {
+            match parent.strip_prefix(base_directory) {
+                // `absolute` keeps `..`, which would lead outside the output folder
+                Ok(p) if !p.components().any(|c| c == Component::ParentDir) => p.to_path_buf(),
+                _ => return None,
+            }
+        } else {
+            let prefix = parent.display().to_string().replace(":", "");
+            PathBuf::from(prefix)
+        };
+        let full_output_directory = output_directory.join(output_path_prefix);
+        Some((full_output_directory, output_file_name))
+    } else {
+        Some((PathBuf::from(output_directory), output_file_name))
+    }
+}
+
+fn build_resize_parameters(
+    options: &CompressionOptions,
+    parameters: &mut CSParameters,
+    buffer: &[u8],
+    mime_type: Option<String>,
+) -> Result<(), Box<dyn Error>> {
+    let (width, height) = get_real_resolution(buffer, mime_type, options.exif)?;
+    (parameters.width, parameters.height) =
+        resize_dimensions(options, width as u32, height as u32, || read_dpi(buffer));
+    Ok(())
+}
+
+/// Dimensions the resizing options give to a `width`x`height` image. A side is 0 when it follows the aspect ratio, and
+/// both are when the image is left as it is.
+fn resize_dimensions(
+    options: &CompressionOptions,
+    width: u32,
+    height: u32,
+    source_dpi: impl FnOnce() -> Option<(u32, u32)>,
+) -> (u32, u32) {
+    let (mut resized_width, mut resized_height) = (0, 0);
+    if let (Some(FitMode::Contain), Some(box_width), Some(box_height)) =
+        (options.fit_mode, options.width, options.height)
+    {
+        if let Some((fit_width, fit_height)) = fit_within(width, height, box_width, box_height) {
+            (resized_width, resized_height) = (fit_width, fit_height);
+        }
+    } else if options.width.is_some() || options.height.is_some() {
+        resized_width = options.width.unwrap_or(0);
+        resized_height = options.height.unwrap_or(0);
+    } else if let Some(long_edge) = options.long_edge {
+        if width > height {
+            resized_width = long_edge;
+        } else {
+            resized_height = long_edge;
+        }
+    } else if let Some(short_edge) = options.short_edge {
+        if width < height {
+            resized_width = short_edge;
+        } else {
+            resized_height = short_edge;
+        }
+    } else if let Some((box_width, box_height)) = options.fit {
+        if let Some((fit_width, fit_height)) = fit_within(width, height, box_width, box_height) {
+            (resized_width, resized_height) = (fit_width, fit_height);
+        }
+    } else if let Some(scale) = options.scale.filter(|&scale| scale < 100.0) {
+        resized_width = scale_down(width, scale);
+        resized_height = scale_down(height, scale);
+    } else if let (true, Some(dpi)) = (options.resample_dpi, options.dpi) {
+        // Without an embedded resolution, the physical size is unknown
+        if let Some((source_dpi_x, source_dpi_y)) = source_dpi().filter(|&(x, y)| x > 0 && y > 0) {
+            resized_width = resample(width, source_dpi_x, dpi);
+            resized_height = resample(height, source_dpi_y, dpi);
+        }
+    }
+
+    if options.no_upscale && (resized_width >= width || resized_height >= height) {
+        return (0, 0);
+    }
+    (resized_width, resized_height)
+}
+
+fn filter_type(filter: ResizeFilter) -> FilterType {
+    match filter {
+        ResizeFilter::Lanczos3 => FilterType::Lanczos3,
+        ResizeFilter::Catmullrom => FilterType::CatmullRom,
+        ResizeFilter::Triangle => FilterType::Triangle,
+        ResizeFilter::Nearest => FilterType::Nearest,
+    }
+}
+
+/// Size of a `width`x`height` image scaled down to fit within the box, preserving the aspect ratio.
+/// Returns `None` when the image already fits, as it is never upscaled.
+fn fit_within(width: u32, height: u32, box_width: u32, box_height: u32) -> Option<(u32, u32)> {
+    if width <= box_width && height <= box_height {
+        return None;
+    }
+
+    let scale = f64::min(box_width as f64 / width as f64, box_height as f64 / height as f64);
+    let fit_width = ((width as f64 * scale).round() as u32).clamp(1, box_width);
+    let fit_height = ((height as f64 * scale).round() as u32).clamp(1, box_height);
+    Some((fit_width, fit_height))
+}
+
+/// Top left corner of a `crop_width`x`crop_height` region of a `width`x`height` image, placed by `gravity`
+fn crop_origin(width: u32, height: u32, crop_width: u32, crop_height: u32, gravity: Gravity) -> (u32, u32) {
+    let (free_width, free_height) = (width.saturating_sub(crop_width), height.saturating_sub(crop_height));
+    let x = match gravity {
+        Gravity::West | Gravity::Northwest | Gravity::Southwest => 0,
+        Gravity::East | Gravity::Northeast | Gravity::Southeast => free_width,
+        Gravity::Center | Gravity::North | Gravity::South => free_width / 2,
+    };
+    let y = match gravity {
+        Gravity::North | Gravity::Northwest | Gravity::Northeast => 0,
+        Gravity::South | Gravity::Southwest | Gravity::Southeast => free_height,
+        Gravity::Center | Gravity::West | Gravity::East => free_height / 2,
+    };
+    (x, y)
+}
+
+/// Number of pixels left after scaling `pixels` down to `percent` of it
+fn scale_down(pixels: u32, percent: f64) -> u32 {
+    ((pixels as f64 * percent / 100.0).round() as u32).max(1)
+}
+
+/// Number of pixels keeping the same physical length when going from `source_dpi` to `target_dpi`
+fn resample(pixels: u32, source_dpi: u32, target_dpi: u32) -> u32 {
+    ((pixels as f64 * target_dpi as f64 / source_dpi as f64).round() as u32).max(1)
+}
+
+fn get_real_resolution(
+    buffer: &[u8],
+    mime_type: Option<String>,
+    keep_metadata: bool,
+) -> Result<(usize, usize), Box<dyn Error>> {
+    let resolution = imagesize::blob_size(buffer)?;
+    let mut orientation = 1;
+    let mime = mime_type.unwrap_or("".to_string());
+    if mime == "image/jpeg" && keep_metadata {
+        if let Ok(e) = exif::Reader::new().read_from_container(&mut std::io::Cursor::new(buffer)) {
+            let exif_field = match e.get_field(exif::Tag::Orientation, exif::In::PRIMARY) {
+                Some(f) => f,
+                None => return Ok((resolution.width, resolution.height)),
+            };
+            orientation = exif_field.value.get_uint(0).unwrap_or(1);
+        };
+    }
+    let (width, height) = match orientation {
+        5..=8 => (resolution.height, resolution.width),
+        _ => (resolution.width, resolution.height),
+    };
+
+    Ok((width, height))
+}
+
+fn preserve_file_times(output_file: &File, original_file_metadata: &Metadata) -> io::Result<()> {
+    let (last_modification_time, last_access_time) =
+        (original_file_metadata.modified()?, original_file_metadata.accessed()?);
+    #[cfg(target_os = "windows")]
+    {
+        let creation_time = original_file_metadata.created()?;
+
+        output_file.set_times(
+            FileTimes::new()
+                .set_modified(last_modification_time)
+                .set_accessed(last_access_time)
+                .set_created(creation_time),
+        )?;
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    {
+        output_file.set_times(
+            FileTimes::new()
+                .set_modified(last_modification_time)
+                .set_accessed(last_access_time),
+        )?;
+    }
+
+    Ok(())
+}
+/// Options with the output format of `input_file` resolved from `format_conversions`, and its quality from
+/// `quality_rules`, or else from `format_qualities`. Formats without a conversion, or files that can't be read, keep
+/// their original format.
+fn options_for_file<'a>(input_file: &Path, options: &'a CompressionOptions) -> Cow<'a, CompressionOptions> {
+    let rule_quality = options.quality.and_then(|_| rule_quality(input_file, options));
+    let has_format_qualities = options.quality.is_some() && !options.format_qualities.is_empty();
+    if options.format_conversions.is_empty() && rule_quality.is_none() && !has_format_qualities {
+        return Cow::Borrowed(options);
+    }
+
+    let needs_input_format = !options.format_conversions.is_empty()
+        || (has_format_qualities && rule_quality.is_none() && options.format == OutputFormat::Original);
+    let input_format = needs_input_format
+        .then(|| infer::get_from_path(input_file).ok().flatten())
+        .flatten()
+        .and_then(|file_type| format_from_mime_type(file_type.mime_type()));
+    let format = if options.format_conversions.is_empty() {
+        options.format
+    } else {
+        input_format
+            .and_then(|input_format| {
+                options
+                    .format_conversions
+                    .iter()
+                    .rev()
+                    .find(|(from, _)| *from == input_format)
+                    .map(|(_, to)| *to)
+            })
+            .unwrap_or(OutputFormat::Original)
+    };
+
+    let output_format = match format {
+        OutputFormat::Original => input_format,
+        format => Some(format),
+    };
+    let format_quality = output_format
+        .filter(|_| has_format_qualities)
+        .and_then(|output_format| {
+            options
+                .format_qualities
+                .iter()
+                .find(|(format, _)| *format == output_format)
+                .map(|(_, quality)| *quality)
+        });
+    let quality = rule_quality.or(format_quality).or(options.quality);
+    if format == options.format && quality == options.quality {
+        return Cow::Borrowed(options);
+    }
+
+    Cow::Owned(CompressionOptions {
+        quality,
+        format,
+        ..options.clone()
+    })
+}
+
+/// Quality of the first rule matching the path of `input_file` relative to the base path, or to its own folder when
+/// it is outside of it
+fn rule_quality(input_file: &Path, options: &CompressionOptions) -> Option<u32> {
+    if options.quality_rules.is_empty() {
+        return None;
+    }
+    let input_file = absolute(input_file).ok()?;
+    let base_path = absolute(&options.base_path).ok()?;
+    let relative_path = match input_file.strip_prefix(&base_path) {
+        Ok(relative_path) => relative_path,
+        Err(_) => Path::new(input_file.file_name()?),
+    };
+
+    quality_for(&options.quality_rules, relative_path)
+}
+
+fn format_from_mime_type(mime_type: &str) -> Option<OutputFormat> {
+    match mime_type {
+        "image/jpeg" => Some(OutputFormat::Jpeg),
+        "image/png" => Some(OutputFormat::Png),
+        "image/gif" => Some(OutputFormat::Gif),
+        "image/webp" => Some(OutputFormat::Webp),
+        "image/tiff" => Some(OutputFormat::Tiff),
+        _ => None,
+    }
+}
+
+fn map_supported_formats(format: OutputFormat) -> SupportedFileTypes {
+    match format {
+        OutputFormat::Jpeg => SupportedFileTypes::Jpeg,
+        OutputFormat::Png => SupportedFileTypes::Png,
+        OutputFormat::Gif => SupportedFileTypes::Gif,
+        OutputFormat::Webp => SupportedFileTypes::WebP,
+        OutputFormat::Tiff => SupportedFileTypes::Tiff,
+        _ => SupportedFileTypes::Unkn,
+    }
+}
+
+/// Format written for the inputs whose own format can't be written, from the header of the file
+fn decoded_output_format(file_path: &Path, options: &CompressionOptions) -> Option<OutputFormat> {
+    if options.raw_preview && is_raw_file(file_path) {
+        return Some(OutputFormat::Jpeg);
+    }
+    let mut header = [0; 32];
+    let length = File::open(file_path).and_then(|mut file| file.read(&mut header)).ok()?;
+    let header = &header[..length];
+    if is_heic(header) {
+        Some(OutputFormat::Jpeg)
+    } else {
+        detect_legacy_format(header).map(|_| OutputFormat::Png)
+    }
+}
+
+fn read_file_to_vec(file_path: &PathBuf) -> io::Result<Vec<u8>> {
+    let mut file = File::open(file_path)?;
+    let mut buffer = Vec::new();
+    file.read_to_end(&mut buffer)?;
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_compute_output_full_path() {
+        // Create temporary directories for testing
+        let temp_dir = tempdir().unwrap();
+        let output_directory = temp_dir.path().join("output");
+        let base_directory = temp_dir.path().join("base");
+        let input_folder = base_directory.join("folder");
+
+        // Create the necessary directory structure
+        fs::create_dir_all(&output_directory).unwrap();
+        fs::create_dir_all(&input_folder).unwrap();
+
+        // Test case 1: keep_structure = true
+        let input_file_path = input_folder.join("test.jpg");
+        let result = compute_output_full_path(
+            &output_directory,
+            &input_file_path,
+            &base_directory,
+            true,
+            "_suffix",
+            OutputFormat::Original,
+            ExtensionCase::Preserve,
+            false,
+        )
+        .unwrap();
+        assert_eq!(result, (output_directory.join("folder"), "test_suffix.jpg".into()));
+
+        // Test case 2: keep_structure = false
+        let result = compute_output_full_path(
+            &output_directory,
+            &input_file_path,
+            &base_directory,
+            false,
+            "_suffix",
+            OutputFormat::Original,
+            ExtensionCase::Preserve,
+            false,
+        )
+        .unwrap();
+        assert_eq!(result, (output_directory.clone(), "test_suffix.jpg".into()));
+
+        // Test case 3: input file without extension
+        let input_file_path = input_folder.join("test");
+        let result = compute_output_full_path(
+            &output_directory,
+            &input_file_path,
+            &base_directory,
+            false,
+            "_suffix",
+            OutputFormat::Original,
+            ExtensionCase::Preserve,
+            false,
+        )
+        .unwrap();
+        assert_eq!(result, (output_directory.clone(), "test_suffix".into()));
+
+        // Test case 4: input file with different base directory
+        let different_base = temp_dir.path().join("different_base");
+        let different_folder = different_base.join("folder");
+        fs::create_dir_all(&different_folder).unwrap();
+        let input_file_path = different_folder.join("test.jpg");
+        let result = compute_output_full_path(
+            &output_directory,
+            &input_file_path,
+            &base_directory,
+            false,
+            "_suffix",
+            OutputFormat::Original,
+            ExtensionCase::Preserve,
+            false,
+        )
+        .unwrap();
+        assert_eq!(result, (output_directory.clone(), "test_suffix.jpg".into()));
+
+        // Test case 5: input file with OutputFormat::Jpeg
+        let result = compute_output_full_path(
+            &output_directory,
+            &input_file_path,
+            &base_directory,
+            false,
+            "_suffix",
+            OutputFormat::Jpeg,
+            ExtensionCase::Preserve,
+            false,
+        )
+        .unwrap();
+        assert_eq!(result, (output_directory.clone(), "test_suffix.jpg".into()));
+
+        // Test case 6: input file with OutputFormat::Png
+        let result = compute_output_full_path(
+            &output_directory,
+            &input_file_path,
+            &base_directory,
+            false,
+            "_suffix",
+            OutputFormat::Png,
+            ExtensionCase::Preserve,
+            false,
+        )
+        .unwrap();
+        assert_eq!(result, (output_directory.clone(), "test_suffix.png".into()));
+
+        // Test case 7: input file with OutputFormat::Webp
+        let result = compute_output_full_path(
+            &output_directory,
+            &input_file_path,
+            &base_directory,
+            false,
+            "_suffix",
+            OutputFormat::Webp,
+            ExtensionCase::Preserve,
+            false,
+        )
+        .unwrap();
+        assert_eq!(result, (output_directory.clone(), "test_suffix.webp".into()));
+
+        // Test case 8: input file with OutputFormat::Tiff
+        let result = compute_output_full_path(
+            &output_directory,
+            &input_file_path,
+            &base_directory,
+            false,
+            "_suffix",
+            OutputFormat::Tiff,
+            ExtensionCase::Preserve,
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(result, (output_directory.clone(), "test_suffix.tiff".into()));
+
+        // Test case 8: input file with OutputFormat::Gif
+        let result = compute_output_full_path(
+            &output_directory,
+            &input_file_path,
+            &base_directory,
+            false,
+            "_suffix",
+            OutputFormat::Gif,
+            ExtensionCase::Preserve,
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(result, (output_directory.clone(), "test_suffix.gif".into()));
+
+        // Test case 9: formats written by their own encoders
+        for (format, name) in [
+            (OutputFormat::Avif, "test_suffix.avif"),
+            (OutputFormat::Jxl, "test_suffix.jxl"),
+        ] {
+            let result = compute_output_full_path(
+                &output_directory,
+                &input_file_path,
+                &base_directory,
+                false,
+                "_suffix",
+                format,
+                ExtensionCase::Preserve,
+                false,
+            )
+            .unwrap();
+            assert_eq!(result, (output_directory.clone(), name.into()));
+        }
+
+        // Test case 10: same_folder_as_input with subfolder
+        let subfolder = input_folder.join("subfolder");
+        fs::create_dir_all(&subfolder).unwrap();
+        let input_file_path = subfolder.join("test.jpg");
+        let result = compute_output_full_path(
+            &output_directory,
+            &input_file_path,
+            &base_directory,
+            true,
+            "_suffix",
+            OutputFormat::Original,
+            ExtensionCase::Preserve,
+            true,
+        )
+        .unwrap();
+        assert_eq!(result, (subfolder, "test_suffix.jpg".into()));
+    }
+
+    #[test]
+    fn test_perform_compression() {
+        let input_files = vec![
+            absolute(PathBuf::from("samples/j0.JPG")).unwrap(),
+            absolute(PathBuf::from("samples/p0.png")).unwrap(),
+            absolute(PathBuf::from("samples/w0.webp")).unwrap(),
+            absolute(PathBuf::from("samples/t0.tif")).unwrap(),
+        ];
+
+        let mut options = setup_options();
+        options.base_path = absolute(PathBuf::from("samples")).unwrap();
+        let temp_dir = tempdir().unwrap().path().to_path_buf();
+        options.output_folder = Some(temp_dir.clone());
+
+        let mut results = start_compression(&input_files, &options, false, None);
+        assert_eq!(results.len(), 4);
+        assert!(results.iter().all(|r| matches!(r.status, CompressionStatus::Success)));
+        assert!(results.iter().all(|r| fs::exists(&r.output_path).unwrap_or(false)));
+        assert_eq!(PathBuf::from(&results[0].output_path), temp_dir.join("j0.jpg"));
+        assert_eq!(PathBuf::from(&results[1].output_path), temp_dir.join("p0.png"));
+        assert_eq!(PathBuf::from(&results[2].output_path), temp_dir.join("w0.webp"));
+        assert_eq!(PathBuf::from(&results[3].output_path), temp_dir.join("t0.tif"));
+
+        let input_files = vec![
+            absolute(PathBuf::from("samples/j0.JPG")).unwrap(),
+            absolute(PathBuf::from("samples/p0.png")).unwrap(),
+            absolute(PathBuf::from("samples/w0.webp")).unwrap(),
+            absolute(PathBuf::from("samples/t0.tif")).unwrap(),
+            absolute(PathBuf::from("samples/level_1_0/level_2_0/p2.png")).unwrap(),
+            absolute(PathBuf::from("samples/level_1_0/level_2_0/level_3_0/g1.gif")).unwrap(),
+            absolute(PathBuf::from("samples/level_1_0/j1.jpg")).unwrap(),
+            absolute(PathBuf::from("samples/level_1_1/w1.webp")).unwrap(),
+        ];
+
+        let temp_dir = tempdir().unwrap().path().to_path_buf();
+        options.output_folder = Some(temp_dir.clone());
+        options.keep_structure = true;
+        results = start_compression(&input_files, &options, false, None);
+        assert_eq!(results.len(), 8);
+        assert!(results.iter().all(|r| matches!(r.status, CompressionStatus::Success)));
+        assert!(results.iter().all(|r| fs::exists(&r.output_path).unwrap_or(false)));
+        assert_eq!(PathBuf::from(&results[0].output_path), temp_dir.join("j0.jpg"));
+        assert_eq!(PathBuf::from(&results[1].output_path), temp_dir.join("p0.png"));
+        assert_eq!(PathBuf::from(&results[2].output_path), temp_dir.join("w0.webp"));
+        assert_eq!(PathBuf::from(&results[3].output_path), temp_dir.join("t0.tif"));
+        assert_eq!(
+            PathBuf::from(&results[4].output_path),
+            temp_dir.join("level_1_0/level_2_0/p2.png")
+        );
+        assert_eq!(
+            PathBuf::from(&results[5].output_path),
+            temp_dir.join("level_1_0/level_2_0/level_3_0/g1.gif")
+        );
+        assert_eq!(
+            PathBuf::from(&results[6].output_path),
+            temp_dir.join("level_1_0/j1.jpg")
+        );
+        assert_eq!(
+            PathBuf::from(&results[7].output_path),
+            temp_dir.join("level_1_1/w1.webp")
+        );
+
+        options.quality = Some(100);
+
+        options.overwrite_policy = OverwritePolicy::Never;
+        results = start_compression(&input_files, &options, false, None);
+        assert!(results.iter().all(|r| matches!(r.status, CompressionStatus::Skipped)));
+        assert!(results.iter().all(|r| fs::exists(&r.output_path).unwrap_or(false)));
+
+        options.quality = Some(100);
+        options.overwrite_policy = OverwritePolicy::Bigger;
+        results = start_compression(&input_files, &options, false, None);
+        assert!(results.iter().all(|r| matches!(r.status, CompressionStatus::Skipped)));
+        assert!(results.iter().all(|r| fs::exists(&r.output_path).unwrap_or(false)));
+
+        options.quality = Some(100);
+        options.overwrite_policy = OverwritePolicy::All;
+        results = start_compression(&input_files, &options, true, None);
+        assert!(results.iter().all(|r| matches!(r.status, CompressionStatus::Success)));
+        assert!(results.iter().all(|r| fs::exists(&r.output_path).unwrap_or(false)));
+
+        options.quality = Some(100);
+        options.png_opt_level = 6;
+        options.lossless = true;
+        options.overwrite_policy = OverwritePolicy::All;
+        results = start_compression(&input_files, &options, true, None);
+        assert!(results.iter().all(|r| matches!(r.status, CompressionStatus::Success)));
+        assert!(results.iter().all(|r| fs::exists(&r.output_path).unwrap_or(false)));
+
+        options.quality = Some(80);
+        options.keep_dates = true;
+        results = start_compression(&input_files, &options, false, None);
+
+        assert!(results.iter().all(|r| matches!(r.status, CompressionStatus::Success)));
+        assert!(results.iter().all(|r| {
+            let original_metadata = fs::metadata(&r.original_path).unwrap();
+            let o_mtime = original_metadata
+                .modified()
+                .unwrap()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            let o_ltime = original_metadata
+                .accessed()
+                .unwrap()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+
+            let compressed_metadata = fs::metadata(&r.output_path).unwrap();
+            let c_mtime = compressed_metadata
+                .modified()
+                .unwrap()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            let c_ltime = compressed_metadata
+                .accessed()
+                .unwrap()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            o_mtime == c_mtime && (o_ltime + 10) >= c_ltime
+        }));
+        assert!(results.iter().all(|r| fs::exists(&r.output_path).unwrap_or(false)));
+    }
+
+    #[test]
+    fn test_compression_to_zip() {
+        let input_files = vec![
+            absolute(PathBuf::from("samples/j0.JPG")).unwrap(),
+            absolute(PathBuf::from("samples/level_1_0/level_2_0/p2.png")).unwrap(),
+        ];
+        let temp_dir = tempdir().unwrap();
+        let archive_path = temp_dir.path().join("output.zip");
+
+        let mut options = setup_options();
+        options.base_path = absolute(PathBuf::from("samples")).unwrap();
+        options.keep_structure = true;
+        let zip_output = Arc::new(ZipOutput::new(archive_path.clone(), OverwritePolicy::All).unwrap());
+        options.output_zip = Some(Arc::clone(&zip_output));
+
+        let results = start_compression(&input_files, &options, false, None);
+        assert!(results.iter().all(|r| matches!(r.status, CompressionStatus::Success)));
+        assert_eq!(PathBuf::from(&results[0].output_path), archive_path.join("j0.jpg"));
+        assert!(zip_output.finish().unwrap());
+
+        let mut archive = zip::ZipArchive::new(File::open(&archive_path).unwrap()).unwrap();
+        let mut names: Vec<String> = archive.file_names().map(String::from).collect();
+        names.sort();
+        assert_eq!(names, vec!["j0.jpg", "level_1_0/level_2_0/p2.png"]);
+        for name in names {
+            let mut entry = archive.by_name(&name).unwrap();
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data).unwrap();
+            assert!(image::load_from_memory(&data).is_ok());
+        }
+        assert!(!temp_dir.path().join("j0.jpg").exists());
+    }
+
+    #[test]
+    fn test_gif_output() {
+        let temp_dir = tempdir().unwrap();
+        let input_file = absolute(PathBuf::from("samples/level_1_0/level_2_0/level_3_0/g1.gif")).unwrap();
+        let mut options = setup_options();
+        options.output_folder = Some(temp_dir.path().to_path_buf());
+        options.base_path = input_file.parent().unwrap().to_path_buf();
+        let decode_frames = |path: &Path| -> Vec<image::RgbaImage> {
+            let decoder = image::codecs::gif::GifDecoder::new(io::BufReader::new(File::open(path).unwrap())).unwrap();
+            image::AnimationDecoder::into_frames(decoder)
+                .map(|frame| frame.unwrap().into_buffer())
+                .collect()
+        };
+
+        // The animation is only re-palettized, whatever the quality
+        for lossless in [true, false] {
+            options.lossless = lossless;
+            let results = start_compression(&[input_file.clone()], &options, false, None);
+            assert!(
+                matches!(results[0].status, CompressionStatus::Success),
+                "{}",
+                results[0].message
+            );
+            let output_size = fs::metadata(&results[0].output_path).unwrap().len();
+            assert_eq!(results[0].compressed_size, output_size);
+            assert!(results[0].compressed_size <= results[0].original_size);
+            let output_frames = decode_frames(Path::new(&results[0].output_path));
+            assert_eq!(output_frames.len(), 10);
+            assert!(output_frames == decode_frames(&input_file));
+        }
+
+        options.width = Some(100);
+        let results = start_compression(&[input_file], &options, false, None);
+        assert!(matches!(results[0].error_kind, Some(CompressionErrorKind::Unsupported)));
+    }
+
+    #[test]
+    fn test_svg_output() {
+        let temp_dir = tempdir().unwrap();
+        let input_file = temp_dir.path().join("logo.svg");
+        fs::write(
+            &input_file,
+            "<?xml version=\"1.0\"?>\n<!-- Drawn by hand -->\n<svg xmlns=\"http://www.w3.org/2000/svg\" \
+             viewBox=\"0 0 10 10\">\n  <path d=\"M 1.123456 2.5 L 8.000001 , 9\" fill-opacity=\"1\"/>\n</svg>\n",
+        )
+        .unwrap();
+        let mut options = setup_options();
+        options.output_folder = Some(temp_dir.path().join("out"));
+        options.base_path = temp_dir.path().to_path_buf();
+
+        let results = start_compression(&[input_file.clone()], &options, true, None);
+        assert!(matches!(results[0].status, CompressionStatus::Success));
+        assert!(!temp_dir.path().join("out/logo.svg").exists());
+
+        let results = start_compression(&[input_file.clone()], &options, false, None);
+        assert!(
+            matches!(results[0].status, CompressionStatus::Success),
+            "{}",
+            results[0].message
+        );
+        assert_eq!(
+            PathBuf::from(&results[0].output_path),
+            temp_dir.path().join("out/logo.svg")
+        );
+        assert_eq!(
+            fs::read_to_string(&results[0].output_path).unwrap(),
+            "<?xml version=\"1.0\"?><svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 10 10\">\
+             <path d=\"M1.1235 2.5L8 9\"/></svg>"
+        );
+        assert_eq!(
+            results[0].compressed_size,
+            fs::metadata(&results[0].output_path).unwrap().len()
+        );
+        assert!(results[0].compressed_size < results[0].original_size);
+
+        options.format = OutputFormat::Png;
+        let results = start_compression(&[input_file], &options, false, None);
+        assert!(matches!(results[0].error_kind, Some(CompressionErrorKind::Unsupported)));
+    }
+
+    #[test]
+    fn test_multi_page_tiff_output() {
+        use tiff::encoder::{colortype, TiffEncoder};
+
+        let temp_dir = tempdir().unwrap();
+        let mut tiff = Vec::new();
+        {
+            let mut encoder = TiffEncoder::new(io::Cursor::new(&mut tiff)).unwrap();
+            for value in [0, 100, 200] {
+                encoder
+                    .write_image::<colortype::Gray8>(32, 16, &[value; 32 * 16])
+                    .unwrap();
+            }
+        }
+        let input_file = temp_dir.path().join("scan.tif");
+        fs::write(&input_file, &tiff).unwrap();
+        let mut options = setup_options();
+        options.output_folder = Some(temp_dir.path().join("out"));
+        options.base_path = temp_dir.path().to_path_buf();
+
+        let results = start_compression(&[input_file.clone()], &options, false, None);
+        assert!(
+            matches!(results[0].status, CompressionStatus::Success),
+            "{}",
+            results[0].message
+        );
+        assert_eq!(results[0].message, "Recompressed 3 pages");
+        let output = fs::read(&results[0].output_path).unwrap();
+        assert_eq!(tiff_page_count(&output), 3);
+        let mut decoder = tiff::decoder::Decoder::new(io::Cursor::new(&output)).unwrap();
+        assert_eq!(decoder.get_tag_u32(tiff::tags::Tag::Compression).unwrap(), 8);
+
+        options.tiff_compression = TiffCompression::Lzw;
+        let results = start_compression(&[input_file.clone()], &options, false, None);
+        let output = fs::read(&results[0].output_path).unwrap();
+        let mut decoder = tiff::decoder::Decoder::new(io::Cursor::new(&output)).unwrap();
+        assert_eq!(decoder.get_tag_u32(tiff::tags::Tag::Compression).unwrap(), 5);
+
+        // The pages would be lost
+        options.format = OutputFormat::Jpeg;
+        let results = start_compression(&[input_file], &options, false, None);
+        assert!(matches!(results[0].status, CompressionStatus::Skipped));
+        assert!(results[0].message.contains("3 pages"), "{}", results[0].message);
+    }
+
+    #[test]
+    fn test_pdf_output() {
+        use lopdf::{dictionary, Document, Object, Stream};
+
+        let temp_dir = tempdir().unwrap();
+        let image = image::RgbImage::from_fn(64, 48, |x, y| image::Rgb([(x * 4) as u8, (y * 5) as u8, 128]));
+        let mut jpeg = Vec::new();
+        image::codecs::jpeg::JpegEncoder::new_with_quality(&mut jpeg, 100)
+            .encode_image(&image)
+            .unwrap();
+        let mut document = Document::with_version("1.5");
+        document.add_object(Stream::new(
+            dictionary! {
+                "Type" => "XObject",
+                "Subtype" => "Image",
+                "Width" => 64,
+                "Height" => 48,
+                "ColorSpace" => "DeviceRGB",
+                "BitsPerComponent" => 8,
+                "Filter" => "DCTDecode",
+            },
+            jpeg,
+        ));
+        let pages_id =
+            document.add_object(dictionary! { "Type" => "Pages", "Kids" => Vec::<Object>::new(), "Count" => 0 });
+        let catalog_id = document.add_object(dictionary! { "Type" => "Catalog", "Pages" => pages_id });
+        document.trailer.set("Root", catalog_id);
+        let input_file = temp_dir.path().join("scan.pdf");
+        document.save(&input_file).unwrap();
+        let mut options = setup_options();
+        options.output_folder = Some(temp_dir.path().join("out"));
+        options.base_path = temp_dir.path().to_path_buf();
+
+        let results = start_compression(&[input_file.clone()], &options, false, None);
+        assert!(
+            matches!(results[0].status, CompressionStatus::Success),
+            "{}",
+            results[0].message
+        );
+        assert_eq!(
+            PathBuf::from(&results[0].output_path),
+            temp_dir.path().join("out/scan.pdf")
+        );
+        assert_eq!(results[0].message, "Recompressed 1 of 1 images");
+        assert!(results[0].compressed_size < results[0].original_size);
+        assert!(Document::load(&results[0].output_path).is_ok());
+
+        options.width = Some(32);
+        let results = start_compression(&[input_file], &options, false, None);
+        assert!(matches!(results[0].error_kind, Some(CompressionErrorKind::Unsupported)));
+    }
+
+    #[test]
+    fn test_animated_gif_to_webp() {
+        let temp_dir = tempdir().unwrap();
+        let input_file = absolute(PathBuf::from("samples/level_1_0/level_2_0/level_3_0/g1.gif")).unwrap();
+        let mut options = setup_options();
+        options.output_folder = Some(temp_dir.path().to_path_buf());
+        options.base_path = input_file.parent().unwrap().to_path_buf();
+        options.format = OutputFormat::Webp;
+
+        let results = start_compression(&[input_file.clone()], &options, false, None);
+        assert!(
+            matches!(results[0].status, CompressionStatus::Success),
+            "{}",
+            results[0].message
+        );
+        assert_eq!(PathBuf::from(&results[0].output_path), temp_dir.path().join("g1.webp"));
+        let output = fs::read(&results[0].output_path).unwrap();
+        let decoder = image::codecs::webp::WebPDecoder::new(io::Cursor::new(&output)).unwrap();
+        assert!(decoder.has_animation());
+        assert_eq!(image::ImageDecoder::dimensions(&decoder), (689, 459));
+
+        options.max_size = Some(10_000);
+        let results = start_compression(&[input_file], &options, false, None);
+        assert!(matches!(results[0].error_kind, Some(CompressionErrorKind::Unsupported)));
+    }
+
+    #[test]
+    fn test_apng_output() {
+        let temp_dir = tempdir().unwrap();
+        let input_file = temp_dir.path().join("spinner.png");
+        let mut input = Vec::new();
+        {
+            let mut encoder = png::Encoder::new(&mut input, 32, 32);
+            encoder.set_color(png::ColorType::Rgb);
+            encoder.set_animated(4, 0).unwrap();
+            let mut writer = encoder.write_header().unwrap();
+            for frame in 0..4u8 {
+                let pixels: Vec<u8> = (0..32 * 32).flat_map(|i| [frame * 60, (i % 32) as u8 * 8, 0]).collect();
+                writer.write_image_data(&pixels).unwrap();
+            }
+            writer.finish().unwrap();
+        }
+        fs::write(&input_file, input).unwrap();
+        let mut options = setup_options();
+        options.output_folder = Some(temp_dir.path().join("out"));
+        options.base_path = temp_dir.path().to_path_buf();
+
+        // Optimized in place of being flattened, whatever the quality
+        let results = start_compression(&[input_file.clone()], &options, false, None);
+        assert!(
+            matches!(results[0].status, CompressionStatus::Success),
+            "{}",
+            results[0].message
+        );
+        let output = fs::read(&results[0].output_path).unwrap();
+        assert!(is_apng(&output));
+        assert_eq!(decode_apng_animation(&output).unwrap().frames.len(), 4);
+
+        options.format = OutputFormat::Webp;
+        let results = start_compression(&[input_file.clone()], &options, false, None);
+        let output = fs::read(&results[0].output_path).unwrap();
+        let decoder = image::codecs::webp::WebPDecoder::new(io::Cursor::new(&output)).unwrap();
+        assert!(decoder.has_animation());
+
+        options.format = OutputFormat::Original;
+        options.width = Some(16);
+        let results = start_compression(&[input_file], &options, false, None);
+        assert!(matches!(results[0].error_kind, Some(CompressionErrorKind::Unsupported)));
+    }
+
+    #[test]
+    fn test_legacy_format_output() {
+        let temp_dir = tempdir().unwrap();
+        let mut fixture = image::RgbImage::new(24, 16);
+        fixture.put_pixel(0, 0, image::Rgb([255, 0, 0]));
+        let input_files: Vec<PathBuf> = [
+            ("sprite.tga", image::ImageFormat::Tga),
+            ("tiles.bmp", image::ImageFormat::Bmp),
+            ("favicon.ico", image::ImageFormat::Ico),
+        ]
+        .iter()
+        .map(|(name, format)| {
+            let input_file = temp_dir.path().join(name);
+            fixture.save_with_format(&input_file, *format).unwrap();
+            input_file
+        })
+        .collect();
+        let mut options = setup_options();
+        options.output_folder = Some(temp_dir.path().join("out"));
+        options.base_path = temp_dir.path().to_path_buf();
+        options.lossless = true;
+
+        // Converted to PNG unless another format is selected
+        let results = start_compression(&input_files, &options, false, None);
+        for (result, name) in results.iter().zip(["sprite.png", "tiles.png", "favicon.png"]) {
+            assert!(
+                matches!(result.status, CompressionStatus::Success),
+                "{}",
+                result.message
+            );
+            assert_eq!(
+                PathBuf::from(&result.output_path),
+                temp_dir.path().join("out").join(name)
+            );
+            let output = image::open(&result.output_path).unwrap().to_rgb8();
+            assert_eq!(output, fixture);
+        }
+
+        options.format = OutputFormat::Webp;
+        let results = start_compression(&input_files[..1], &options, false, None);
+        assert_eq!(
+            PathBuf::from(&results[0].output_path),
+            temp_dir.path().join("out/sprite.webp")
+        );
+        let output = fs::read(&results[0].output_path).unwrap();
+        assert_eq!(get_file_mime_type_from_buffer(&output).as_deref(), Some("image/webp"));
+    }
+
+    #[test]
+    fn test_raw_preview_input() {
+        let temp_dir = tempdir().unwrap();
+        // A little endian TIFF of a single IFD pointing to the JPEG preview
+        let preview = fs::read("samples/j0.JPG").unwrap();
+        let mut raw = b"II*\0".to_vec();
+        raw.extend_from_slice(&8u32.to_le_bytes());
+        raw.extend_from_slice(&2u16.to_le_bytes());
+        for (tag, value) in [(0x0201u16, 8 + 2 + 2 * 12 + 4), (0x0202, preview.len() as u32)] {
+            raw.extend_from_slice(&tag.to_le_bytes());
+            raw.extend_from_slice(&4u16.to_le_bytes());
+            raw.extend_from_slice(&1u32.to_le_bytes());
+            raw.extend_from_slice(&value.to_le_bytes());
+        }
+        raw.extend_from_slice(&0u32.to_le_bytes());
+        raw.extend_from_slice(&preview);
+        let input_file = temp_dir.path().join("DSC_0001.NEF");
+        fs::write(&input_file, &raw).unwrap();
+        let mut options = setup_options();
+        options.output_folder = Some(temp_dir.path().join("out"));
+        options.base_path = temp_dir.path().to_path_buf();
+        options.width = Some(64);
+
+        // Without the flag, it is read as a TIFF of no image data
+        let results = start_compression(&[input_file.clone()], &options, false, None);
+        assert!(!matches!(results[0].status, CompressionStatus::Success));
+
+        options.raw_preview = true;
+        let results = start_compression(&[input_file], &options, false, None);
+        assert!(
+            matches!(results[0].status, CompressionStatus::Success),
+            "{}",
+            results[0].message
+        );
+        assert_eq!(
+            PathBuf::from(&results[0].output_path),
+            temp_dir.path().join("out/DSC_0001.jpg")
+        );
+        assert_eq!(image::image_dimensions(&results[0].output_path).unwrap().0, 64);
+    }
+
+    #[test]
+    fn test_avif_output() {
+        let temp_dir = tempdir().unwrap();
+        let input_files = vec![
+            absolute(PathBuf::from("samples/j0.JPG")).unwrap(),
+            absolute(PathBuf::from("samples/p0.png")).unwrap(),
+        ];
+        let mut options = setup_options();
+        options.output_folder = Some(temp_dir.path().to_path_buf());
+        options.base_path = absolute(PathBuf::from("samples")).unwrap();
+        options.format = OutputFormat::Avif;
+        options.width = Some(64);
+
+        let results = start_compression(&input_files, &options, false, None);
+        for (result, name) in results.iter().zip(["j0.avif", "p0.avif"]) {
+            assert!(
+                matches!(result.status, CompressionStatus::Success),
+                "{}",
+                result.message
+            );
+            assert_eq!(PathBuf::from(&result.output_path), temp_dir.path().join(name));
+            let output = fs::read(&result.output_path).unwrap();
+            assert_eq!(get_file_mime_type_from_buffer(&output).as_deref(), Some("image/avif"));
+            assert_eq!(imagesize::blob_size(&output).unwrap().width, 64);
+        }
+
+        // AVIF is only written lossy, with a fixed quality
+        options.lossless = true;
+        let results = start_compression(&input_files[..1], &options, true, None);
+        assert_eq!(PathBuf::from(&results[0].output_path), temp_dir.path().join("j0.avif"));
+        let results = start_compression(&input_files[..1], &options, false, None);
+        assert!(matches!(results[0].error_kind, Some(CompressionErrorKind::Unsupported)));
+        options.lossless = false;
+        options.target_ssim = Some(0.95);
+        let results = start_compression(&input_files[..1], &options, false, None);
+        assert!(matches!(results[0].error_kind, Some(CompressionErrorKind::Unsupported)));
+    }
+
+    #[test]
+    #[cfg(feature = "jxl")]
+    fn test_jxl_output() {
+        let is_jxl = |buffer: &[u8]| {
+            // A bare codestream, or the container needed to rebuild a transcoded JPEG
+            buffer.starts_with(&[0xFF, 0x0A]) || buffer.starts_with(b"\0\0\0\x0CJXL \r\n\x87\n")
+        };
+        let compress = |input: &str, configure: &dyn Fn(&mut CompressionOptions)| {
+            let mut options = setup_options();
+            options.format = OutputFormat::Jxl;
+            configure(&mut options);
+            let mut output = Vec::new();
+            compress_stream(&mut io::Cursor::new(fs::read(input).unwrap()), &mut output, &options).unwrap();
+            output
+        };
+
+        // Reversible transcoding of a JPEG, smaller than the original
+        let original = fs::read("samples/j0.JPG").unwrap();
+        let transcoded = compress("samples/j0.JPG", &|options| options.lossless = true);
+        assert!(is_jxl(&transcoded));
+        assert!(transcoded.len() < original.len());
+
+        let lossy = compress("samples/p0.png", &|options| options.quality = Some(60));
+        let lossless = compress("samples/p0.png", &|options| options.lossless = true);
+        assert!(is_jxl(&lossy) && is_jxl(&lossless));
+        assert!(lossy.len() < lossless.len());
+        // Resized JPEGs are encoded from their pixels
+        assert!(is_jxl(&compress("samples/j0.JPG", &|options| options.width = Some(64))));
+
+        // Only the quality search modes are not supported
+        let mut options = setup_options();
+        options.format = OutputFormat::Jxl;
+        options.max_size = Some(10_000);
+        let mut output = Vec::new();
+        let result = compress_stream(&mut io::Cursor::new(&original), &mut output, &options);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    #[cfg(not(feature = "jxl"))]
+    fn test_jxl_output_without_feature() {
+        let temp_dir = tempdir().unwrap();
+        let mut options = setup_options();
+        options.format = OutputFormat::Jxl;
+        options.output_folder = Some(temp_dir.path().to_path_buf());
+        options.base_path = PathBuf::from("samples");
+        let results = start_compression(&[PathBuf::from("samples/p0.png")], &options, false, None);
+        assert_eq!(results[0].error_kind, Some(CompressionErrorKind::Unsupported));
+        assert!(results[0].message.contains("`jxl` feature"));
+    }
+
+    #[test]
+    #[cfg(feature = "jxl")]
+    fn test_jxl_distance() {
+        assert_eq!(jxl_distance(100), 0.0);
+        assert!((jxl_distance(90) - 1.0).abs() < 1e-6);
+        assert!((jxl_distance(30) - 6.4).abs() < 1e-5);
+        assert!((jxl_distance(0) - 25.0).abs() < 1e-6);
+        assert!(jxl_distance(60) < jxl_distance(40));
+    }
+
+    #[test]
+    fn test_compress_stream() {
+        let input = fs::read("samples/p0.png").unwrap();
+        let mut options = setup_options();
+        options.format = OutputFormat::Png;
+        options.quality = Some(60);
+
+        let mut output = Vec::new();
+        compress_stream(&mut io::Cursor::new(&input), &mut output, &options).unwrap();
+        assert!(!output.is_empty());
+        assert!(output.len() < input.len());
+        assert!(image::load_from_memory(&output).is_ok());
+
+        let mut output = Vec::new();
+        let result = compress_stream(&mut io::Cursor::new(b"not an image"), &mut output, &options);
+        assert!(result.is_err());
+        assert!(output.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_output_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = tempdir().unwrap();
+        let input_root = temp_dir.path().join("input");
+        let nested = input_root.join("a").join("b");
+        fs::create_dir_all(&nested).unwrap();
+        fs::copy("samples/j0.JPG", nested.join("first.jpg")).unwrap();
+        fs::copy("samples/j0.JPG", nested.join("copy.jpg")).unwrap();
+
+        let output_folder = temp_dir.path().join("output");
+        let mut options = setup_options();
+        options.keep_structure = true;
+        options.dedupe = true;
+        options.base_path = input_root.clone();
+        options.output_folder = Some(output_folder.clone());
+        // Group writable modes, which the usual 022 umask would remove
+        options.file_mode = Some(0o664);
+        options.dir_mode = Some(0o775);
+
+        let input_files = vec![nested.join("first.jpg"), nested.join("copy.jpg")];
+        let results = start_compression(&input_files, &options, false, None);
+        assert!(results.iter().all(|r| !matches!(r.status, CompressionStatus::Error)));
+
+        let mode = |path: PathBuf| fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+        assert_eq!(mode(output_folder.clone()), 0o775);
+        assert_eq!(mode(output_folder.join("a")), 0o775);
+        assert_eq!(mode(output_folder.join("a").join("b")), 0o775);
+        assert_eq!(mode(output_folder.join("a").join("b").join("first.jpg")), 0o664);
+        assert_eq!(mode(output_folder.join("a").join("b").join("copy.jpg")), 0o664);
+    }
+
+    #[test]
+    fn test_keep_dates_preserves_directory_times() {
+        let temp_dir = tempdir().unwrap();
+        let input_root = temp_dir.path().join("input");
+        let nested = input_root.join("a").join("b");
+        fs::create_dir_all(&nested).unwrap();
+        fs::copy("samples/j0.JPG", nested.join("first.jpg")).unwrap();
+        fs::copy("samples/p0.png", nested.join("second.png")).unwrap();
+
+        let old_time = UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        for (i, directory) in [input_root.join("a"), nested.clone()].iter().enumerate() {
+            let time = old_time + Duration::from_secs(i as u64 * 3600);
+            open_directory(directory)
+                .unwrap()
+                .set_times(FileTimes::new().set_modified(time).set_accessed(time))
+                .unwrap();
+        }
+
+        let input_files = vec![nested.join("first.jpg"), nested.join("second.png")];
+        let output_folder = temp_dir.path().join("output");
+        let mut options = setup_options();
+        options.base_path = input_root.clone();
+        options.output_folder = Some(output_folder.clone());
+        options.keep_structure = true;
+        options.keep_dates = true;
+
+        let results = start_compression(&input_files, &options, false, None);
+        assert!(results.iter().all(|r| matches!(r.status, CompressionStatus::Success)));
+
+        for relative in ["a", "a/b"] {
+            let input_mtime = fs::metadata(input_root.join(relative)).unwrap().modified().unwrap();
+            let output_mtime = fs::metadata(output_folder.join(relative)).unwrap().modified().unwrap();
+            assert_eq!(input_mtime, output_mtime);
+        }
+        assert_ne!(
+            fs::metadata(&output_folder).unwrap().modified().unwrap(),
+            fs::metadata(&input_root).unwrap().modified().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_no_upscale_prevents_resize() {
+        // Use an existing sample image and determine its real resolution
+        let input_path = absolute(PathBuf::from("samples/p0.png")).unwrap();
+        let buffer = std::fs::read(&input_path).unwrap();
+        let (w, h) = get_real_resolution(&buffer, get_file_mime_type_from_buffer(&buffer), true).unwrap();
+
+        let mut options = setup_options();
+        options.no_upscale = true;
+        options.width = Some((w + 100) as u32);
+        let params = build_compression_parameters(&options, &buffer).unwrap();
+        assert_eq!(params.width, 0);
+        assert_eq!(params.height, 0);
+
+        let mut options = setup_options();
+        options.no_upscale = true;
+        options.height = Some((h + 100) as u32);
+        let params = build_compression_parameters(&options, &buffer).unwrap();
+        assert_eq!(params.width, 0);
+        assert_eq!(params.height, 0);
+
+        let mut options = setup_options();
+        options.no_upscale = true;
+        options.long_edge = Some((w.max(h) + 100) as u32);
+        let params = build_compression_parameters(&options, &buffer).unwrap();
+        assert_eq!(params.width, 0);
+        assert_eq!(params.height, 0);
+
+        let mut options = setup_options();
+        options.no_upscale = true;
+        options.short_edge = Some((w.min(h) + 100) as u32);
+        let params = build_compression_parameters(&options, &buffer).unwrap();
+        assert_eq!(params.width, 0);
+        assert_eq!(params.height, 0);
+    }
+
+    #[test]
+    fn test_no_upscale_keeps_unresizable_formats() {
+        let temp_dir = tempdir().unwrap();
+        let input_file = absolute(PathBuf::from("samples/level_1_0/level_2_0/level_3_0/g1.gif")).unwrap();
+        let mut options = setup_options();
+        options.width = Some(2000);
+        options.base_path = absolute(PathBuf::from("samples")).unwrap();
+        options.output_folder = Some(temp_dir.path().to_path_buf());
+
+        let result = perform_compression(&input_file, &options, None, None, false);
+        assert!(matches!(result.error_kind, Some(CompressionErrorKind::Unsupported)));
+
+        // Left at its size, the GIF file doesn't need to be resized
+        options.no_upscale = true;
+        let result = perform_compression(&input_file, &options, None, None, false);
+        assert!(
+            matches!(result.status, CompressionStatus::Success),
+            "{}",
+            result.message
+        );
+        assert_eq!(imagesize::size(&result.output_path).unwrap().width, 689);
+    }
+
+    #[test]
+    fn test_build_resize_parameters() {
+        let input_path = absolute(PathBuf::from("samples/j0.JPG")).unwrap();
+        let buffer = std::fs::read(&input_path).unwrap();
+        let mime_type = get_file_mime_type_from_buffer(&buffer);
+
+        let mut options = setup_options();
+        options.width = Some(100);
+        options.height = Some(100);
+        let mut params = CSParameters::new();
+        build_resize_parameters(&options, &mut params, &buffer, mime_type.clone()).unwrap();
+        assert_eq!(params.width, 100);
+        assert_eq!(params.height, 100);
+
+        let mut options = setup_options();
+        options.width = Some(100);
+        let mut params = CSParameters::new();
+        build_resize_parameters(&options, &mut params, &buffer, mime_type.clone()).unwrap();
+        assert_eq!(params.width, 100);
+        assert_eq!(params.height, 0);
+
+        let mut options = setup_options();
+        options.height = Some(100);
+        let mut params = CSParameters::new();
+        build_resize_parameters(&options, &mut params, &buffer, mime_type.clone()).unwrap();
+        assert_eq!(params.width, 0);
+        assert_eq!(params.height, 100);
+
+        let mut options = setup_options();
+        options.long_edge = Some(100);
+        let mut params = CSParameters::new();
+        build_resize_parameters(&options, &mut params, &buffer, mime_type.clone()).unwrap();
+        assert_eq!(params.width, 0);
+        assert_eq!(params.height, 100);
+
+        let mut options = setup_options();
+        options.short_edge = Some(50);
+        let mut params = CSParameters::new();
+        build_resize_parameters(&options, &mut params, &buffer, mime_type.clone()).unwrap();
+        assert_eq!(params.width, 50);
+        assert_eq!(params.height, 0);
+
+        // Portrait source: the height is the limiting side
+        let (width, height) = get_real_resolution(&buffer, mime_type.clone(), options.exif).unwrap();
+        let mut options = setup_options();
+        options.fit = Some((200, 200));
+        let mut params = CSParameters::new();
+        build_resize_parameters(&options, &mut params, &buffer, mime_type.clone()).unwrap();
+        assert_eq!(params.height, 200);
+        assert_eq!(
+            params.width,
+            fit_within(width as u32, height as u32, 200, 200).unwrap().0
+        );
+        assert!(params.width < 200);
+
+        let mut options = setup_options();
+        options.fit = Some((20000, 20000));
+        let mut params = CSParameters::new();
+        build_resize_parameters(&options, &mut params, &buffer, mime_type.clone()).unwrap();
+        assert_eq!(params.width, 0);
+        assert_eq!(params.height, 0);
+
+        let mut options = setup_options();
+        options.scale = Some(25.0);
+        let mut params = CSParameters::new();
+        build_resize_parameters(&options, &mut params, &buffer, mime_type.clone()).unwrap();
+        assert_eq!(params.width, scale_down(width as u32, 25.0));
+        assert_eq!(params.height, scale_down(height as u32, 25.0));
+
+        // The full size is kept as is
+        options.scale = Some(100.0);
+        let mut params = CSParameters::new();
+        build_resize_parameters(&options, &mut params, &buffer, mime_type.clone()).unwrap();
+        assert_eq!(params.width, 0);
+        assert_eq!(params.height, 0);
+
+        let mut options = setup_options();
+        options.no_upscale = true;
+        options.width = Some(20000);
+        let mut params = CSParameters::new();
+        build_resize_parameters(&options, &mut params, &buffer, mime_type.clone()).unwrap();
+        assert_eq!(params.width, 0);
+        assert_eq!(params.height, 0);
+    }
+
+    #[test]
+    fn test_optimize() {
+        let temp_dir = tempdir().unwrap();
+        let mut options = setup_options();
+        options.quality = None;
+        options.optimize = true;
+        options.base_path = absolute(PathBuf::from("samples")).unwrap();
+        options.output_folder = Some(temp_dir.path().to_path_buf());
+
+        // An already lossy JPEG shrinks without any quality
+        let input_file = absolute(PathBuf::from("samples/j0.JPG")).unwrap();
+        let result = perform_compression(&input_file, &options, None, None, false);
+        assert!(
+            matches!(result.status, CompressionStatus::Success),
+            "{}",
+            result.message
+        );
+        assert!(result.compressed_size < result.original_size);
+        let params = build_compression_parameters(&options, &[]).unwrap();
+        assert!(params.jpeg.optimize && params.png.optimize && params.webp.lossless);
+        assert_eq!(params.gif.quality, 100);
+        assert_eq!(describe_compression(&options), "optimize");
+
+        // A lossy WebP can't be re-encoded without changing its pixels
+        let webp = std::fs::read("samples/w0.webp").unwrap();
+        assert!(!is_lossless_webp(&webp));
+        let input_file = absolute(PathBuf::from("samples/w0.webp")).unwrap();
+        let result = perform_compression(&input_file, &options, None, None, false);
+        assert!(
+            matches!(result.status, CompressionStatus::Skipped),
+            "{}",
+            result.message
+        );
+
+        let mut lossless_webp = Vec::new();
+        image::RgbImage::new(4, 4)
+            .write_to(&mut Cursor::new(&mut lossless_webp), image::ImageFormat::WebP)
+            .unwrap();
+        assert!(is_lossless_webp(&lossless_webp));
+    }
+
+    #[test]
+    fn test_gif_quality_lossless_and_zero() {
+        let input_path = absolute(PathBuf::from("samples/level_1_0/level_2_0/level_3_0/g1.gif")).unwrap();
+        let buffer = std::fs::read(&input_path).unwrap();
+
+        // Test case 1: lossless = true should set gif.quality to 100
+        let mut options = setup_options();
+        options.lossless = true;
+        options.quality = Some(80);
+        let params = build_compression_parameters(&options, &buffer).unwrap();
+        assert_eq!(params.gif.quality, 100);
+
+        // Test case 2: quality = 0 should set gif.quality to 1
+        let mut options = setup_options();
+        options.lossless = false;
+        options.quality = Some(0);
+        let params = build_compression_parameters(&options, &buffer).unwrap();
+        assert_eq!(params.gif.quality, 1);
+
+        // Test case 3: normal quality should pass through unchanged
+        let mut options = setup_options();
+        options.lossless = false;
+        options.quality = Some(75);
+        let params = build_compression_parameters(&options, &buffer).unwrap();
+        assert_eq!(params.gif.quality, 75);
+    }
+
+    #[test]
+    fn test_min_savings_skips_files() {
+        let input_files = vec![absolute(PathBuf::from("samples/j0.JPG")).unwrap()];
+
+        // Test with very high percentage threshold - should skip files
+        let temp_dir = tempdir().unwrap().path().to_path_buf();
+        let mut options = setup_options();
+        options.base_path = absolute(PathBuf::from("samples")).unwrap();
+        options.output_folder = Some(temp_dir.clone());
+        options.quality = Some(95); // High quality = small savings
+        options.min_savings = Some(MinSavingsThreshold::Percentage(99.0)); // Require 99% savings (unrealistic)
+
+        let results = start_compression(&input_files, &options, false, None);
+        assert!(results.iter().all(|r| matches!(r.status, CompressionStatus::Skipped)));
+        assert!(results.iter().all(|r| r.message.contains("Insufficient savings")));
+        // Files should NOT be written when skipped
+        assert!(results.iter().all(|r| !fs::exists(&r.output_path).unwrap_or(true)));
+
+        // Test with very high byte threshold - should skip files
+        let temp_dir2 = tempdir().unwrap().path().to_path_buf();
+        let mut options2 = setup_options();
+        options2.base_path = absolute(PathBuf::from("samples")).unwrap();
+        options2.output_folder = Some(temp_dir2.clone());
+        options2.quality = Some(95);
+        options2.min_savings = Some(MinSavingsThreshold::Bytes(100_000_000)); // Require 100MB savings (unrealistic)
+
+        let results2 = start_compression(&input_files, &options2, false, None);
+        assert!(results2.iter().all(|r| matches!(r.status, CompressionStatus::Skipped)));
+        assert!(results2.iter().all(|r| r.message.contains("Insufficient savings")));
+
+        // Test with low threshold - should succeed
+        let temp_dir3 = tempdir().unwrap().path().to_path_buf();
+        let mut options3 = setup_options();
+        options3.base_path = absolute(PathBuf::from("samples")).unwrap();
+        options3.output_folder = Some(temp_dir3.clone());
+        options3.quality = Some(50); // Lower quality = more savings
+        options3.min_savings = Some(MinSavingsThreshold::Percentage(0.1)); // Very low threshold
+
+        let results3 = start_compression(&input_files, &options3, false, None);
+        assert!(results3.iter().all(|r| matches!(r.status, CompressionStatus::Success)));
+        assert!(results3.iter().all(|r| fs::exists(&r.output_path).unwrap_or(false)));
+
+        // Test with decimal percentage threshold
+        let temp_dir4 = tempdir().unwrap().path().to_path_buf();
+        let mut options4 = setup_options();
+        options4.base_path = absolute(PathBuf::from("samples")).unwrap();
+        options4.output_folder = Some(temp_dir4.clone());
+        options4.quality = Some(95);
+        options4.min_savings = Some(MinSavingsThreshold::Percentage(50.5)); // 50.5% threshold
+
+        let results4 = start_compression(&input_files, &options4, false, None);
+        // With high quality (95), savings should be less than 50.5%, so files should be skipped
+        assert!(results4.iter().all(|r| matches!(r.status, CompressionStatus::Skipped)));
+
+        // Test with no min_savings - should always succeed
+        let temp_dir5 = tempdir().unwrap().path().to_path_buf();
+        let mut options5 = setup_options();
+        options5.base_path = absolute(PathBuf::from("samples")).unwrap();
+        options5.output_folder = Some(temp_dir5.clone());
+        options5.quality = Some(95);
+        options5.min_savings = None;
+
+        let results5 = start_compression(&input_files, &options5, false, None);
+        assert!(results5.iter().all(|r| matches!(r.status, CompressionStatus::Success)));
+    }
+
+    #[test]
+    fn test_timeout() {
+        let temp_dir = tempdir().unwrap();
+        let input_file = absolute(PathBuf::from("samples/j0.JPG")).unwrap();
+        let mut options = setup_options();
+        options.base_path = absolute(PathBuf::from("samples")).unwrap();
+        options.output_folder = Some(temp_dir.path().to_path_buf());
+        options.retries = 3;
+
+        // Past the deadline as soon as the file is read, and never retried
+        options.timeout = Some(Duration::ZERO);
+        let results = start_compression(&[input_file.clone()], &options, false, None);
+        assert!(matches!(results[0].status, CompressionStatus::Error));
+        assert_eq!(results[0].error_kind, Some(CompressionErrorKind::Timeout));
+        assert!(results[0].message.contains("timed out"));
+        assert!(!CompressionErrorKind::Timeout.is_retryable());
+        assert!(!temp_dir.path().join("j0.jpg").exists());
+
+        options.timeout = Some(Duration::from_secs(60));
+        let results = start_compression(&[input_file], &options, false, None);
+        assert!(
+            matches!(results[0].status, CompressionStatus::Success),
+            "{}",
+            results[0].message
+        );
+        assert!(temp_dir.path().join("j0.jpg").exists());
+    }
+
+    #[test]
+    fn test_timeout_stops_the_quality_search() {
+        let input = fs::read("samples/j0.JPG").unwrap();
+        let mut options = setup_options();
+        options.quality = None;
+        options.exif = false;
+        // Never reached, so the search encodes the image at every iteration
+        options.max_size = Some(1000);
+        options.max_size_iterations = Some(8);
+        let start = Instant::now();
+        encode_with_quality(&input, &options, 50).unwrap();
+        let encode_time = start.elapsed();
+        let compress = |options: &CompressionOptions| {
+            let mut compression_result = CompressionResult {
+                original_path: String::new(),
+                output_path: String::new(),
+                original_size: input.len() as u64,
+                compressed_size: 0,
+                status: CompressionStatus::Success,
+                error_kind: None,
+                message: String::new(),
+            };
+            let output = compress_buffer(input.clone(), options, &mut compression_result);
+            (output, compression_result)
+        };
+
+        // The deadline passes during the search, at the latest after its first encoding
+        options.timeout = Some(encode_time / 2);
+        options.deadline = Some(Instant::now() + encode_time / 2);
+        let (output, compression_result) = compress(&options);
+        assert!(output.is_none());
+        assert!(matches!(compression_result.status, CompressionStatus::Error));
+        assert_eq!(compression_result.error_kind, Some(CompressionErrorKind::Timeout));
+        assert!(compression_result.message.contains("timed out"));
+
+        // Without a deadline the same search runs to the end
+        options.deadline = None;
+        let (output, compression_result) = compress(&options);
+        assert!(output.is_some(), "{}", compression_result.message);
+    }
+
+    #[test]
+    fn test_error_kinds() {
+        let temp_dir = tempdir().unwrap();
+        let mut options = setup_options();
+        options.base_path = temp_dir.path().to_path_buf();
+        options.output_folder = Some(temp_dir.path().join("output"));
+
+        // Read: the input file does not exist
+        let missing_file = temp_dir.path().join("missing.jpg");
+        let result = perform_compression(&missing_file, &options, None, None, false);
+        assert!(matches!(result.status, CompressionStatus::Error));
+        assert_eq!(result.error_kind, Some(CompressionErrorKind::Read));
+        assert_eq!(result.message, "File not found");
+
+        // Unsupported: a text file with an image extension
+        let text_file = temp_dir.path().join("text.jpg");
+        fs::write(&text_file, b"This is not an image").unwrap();
+        let result = perform_compression(&text_file, &options, None, None, false);
+        assert!(matches!(result.status, CompressionStatus::Error));
+        assert_eq!(result.error_kind, Some(CompressionErrorKind::Unsupported));
+
+        // Decode: valid JPEG magic bytes followed by garbage
+        let corrupted_file = temp_dir.path().join("corrupted.jpg");
+        fs::write(&corrupted_file, [0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x00, 0x00, 0x00]).unwrap();
+        let result = perform_compression(&corrupted_file, &options, None, None, false);
+        assert!(matches!(result.status, CompressionStatus::Error));
+        assert_eq!(result.error_kind, Some(CompressionErrorKind::Decode));
+
+        // Write: the output folder is an existing regular file
+        let blocking_file = temp_dir.path().join("not_a_folder");
+        fs::write(&blocking_file, b"").unwrap();
+        options.output_folder = Some(blocking_file.join("output"));
+        let input_file = absolute(PathBuf::from("samples/j0.JPG")).unwrap();
+        let result = perform_compression(&input_file, &options, None, None, false);
+        assert!(matches!(result.status, CompressionStatus::Error));
+        assert_eq!(result.error_kind, Some(CompressionErrorKind::Write));
+
+        // Successful results carry no error kind
+        options.output_folder = Some(temp_dir.path().join("output"));
+        let result = perform_compression(&input_file, &options, None, None, false);
+        assert!(matches!(result.status, CompressionStatus::Success));
+        assert_eq!(result.error_kind, None);
+    }
+
+    #[test]
+    fn test_empty_and_truncated_inputs() {
+        let temp_dir = tempdir().unwrap();
+        let mut options = setup_options();
+        options.base_path = temp_dir.path().to_path_buf();
+        options.output_folder = Some(temp_dir.path().join("output"));
+
+        let empty_file = temp_dir.path().join("empty.jpg");
+        fs::write(&empty_file, b"").unwrap();
+        let result = perform_compression(&empty_file, &options, None, None, false);
+        assert!(matches!(result.status, CompressionStatus::Skipped));
+        assert!(result.message.contains("Empty file"));
+
+        for sample in ["samples/j0.JPG", "samples/p0.png", "samples/w0.webp"] {
+            let data = fs::read(sample).unwrap();
+            let truncated_file = temp_dir.path().join(Path::new(sample).file_name().unwrap());
+            fs::write(&truncated_file, &data[..data.len() * 2 / 3]).unwrap();
+
+            let result = perform_compression(&truncated_file, &options, None, None, false);
+            assert!(matches!(result.status, CompressionStatus::Error), "{sample}");
+            assert_eq!(result.error_kind, Some(CompressionErrorKind::Decode));
+            assert_eq!(result.message, "Corrupt or truncated image");
+
+            assert!(!is_truncated(&data, get_file_mime_type_from_buffer(&data).as_deref()));
+        }
+    }
+
+    #[test]
+    fn test_lossless_conversions() {
+        let temp_dir = tempdir().unwrap();
+        let input_file = temp_dir.path().join("alpha.png");
+        // Gradients under eight alpha levels, fully transparent pixels included, whose colors must be kept too
+        let fixture = image::RgbaImage::from_fn(64, 64, |x, y| {
+            image::Rgba([
+                (x * 4) as u8,
+                (y * 4) as u8,
+                (x ^ y) as u8 * 4,
+                ((x + y) % 8 * 36) as u8,
+            ])
+        });
+        fixture.save(&input_file).unwrap();
+        let mut options = setup_options();
+        options.quality = None;
+        options.lossless = true;
+        options.base_path = temp_dir.path().to_path_buf();
+        options.output_folder = Some(temp_dir.path().join("out"));
+
+        options.format = OutputFormat::Jpeg;
+        let result = perform_compression(&input_file, &options, None, None, false);
+        assert!(matches!(result.status, CompressionStatus::Error));
+        assert_eq!(result.error_kind, Some(CompressionErrorKind::Unsupported));
+        assert!(!temp_dir.path().join("out").join("alpha.jpg").exists());
+
+        // Lossless WebP data is stored in a VP8L chunk
+        options.format = OutputFormat::Webp;
+        let result = perform_compression(&input_file, &options, None, None, false);
+        assert!(
+            matches!(result.status, CompressionStatus::Success),
+            "{}",
+            result.message
+        );
+        let data = fs::read(&result.output_path).unwrap();
+        assert_eq!(&data[12..16], b"VP8L");
+
+        let output = image::load_from_memory(&data).unwrap().to_rgba8();
+        assert!(output == fixture, "the lossless WebP pixels differ from the original");
+
+        // Recompressing a JPEG losslessly stays allowed
+        options.format = OutputFormat::Original;
+        let jpeg_file = absolute(PathBuf::from("samples/j0.JPG")).unwrap();
+        options.base_path = jpeg_file.parent().unwrap().to_path_buf();
+        let result = perform_compression(&jpeg_file, &options, None, None, false);
+        assert!(
+            matches!(result.status, CompressionStatus::Success),
+            "{}",
+            result.message
+        );
+    }
+
+    #[test]
+    fn test_misnamed_file_keeps_its_content_format() {
+        let temp_dir = tempdir().unwrap();
+        let input_file = temp_dir.path().join("input").join("picture.jpg");
+        fs::create_dir_all(input_file.parent().unwrap()).unwrap();
+        fs::copy("samples/p0.png", &input_file).unwrap();
+        assert_eq!(detect_misnamed_format(&input_file), Some(OutputFormat::Png));
+        assert_eq!(detect_misnamed_format(Path::new("samples/j0.JPG")), None);
+        assert_eq!(detect_misnamed_format(Path::new("samples/t0.tif")), None);
+
+        let mut options = setup_options();
+        options.base_path = input_file.parent().unwrap().to_path_buf();
+        options.output_folder = Some(temp_dir.path().join("output"));
+        let result = perform_compression(&input_file, &options, None, None, false);
+        assert!(
+            matches!(result.status, CompressionStatus::Success),
+            "{}",
+            result.message
+        );
+        assert_eq!(
+            PathBuf::from(&result.output_path),
+            temp_dir.path().join("output").join("picture.png")
+        );
+        assert_eq!(
+            result.message,
+            "Detected PNG content, saved with the .png extension instead of .jpg"
+        );
+        let data = fs::read(&result.output_path).unwrap();
+        assert_eq!(get_file_mime_type_from_buffer(&data).as_deref(), Some("image/png"));
+
+        // An explicit output format wins
+        options.format = OutputFormat::Webp;
+        let result = perform_compression(&input_file, &options, None, None, true);
+        assert!(result.output_path.ends_with("picture.webp"));
+        assert!(result.message.is_empty());
+    }
+
+    #[test]
+    fn test_flatten_numbers_outputs_in_sorted_order() {
+        let temp_dir = tempdir().unwrap();
+        let base_path = absolute(PathBuf::from("samples")).unwrap();
+        let input_files = vec![
+            base_path.join("p0.png"),
+            base_path.join("level_1_0").join("level_2_0").join("p2.png"),
+            base_path.join("j0.JPG"),
+            base_path.join("level_1_0").join("j1.jpg"),
+            base_path.join("missing.jpg"),
+        ];
+
+        let output_folder = temp_dir.path().join("output");
+        let mut options = setup_options();
+        options.base_path = base_path.clone();
+        options.output_folder = Some(output_folder.clone());
+        options.flatten = Some(NumberFormat::default());
+        options.format_conversions = vec![(OutputFormat::Png, OutputFormat::Webp)];
+
+        let results = start_compression(&input_files, &options, false, None);
+        let output_paths: Vec<PathBuf> = results.iter().map(|r| PathBuf::from(&r.output_path)).collect();
+        // Sorted inputs: j0.JPG, level_1_0/j1.jpg, level_1_0/level_2_0/p2.png, p0.png
+        assert_eq!(
+            output_paths,
+            vec![
+                output_folder.join("0004.webp"),
+                output_folder.join("0003.webp"),
+                output_folder.join("0001.jpg"),
+                output_folder.join("0002.jpg"),
+                PathBuf::new(),
+            ]
+        );
+        assert!(results[..4]
+            .iter()
+            .all(|r| matches!(r.status, CompressionStatus::Success)));
+        assert!(matches!(results[4].status, CompressionStatus::Error));
+        assert!(output_folder.join("0001.jpg").exists());
+        assert!(output_folder.join("0004.webp").exists());
+    }
+
+    #[test]
+    fn test_output_extension_case() {
+        let temp_dir = tempdir().unwrap();
+        let input_file = temp_dir.path().join("input").join("Photo.JPG");
+        fs::create_dir_all(input_file.parent().unwrap()).unwrap();
+        fs::copy("samples/j0.JPG", &input_file).unwrap();
+        let output_folder = temp_dir.path().join("output");
+
+        let mut options = setup_options();
+        options.base_path = input_file.parent().unwrap().to_path_buf();
+        options.output_folder = Some(output_folder.clone());
+        let output_path = |options: &CompressionOptions| {
+            let result = perform_compression(&input_file, options, None, None, true);
+            assert!(
+                matches!(result.status, CompressionStatus::Success),
+                "{}",
+                result.message
+            );
+            PathBuf::from(result.output_path)
+        };
+
+        // Only the extension changes, not the stem
+        assert_eq!(output_path(&options), output_folder.join("Photo.jpg"));
+        options.extension_case = ExtensionCase::Preserve;
+        assert_eq!(output_path(&options), output_folder.join("Photo.JPG"));
+        options.extension_case = ExtensionCase::Upper;
+        assert_eq!(output_path(&options), output_folder.join("Photo.JPG"));
+
+        // Explicit conversions too
+        options.format = OutputFormat::Webp;
+        assert_eq!(output_path(&options), output_folder.join("Photo.WEBP"));
+        options.extension_case = ExtensionCase::Lower;
+        assert_eq!(output_path(&options), output_folder.join("Photo.webp"));
+    }
+
+    #[test]
+    fn test_newer_skips_up_to_date_outputs() {
+        let temp_dir = tempdir().unwrap();
+        let input_file = temp_dir.path().join("input").join("photo.jpg");
+        fs::create_dir_all(input_file.parent().unwrap()).unwrap();
+        fs::copy("samples/j0.JPG", &input_file).unwrap();
+        let output_file = temp_dir.path().join("output").join("photo.jpg");
+
+        let mut options = setup_options();
+        options.newer = true;
+        options.base_path = input_file.parent().unwrap().to_path_buf();
+        options.output_folder = Some(temp_dir.path().join("output"));
+
+        // No output yet
+        let result = perform_compression(&input_file, &options, None, None, false);
+        assert!(
+            matches!(result.status, CompressionStatus::Success),
+            "{}",
+            result.message
+        );
+
+        let set_modified = |path: &Path, time: SystemTime| {
+            let file = fs::OpenOptions::new().write(true).open(path).unwrap();
+            file.set_times(FileTimes::new().set_modified(time)).unwrap();
+        };
+        let now = SystemTime::now();
+
+        // Output more recent than the input, e.g. from a previous run, and with the same date as with --keep-dates
+        for output_time in [now, now - Duration::from_secs(3600)] {
+            set_modified(&input_file, now - Duration::from_secs(3600));
+            set_modified(&output_file, output_time);
+            let result = perform_compression(&input_file, &options, None, None, false);
+            assert!(
+                matches!(result.status, CompressionStatus::Skipped),
+                "{}",
+                result.message
+            );
+            assert_eq!(result.message, "Up to date");
+        }
+
+        // The input has been modified since
+        set_modified(&input_file, now);
+        set_modified(&output_file, now - Duration::from_secs(3600));
+        let result = perform_compression(&input_file, &options, None, None, false);
+        assert!(
+            matches!(result.status, CompressionStatus::Success),
+            "{}",
+            result.message
+        );
+        assert!(fs::metadata(&output_file).unwrap().modified().unwrap() >= now - Duration::from_secs(1));
+
+        // Without --newer, the output is always written again
+        options.newer = false;
+        set_modified(&output_file, now + Duration::from_secs(3600));
+        let result = perform_compression(&input_file, &options, None, None, false);
+        assert!(matches!(result.status, CompressionStatus::Success));
+    }
+
+    #[test]
+    fn test_verify_output() {
+        let temp_dir = tempdir().unwrap();
+        let input_file = absolute(PathBuf::from("samples/p0.png")).unwrap();
+        let mut options = setup_options();
+        options.verify = true;
+        options.base_path = input_file.parent().unwrap().to_path_buf();
+        options.output_folder = Some(temp_dir.path().to_path_buf());
+
+        let result = perform_compression(&input_file, &options, None, None, false);
+        assert!(
+            matches!(result.status, CompressionStatus::Success),
+            "{}",
+            result.message
+        );
+        let output_path = PathBuf::from(&result.output_path);
+        let compressed_image = fs::read(&output_path).unwrap();
+        assert!(verify_output(&output_path, &compressed_image).is_ok());
+
+        // Corruption after the write, e.g. a flipped bit on a bad disk
+        let mut corrupted = compressed_image.clone();
+        let middle = corrupted.len() / 2;
+        corrupted[middle] ^= 0x01;
+        fs::write(&output_path, &corrupted).unwrap();
+        let error = verify_output(&output_path, &compressed_image).unwrap_err();
+        assert!(error.starts_with("Verification failed"));
+
+        // Truncated or missing outputs fail too
+        fs::write(&output_path, &compressed_image[..middle]).unwrap();
+        assert!(verify_output(&output_path, &compressed_image).is_err());
+        fs::remove_file(&output_path).unwrap();
+        assert!(verify_output(&output_path, &compressed_image).is_err());
+    }
+
+    #[test]
+    fn test_dpi_output() {
+        let temp_dir = tempdir().unwrap();
+        let mut options = setup_options();
+        options.dpi = Some(72);
+        options.output_folder = Some(temp_dir.path().to_path_buf());
+
+        for sample in ["samples/j0.JPG", "samples/p0.png", "samples/t0.tif"] {
+            let input_file = absolute(PathBuf::from(sample)).unwrap();
+            options.base_path = input_file.parent().unwrap().to_path_buf();
+            let result = perform_compression(&input_file, &options, None, None, false);
+            assert!(
+                matches!(result.status, CompressionStatus::Success),
+                "{sample}: {}",
+                result.message
+            );
+            let data = fs::read(&result.output_path).unwrap();
+            assert_eq!(read_dpi(&data), Some((72, 72)), "{sample}");
+        }
+
+        // WebP has no resolution field, the output is written anyway
+        let input_file = absolute(PathBuf::from("samples/w0.webp")).unwrap();
+        let result = perform_compression(&input_file, &options, None, None, false);
+        assert!(
+            matches!(result.status, CompressionStatus::Success),
+            "{}",
+            result.message
+        );
+    }
+
+    #[test]
+    fn test_quality_variants() {
+        let temp_dir = tempdir().unwrap();
+        let input_files = vec![
+            absolute(PathBuf::from("samples/j0.JPG")).unwrap(),
+            absolute(PathBuf::from("samples/p0.png")).unwrap(),
+        ];
+        let mut options = setup_options();
+        options.output_folder = Some(temp_dir.path().to_path_buf());
+        options.base_path = absolute(PathBuf::from("samples")).unwrap();
+        options.suffix = Some("_small".to_string());
+        options.variants = [60, 80, 95]
+            .map(|quality| Variant {
+                suffix: format!("_q{quality}"),
+                quality: Some(quality),
+                ..Default::default()
+            })
+            .to_vec();
+
+        let mut finished = 0;
+        let mut on_progress = |event: ProgressEvent| {
+            if matches!(event, ProgressEvent::Finished { .. }) {
+                finished += 1;
+            }
+        };
+        let results = start_compression(&input_files, &options, false, Some(&mut on_progress));
+        assert_eq!(results.len(), 6);
+        assert_eq!(finished, 6);
+        assert!(results.iter().all(|r| matches!(r.status, CompressionStatus::Success)));
+
+        // Grouped by variant, in the input order
+        let outputs: Vec<PathBuf> = results.iter().map(|r| PathBuf::from(&r.output_path)).collect();
+        let expected: Vec<PathBuf> = ["60", "80", "95"]
+            .iter()
+            .flat_map(|quality| {
+                [
+                    temp_dir.path().join(format!("j0_small_q{quality}.jpg")),
+                    temp_dir.path().join(format!("p0_small_q{quality}.png")),
+                ]
+            })
+            .collect();
+        assert_eq!(outputs, expected);
+
+        // Higher qualities give larger JPEG files
+        let jpeg_sizes: Vec<u64> = results.iter().step_by(2).map(|r| r.compressed_size).collect();
+        assert!(jpeg_sizes.windows(2).all(|pair| pair[0] < pair[1]), "{jpeg_sizes:?}");
+        for (result, size) in results.iter().step_by(2).zip(&jpeg_sizes) {
+            assert_eq!(fs::metadata(&result.output_path).unwrap().len(), *size);
+        }
+    }
+
+    #[test]
+    fn test_fail_fast() {
+        let temp_dir = tempdir().unwrap();
+        let input_files = vec![
+            absolute(PathBuf::from("samples/j0.JPG")).unwrap(),
+            absolute(PathBuf::from("samples/missing.jpg")).unwrap(),
+            absolute(PathBuf::from("samples/p0.png")).unwrap(),
+            absolute(PathBuf::from("samples/w0.webp")).unwrap(),
+        ];
+        let mut options = setup_options();
+        options.output_folder = Some(temp_dir.path().to_path_buf());
+        options.base_path = absolute(PathBuf::from("samples")).unwrap();
+        options.fail_fast = true;
+
+        // A single thread processes the files in order
+        let thread_pool = rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        let results = thread_pool.install(|| start_compression(&input_files, &options, false, None));
+        let statuses: Vec<&CompressionStatus> = results.iter().map(|r| &r.status).collect();
+        assert!(matches!(
+            statuses[..],
+            [
+                CompressionStatus::Success,
+                CompressionStatus::Error,
+                CompressionStatus::Skipped,
+                CompressionStatus::Skipped
+            ]
+        ));
+        for result in &results[2..] {
+            assert_eq!(result.message, "Aborted after an error");
+            assert!(result.output_path.is_empty());
+        }
+        assert!(temp_dir.path().join("j0.jpg").exists());
+        assert!(!temp_dir.path().join("p0.png").exists());
+        assert!(!temp_dir.path().join("w0.webp").exists());
+
+        options.fail_fast = false;
+        let results = thread_pool.install(|| start_compression(&input_files, &options, false, None));
+        assert!(matches!(results[3].status, CompressionStatus::Success));
+    }
+
+    #[test]
+    fn test_rename_map() {
+        let temp_dir = tempdir().unwrap();
+        let input_files = vec![
+            absolute(PathBuf::from("samples/j0.JPG")).unwrap(),
+            absolute(PathBuf::from("samples/p0.png")).unwrap(),
+            absolute(PathBuf::from("samples/w0.webp")).unwrap(),
+        ];
+        let rename_map =
+            crate::rename_map::parse_rename_map("j0.JPG,red-shoe.jpg\np0.png,blue shoe", Path::new("samples")).unwrap();
+        let mut options = setup_options();
+        options.output_folder = Some(temp_dir.path().to_path_buf());
+        options.base_path = absolute(PathBuf::from("samples")).unwrap();
+        options.format = OutputFormat::Webp;
+        options.rename_map = Some(Arc::new(rename_map));
+
+        let results = start_compression(&input_files, &options, true, None);
+        let outputs: Vec<PathBuf> = results.iter().map(|r| PathBuf::from(&r.output_path)).collect();
+        assert_eq!(
+            outputs,
+            vec![
+                temp_dir.path().join("red-shoe.webp"),
+                temp_dir.path().join("blue shoe.webp"),
+                temp_dir.path().join("w0.webp"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dry_run_same_folder_as_input() {
+        let temp_dir = tempdir().unwrap();
+        fs::create_dir(temp_dir.path().join("nested")).unwrap();
+        let input_files = vec![
+            temp_dir.path().join("photo.jpg"),
+            temp_dir.path().join("nested/logo.png"),
+        ];
+        fs::copy("samples/j0.JPG", &input_files[0]).unwrap();
+        fs::copy("samples/p0.png", &input_files[1]).unwrap();
+        let snapshot = || {
+            walkdir::WalkDir::new(temp_dir.path())
+                .sort_by_file_name()
+                .into_iter()
+                .map(|entry| {
+                    let entry = entry.unwrap();
+                    let metadata = entry.metadata().unwrap();
+                    let content = metadata.is_file().then(|| fs::read(entry.path()).unwrap());
+                    (entry.into_path(), metadata.modified().unwrap(), content)
+                })
+                .collect::<Vec<_>>()
+        };
+        let before = snapshot();
+
+        for overwrite_policy in [OverwritePolicy::All, OverwritePolicy::Ask, OverwritePolicy::Bigger] {
+            let mut options = setup_options();
+            options.same_folder_as_input = true;
+            options.base_path = temp_dir.path().to_path_buf();
+            options.overwrite_policy = overwrite_policy;
+            // Would be asked for every file if the dry run did not stop first
+            options.overwrite_prompt = Some(Arc::new(OverwritePrompt::new(|_| OverwriteAnswer::Quit)));
+            options.trash_original = true;
+            options.keep_dates = true;
+
+            let results = start_compression(&input_files, &options, true, None);
+            for (result, input_file) in results.iter().zip(&input_files) {
+                assert!(
+                    matches!(result.status, CompressionStatus::Success),
+                    "{}",
+                    result.message
+                );
+                assert_eq!(PathBuf::from(&result.output_path), *input_file);
+            }
+        }
+
+        // Same bytes, same modification times, and no temporary file or folder left behind
+        assert_eq!(snapshot(), before);
+    }
+
+    #[test]
+    fn test_in_place_inputs() {
+        let input_files = vec![
+            absolute(PathBuf::from("samples/j0.JPG")).unwrap(),
+            absolute(PathBuf::from("samples/p0.png")).unwrap(),
+        ];
+        let in_place = |configure: &dyn Fn(&mut CompressionOptions)| {
+            let mut options = setup_options();
+            options.base_path = absolute(PathBuf::from("samples")).unwrap();
+            options.same_folder_as_input = true;
+            configure(&mut options);
+            in_place_inputs(&input_files, &options)
+        };
+
+        // Same name, only the extension case changes
+        assert_eq!(in_place(&|_| {}), input_files);
+        assert!(in_place(&|options| {
+            options.same_folder_as_input = false;
+            options.output_folder = Some(PathBuf::from("/output"));
+        })
+        .is_empty());
+
+        // Written beside the original
+        assert!(in_place(&|options| options.suffix = Some("_min".to_string())).is_empty());
+        assert!(in_place(&|options| options.format = OutputFormat::Webp).is_empty());
+        assert!(in_place(&|options| {
+            options.variants = vec![Variant {
+                suffix: "-480w".to_string(),
+                long_edge: Some(480),
+                ..Default::default()
+            }]
+        })
+        .is_empty());
+
+        // Only the inputs whose format changes are kept
+        assert_eq!(
+            in_place(&|options| options.format = OutputFormat::Jpeg),
+            vec![input_files[0].clone()]
+        );
+        assert_eq!(
+            in_place(&|options| options.format_conversions = vec![(OutputFormat::Png, OutputFormat::Webp)]),
+            vec![input_files[0].clone()]
+        );
+    }
+
+    #[test]
+    fn test_size_variants() {
+        let temp_dir = tempdir().unwrap();
+        let input_files = vec![absolute(PathBuf::from("samples/j0.JPG")).unwrap()];
+        let source = imagesize::size(&input_files[0]).unwrap();
+        let source_long_edge = source.width.max(source.height) as u32;
+        let mut options = setup_options();
+        options.output_folder = Some(temp_dir.path().to_path_buf());
+        options.base_path = absolute(PathBuf::from("samples")).unwrap();
+        options.format = OutputFormat::Webp;
+        options.width = Some(50);
+        // The largest size is above the source one and keeps it
+        let sizes = [100, 200, source_long_edge + 100];
+        options.variants = sizes
+            .map(|size| Variant {
+                suffix: format!("-{size}w"),
+                long_edge: Some(size),
+                ..Default::default()
+            })
+            .to_vec();
+
+        let results = start_compression(&input_files, &options, false, None);
+        assert_eq!(results.len(), 3);
+        for (result, size) in results.iter().zip(sizes) {
+            assert!(
+                matches!(result.status, CompressionStatus::Success),
+                "{}",
+                result.message
+            );
+            assert_eq!(
+                PathBuf::from(&result.output_path),
+                temp_dir.path().join(format!("j0-{size}w.webp"))
+            );
+            let output = imagesize::size(&result.output_path).unwrap();
+            assert_eq!(output.width.max(output.height) as u32, size.min(source_long_edge));
+        }
+    }
+
+    #[test]
+    fn test_variants_read_the_input_once() {
+        let temp_dir = tempdir().unwrap();
+        let input_file = temp_dir.path().join("photo.jpg");
+        fs::copy("samples/j0.JPG", &input_file).unwrap();
+        let mut options = setup_options();
+        options.output_folder = Some(temp_dir.path().join("out"));
+        options.base_path = temp_dir.path().to_path_buf();
+        let widths = [50, 100, 200];
+        options.variants = widths
+            .map(|width| Variant {
+                suffix: format!("-{width}w"),
+                width: Some(width),
+                ..Default::default()
+            })
+            .to_vec();
+
+        // The input is replaced once its first variant is written, the following ones must not read it again
+        let mut on_progress = |event: ProgressEvent| {
+            if matches!(event, ProgressEvent::Finished { .. }) {
+                fs::write(&input_file, b"not an image").unwrap();
+            }
+        };
+        let results = start_compression(&[input_file.clone()], &options, false, Some(&mut on_progress));
+        assert_eq!(results.len(), 3);
+        for (result, width) in results.iter().zip(widths) {
+            assert!(
+                matches!(result.status, CompressionStatus::Success),
+                "{}",
+                result.message
+            );
+            assert_eq!(imagesize::size(&result.output_path).unwrap().width as u32, width);
+        }
+    }
+
+    #[test]
+    fn test_cmyk_jpeg() {
+        let input = fs::read("tests/fixtures/cmyk.jpg").unwrap();
+        let compress = |options: &CompressionOptions| {
+            let mut output = Vec::new();
+            compress_stream(&mut io::Cursor::new(&input), &mut output, options).unwrap();
+            output
+        };
+
+        let mut options = setup_options();
+        let output = compress(&options);
+        assert_eq!(get_file_mime_type_from_buffer(&output).as_deref(), Some("image/jpeg"));
+        let decoded = image::load_from_memory(&output).unwrap();
+        assert_eq!(decoded.color(), image::ColorType::Rgb8);
+        // A red top-left quadrant, which would come out cyan if the inverted CMYK was read as is
+        let [r, g, b] = decoded.to_rgb8().get_pixel(0, 0).0;
+        assert!(r > 200 && g < 60 && b < 60, "top-left pixel is {:?}", [r, g, b]);
+
+        // Converting to a format without CMYK goes through RGB too
+        options.format = OutputFormat::Png;
+        let output = compress(&options);
+        let decoded = image::load_from_memory(&output).unwrap().to_rgb8();
+        let [r, g, b] = decoded.get_pixel(0, 0).0;
+        assert!(r > 200 && g < 60 && b < 60, "top-left pixel is {:?}", [r, g, b]);
+    }
+
+    #[test]
+    fn test_heic_input() {
+        let temp_dir = tempdir().unwrap();
+        let input_dir = temp_dir.path().join("photos");
+        fs::create_dir(&input_dir).unwrap();
+        // The file type box of a HEIC photo, with no image after it
+        let input_file = input_dir.join("IMG_0042.HEIC");
+        let mut header = vec![0, 0, 0, 0x18];
+        header.extend_from_slice(b"ftypheic\0\0\0\0mif1heic");
+        fs::write(&input_file, header).unwrap();
+        let output_dir = temp_dir.path().join("out");
+        let mut options = setup_options();
+        options.output_folder = Some(output_dir.clone());
+        options.base_path = input_dir.clone();
+
+        // HEIC can't be written, JPEG is the default output
+        let results = start_compression(&[input_file.clone()], &options, true, None);
+        assert_eq!(PathBuf::from(&results[0].output_path), output_dir.join("IMG_0042.jpg"));
+        options.format = OutputFormat::Webp;
+        let results = start_compression(&[input_file.clone()], &options, true, None);
+        assert_eq!(PathBuf::from(&results[0].output_path), output_dir.join("IMG_0042.webp"));
+
+        let results = start_compression(&[input_file], &options, false, None);
+        assert!(matches!(results[0].status, CompressionStatus::Error));
+        let expected_kind = match cfg!(feature = "heic") {
+            true => CompressionErrorKind::Decode,
+            false => CompressionErrorKind::Unsupported,
+        };
+        assert_eq!(results[0].error_kind, Some(expected_kind));
+        assert!(results[0].message.starts_with("Error decoding HEIC"));
+    }
+
+    #[test]
+    fn test_rotate_and_flip() {
+        let temp_dir = tempdir().unwrap();
+        // 4x2 image, black but for a red top-left corner
+        let mut fixture = image::RgbImage::new(4, 2);
+        fixture.put_pixel(0, 0, image::Rgb([255, 0, 0]));
+        let input_file = temp_dir.path().join("scan.png");
+        fixture.save(&input_file).unwrap();
+        let transform = |rotate: Option<u16>, flip: Option<Flip>, width: Option<u32>| {
+            let mut options = setup_options();
+            options.lossless = true;
+            options.rotate = rotate;
+            options.flip = flip;
+            options.width = width;
+            let mut output = Vec::new();
+            compress_stream(
+                &mut io::Cursor::new(fs::read(&input_file).unwrap()),
+                &mut output,
+                &options,
+            )
+            .unwrap();
+            image::load_from_memory(&output).unwrap().to_rgb8()
+        };
+        let red_corner = |output: &image::RgbImage| {
+            let (right, bottom) = (output.width() - 1, output.height() - 1);
+            [(0, 0), (right, 0), (0, bottom), (right, bottom)]
+                .into_iter()
+                .find(|&(x, y)| output.get_pixel(x, y).0 == [255, 0, 0])
+        };
+
+        // Clockwise, the top-left corner goes to the top-right
+        let output = transform(Some(90), None, None);
+        assert_eq!(output.dimensions(), (2, 4));
+        assert_eq!(red_corner(&output), Some((1, 0)));
+
+        let output = transform(Some(270), None, None);
+        assert_eq!(output.dimensions(), (2, 4));
+        assert_eq!(red_corner(&output), Some((0, 3)));
+
+        let output = transform(Some(180), Some(Flip::Horizontal), None);
+        assert_eq!(output.dimensions(), (4, 2));
+        assert_eq!(red_corner(&output), Some((0, 1)));
+
+        let output = transform(None, Some(Flip::Horizontal), None);
+        assert_eq!(red_corner(&output), Some((3, 0)));
+        let output = transform(None, Some(Flip::Vertical), None);
+        assert_eq!(red_corner(&output), Some((0, 1)));
+
+        // The resize applies to the rotated image
+        let output = transform(Some(90), None, Some(1));
+        assert_eq!(output.dimensions(), (1, 2));
+    }
+
+    #[test]
+    fn test_webp_near_lossless() {
+        let input = fs::read("samples/p0.png").unwrap();
+        let mut options = setup_options();
+        options.format = OutputFormat::Webp;
+
+        let sizes: Vec<usize> = [20, 60, 100]
+            .into_iter()
+            .map(|level| {
+                options.webp_near_lossless = Some(level);
+                let mut output = vec![];
+                compress_stream(&mut input.as_slice(), &mut output, &options).unwrap();
+                assert_eq!(infer::get(&output).unwrap().mime_type(), "image/webp");
+                output.len()
+            })
+            .collect();
+        // Lower levels alter more pixels to compress better
+        assert!(sizes.windows(2).all(|pair| pair[0] <= pair[1]), "{sizes:?}");
+
+        // Ignored for the other formats
+        options.format = OutputFormat::Png;
+        let mut output = vec![];
+        compress_stream(&mut input.as_slice(), &mut output, &options).unwrap();
+        assert_eq!(infer::get(&output).unwrap().mime_type(), "image/png");
+    }
+
+    #[test]
+    fn test_webp_effort() {
+        let input = fs::read("samples/p0.png").unwrap();
+        let mut options = setup_options();
+        options.format = OutputFormat::Webp;
+        options.lossless = true;
+
+        let sizes: Vec<usize> = [0, 6]
+            .into_iter()
+            .map(|effort| {
+                options.webp_effort = Some(effort);
+                let mut output = vec![];
+                compress_stream(&mut input.as_slice(), &mut output, &options).unwrap();
+                assert_eq!(infer::get(&output).unwrap().mime_type(), "image/webp");
+                output.len()
+            })
+            .collect();
+        // A higher effort searches longer for a smaller file
+        assert!(sizes[1] < sizes[0], "{sizes:?}");
+
+        // Lossy outputs keep the quality
+        options.lossless = false;
+        options.quality = Some(60);
+        let mut output = vec![];
+        compress_stream(&mut input.as_slice(), &mut output, &options).unwrap();
+        assert_eq!(infer::get(&output).unwrap().mime_type(), "image/webp");
+        assert!(output.len() < sizes[1], "{}", output.len());
+    }
+
+    #[test]
+    fn test_comment_output() {
+        let temp_dir = tempdir().unwrap();
+        let mut options = setup_options();
+        options.exif = false;
+        options.comment = Some("batch 2024-06".to_string());
+        options.software_tag = true;
+        options.output_folder = Some(temp_dir.path().to_path_buf());
+        let software = format!("caesium-clt {}", env!("CARGO_PKG_VERSION"));
+
+        for sample in ["samples/j0.JPG", "samples/p0.png", "samples/w0.webp"] {
+            let input_file = absolute(PathBuf::from(sample)).unwrap();
+            options.base_path = input_file.parent().unwrap().to_path_buf();
+            let result = perform_compression(&input_file, &options, None, None, false);
+            assert!(
+                matches!(result.status, CompressionStatus::Success),
+                "{sample}: {}",
+                result.message
+            );
+            let data = fs::read(&result.output_path).unwrap();
+            let contains = |text: &str| data.windows(text.len()).any(|window| window == text.as_bytes());
+            assert!(contains("batch 2024-06"), "{sample}");
+            assert!(contains(&software), "{sample}");
+        }
+    }
+
+    #[test]
+    fn test_resample_dpi() {
+        let temp_dir = tempdir().unwrap();
+        let original = fs::read("samples/p0.png").unwrap();
+        let input_file = temp_dir.path().join("input").join("p0.png");
+        fs::create_dir_all(input_file.parent().unwrap()).unwrap();
+        fs::write(&input_file, set_dpi(&original, 300).unwrap()).unwrap();
+        let size = imagesize::blob_size(&original).unwrap();
+
+        let mut options = setup_options();
+        options.dpi = Some(150);
+        options.resample_dpi = true;
+        options.base_path = input_file.parent().unwrap().to_path_buf();
+        options.output_folder = Some(temp_dir.path().join("output"));
+        let result = perform_compression(&input_file, &options, None, None, false);
+        assert!(
+            matches!(result.status, CompressionStatus::Success),
+            "{}",
+            result.message
+        );
+        let data = fs::read(&result.output_path).unwrap();
+        let resized = imagesize::blob_size(&data).unwrap();
+        assert_eq!(resized.width, resample(size.width as u32, 300, 150) as usize);
+        assert_eq!(resized.height, resample(size.height as u32, 300, 150) as usize);
+        assert_eq!(read_dpi(&data), Some((150, 150)));
+
+        // Without an embedded resolution, the pixels are kept
+        let buffer = fs::read("samples/p0.png").unwrap();
+        let mut params = CSParameters::new();
+        build_resize_parameters(&options, &mut params, &buffer, Some("image/png".to_string())).unwrap();
+        assert_eq!((params.width, params.height), (0, 0));
+    }
+
+    #[test]
+    fn test_keep_structure_outside_base_folder() {
+        let temp_dir = tempdir().unwrap();
+        let base_path = absolute(PathBuf::from("samples/level_1_0")).unwrap();
+        let mut options = setup_options();
+        options.keep_structure = true;
+        options.base_path = base_path.clone();
+        options.output_folder = Some(temp_dir.path().to_path_buf());
+
+        let nested = base_path.join("level_2_0").join("p2.png");
+        let result = perform_compression(&nested, &options, None, None, true);
+        assert!(
+            matches!(result.status, CompressionStatus::Success),
+            "{}",
+            result.message
+        );
+        assert_eq!(
+            PathBuf::from(&result.output_path),
+            temp_dir.path().join("level_2_0").join("p2.png")
+        );
+
+        for outside in [
+            absolute(PathBuf::from("samples/level_1_1/w1.webp")).unwrap(),
+            base_path.join("../level_1_1/w1.webp"),
+        ] {
+            let result = perform_compression(&outside, &options, None, None, true);
+            assert!(matches!(result.status, CompressionStatus::Error));
+            assert!(
+                result.message.starts_with("Unable to keep the folder structure"),
+                "{}",
+                result.message
+            );
+        }
+    }
+
+    #[test]
+    fn test_fit_within() {
+        // Landscape and portrait sources against the same box
+        assert_eq!(fit_within(4000, 3000, 1920, 1080), Some((1440, 1080)));
+        assert_eq!(fit_within(3000, 4000, 1920, 1080), Some((810, 1080)));
+        assert_eq!(fit_within(3840, 1080, 1920, 1080), Some((1920, 540)));
+
+        // Images already fitting are not upscaled
+        assert_eq!(fit_within(1920, 1080, 1920, 1080), None);
+        assert_eq!(fit_within(800, 600, 1920, 1080), None);
+
+        // Extreme ratios keep at least one pixel
+        assert_eq!(fit_within(10000, 1, 100, 100), Some((100, 1)));
+    }
+
+    #[test]
+    fn test_resize_filter() {
+        let temp_dir = tempdir().unwrap();
+        let input_file = temp_dir.path().join("checker.png");
+        image::RgbImage::from_fn(64, 48, |x, y| {
+            image::Rgb([if (x / 2 + y / 2) % 2 == 0 { 0 } else { 255 }; 3])
+        })
+        .save(&input_file)
+        .unwrap();
+        let mut options = setup_options();
+        options.lossless = true;
+        options.width = Some(32);
+        options.resize_filter = ResizeFilter::Nearest;
+        options.output_folder = Some(temp_dir.path().join("out"));
+        options.base_path = temp_dir.path().to_path_buf();
+
+        let results = start_compression(&[input_file], &options, false, None);
+        assert!(
+            matches!(results[0].status, CompressionStatus::Success),
+            "{}",
+            results[0].message
+        );
+        let output = image::open(&results[0].output_path).unwrap().to_rgb8();
+        assert_eq!(output.dimensions(), (32, 24));
+        // Pixel art keeps its hard edges
+        assert!(output.pixels().all(|pixel| pixel.0 == [0; 3] || pixel.0 == [255; 3]));
+    }
+
+    #[test]
+    fn test_transformed_jpeg_keeps_metadata() {
+        let temp_dir = tempdir().unwrap();
+        let mut options = setup_options();
+        options.lossless = true;
+        options.width = Some(100);
+        options.resize_filter = ResizeFilter::Nearest;
+        options.rotate = Some(90);
+        options.output_folder = Some(temp_dir.path().join("out"));
+        options.base_path = PathBuf::from("samples");
+
+        // A lossless JPEG can't be kept once its pixels change, it is encoded again
+        let results = start_compression(&[PathBuf::from("samples/j0.JPG")], &options, false, None);
+        assert!(
+            matches!(results[0].status, CompressionStatus::Success),
+            "{}",
+            results[0].message
+        );
+        let output = fs::read(&results[0].output_path).unwrap();
+        assert_eq!(image::load_from_memory(&output).unwrap().width(), 100);
+        let exif = exif::Reader::new()
+            .read_from_container(&mut io::Cursor::new(&output))
+            .unwrap();
+        // The orientation has been applied to the pixels
+        assert!(exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY).is_none());
+        assert!(exif.fields().next().is_some());
+
+        options.exif = false;
+        let results = start_compression(&[PathBuf::from("samples/j0.JPG")], &options, false, None);
+        let output = fs::read(&results[0].output_path).unwrap();
+        assert!(extract_exif(&output).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_crop_origin() {
+        assert_eq!(crop_origin(1000, 600, 400, 400, Gravity::Center), (300, 100));
+        assert_eq!(crop_origin(1000, 600, 400, 400, Gravity::North), (300, 0));
+        assert_eq!(crop_origin(1000, 600, 400, 400, Gravity::Southeast), (600, 200));
+        assert_eq!(crop_origin(1000, 600, 400, 400, Gravity::West), (0, 100));
+        assert_eq!(crop_origin(300, 300, 300, 300, Gravity::Southwest), (0, 0));
+    }
+
+    #[test]
+    fn test_crop() {
+        let temp_dir = tempdir().unwrap();
+        let input_file = temp_dir.path().join("halves.png");
+        // Red on the left, blue on the right
+        image::RgbImage::from_fn(80, 40, |x, _| {
+            image::Rgb(if x < 40 { [255, 0, 0] } else { [0, 0, 255] })
+        })
+        .save(&input_file)
+        .unwrap();
+        let mut options = setup_options();
+        options.lossless = true;
+        options.crop = Some((20, 60));
+        options.gravity = Gravity::East;
+        options.output_folder = Some(temp_dir.path().join("out"));
+        options.base_path = temp_dir.path().to_path_buf();
+
+        let results = start_compression(&[input_file.clone()], &options, false, None);
+        assert!(
+            matches!(results[0].status, CompressionStatus::Success),
+            "{}",
+            results[0].message
+        );
+        // The height is kept as the image is smaller than the crop
+        let output = image::open(&results[0].output_path).unwrap().to_rgb8();
+        assert_eq!(output.dimensions(), (20, 40));
+        assert!(output.pixels().all(|pixel| pixel.0 == [0, 0, 255]));
+
+        // Resized after the crop
+        options.gravity = Gravity::West;
+        options.width = Some(10);
+        let results = start_compression(&[input_file], &options, false, None);
+        let output = image::open(&results[0].output_path).unwrap().to_rgb8();
+        assert_eq!(output.dimensions(), (10, 20));
+        let [red, _, blue] = output.get_pixel(5, 10).0;
+        assert!(red > 250 && blue < 5, "{red} {blue}");
+    }
+
+    #[test]
+    fn test_fit_modes() {
+        let temp_dir = tempdir().unwrap();
+        let input_file = temp_dir.path().join("halves.png");
+        // Red on the left, blue on the right
+        image::RgbImage::from_fn(80, 40, |x, _| {
+            image::Rgb(if x < 40 { [255, 0, 0] } else { [0, 0, 255] })
+        })
+        .save(&input_file)
+        .unwrap();
+        let mut options = setup_options();
+        options.lossless = true;
+        options.width = Some(100);
+        options.height = Some(100);
+        options.fit_mode = Some(FitMode::Pad);
+        options.pad_color = [255, 255, 255, 255];
+        options.output_folder = Some(temp_dir.path().join("out"));
+        options.base_path = temp_dir.path().to_path_buf();
+
+        // Letterboxed in the middle of the box
+        let results = start_compression(&[input_file.clone()], &options, false, None);
+        assert!(
+            matches!(results[0].status, CompressionStatus::Success),
+            "{}",
+            results[0].message
+        );
+        let output = image::open(&results[0].output_path).unwrap();
+        assert!(!output.color().has_alpha());
+        let output = output.to_rgb8();
+        assert_eq!(output.dimensions(), (100, 100));
+        assert_eq!(output.get_pixel(50, 5).0, [255, 255, 255]);
+        assert_eq!(output.get_pixel(50, 94).0, [255, 255, 255]);
+        assert_eq!(output.get_pixel(10, 50).0, [255, 0, 0]);
+
+        // Cropped to the box on the side of the gravity
+        options.width = Some(20);
+        options.height = Some(20);
+        options.fit_mode = Some(FitMode::Cover);
+        options.gravity = Gravity::East;
+        let results = start_compression(&[input_file.clone()], &options, false, None);
+        let output = image::open(&results[0].output_path).unwrap().to_rgb8();
+        assert_eq!(output.dimensions(), (20, 20));
+        assert!(output.pixels().all(|pixel| pixel.0[2] > 250 && pixel.0[0] < 5));
+
+        // Like --fit WIDTHxHEIGHT
+        options.width = Some(40);
+        options.height = Some(40);
+        options.fit_mode = Some(FitMode::Contain);
+        let results = start_compression(&[input_file], &options, false, None);
+        assert_eq!(imagesize::size(&results[0].output_path).unwrap().width, 40);
+        assert_eq!(imagesize::size(&results[0].output_path).unwrap().height, 20);
+    }
+
+    #[test]
+    fn test_scale_down() {
+        assert_eq!(scale_down(4000, 50.0), 2000);
+        assert_eq!(scale_down(1081, 50.0), 541);
+        assert_eq!(scale_down(3, 10.0), 1);
+    }
+
+    #[test]
+    fn test_quality_rules() {
+        let samples = absolute(PathBuf::from("samples")).unwrap();
+        let mut options = setup_options();
+        options.base_path = samples.clone();
+        options.output_folder = Some(PathBuf::from("out"));
+        options.quality_rules = [("/level_1_1/*.webp", 40), ("*.webp", 60), ("level_1_0/", 95)]
+            .iter()
+            .map(|(glob, quality)| QualityRule::new(glob, *quality).unwrap())
+            .collect();
+
+        let input_files: Vec<PathBuf> = ["j0.JPG", "w0.webp", "level_1_0/j1.jpg", "level_1_1/w1.webp"]
+            .iter()
+            .map(|file| samples.join(file))
+            .collect();
+        let compressions: Vec<String> = plan_outputs(&input_files, &options)
+            .into_iter()
+            .map(|planned_output| planned_output.compression)
+            .collect();
+        // Files matching no rule keep the global quality, and overlapping rules resolve to the first one
+        assert_eq!(compressions, ["quality 80", "quality 60", "quality 95", "quality 40"]);
+
+        // Files outside of the base path are matched by name
+        options.base_path = samples.join("level_1_0");
+        assert_eq!(options_for_file(&samples.join("w0.webp"), &options).quality, Some(60));
+        assert_eq!(options_for_file(&samples.join("j0.JPG"), &options).quality, Some(80));
+
+        // The quality of a variant replaces the rules
+        options.variants = vec![Variant {
+            suffix: "_q70".to_string(),
+            quality: Some(70),
+            ..Default::default()
+        }];
+        let planned_outputs = plan_outputs(&input_files, &options);
+        assert!(planned_outputs
+            .iter()
+            .all(|planned_output| planned_output.compression == "quality 70"));
+
+        // Rules only replace a quality
+        options.variants.clear();
+        options.quality = None;
+        options.lossless = true;
+        assert_eq!(options_for_file(&samples.join("w0.webp"), &options).quality, None);
+    }
+
+    #[test]
+    fn test_format_qualities() {
+        let samples = absolute(PathBuf::from("samples")).unwrap();
+        let mut options = setup_options();
+        options.base_path = samples.clone();
+        options.format_qualities = vec![(OutputFormat::Jpeg, 70), (OutputFormat::Webp, 50)];
+        let quality = |file: &str, options: &CompressionOptions| options_for_file(&samples.join(file), options).quality;
+
+        // The output format is the original one, or the converted one
+        assert_eq!(quality("j0.JPG", &options), Some(70));
+        assert_eq!(quality("w0.webp", &options), Some(50));
+        assert_eq!(quality("p0.png", &options), Some(80));
+        options.format_conversions = vec![(OutputFormat::Png, OutputFormat::Webp)];
+        assert_eq!(quality("p0.png", &options), Some(50));
+        options.format_conversions.clear();
+        options.format = OutputFormat::Png;
+        assert_eq!(quality("j0.JPG", &options), Some(80));
+
+        // The quality rules take precedence
+        options.format = OutputFormat::Original;
+        options.quality_rules = vec![QualityRule::new("*.webp", 95).unwrap()];
+        assert_eq!(quality("w0.webp", &options), Some(95));
+        assert_eq!(quality("j0.JPG", &options), Some(70));
+
+        // Only a quality is replaced
+        options.quality = None;
+        options.lossless = true;
+        assert_eq!(quality("j0.JPG", &options), None);
+    }
+
+    #[test]
+    fn test_auto_format() {
+        let temp_dir = tempdir().unwrap();
+        let mut options = setup_options();
+        options.base_path = absolute(PathBuf::from("samples")).unwrap();
+        options.output_folder = Some(temp_dir.path().to_path_buf());
+        let size = |buffer: &[u8], format: OutputFormat, options: &CompressionOptions| {
+            let mut output = vec![];
+            let options = CompressionOptions {
+                format,
+                ..options.clone()
+            };
+            compress_stream(&mut &buffer[..], &mut output, &options).unwrap();
+            output.len()
+        };
+
+        // The smallest candidate wins
+        let jpeg = fs::read("samples/j0.JPG").unwrap();
+        let expected = match size(&jpeg, OutputFormat::Jpeg, &options) <= size(&jpeg, OutputFormat::Webp, &options) {
+            true => OutputFormat::Jpeg,
+            false => OutputFormat::Webp,
+        };
+        assert_eq!(auto_format(&jpeg, &options), expected);
+
+        // Transparent images are never written as JPEG
+        let mut transparent = Vec::new();
+        image::RgbaImage::from_fn(64, 64, |x, _| image::Rgba([200, 40, 40, (x * 4) as u8]))
+            .write_to(&mut Cursor::new(&mut transparent), image::ImageFormat::Png)
+            .unwrap();
+        assert!(has_alpha(&transparent));
+        assert!(!has_alpha(&jpeg));
+        assert!(matches!(
+            auto_format(&transparent, &options),
+            OutputFormat::Png | OutputFormat::Webp
+        ));
+
+        // The output is named after the chosen format
+        options.format = OutputFormat::Auto;
+        let input_file = absolute(PathBuf::from("samples/j0.JPG")).unwrap();
+        let result = perform_compression(&input_file, &options, None, None, false);
+        assert!(
+            matches!(result.status, CompressionStatus::Success),
+            "{}",
+            result.message
+        );
+        let extension = output_extension(expected).unwrap();
+        assert_eq!(
+            PathBuf::from(&result.output_path),
+            temp_dir.path().join(format!("j0.{extension}"))
+        );
+        let data = fs::read(&result.output_path).unwrap();
+        assert_eq!(
+            format_from_mime_type(infer::get(&data).unwrap().mime_type()),
+            Some(expected)
+        );
+    }
+
+    #[test]
+    fn test_format_conversions() {
+        let temp_dir = tempdir().unwrap();
+        let mut options = setup_options();
+        options.base_path = absolute(PathBuf::from("samples")).unwrap();
+        options.output_folder = Some(temp_dir.path().to_path_buf());
+        options.format_conversions = vec![
+            (OutputFormat::Png, OutputFormat::Webp),
+            (OutputFormat::Tiff, OutputFormat::Png),
+        ];
+
+        let cases = [
+            // Converted
+            ("samples/p0.png", "p0.webp", "image/webp"),
+            // Unmapped formats are kept
+            ("samples/j0.JPG", "j0.jpg", "image/jpeg"),
+            ("samples/w0.webp", "w0.webp", "image/webp"),
+        ];
+        for (input, output, mime_type) in cases {
+            let input_file = absolute(PathBuf::from(input)).unwrap();
+            let result = perform_compression(&input_file, &options, None, None, false);
+            assert!(
+                matches!(result.status, CompressionStatus::Success),
+                "{input}: {}",
+                result.message
+            );
+            assert_eq!(PathBuf::from(&result.output_path), temp_dir.path().join(output));
+
+            let data = fs::read(&result.output_path).unwrap();
+            assert_eq!(get_file_mime_type_from_buffer(&data).as_deref(), Some(mime_type));
+        }
+
+        let tiff = absolute(PathBuf::from("samples/t0.tif")).unwrap();
+        assert_eq!(options_for_file(&tiff, &options).format, OutputFormat::Png);
+        options.format_conversions.clear();
+        assert_eq!(options_for_file(&tiff, &options).format, OutputFormat::Original);
+    }
+
+    #[test]
+    fn test_target_ssim_search() {
+        let temp_dir = tempdir().unwrap();
+        let input_file = absolute(PathBuf::from("samples/j0.JPG")).unwrap();
+        let mut options = setup_options();
+        options.quality = None;
+        options.target_ssim = Some(0.95);
+        options.base_path = input_file.parent().unwrap().to_path_buf();
+        options.output_folder = Some(temp_dir.path().to_path_buf());
+
+        let result = perform_compression(&input_file, &options, None, None, false);
+        assert!(
+            matches!(result.status, CompressionStatus::Success),
+            "{}",
+            result.message
+        );
+
+        let quality: u32 = result
+            .message
+            .strip_prefix("Quality ")
+            .and_then(|m| m.split_whitespace().next())
+            .and_then(|q| q.parse().ok())
+            .unwrap();
+        assert!((1..=100).contains(&quality));
+
+        let original = decode_luma(&fs::read(&input_file).unwrap()).unwrap();
+        let ssim = ssim_against(&original, &fs::read(&result.output_path).unwrap()).unwrap();
+        assert!(ssim >= 0.95, "SSIM {ssim} below target");
+
+        let (_, lower_quality, _) = compress_to_target_ssim(
+            &fs::read(&input_file).unwrap(),
+            &CompressionOptions {
+                target_ssim: Some(0.5),
+                ..options.clone()
+            },
+            0.5,
+        )
+        .unwrap();
+        assert!(lower_quality <= quality);
+    }
+
+    #[test]
+    fn test_bpp_target_search() {
+        let temp_dir = tempdir().unwrap();
+        let input_files = [
+            absolute(PathBuf::from("samples/j0.JPG")).unwrap(),
+            absolute(PathBuf::from("samples/level_1_0/j1.jpg")).unwrap(),
+        ];
+        let target = 1.0;
+        let mut options = setup_options();
+        options.quality = None;
+        options.bpp_target = Some(target);
+        options.exif = false;
+        // Same width, different heights, to keep the encodings short
+        options.width = Some(600);
+        options.base_path = absolute(PathBuf::from("samples")).unwrap();
+        options.output_folder = Some(temp_dir.path().to_path_buf());
+
+        let mut pixel_counts = vec![];
+        for input_file in &input_files {
+            let result = perform_compression(input_file, &options, None, None, false);
+            assert!(
+                matches!(result.status, CompressionStatus::Success),
+                "{}",
+                result.message
+            );
+            let quality: u32 = result
+                .message
+                .strip_prefix("Quality ")
+                .and_then(|m| m.split_whitespace().next())
+                .and_then(|q| q.parse().ok())
+                .unwrap();
+            assert!((1..=100).contains(&quality));
+
+            let size = imagesize::size(&result.output_path).unwrap();
+            pixel_counts.push(size.width * size.height);
+            let bpp = result.compressed_size as f64 * 8.0 / (size.width * size.height) as f64;
+            assert!(
+                (bpp - target).abs() / target < 0.2,
+                "{bpp} bpp for a target of {target}"
+            );
+        }
+        assert_ne!(pixel_counts[0], pixel_counts[1]);
     }
-}
 
-fn read_file_to_vec(file_path: &PathBuf) -> io::Result<Vec<u8>> {
-    let mut file = File::open(file_path)?;
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer)?;
-    Ok(buffer)
-}
+    #[test]
+    fn test_total_budget() {
+        let temp_dir = tempdir().unwrap();
+        let input_files = vec![
+            absolute(PathBuf::from("samples/j0.JPG")).unwrap(),
+            absolute(PathBuf::from("samples/level_1_0/j1.jpg")).unwrap(),
+        ];
+        let total_budget = 120_000;
+        let budgets = allocate_budget(&input_files, total_budget);
+        let pixels: Vec<usize> = input_files
+            .iter()
+            .map(|input_file| {
+                let size = imagesize::size(input_file).unwrap();
+                size.width * size.height
+            })
+            .collect();
+        assert_eq!(
+            budgets[input_files[0].as_path()] > budgets[input_files[1].as_path()],
+            pixels[0] > pixels[1]
+        );
+        assert!(budgets.values().sum::<u64>() <= total_budget);
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use indicatif::ProgressDrawTarget;
-    use std::time::UNIX_EPOCH;
-    use tempfile::tempdir;
+        let mut options = setup_options();
+        options.quality = None;
+        options.total_budget = Some(total_budget);
+        options.exif = false;
+        options.width = Some(600);
+        options.base_path = absolute(PathBuf::from("samples")).unwrap();
+        options.output_folder = Some(temp_dir.path().to_path_buf());
+
+        let results = start_compression(&input_files, &options, false, None);
+        for result in &results {
+            assert!(
+                matches!(result.status, CompressionStatus::Success),
+                "{}",
+                result.message
+            );
+        }
+        assert!(results.iter().map(|result| result.compressed_size).sum::<u64>() <= total_budget);
+    }
 
     #[test]
-    fn test_compute_output_full_path() {
-        // Create temporary directories for testing
+    fn test_max_size_search() {
         let temp_dir = tempdir().unwrap();
-        let output_directory = temp_dir.path().join("output");
-        let base_directory = temp_dir.path().join("base");
-        let input_folder = base_directory.join("folder");
+        let input_file = absolute(PathBuf::from("samples/level_1_0/j1.jpg")).unwrap();
+        let max_size = 60_000;
+        let mut options = setup_options();
+        options.quality = None;
+        options.max_size = Some(max_size);
+        options.max_size_tolerance = Some(20.0);
+        options.exif = false;
+        options.width = Some(600);
+        options.base_path = absolute(PathBuf::from("samples")).unwrap();
+        options.output_folder = Some(temp_dir.path().to_path_buf());
 
-        // Create the necessary directory structure
-        fs::create_dir_all(&output_directory).unwrap();
-        fs::create_dir_all(&input_folder).unwrap();
+        let result = perform_compression(&input_file, &options, None, None, false);
+        assert!(
+            matches!(result.status, CompressionStatus::Success),
+            "{}",
+            result.message
+        );
+        assert!(result.message.starts_with("Quality "), "{}", result.message);
+        assert!(result.compressed_size <= max_size);
 
-        // Test case 1: keep_structure = true
-        let input_file_path = input_folder.join("test.jpg");
-        let result = compute_output_full_path(
-            &output_directory,
-            &input_file_path,
-            &base_directory,
-            true,
-            "_suffix",
-            OutputFormat::Original,
-            false,
-        )
-        .unwrap();
-        assert_eq!(result, (output_directory.join("folder"), "test_suffix.jpg".into()));
+        // A single encoding, at the middle quality
+        options.max_size_tolerance = None;
+        options.max_size_iterations = Some(1);
+        let result = perform_compression(&input_file, &options, None, None, false);
+        assert_eq!(result.message, "Quality 50");
+    }
 
-        // Test case 2: keep_structure = false
-        let result = compute_output_full_path(
-            &output_directory,
-            &input_file_path,
-            &base_directory,
-            false,
-            "_suffix",
-            OutputFormat::Original,
-            false,
-        )
-        .unwrap();
-        assert_eq!(result, (output_directory.clone(), "test_suffix.jpg".into()));
+    #[test]
+    fn test_overwrite_answer_parse() {
+        assert_eq!(OverwriteAnswer::parse("y\n"), OverwriteAnswer::Yes);
+        assert_eq!(OverwriteAnswer::parse(" YES "), OverwriteAnswer::Yes);
+        assert_eq!(OverwriteAnswer::parse("a"), OverwriteAnswer::All);
+        assert_eq!(OverwriteAnswer::parse("all"), OverwriteAnswer::All);
+        assert_eq!(OverwriteAnswer::parse("q"), OverwriteAnswer::Quit);
+        assert_eq!(OverwriteAnswer::parse("Quit"), OverwriteAnswer::Quit);
+        assert_eq!(OverwriteAnswer::parse("n"), OverwriteAnswer::No);
+        assert_eq!(OverwriteAnswer::parse(""), OverwriteAnswer::No);
+        assert_eq!(OverwriteAnswer::parse("maybe"), OverwriteAnswer::No);
+    }
 
-        // Test case 3: input file without extension
-        let input_file_path = input_folder.join("test");
-        let result = compute_output_full_path(
-            &output_directory,
-            &input_file_path,
-            &base_directory,
-            false,
-            "_suffix",
-            OutputFormat::Original,
-            false,
-        )
-        .unwrap();
-        assert_eq!(result, (output_directory.clone(), "test_suffix".into()));
+    #[test]
+    fn test_overwrite_prompt() {
+        let scripted = |answers: Vec<OverwriteAnswer>| {
+            let answers = Mutex::new(answers.into_iter());
+            let asked = Arc::new(Mutex::new(0));
+            let counter = asked.clone();
+            let prompt = OverwritePrompt::new(move |_| {
+                *counter.lock().unwrap() += 1;
+                answers.lock().unwrap().next().unwrap()
+            });
+            (prompt, asked)
+        };
+        let path = Path::new("out.jpg");
 
-        // Test case 4: input file with different base directory
-        let different_base = temp_dir.path().join("different_base");
-        let different_folder = different_base.join("folder");
-        fs::create_dir_all(&different_folder).unwrap();
-        let input_file_path = different_folder.join("test.jpg");
-        let result = compute_output_full_path(
-            &output_directory,
-            &input_file_path,
-            &base_directory,
-            false,
-            "_suffix",
-            OutputFormat::Original,
-            false,
-        )
-        .unwrap();
-        assert_eq!(result, (output_directory.clone(), "test_suffix.jpg".into()));
+        let (prompt, asked) = scripted(vec![OverwriteAnswer::No, OverwriteAnswer::Yes, OverwriteAnswer::All]);
+        assert_eq!(prompt.confirm(path), OverwriteAnswer::No);
+        assert_eq!(prompt.confirm(path), OverwriteAnswer::Yes);
+        assert_eq!(prompt.confirm(path), OverwriteAnswer::Yes);
+        // `all` is remembered
+        assert_eq!(prompt.confirm(path), OverwriteAnswer::Yes);
+        assert_eq!(*asked.lock().unwrap(), 3);
+        assert!(!prompt.is_aborted());
 
-        // Test case 5: input file with OutputFormat::Jpeg
-        let result = compute_output_full_path(
-            &output_directory,
-            &input_file_path,
-            &base_directory,
-            false,
-            "_suffix",
-            OutputFormat::Jpeg,
-            false,
-        )
-        .unwrap();
-        assert_eq!(result, (output_directory.clone(), "test_suffix.jpg".into()));
+        let (prompt, asked) = scripted(vec![OverwriteAnswer::Quit]);
+        assert_eq!(prompt.confirm(path), OverwriteAnswer::Quit);
+        assert_eq!(prompt.confirm(path), OverwriteAnswer::Quit);
+        assert_eq!(*asked.lock().unwrap(), 1);
+        assert!(prompt.is_aborted());
+    }
 
-        // Test case 6: input file with OutputFormat::Png
-        let result = compute_output_full_path(
-            &output_directory,
-            &input_file_path,
-            &base_directory,
-            false,
-            "_suffix",
-            OutputFormat::Png,
-            false,
-        )
-        .unwrap();
-        assert_eq!(result, (output_directory.clone(), "test_suffix.png".into()));
+    #[test]
+    fn test_overwrite_policy_ask() {
+        let temp_dir = tempdir().unwrap();
+        let existing = temp_dir.path().join("existing.jpg");
+        fs::write(&existing, b"existing").unwrap();
+        let missing = temp_dir.path().join("missing.jpg");
+        let mut options = setup_options();
+        options.overwrite_policy = OverwritePolicy::Ask;
 
-        // Test case 7: input file with OutputFormat::Webp
-        let result = compute_output_full_path(
-            &output_directory,
-            &input_file_path,
-            &base_directory,
-            false,
-            "_suffix",
-            OutputFormat::Webp,
-            false,
-        )
-        .unwrap();
-        assert_eq!(result, (output_directory.clone(), "test_suffix.webp".into()));
+        let skips = |options: &CompressionOptions, path: &Path| {
+            let mut result = CompressionResult {
+                original_path: String::new(),
+                output_path: String::new(),
+                original_size: 10,
+                compressed_size: 0,
+                status: CompressionStatus::Success,
+                error_kind: None,
+                message: String::new(),
+            };
+            (
+                skip_due_to_overwrite_policy(options, path, 10, &mut result),
+                result.message,
+            )
+        };
 
-        // Test case 8: input file with OutputFormat::Tiff
-        let result = compute_output_full_path(
-            &output_directory,
-            &input_file_path,
-            &base_directory,
-            false,
-            "_suffix",
-            OutputFormat::Tiff,
-            false,
-        )
-        .unwrap();
+        // Without a prompt, it behaves like `never`
+        assert!(skips(&options, &existing).0);
+        assert!(!skips(&options, &missing).0);
 
-        assert_eq!(result, (output_directory.clone(), "test_suffix.tiff".into()));
+        options.overwrite_prompt = Some(Arc::new(OverwritePrompt::new(|_| OverwriteAnswer::Yes)));
+        assert!(!skips(&options, &existing).0);
 
-        // Test case 8: input file with OutputFormat::Gif
-        let result = compute_output_full_path(
-            &output_directory,
-            &input_file_path,
-            &base_directory,
-            false,
-            "_suffix",
-            OutputFormat::Gif,
-            false,
-        )
-        .unwrap();
+        options.overwrite_prompt = Some(Arc::new(OverwritePrompt::new(|_| OverwriteAnswer::Quit)));
+        let (skipped, message) = skips(&options, &existing);
+        assert!(skipped);
+        assert_eq!(message, "Aborted by user");
 
-        assert_eq!(result, (output_directory.clone(), "test_suffix.gif".into()));
+        let result = perform_compression(&PathBuf::from("samples/j0.JPG"), &options, None, None, false);
+        assert!(matches!(result.status, CompressionStatus::Skipped));
+        assert_eq!(result.message, "Aborted by user");
+    }
 
-        // Test case 10: same_folder_as_input with subfolder
-        let subfolder = input_folder.join("subfolder");
-        fs::create_dir_all(&subfolder).unwrap();
-        let input_file_path = subfolder.join("test.jpg");
-        let result = compute_output_full_path(
-            &output_directory,
-            &input_file_path,
-            &base_directory,
-            true,
-            "_suffix",
-            OutputFormat::Original,
-            true,
-        )
-        .unwrap();
-        assert_eq!(result, (subfolder, "test_suffix.jpg".into()));
+    #[test]
+    fn test_savings_percent() {
+        assert_eq!(savings_percent(1000, 800), 20.0);
+        assert_eq!(savings_percent(1000, 1000), 0.0);
+        assert_eq!(savings_percent(800, 1000), -25.0);
+        assert_eq!(savings_percent(0, 100), 0.0);
     }
 
     #[test]
-    fn test_perform_compression() {
-        let input_files = vec![
-            absolute(PathBuf::from("samples/j0.JPG")).unwrap(),
-            absolute(PathBuf::from("samples/p0.png")).unwrap(),
-            absolute(PathBuf::from("samples/w0.webp")).unwrap(),
-            absolute(PathBuf::from("samples/t0.tif")).unwrap(),
-        ];
+    fn test_retry_with_backoff() {
+        let failed_result = |error_kind| CompressionResult {
+            original_path: String::from("in.jpg"),
+            output_path: String::new(),
+            original_size: 1000,
+            compressed_size: 0,
+            status: CompressionStatus::Error,
+            error_kind: Some(error_kind),
+            message: String::from("Failed"),
+        };
 
-        let mut options = setup_options();
-        options.base_path = absolute(PathBuf::from("samples")).unwrap();
-        let multi_progress = indicatif::MultiProgress::new();
-        multi_progress.set_draw_target(ProgressDrawTarget::hidden());
-        let progress_bar = multi_progress.add(ProgressBar::new(input_files.len() as u64));
-        let temp_dir = tempdir().unwrap().path().to_path_buf();
-        options.output_folder = Some(temp_dir.clone());
+        // Transient read error on the first attempt only
+        let mut attempts = 0;
+        let result = retry_with_backoff(3, Duration::from_millis(1), || {
+            attempts += 1;
+            if attempts == 1 {
+                return failed_result(CompressionErrorKind::Read);
+            }
+            CompressionResult {
+                status: CompressionStatus::Success,
+                error_kind: None,
+                ..failed_result(CompressionErrorKind::Read)
+            }
+        });
+        assert!(matches!(result.status, CompressionStatus::Success));
+        assert_eq!(attempts, 2);
 
-        let mut results = start_compression(&input_files, &options, &multi_progress, &progress_bar, false);
-        assert_eq!(results.len(), 4);
-        assert!(results.iter().all(|r| matches!(r.status, CompressionStatus::Success)));
-        assert!(results.iter().all(|r| fs::exists(&r.output_path).unwrap_or(false)));
-        assert_eq!(PathBuf::from(&results[0].output_path), temp_dir.join("j0.JPG"));
-        assert_eq!(PathBuf::from(&results[1].output_path), temp_dir.join("p0.png"));
-        assert_eq!(PathBuf::from(&results[2].output_path), temp_dir.join("w0.webp"));
-        assert_eq!(PathBuf::from(&results[3].output_path), temp_dir.join("t0.tif"));
+        // Non-retryable errors fail immediately
+        let mut attempts = 0;
+        let result = retry_with_backoff(3, Duration::from_millis(1), || {
+            attempts += 1;
+            failed_result(CompressionErrorKind::Decode)
+        });
+        assert_eq!(result.error_kind, Some(CompressionErrorKind::Decode));
+        assert_eq!(attempts, 1);
 
+        // Persistent I/O errors give up after the allowed retries
+        let mut attempts = 0;
+        let result = retry_with_backoff(2, Duration::from_millis(1), || {
+            attempts += 1;
+            failed_result(CompressionErrorKind::Write)
+        });
+        assert_eq!(result.error_kind, Some(CompressionErrorKind::Write));
+        assert_eq!(attempts, 3);
+
+        // No retries by default
+        let mut attempts = 0;
+        retry_with_backoff(0, Duration::from_millis(1), || {
+            attempts += 1;
+            failed_result(CompressionErrorKind::Io)
+        });
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn test_resolve_collisions() {
+        let temp_dir = tempdir().unwrap();
         let input_files = vec![
-            absolute(PathBuf::from("samples/j0.JPG")).unwrap(),
-            absolute(PathBuf::from("samples/p0.png")).unwrap(),
-            absolute(PathBuf::from("samples/w0.webp")).unwrap(),
-            absolute(PathBuf::from("samples/t0.tif")).unwrap(),
-            absolute(PathBuf::from("samples/level_1_0/level_2_0/p2.png")).unwrap(),
-            absolute(PathBuf::from("samples/level_1_0/level_2_0/level_3_0/g1.gif")).unwrap(),
-            absolute(PathBuf::from("samples/level_1_0/j1.jpg")).unwrap(),
-            absolute(PathBuf::from("samples/level_1_1/w1.webp")).unwrap(),
+            temp_dir.path().join("a/photo.jpg"),
+            temp_dir.path().join("b/photo.jpg"),
+            temp_dir.path().join("c/photo.png"),
+            temp_dir.path().join("a/photo.jpg"),
+            temp_dir.path().join("d/photo_1.jpg"),
         ];
+        let output_folder = temp_dir.path().join("output");
+        let mut options = setup_options();
+        options.output_folder = Some(output_folder.clone());
 
-        let temp_dir = tempdir().unwrap().path().to_path_buf();
-        options.output_folder = Some(temp_dir.clone());
-        options.keep_structure = true;
-        results = start_compression(&input_files, &options, &multi_progress, &progress_bar, false);
-        assert_eq!(results.len(), 8);
-        assert!(results.iter().all(|r| matches!(r.status, CompressionStatus::Success)));
-        assert!(results.iter().all(|r| fs::exists(&r.output_path).unwrap_or(false)));
-        assert_eq!(PathBuf::from(&results[0].output_path), temp_dir.join("j0.JPG"));
-        assert_eq!(PathBuf::from(&results[1].output_path), temp_dir.join("p0.png"));
-        assert_eq!(PathBuf::from(&results[2].output_path), temp_dir.join("w0.webp"));
-        assert_eq!(PathBuf::from(&results[3].output_path), temp_dir.join("t0.tif"));
+        // Without conversion only the two photo.jpg collide, the same input listed twice does not
+        let collisions = resolve_collisions(&input_files, &options);
         assert_eq!(
-            PathBuf::from(&results[4].output_path),
-            temp_dir.join("level_1_0/level_2_0/p2.png")
+            collisions,
+            vec![
+                None,
+                Some(Collision::Rename(output_folder.join("photo_2.jpg"))),
+                None,
+                None,
+                None
+            ]
         );
+
+        // The format decides the extension, so photo.png collides as well
+        options.format = OutputFormat::Jpeg;
+        let collisions = resolve_collisions(&input_files, &options);
         assert_eq!(
-            PathBuf::from(&results[5].output_path),
-            temp_dir.join("level_1_0/level_2_0/level_3_0/g1.gif")
+            collisions[1],
+            Some(Collision::Rename(output_folder.join("photo_2.jpg")))
+        );
+        assert_eq!(
+            collisions[2],
+            Some(Collision::Rename(output_folder.join("photo_3.jpg")))
+        );
+
+        // The suffix is part of the output name
+        options.format = OutputFormat::Original;
+        options.suffix = Some(String::from("_min"));
+        let collisions = resolve_collisions(&input_files, &options);
+        assert_eq!(
+            collisions[1],
+            Some(Collision::Rename(output_folder.join("photo_min_1.jpg")))
         );
+
+        options.suffix = None;
+        options.collision_policy = CollisionPolicy::Error;
+        let collisions = resolve_collisions(&input_files, &options);
+        assert_eq!(collisions[1], Some(Collision::Error(input_files[0].clone())));
+        assert_eq!(collisions[2], None);
+
+        options.collision_policy = CollisionPolicy::Overwrite;
+        assert!(resolve_collisions(&input_files, &options).iter().all(|c| c.is_none()));
+    }
+
+    #[test]
+    fn test_collision_policies() {
+        let temp_dir = tempdir().unwrap();
+        let input_files = vec![temp_dir.path().join("a/image.jpg"), temp_dir.path().join("b/image.jpg")];
+        fs::create_dir_all(temp_dir.path().join("a")).unwrap();
+        fs::create_dir_all(temp_dir.path().join("b")).unwrap();
+        fs::copy("samples/j0.JPG", &input_files[0]).unwrap();
+        fs::copy("samples/level_1_0/j1.jpg", &input_files[1]).unwrap();
+
+        let output_folder = temp_dir.path().join("output");
+        let mut options = setup_options();
+        options.base_path = temp_dir.path().to_path_buf();
+        options.output_folder = Some(output_folder.clone());
+
+        let results = start_compression(&input_files, &options, false, None);
+        assert!(results.iter().all(|r| matches!(r.status, CompressionStatus::Success)));
+        assert_eq!(PathBuf::from(&results[0].output_path), output_folder.join("image.jpg"));
         assert_eq!(
-            PathBuf::from(&results[6].output_path),
-            temp_dir.join("level_1_0/j1.jpg")
+            PathBuf::from(&results[1].output_path),
+            output_folder.join("image_1.jpg")
         );
         assert_eq!(
-            PathBuf::from(&results[7].output_path),
-            temp_dir.join("level_1_1/w1.webp")
+            fs::metadata(output_folder.join("image_1.jpg")).unwrap().len(),
+            results[1].compressed_size
         );
 
-        options.quality = Some(100);
+        fs::remove_dir_all(&output_folder).unwrap();
+        options.collision_policy = CollisionPolicy::Error;
+        let results = start_compression(&input_files, &options, false, None);
+        assert!(matches!(results[0].status, CompressionStatus::Success));
+        assert!(matches!(results[1].status, CompressionStatus::Error));
+        assert_eq!(results[1].error_kind, Some(CompressionErrorKind::Collision));
+        assert!(!output_folder.join("image_1.jpg").exists());
+    }
 
-        options.overwrite_policy = OverwritePolicy::Never;
-        results = start_compression(&input_files, &options, &multi_progress, &progress_bar, false);
-        assert!(results.iter().all(|r| matches!(r.status, CompressionStatus::Skipped)));
-        assert!(results.iter().all(|r| fs::exists(&r.output_path).unwrap_or(false)));
+    #[test]
+    fn test_find_duplicates() {
+        let temp_dir = tempdir().unwrap();
+        let copy = temp_dir.path().join("copy.jpg");
+        fs::copy("samples/j0.JPG", &copy).unwrap();
 
-        options.quality = Some(100);
-        options.overwrite_policy = OverwritePolicy::Bigger;
-        results = start_compression(&input_files, &options, &multi_progress, &progress_bar, false);
-        assert!(results.iter().all(|r| matches!(r.status, CompressionStatus::Skipped)));
-        assert!(results.iter().all(|r| fs::exists(&r.output_path).unwrap_or(false)));
+        let input_files = vec![
+            PathBuf::from("samples/j0.JPG"),
+            PathBuf::from("samples/p0.png"),
+            copy,
+            PathBuf::from("samples/j0.JPG"),
+        ];
+        assert_eq!(find_duplicates(&input_files), vec![None, None, Some(0), Some(0)]);
+    }
 
-        options.quality = Some(100);
-        options.overwrite_policy = OverwritePolicy::All;
-        results = start_compression(&input_files, &options, &multi_progress, &progress_bar, true);
-        assert!(results.iter().all(|r| matches!(r.status, CompressionStatus::Success)));
-        assert!(results.iter().all(|r| fs::exists(&r.output_path).unwrap_or(false)));
+    #[test]
+    fn test_dedupe_copies_output() {
+        let temp_dir = tempdir().unwrap();
+        let input_root = temp_dir.path().join("input");
+        fs::create_dir_all(input_root.join("a")).unwrap();
+        fs::create_dir_all(input_root.join("b")).unwrap();
+        let input_files = vec![input_root.join("a/image.jpg"), input_root.join("b/image.jpg")];
+        for input_file in &input_files {
+            fs::copy("samples/j0.JPG", input_file).unwrap();
+        }
 
-        options.quality = Some(100);
-        options.png_opt_level = 6;
-        options.lossless = true;
-        options.overwrite_policy = OverwritePolicy::All;
-        results = start_compression(&input_files, &options, &multi_progress, &progress_bar, true);
-        assert!(results.iter().all(|r| matches!(r.status, CompressionStatus::Success)));
-        assert!(results.iter().all(|r| fs::exists(&r.output_path).unwrap_or(false)));
+        let output_folder = temp_dir.path().join("output");
+        let mut options = setup_options();
+        options.base_path = input_root.clone();
+        options.output_folder = Some(output_folder.clone());
+        options.keep_structure = true;
+        options.dedupe = true;
 
-        options.quality = Some(80);
-        options.keep_dates = true;
-        results = start_compression(&input_files, &options, &multi_progress, &progress_bar, false);
+        let results = start_compression(&input_files, &options, false, None);
 
-        assert!(results.iter().all(|r| matches!(r.status, CompressionStatus::Success)));
-        assert!(results.iter().all(|r| {
-            let original_metadata = fs::metadata(&r.original_path).unwrap();
-            let o_mtime = original_metadata
-                .modified()
-                .unwrap()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs();
-            let o_ltime = original_metadata
-                .accessed()
-                .unwrap()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs();
+        assert!(matches!(results[0].status, CompressionStatus::Success));
+        assert!(matches!(results[1].status, CompressionStatus::Skipped));
+        assert!(results[1].message.contains("Duplicate of"));
+        assert_eq!(
+            PathBuf::from(&results[1].output_path),
+            output_folder.join("b/image.jpg")
+        );
+        assert_eq!(
+            fs::read(output_folder.join("a/image.jpg")).unwrap(),
+            fs::read(output_folder.join("b/image.jpg")).unwrap()
+        );
 
-            let compressed_metadata = fs::metadata(&r.output_path).unwrap();
-            let c_mtime = compressed_metadata
-                .modified()
-                .unwrap()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs();
-            let c_ltime = compressed_metadata
-                .accessed()
-                .unwrap()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs();
-            o_mtime == c_mtime && (o_ltime + 10) >= c_ltime
-        }));
-        assert!(results.iter().all(|r| fs::exists(&r.output_path).unwrap_or(false)));
+        // Another quality for the duplicate gives it its own output
+        options.quality_rules = vec![QualityRule::new("b/", 40).unwrap()];
+        let results = start_compression(&input_files, &options, false, None);
+        assert!(
+            matches!(results[1].status, CompressionStatus::Success),
+            "{}",
+            results[1].message
+        );
+        assert!(
+            fs::metadata(output_folder.join("b/image.jpg")).unwrap().len()
+                < fs::metadata(output_folder.join("a/image.jpg")).unwrap().len()
+        );
     }
 
     #[test]
-    fn test_no_upscale_prevents_resize() {
-        // Use an existing sample image and determine its real resolution
-        let input_path = absolute(PathBuf::from("samples/p0.png")).unwrap();
-        let buffer = std::fs::read(&input_path).unwrap();
-        let (w, h) = get_real_resolution(&buffer, get_file_mime_type_from_buffer(&buffer), true).unwrap();
-
+    fn test_progress_events() {
+        let input_files = vec![
+            PathBuf::from("samples/j0.JPG"),
+            PathBuf::from("samples/p0.png"),
+            PathBuf::from("samples/w0.webp"),
+            PathBuf::from("samples/missing.jpg"),
+        ];
         let mut options = setup_options();
-        options.no_upscale = true;
-        options.width = Some((w + 100) as u32);
-        let params = build_compression_parameters(&options, &buffer).unwrap();
-        assert_eq!(params.width, 0);
-        assert_eq!(params.height, 0);
+        options.output_folder = Some(tempdir().unwrap().path().to_path_buf());
 
-        let mut options = setup_options();
-        options.no_upscale = true;
-        options.height = Some((h + 100) as u32);
-        let params = build_compression_parameters(&options, &buffer).unwrap();
-        assert_eq!(params.width, 0);
-        assert_eq!(params.height, 0);
+        let mut events: Vec<(String, PathBuf)> = vec![];
+        let mut on_progress = |event: ProgressEvent| match event {
+            ProgressEvent::Started { path } => events.push((String::from("started"), path.to_path_buf())),
+            ProgressEvent::Finished { path, result, .. } => {
+                assert_eq!(result.original_path, path.display().to_string());
+                events.push((String::from("finished"), path.to_path_buf()))
+            }
+        };
+        let results = start_compression(&input_files, &options, true, Some(&mut on_progress));
+        assert_eq!(results.len(), input_files.len());
 
-        let mut options = setup_options();
-        options.no_upscale = true;
-        options.long_edge = Some((w.max(h) + 100) as u32);
-        let params = build_compression_parameters(&options, &buffer).unwrap();
-        assert_eq!(params.width, 0);
-        assert_eq!(params.height, 0);
+        for input_file in &input_files {
+            let file_events: Vec<&str> = events
+                .iter()
+                .filter(|(_, path)| path == input_file)
+                .map(|(kind, _)| kind.as_str())
+                .collect();
+            assert_eq!(file_events, vec!["started", "finished"]);
+        }
+    }
 
+    #[test]
+    fn test_running_totals_match_results() {
+        let temp_dir = tempdir().unwrap();
+        let input_root = temp_dir.path().join("input");
+        fs::create_dir_all(&input_root).unwrap();
+        fs::copy("samples/j0.JPG", input_root.join("copy.jpg")).unwrap();
+        let input_files = vec![
+            PathBuf::from("samples/j0.JPG"),
+            PathBuf::from("samples/p0.png"),
+            PathBuf::from("samples/w0.webp"),
+            PathBuf::from("samples/t0.tif"),
+            PathBuf::from("samples/level_1_0/j1.jpg"),
+            PathBuf::from("samples/missing.jpg"),
+            input_root.join("copy.jpg"),
+        ];
         let mut options = setup_options();
-        options.no_upscale = true;
-        options.short_edge = Some((w.min(h) + 100) as u32);
-        let params = build_compression_parameters(&options, &buffer).unwrap();
-        assert_eq!(params.width, 0);
-        assert_eq!(params.height, 0);
+        options.dedupe = true;
+        options.output_folder = Some(temp_dir.path().join("output"));
+
+        let mut snapshots: Vec<(u64, u64)> = vec![];
+        let mut on_progress = |event: ProgressEvent| {
+            if let ProgressEvent::Finished { totals, .. } = event {
+                snapshots.push((totals.original_size(), totals.compressed_size()));
+            }
+        };
+        let results = start_compression(&input_files, &options, false, Some(&mut on_progress));
+
+        let (original_size, compressed_size) = results.iter().fold((0, 0), |(original, compressed), result| {
+            (original + result.original_size, compressed + result.compressed_size)
+        });
+        assert_eq!(snapshots.len(), input_files.len());
+        assert_eq!(snapshots.last(), Some(&(original_size, compressed_size)));
+        // Events are delivered one at a time, each one sees the totals growing
+        assert!(snapshots
+            .windows(2)
+            .all(|pair| pair[0].0 <= pair[1].0 && pair[0].1 <= pair[1].1));
     }
 
-    #[test]
-    fn test_build_resize_parameters() {
-        let input_path = absolute(PathBuf::from("samples/j0.JPG")).unwrap();
-        let buffer = std::fs::read(&input_path).unwrap();
-        let mime_type = get_file_mime_type_from_buffer(&buffer);
+    /// Collects the messages of every log record, whatever the module
+    struct TestLogger;
 
-        let mut options = setup_options();
-        options.width = Some(100);
-        options.height = Some(100);
-        let mut params = CSParameters::new();
-        build_resize_parameters(&options, &mut params, &buffer, mime_type.clone()).unwrap();
-        assert_eq!(params.width, 100);
-        assert_eq!(params.height, 100);
+    static LOG_MESSAGES: Mutex<Vec<String>> = Mutex::new(vec![]);
 
-        let mut options = setup_options();
-        options.width = Some(100);
-        let mut params = CSParameters::new();
-        build_resize_parameters(&options, &mut params, &buffer, mime_type.clone()).unwrap();
-        assert_eq!(params.width, 100);
-        assert_eq!(params.height, 0);
+    impl log::Log for TestLogger {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            true
+        }
 
-        let mut options = setup_options();
-        options.height = Some(100);
-        let mut params = CSParameters::new();
-        build_resize_parameters(&options, &mut params, &buffer, mime_type.clone()).unwrap();
-        assert_eq!(params.width, 0);
-        assert_eq!(params.height, 100);
+        fn log(&self, record: &log::Record) {
+            LOG_MESSAGES
+                .lock()
+                .unwrap()
+                .push(format!("{} {}", record.level(), record.args()));
+        }
 
-        let mut options = setup_options();
-        options.long_edge = Some(100);
-        let mut params = CSParameters::new();
-        build_resize_parameters(&options, &mut params, &buffer, mime_type.clone()).unwrap();
-        assert_eq!(params.width, 0);
-        assert_eq!(params.height, 100);
+        fn flush(&self) {}
+    }
 
-        let mut options = setup_options();
-        options.short_edge = Some(50);
-        let mut params = CSParameters::new();
-        build_resize_parameters(&options, &mut params, &buffer, mime_type.clone()).unwrap();
-        assert_eq!(params.width, 50);
-        assert_eq!(params.height, 0);
+    #[test]
+    fn test_log_events() {
+        // The logger can only be set once per process, other tests log into it too
+        let _ = log::set_logger(&TestLogger).map(|()| log::set_max_level(log::LevelFilter::Trace));
 
+        let temp_dir = tempdir().unwrap();
+        let input_file = temp_dir.path().join("logged.jpg");
+        fs::copy("samples/j0.JPG", &input_file).unwrap();
         let mut options = setup_options();
-        options.no_upscale = true;
-        options.width = Some(20000);
-        let mut params = CSParameters::new();
-        build_resize_parameters(&options, &mut params, &buffer, mime_type.clone()).unwrap();
-        assert_eq!(params.width, 0);
-        assert_eq!(params.height, 0);
+        options.base_path = temp_dir.path().to_path_buf();
+        options.output_folder = Some(temp_dir.path().join("output"));
+        let results = start_compression(&[input_file.clone()], &options, false, None);
+        assert!(matches!(results[0].status, CompressionStatus::Success));
+
+        let messages = LOG_MESSAGES.lock().unwrap();
+        let path = input_file.display().to_string();
+        assert!(messages.iter().any(|m| m == "DEBUG Compressing 1 files"));
+        assert!(messages
+            .iter()
+            .any(|m| m.starts_with(&format!("TRACE {path}: read ")) && m.contains(" bytes in ")));
+        assert!(messages
+            .iter()
+            .any(|m| m.starts_with(&format!("DEBUG {path}: decoded and encoded in "))));
     }
 
     #[test]
-    fn test_gif_quality_lossless_and_zero() {
-        let input_path = absolute(PathBuf::from("samples/level_1_0/level_2_0/level_3_0/g1.gif")).unwrap();
-        let buffer = std::fs::read(&input_path).unwrap();
+    fn test_is_in_place_write() {
+        let temp_dir = tempdir().unwrap();
+        let input_file = temp_dir.path().join("image.jpg");
+        fs::copy("samples/j0.JPG", &input_file).unwrap();
+        fs::create_dir(temp_dir.path().join("sub")).unwrap();
 
-        // Test case 1: lossless = true should set gif.quality to 100
-        let mut options = setup_options();
-        options.lossless = true;
-        options.quality = Some(80);
-        let params = build_compression_parameters(&options, &buffer).unwrap();
-        assert_eq!(params.gif.quality, 100);
+        assert!(is_in_place_write(&input_file, &input_file));
+        assert!(is_in_place_write(
+            &input_file,
+            &temp_dir.path().join("sub/../image.jpg")
+        ));
+        assert!(!is_in_place_write(&input_file, &temp_dir.path().join("sub/image.jpg")));
+        assert!(!is_in_place_write(
+            &input_file,
+            &temp_dir.path().join("image_compressed.jpg")
+        ));
+    }
 
-        // Test case 2: quality = 0 should set gif.quality to 1
-        let mut options = setup_options();
-        options.lossless = false;
-        options.quality = Some(0);
-        let params = build_compression_parameters(&options, &buffer).unwrap();
-        assert_eq!(params.gif.quality, 1);
+    #[test]
+    fn test_skip_due_to_size_policy() {
+        let temp_dir = tempdir().unwrap();
+        let larger_target = temp_dir.path().join("larger.jpg");
+        let smaller_target = temp_dir.path().join("smaller.jpg");
+        let missing_target = temp_dir.path().join("missing.jpg");
+        fs::write(&larger_target, vec![0u8; 2000]).unwrap();
+        fs::write(&smaller_target, vec![0u8; 500]).unwrap();
 
-        // Test case 3: normal quality should pass through unchanged
         let mut options = setup_options();
-        options.lossless = false;
-        options.quality = Some(75);
-        let params = build_compression_parameters(&options, &buffer).unwrap();
-        assert_eq!(params.gif.quality, 75);
+        let mut skips = |policy: OverwritePolicy, target: &Path| {
+            options.overwrite_policy = policy;
+            let mut result = CompressionResult {
+                original_path: String::from("in.jpg"),
+                output_path: target.display().to_string(),
+                original_size: 3000,
+                compressed_size: 0,
+                status: CompressionStatus::Error,
+                error_kind: None,
+                message: String::new(),
+            };
+            skip_due_to_size_policy(&options, target, 1000, 3000, &mut result)
+        };
+
+        // The new output is 1000 bytes
+        assert!(!skips(OverwritePolicy::All, &larger_target));
+        assert!(!skips(OverwritePolicy::All, &smaller_target));
+        assert!(!skips(OverwritePolicy::Bigger, &larger_target));
+        assert!(skips(OverwritePolicy::Bigger, &smaller_target));
+        assert!(skips(OverwritePolicy::Smaller, &larger_target));
+        assert!(!skips(OverwritePolicy::Smaller, &smaller_target));
+        assert!(!skips(OverwritePolicy::Bigger, &missing_target));
+        assert!(!skips(OverwritePolicy::Smaller, &missing_target));
     }
 
     #[test]
-    fn test_min_savings_skips_files() {
-        let input_files = vec![absolute(PathBuf::from("samples/j0.JPG")).unwrap()];
+    fn test_skip_reasons() {
+        let temp_dir = tempdir().unwrap();
+        let input_file = absolute(PathBuf::from("samples/j0.JPG")).unwrap();
+        let output_folder = temp_dir.path().join("output");
+        let existing_output = output_folder.join("j0.jpg");
+        fs::create_dir_all(&output_folder).unwrap();
+        let mut options = setup_options();
+        options.base_path = input_file.parent().unwrap().to_path_buf();
+        options.output_folder = Some(output_folder);
 
-        let multi_progress = indicatif::MultiProgress::new();
-        multi_progress.set_draw_target(ProgressDrawTarget::hidden());
-        let progress_bar = multi_progress.add(ProgressBar::new(input_files.len() as u64));
+        let skip_reason = |options: &CompressionOptions, input_file: &PathBuf| {
+            let result = perform_compression(input_file, options, None, None, false);
+            assert!(
+                matches!(result.status, CompressionStatus::Skipped),
+                "{}",
+                result.message
+            );
+            result.message
+        };
 
-        // Test with very high percentage threshold - should skip files
-        let temp_dir = tempdir().unwrap().path().to_path_buf();
-        let mut options = setup_options();
-        options.base_path = absolute(PathBuf::from("samples")).unwrap();
-        options.output_folder = Some(temp_dir.clone());
-        options.quality = Some(95); // High quality = small savings
-        options.min_savings = Some(MinSavingsThreshold::Percentage(99.0)); // Require 99% savings (unrealistic)
+        let empty_file = temp_dir.path().join("empty.jpg");
+        File::create(&empty_file).unwrap();
+        assert_eq!(skip_reason(&options, &empty_file), "Empty file, skipping.");
 
-        let results = start_compression(&input_files, &options, &multi_progress, &progress_bar, false);
-        assert!(results.iter().all(|r| matches!(r.status, CompressionStatus::Skipped)));
-        assert!(results.iter().all(|r| r.message.contains("Insufficient savings")));
-        // Files should NOT be written when skipped
-        assert!(results.iter().all(|r| !fs::exists(&r.output_path).unwrap_or(true)));
+        let huge_file = temp_dir.path().join("huge.jpg");
+        File::create(&huge_file).unwrap().set_len(MAX_FILE_SIZE + 1).unwrap();
+        assert_eq!(skip_reason(&options, &huge_file), "File exceeds 500Mb, skipping.");
 
-        // Test with very high byte threshold - should skip files
-        let temp_dir2 = tempdir().unwrap().path().to_path_buf();
-        let mut options2 = setup_options();
-        options2.base_path = absolute(PathBuf::from("samples")).unwrap();
-        options2.output_folder = Some(temp_dir2.clone());
-        options2.quality = Some(95);
-        options2.min_savings = Some(MinSavingsThreshold::Bytes(100_000_000)); // Require 100MB savings (unrealistic)
+        fs::write(&existing_output, vec![0u8; 10]).unwrap();
+        options.overwrite_policy = OverwritePolicy::Never;
+        assert_eq!(
+            skip_reason(&options, &input_file),
+            "File already exists, skipped due overwrite policy 'never'"
+        );
 
-        let results2 = start_compression(&input_files, &options2, &multi_progress, &progress_bar, false);
-        assert!(results2.iter().all(|r| matches!(r.status, CompressionStatus::Skipped)));
-        assert!(results2.iter().all(|r| r.message.contains("Insufficient savings")));
+        options.overwrite_policy = OverwritePolicy::Ask;
+        assert!(skip_reason(&options, &input_file).contains("without a terminal"));
+        options.overwrite_prompt = Some(Arc::new(OverwritePrompt::new(|_| OverwriteAnswer::No)));
+        assert_eq!(
+            skip_reason(&options, &input_file),
+            "File already exists, overwrite declined"
+        );
+        options.overwrite_prompt = Some(Arc::new(OverwritePrompt::new(|_| OverwriteAnswer::Quit)));
+        assert_eq!(skip_reason(&options, &input_file), "Aborted by user");
+        assert_eq!(skip_reason(&options, &input_file), "Aborted by user");
+        options.overwrite_prompt = None;
 
-        // Test with low threshold - should succeed
-        let temp_dir3 = tempdir().unwrap().path().to_path_buf();
-        let mut options3 = setup_options();
-        options3.base_path = absolute(PathBuf::from("samples")).unwrap();
-        options3.output_folder = Some(temp_dir3.clone());
-        options3.quality = Some(50); // Lower quality = more savings
-        options3.min_savings = Some(MinSavingsThreshold::Percentage(0.1)); // Very low threshold
+        options.overwrite_policy = OverwritePolicy::Bigger;
+        assert!(skip_reason(&options, &input_file).starts_with("Existing file is not bigger than the new output"));
+        options.overwrite_policy = OverwritePolicy::Smaller;
+        File::create(&existing_output).unwrap().set_len(100_000_000).unwrap();
+        assert!(skip_reason(&options, &input_file).starts_with("Existing file is not smaller than the new output"));
 
-        let results3 = start_compression(&input_files, &options3, &multi_progress, &progress_bar, false);
-        assert!(results3.iter().all(|r| matches!(r.status, CompressionStatus::Success)));
-        assert!(results3.iter().all(|r| fs::exists(&r.output_path).unwrap_or(false)));
+        options.overwrite_policy = OverwritePolicy::All;
+        options.min_savings = Some(MinSavingsThreshold::Percentage(100.0));
+        assert!(skip_reason(&options, &input_file).starts_with("Insufficient savings"));
+        options.min_savings = None;
 
-        // Test with decimal percentage threshold
-        let temp_dir4 = tempdir().unwrap().path().to_path_buf();
-        let mut options4 = setup_options();
-        options4.base_path = absolute(PathBuf::from("samples")).unwrap();
-        options4.output_folder = Some(temp_dir4.clone());
-        options4.quality = Some(95);
-        options4.min_savings = Some(MinSavingsThreshold::Percentage(50.5)); // 50.5% threshold
+        let original_result = perform_compression(&input_file, &options, None, None, false);
+        let copy = temp_dir.path().join("copy.jpg");
+        fs::copy(&input_file, &copy).unwrap();
+        options.base_path = temp_dir.path().to_path_buf();
+        let result = reuse_duplicate_output(&copy, &original_result, &options, None, false);
+        assert!(matches!(result.status, CompressionStatus::Skipped));
+        assert_eq!(result.message, format!("Duplicate of {}", input_file.display()));
+    }
 
-        let results4 = start_compression(&input_files, &options4, &multi_progress, &progress_bar, false);
-        // With high quality (95), savings should be less than 50.5%, so files should be skipped
-        assert!(results4.iter().all(|r| matches!(r.status, CompressionStatus::Skipped)));
+    #[test]
+    fn test_skip_due_to_insufficient_savings() {
+        let new_result = || CompressionResult {
+            original_path: String::from("in.jpg"),
+            output_path: String::from("out.jpg"),
+            original_size: 1000,
+            compressed_size: 0,
+            status: CompressionStatus::Error,
+            error_kind: None,
+            message: String::new(),
+        };
 
-        // Test with no min_savings - should always succeed
-        let temp_dir5 = tempdir().unwrap().path().to_path_buf();
-        let mut options5 = setup_options();
-        options5.base_path = absolute(PathBuf::from("samples")).unwrap();
-        options5.output_folder = Some(temp_dir5.clone());
-        options5.quality = Some(95);
-        options5.min_savings = None;
+        // Barely shrinks: 1% saved, below a 5% threshold
+        let mut result = new_result();
+        let threshold = Some(MinSavingsThreshold::Percentage(5.0));
+        assert!(skip_due_to_insufficient_savings(threshold, 1000, 990, &mut result));
+        assert!(matches!(result.status, CompressionStatus::Skipped));
+        assert_eq!(result.compressed_size, 1000);
+        assert!(result.message.contains("1.00% < 5.00%"));
 
-        let results5 = start_compression(&input_files, &options5, &multi_progress, &progress_bar, false);
-        assert!(results5.iter().all(|r| matches!(r.status, CompressionStatus::Success)));
+        // Grows: reported with a negative percentage
+        let mut result = new_result();
+        assert!(skip_due_to_insufficient_savings(threshold, 1000, 1100, &mut result));
+        assert!(result.message.contains("-10.00% < 5.00%"));
+
+        // Shrinks a lot: 60% saved, above the threshold
+        let mut result = new_result();
+        assert!(!skip_due_to_insufficient_savings(threshold, 1000, 400, &mut result));
+        assert!(matches!(result.status, CompressionStatus::Error));
+
+        // Exactly on the threshold is enough
+        let mut result = new_result();
+        assert!(!skip_due_to_insufficient_savings(threshold, 1000, 950, &mut result));
+
+        // Byte thresholds
+        let threshold = Some(MinSavingsThreshold::Bytes(100));
+        let mut result = new_result();
+        assert!(skip_due_to_insufficient_savings(threshold, 1000, 950, &mut result));
+        assert!(matches!(result.status, CompressionStatus::Skipped));
+        let mut result = new_result();
+        assert!(!skip_due_to_insufficient_savings(threshold, 1000, 900, &mut result));
+
+        // No threshold never skips
+        let mut result = new_result();
+        assert!(!skip_due_to_insufficient_savings(None, 1000, 1000, &mut result));
     }
 
     fn setup_options() -> CompressionOptions {
         CompressionOptions {
             quality: Some(80),
+            quality_rules: vec![],
+            format_qualities: vec![],
             lossless: false,
+            optimize: false,
+            webp_near_lossless: None,
+            webp_effort: None,
             output_folder: None,
+            output_zip: None,
             same_folder_as_input: false,
             overwrite_policy: OverwritePolicy::All,
+            overwrite_prompt: None,
+            collision_policy: CollisionPolicy::Rename,
+            trash_original: false,
+            dedupe: false,
+            newer: false,
+            verify: false,
+            retries: 0,
             format: OutputFormat::Original,
+            extension_case: ExtensionCase::Lower,
+            format_conversions: vec![],
             suffix: None,
             keep_structure: false,
             width: None,
             height: None,
             long_edge: None,
             short_edge: None,
+            fit: None,
+            fit_mode: None,
+            pad_color: [0, 0, 0, 255],
+            scale: None,
+            dpi: None,
+            resample_dpi: false,
+            comment: None,
+            software_tag: false,
             max_size: None,
+            target_ssim: None,
+            bpp_target: None,
+            total_budget: None,
+            max_iterations: 8,
+            max_size_tolerance: None,
+            max_size_iterations: None,
             keep_dates: false,
             exif: true,
             png_opt_level: 0,
+            avif_speed: 10,
+            tiff_compression: TiffCompression::Deflate,
+            raw_preview: false,
             jpeg_chroma_subsampling: ChromaSubsampling::Auto,
             jpeg_baseline: false,
             zopfli: false,
             base_path: PathBuf::new(),
             no_upscale: false,
+            resize_filter: ResizeFilter::Lanczos3,
+            rotate: None,
+            flip: None,
+            crop: None,
+            gravity: Gravity::Center,
             strip_icc: false,
+            strip_tags: vec![],
             min_savings: None,
+            timeout: None,
+            deadline: None,
+            fail_fast: false,
+            memory_budget: None,
+            rename_map: None,
+            variants: vec![],
         }
     }
 }
@@ -0,0 +1,1145 @@
+use caesiumclt::options::{
+    file_mode_validator, max_size_validator, min_savings_validator, quality_rule_validator, CollisionPolicy,
+    CommandLineArgs, ExtensionCase, Fit, FitMode, JpegChromaSubsampling, OutputFormat, OverwritePolicy, TargetMetric,
+    Threads, TiffCompression,
+};
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, ValueEnum};
+use serde::Deserialize;
+use std::ffi::OsString;
+use std::fs;
+use std::path::PathBuf;
+
+/// Config file looked up in the current directory when `--config` is not given
+pub const DEFAULT_CONFIG_FILE: &str = "caesium-clt.toml";
+
+/// Defaults read from a TOML config file.
+/// Every value is optional and is only used when the matching flag is not set on the command line.
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ConfigFile {
+    pub quality: Option<u32>,
+    /// `GLOB=QUALITY` rules, as given to `--quality-for`
+    pub quality_for: Option<Vec<String>>,
+    pub jpeg_quality: Option<u32>,
+    pub png_quality: Option<u32>,
+    pub webp_quality: Option<u32>,
+    pub lossless: Option<bool>,
+    pub optimize: Option<bool>,
+    pub max_size: Option<String>,
+    pub target_ssim: Option<f64>,
+    pub bpp_target: Option<f64>,
+    pub total_budget: Option<String>,
+    pub max_iterations: Option<u32>,
+    pub max_size_tolerance: Option<f64>,
+    pub max_size_iterations: Option<u32>,
+    pub output: Option<PathBuf>,
+    pub same_folder_as_input: Option<bool>,
+    pub format: Option<String>,
+    pub png_opt_level: Option<u8>,
+    pub avif_speed: Option<u8>,
+    pub tiff_compression: Option<String>,
+    pub jpeg_chroma_subsampling: Option<String>,
+    pub jpeg_baseline: Option<bool>,
+    pub zopfli: Option<bool>,
+    pub webp_near_lossless: Option<u8>,
+    pub webp_effort: Option<u8>,
+    pub raw_preview: Option<bool>,
+    pub exif: Option<bool>,
+    pub keep_dates: Option<bool>,
+    pub strip_icc: Option<bool>,
+    pub comment: Option<String>,
+    pub software_tag: Option<bool>,
+    pub suffix: Option<String>,
+    pub recursive: Option<bool>,
+    pub max_depth: Option<usize>,
+    pub follow_symlinks: Option<bool>,
+    pub use_ignore: Option<bool>,
+    pub keep_structure: Option<bool>,
+    pub threads: Option<u32>,
+    pub retries: Option<u32>,
+    pub max_memory: Option<String>,
+    pub check_extension_only: Option<bool>,
+    pub overwrite: Option<String>,
+    pub on_collision: Option<String>,
+    pub output_extension_case: Option<String>,
+    pub trash_original: Option<bool>,
+    pub chmod_files: Option<String>,
+    pub chmod_dirs: Option<String>,
+    pub dedupe: Option<bool>,
+    pub newer: Option<bool>,
+    pub verify: Option<bool>,
+    pub min_savings: Option<String>,
+}
+
+/// Returns the path passed with `--config`, if any, without running the full parser
+pub fn find_config_arg(args: &[String]) -> Option<PathBuf> {
+    let mut iter = args.iter().skip(1);
+    while let Some(arg) = iter.next() {
+        if arg == "--" {
+            break;
+        }
+        if arg == "--config" {
+            return iter.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(PathBuf::from(path));
+        }
+    }
+
+    None
+}
+
+/// Replaces each `@path` argument with the arguments read from that file, separated by whitespace or new lines.
+/// Quotes group an argument containing spaces, e.g. `"my photos/a.jpg"`, and are removed. Arguments after `--` and
+/// `@path` that is not an existing file (e.g. an image named `@2x.png`) are kept as they are.
+pub fn expand_arg_files(args: Vec<String>) -> Result<Vec<String>, String> {
+    let mut expanded = Vec::with_capacity(args.len());
+    let mut iter = args.into_iter();
+    expanded.extend(iter.next());
+    while let Some(arg) = iter.next() {
+        if arg == "--" {
+            expanded.push(arg);
+            expanded.extend(iter.by_ref());
+            break;
+        }
+        match arg.strip_prefix('@').map(PathBuf::from) {
+            Some(path) if path.is_file() => {
+                let content = fs::read_to_string(&path)
+                    .map_err(|e| format!("Unable to read arguments from {}: {e}", path.display()))?;
+                expanded.extend(split_arg_file(&content).map_err(|e| format!("{}: {e}", path.display()))?);
+            }
+            _ => expanded.push(arg),
+        }
+    }
+
+    Ok(expanded)
+}
+
+fn split_arg_file(content: &str) -> Result<Vec<String>, String> {
+    let mut args = vec![];
+    let mut current: Option<String> = None;
+    let mut quote: Option<char> = None;
+    for c in content.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => current.get_or_insert_with(String::new).push(c),
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                current.get_or_insert_with(String::new);
+            }
+            (None, c) if c.is_whitespace() => args.extend(current.take()),
+            (None, c) => current.get_or_insert_with(String::new).push(c),
+        }
+    }
+
+    if quote.is_some() {
+        return Err("unterminated quote".to_string());
+    }
+    args.extend(current);
+    Ok(args)
+}
+
+/// Loads the config file given with `--config` or, when not set, the default one if it exists
+pub fn load_config(explicit_path: Option<PathBuf>) -> Result<Option<ConfigFile>, String> {
+    let path = match explicit_path {
+        Some(p) => p,
+        None => {
+            let default_path = PathBuf::from(DEFAULT_CONFIG_FILE);
+            if !default_path.is_file() {
+                return Ok(None);
+            }
+            default_path
+        }
+    };
+
+    let content =
+        fs::read_to_string(&path).map_err(|e| format!("Unable to read config file '{}': {e}", path.display()))?;
+    let config = parse_config(&content).map_err(|e| format!("Invalid config file '{}': {e}", path.display()))?;
+
+    Ok(Some(config))
+}
+
+fn parse_config(content: &str) -> Result<ConfigFile, String> {
+    let config: ConfigFile = toml::from_str(content).map_err(|e| e.to_string())?;
+
+    if let Some(quality) = config.quality {
+        if quality > 100 {
+            return Err(format!("quality must be between 0 and 100, but got {quality}"));
+        }
+    }
+    for (name, quality) in [
+        ("jpeg-quality", config.jpeg_quality),
+        ("png-quality", config.png_quality),
+        ("webp-quality", config.webp_quality),
+    ] {
+        if let Some(quality) = quality.filter(|quality| *quality > 100) {
+            return Err(format!("{name} must be between 0 and 100, but got {quality}"));
+        }
+    }
+    if let Some(target) = config.target_ssim {
+        if !(0.0..=1.0).contains(&target) {
+            return Err(format!("target-ssim must be between 0 and 1, but got {target}"));
+        }
+    }
+    if let Some(target) = config.bpp_target {
+        if !(0.01..=24.0).contains(&target) {
+            return Err(format!("bpp-target must be between 0.01 and 24, but got {target}"));
+        }
+    }
+    if let Some(iterations) = config.max_iterations {
+        if !(1..=20).contains(&iterations) {
+            return Err(format!("max-iterations must be between 1 and 20, but got {iterations}"));
+        }
+    }
+    if let Some(tolerance) = config.max_size_tolerance {
+        if !(0.0..=100.0).contains(&tolerance) {
+            return Err(format!(
+                "max-size-tolerance must be between 0 and 100, but got {tolerance}"
+            ));
+        }
+    }
+    if let Some(iterations) = config.max_size_iterations {
+        if !(1..=20).contains(&iterations) {
+            return Err(format!(
+                "max-size-iterations must be between 1 and 20, but got {iterations}"
+            ));
+        }
+    }
+    if let Some(level) = config.png_opt_level {
+        if level > 6 {
+            return Err(format!("png-opt-level must be between 0 and 6, but got {level}"));
+        }
+    }
+    if let Some(level) = config.webp_near_lossless {
+        if level > 100 {
+            return Err(format!("webp-near-lossless must be between 0 and 100, but got {level}"));
+        }
+    }
+    if let Some(effort) = config.webp_effort {
+        if effort > 6 {
+            return Err(format!("webp-effort must be between 0 and 6, but got {effort}"));
+        }
+    }
+    if let Some(speed) = config.avif_speed {
+        if !(1..=10).contains(&speed) {
+            return Err(format!("avif-speed must be between 1 and 10, but got {speed}"));
+        }
+    }
+
+    Ok(config)
+}
+
+/// Parses the command line and fills every flag not explicitly set with the config file value.
+/// Precedence is: command line flag > config file > built-in default.
+pub fn parse_args<I, T>(itr: I, config: Option<&ConfigFile>) -> Result<CommandLineArgs, clap::Error>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+{
+    // Required groups and arguments may be satisfied by the config file, and no destination is needed when reading
+    // from stdin, so they are checked after merging
+    let mut command = CommandLineArgs::command()
+        .mut_group("Compression", |g| g.required(false))
+        .mut_group("OutputDestination", |g| g.required(false))
+        .mut_arg("sync", |a| a.requires(None));
+    let matches = command.try_get_matches_from_mut(itr)?;
+    let mut args = CommandLineArgs::from_arg_matches(&matches)?;
+
+    if let Some(config) = config {
+        merge_config(&mut args, &matches, config).map_err(|e| command.error(ErrorKind::InvalidValue, e))?;
+    }
+
+    // --target-value runs the search of its metric, so it is checked as that search from here on
+    if let Some(value) = args.compression.target_value.take() {
+        match args.target_metric {
+            Some(TargetMetric::Ssim) | None => {
+                if !(0.0..=1.0).contains(&value) {
+                    return Err(command.error(
+                        ErrorKind::InvalidValue,
+                        format!("--target-value must be between 0 and 1 with --target-metric ssim, but got {value}"),
+                    ));
+                }
+                args.compression.target_ssim = Some(value);
+            }
+        }
+    }
+
+    // Nothing is compressed or written when only counting the files
+    if !args.count_only
+        && args.compression.quality.is_none()
+        && !args.compression.lossless
+        && !args.compression.optimize
+        && args.compression.max_size.is_none()
+        && args.compression.target_ssim.is_none()
+        && args.compression.bpp_target.is_none()
+        && args.compression.total_budget.is_none()
+    {
+        return Err(command.error(
+            ErrorKind::MissingRequiredArgument,
+            "one of --quality, --lossless, --optimize, --max-size, --target-ssim, --bpp-target or --total-budget must be set on the command line or in the config file",
+        ));
+    }
+    if !args.is_stdin_input()
+        && !args.count_only
+        && args.output_destination.output.is_none()
+        && !args.output_destination.same_folder_as_input
+        && args.output_destination.output_zip.is_none()
+    {
+        return Err(command.error(
+            ErrorKind::MissingRequiredArgument,
+            "one of --output, --same-folder-as-input or --output-zip must be set on the command line or in the config file",
+        ));
+    }
+    // The config file is merged past the conflicts of clap, so they are checked again
+    let compression = &args.compression;
+    let searches_quality = compression.max_size.is_some()
+        || compression.target_ssim.is_some()
+        || compression.bpp_target.is_some()
+        || compression.total_budget.is_some();
+    let modes = [
+        ("--quality", compression.quality.is_some()),
+        ("--lossless", compression.lossless),
+        ("--optimize", compression.optimize),
+        ("--max-size", compression.max_size.is_some()),
+        ("--target-ssim", compression.target_ssim.is_some()),
+        ("--bpp-target", compression.bpp_target.is_some()),
+        ("--total-budget", compression.total_budget.is_some()),
+    ];
+    let set_modes: Vec<&str> = modes.iter().filter(|(_, set)| *set).map(|(flag, _)| *flag).collect();
+    if let [first, second, ..] = set_modes[..] {
+        return Err(command.error(
+            ErrorKind::ArgumentConflict,
+            format!("{first} can't be used with {second}, check the config file"),
+        ));
+    }
+    if (!args.format_quality.qualities().is_empty() || !args.quality_for.is_empty())
+        && (compression.lossless || compression.optimize || searches_quality)
+    {
+        return Err(command.error(
+            ErrorKind::ArgumentConflict,
+            "--jpeg-quality, --png-quality, --webp-quality and --quality-for can only be used with --quality, check the config file",
+        ));
+    }
+    if args.webp_near_lossless.is_some() && (compression.lossless || compression.optimize || searches_quality) {
+        return Err(command.error(
+            ErrorKind::ArgumentConflict,
+            "--webp-near-lossless can't be used with --lossless, --optimize, --max-size, --target-ssim, --bpp-target or --total-budget, check the config file",
+        ));
+    }
+    if args.webp_effort.is_some() && searches_quality {
+        return Err(command.error(
+            ErrorKind::ArgumentConflict,
+            "--webp-effort can't be used with --max-size, --target-ssim, --bpp-target or --total-budget, check the config file",
+        ));
+    }
+    if (args.max_size_tolerance.is_some() || args.max_size_iterations.is_some()) && compression.max_size.is_none() {
+        return Err(command.error(
+            ErrorKind::MissingRequiredArgument,
+            "--max-size-tolerance and --max-size-iterations can only be used with --max-size",
+        ));
+    }
+    if args.compression.lossless
+        && (args.format == OutputFormat::Jpeg || args.convert.iter().any(|(_, to)| *to == OutputFormat::Jpeg))
+    {
+        return Err(command.error(
+            ErrorKind::ArgumentConflict,
+            "--lossless can't be used to convert to JPEG, as JPEG has no lossless mode",
+        ));
+    }
+    if args.compression.optimize && (args.format != OutputFormat::Original || !args.convert.is_empty()) {
+        return Err(command.error(
+            ErrorKind::ArgumentConflict,
+            "--optimize keeps the format of each file, it can't be used with --format or --convert, check the config file",
+        ));
+    }
+    if !args.convert.is_empty() && args.format != OutputFormat::Original {
+        return Err(command.error(
+            ErrorKind::ArgumentConflict,
+            "--convert can't be used with --format, check the config file",
+        ));
+    }
+    if args.flatten && args.keep_structure {
+        return Err(command.error(
+            ErrorKind::ArgumentConflict,
+            "--flatten can't be used with --keep-structure, check the config file",
+        ));
+    }
+    if args.srcset.is_some() && args.resize.sizes.is_empty() && args.resize.widths.is_empty() {
+        return Err(command.error(
+            ErrorKind::MissingRequiredArgument,
+            "--srcset can only be used with --sizes or --widths",
+        ));
+    }
+    if args.compression.total_budget.is_some() && !(args.resize.sizes.is_empty() && args.resize.widths.is_empty()) {
+        return Err(command.error(
+            ErrorKind::ArgumentConflict,
+            "--total-budget can't be used with --sizes or --widths",
+        ));
+    }
+    let has_box = args.resize.width.is_some() || args.resize.height.is_some();
+    match args.resize.fit {
+        Some(Fit::Within(..)) if has_box => {
+            return Err(command.error(
+                ErrorKind::ArgumentConflict,
+                "--fit WIDTHxHEIGHT can't be used with --width or --height",
+            ));
+        }
+        Some(Fit::Mode(_)) if args.resize.width.is_none() || args.resize.height.is_none() => {
+            return Err(command.error(
+                ErrorKind::MissingRequiredArgument,
+                "--fit cover, contain and pad can only be used with both --width and --height",
+            ));
+        }
+        _ => {}
+    }
+    if args.resize.pad_color.is_some() && args.resize.fit != Some(Fit::Mode(FitMode::Pad)) {
+        return Err(command.error(
+            ErrorKind::MissingRequiredArgument,
+            "--pad-color can only be used with --fit pad",
+        ));
+    }
+    if args.gravity.is_some()
+        && args.crop.is_none()
+        && !matches!(args.resize.fit, Some(Fit::Mode(FitMode::Cover | FitMode::Pad)))
+    {
+        return Err(command.error(
+            ErrorKind::MissingRequiredArgument,
+            "--gravity can only be used with --crop, --fit cover or --fit pad",
+        ));
+    }
+    if args.sync && args.output_destination.output.is_none() {
+        return Err(command.error(
+            ErrorKind::MissingRequiredArgument,
+            "--sync can only be used with --output",
+        ));
+    }
+
+    Ok(args)
+}
+
+fn is_explicit(matches: &ArgMatches, id: &str) -> bool {
+    matches.value_source(id) == Some(ValueSource::CommandLine)
+}
+
+fn parse_value_enum<T: ValueEnum>(val: &str, field_name: &str) -> Result<T, String> {
+    T::from_str(val, true).map_err(|_| format!("'{val}' is not a valid value for {field_name}"))
+}
+
+fn merge_config(args: &mut CommandLineArgs, matches: &ArgMatches, config: &ConfigFile) -> Result<(), String> {
+    // Compression and destination options are mutually exclusive, so any flag given on the command line
+    // replaces the whole group from the config file
+    if ![
+        "quality",
+        "lossless",
+        "optimize",
+        "max_size",
+        "target_ssim",
+        "target_value",
+        "bpp_target",
+        "total_budget",
+    ]
+    .iter()
+    .any(|id| is_explicit(matches, id))
+    {
+        if let Some(quality) = config.quality {
+            args.compression.quality = Some(vec![quality]);
+        }
+        if let Some(lossless) = config.lossless {
+            args.compression.lossless = lossless;
+        }
+        if let Some(optimize) = config.optimize {
+            args.compression.optimize = optimize;
+        }
+        if let Some(max_size) = &config.max_size {
+            args.compression.max_size = Some(max_size_validator(max_size)?);
+        }
+        if let Some(target) = config.target_ssim {
+            args.compression.target_ssim = Some(target);
+        }
+        if let Some(target) = config.bpp_target {
+            args.compression.bpp_target = Some(target);
+        }
+        if let Some(total_budget) = &config.total_budget {
+            args.compression.total_budget = Some(max_size_validator(total_budget)?);
+        }
+    }
+    // Near-lossless is kept with --quality, but not with the flags it conflicts with
+    if ![
+        "webp_near_lossless",
+        "lossless",
+        "optimize",
+        "max_size",
+        "target_ssim",
+        "target_value",
+        "bpp_target",
+        "total_budget",
+    ]
+    .iter()
+    .any(|id| is_explicit(matches, id))
+    {
+        if let Some(level) = config.webp_near_lossless {
+            args.webp_near_lossless = Some(level);
+        }
+    }
+    if ![
+        "webp_effort",
+        "max_size",
+        "target_ssim",
+        "target_value",
+        "bpp_target",
+        "total_budget",
+    ]
+    .iter()
+    .any(|id| is_explicit(matches, id))
+    {
+        if let Some(effort) = config.webp_effort {
+            args.webp_effort = Some(effort);
+        }
+    }
+
+    let format_quality = &mut args.format_quality;
+    for (id, value, target) in [
+        ("jpeg_quality", config.jpeg_quality, &mut format_quality.jpeg_quality),
+        ("png_quality", config.png_quality, &mut format_quality.png_quality),
+        ("webp_quality", config.webp_quality, &mut format_quality.webp_quality),
+    ] {
+        if let (false, Some(quality)) = (is_explicit(matches, id), value) {
+            *target = Some(quality);
+        }
+    }
+
+    // Rules given on the command line replace all the rules of the config file
+    if let (false, Some(rules)) = (is_explicit(matches, "quality_for"), &config.quality_for) {
+        args.quality_for = rules
+            .iter()
+            .map(|rule| quality_rule_validator(rule).map_err(|e| format!("quality-for: {e}")))
+            .collect::<Result<_, _>>()?;
+    }
+
+    if !["output", "same_folder_as_input", "output_zip"]
+        .iter()
+        .any(|id| is_explicit(matches, id))
+    {
+        if let Some(output) = &config.output {
+            args.output_destination.output = Some(output.clone());
+        }
+        if let Some(same_folder_as_input) = config.same_folder_as_input {
+            args.output_destination.same_folder_as_input = same_folder_as_input;
+        }
+    }
+
+    if let (false, Some(format)) = (is_explicit(matches, "format"), &config.format) {
+        args.format = parse_value_enum::<OutputFormat>(format, "format")?;
+    }
+    if let (false, Some(level)) = (is_explicit(matches, "png_opt_level"), config.png_opt_level) {
+        args.png_opt_level = level;
+    }
+    if let (false, Some(speed)) = (is_explicit(matches, "avif_speed"), config.avif_speed) {
+        args.avif_speed = speed;
+    }
+    if let (false, Some(compression)) = (is_explicit(matches, "tiff_compression"), &config.tiff_compression) {
+        args.tiff_compression = parse_value_enum::<TiffCompression>(compression, "tiff-compression")?;
+    }
+    if let (false, Some(subsampling)) = (
+        is_explicit(matches, "jpeg_chroma_subsampling"),
+        &config.jpeg_chroma_subsampling,
+    ) {
+        args.jpeg_chroma_subsampling =
+            parse_value_enum::<JpegChromaSubsampling>(subsampling, "jpeg-chroma-subsampling")?;
+    }
+    if let (false, Some(comment)) = (is_explicit(matches, "comment"), &config.comment) {
+        args.comment = Some(comment.clone());
+    }
+    if let (false, Some(suffix)) = (is_explicit(matches, "suffix"), &config.suffix) {
+        args.suffix = Some(suffix.clone());
+    }
+    if let (false, Some(threads)) = (is_explicit(matches, "threads"), config.threads) {
+        args.threads = Threads::Count(threads);
+    }
+    if let (false, Some(iterations)) = (is_explicit(matches, "max_iterations"), config.max_iterations) {
+        args.max_iterations = iterations;
+    }
+    if let (false, Some(tolerance)) = (is_explicit(matches, "max_size_tolerance"), config.max_size_tolerance) {
+        args.max_size_tolerance = Some(tolerance);
+    }
+    if let (false, Some(iterations)) = (is_explicit(matches, "max_size_iterations"), config.max_size_iterations) {
+        args.max_size_iterations = Some(iterations);
+    }
+    if let (false, Some(max_memory)) = (is_explicit(matches, "max_memory"), &config.max_memory) {
+        args.max_memory = Some(max_size_validator(max_memory)?);
+    }
+    if let (false, Some(max_depth)) = (is_explicit(matches, "max_depth"), config.max_depth) {
+        args.max_depth = Some(max_depth);
+    }
+    if let (false, Some(retries)) = (is_explicit(matches, "retries"), config.retries) {
+        args.retries = retries;
+    }
+    if let (false, Some(overwrite)) = (is_explicit(matches, "overwrite"), &config.overwrite) {
+        args.overwrite = parse_value_enum::<OverwritePolicy>(overwrite, "overwrite")?;
+    }
+    if let (false, Some(on_collision)) = (is_explicit(matches, "on_collision"), &config.on_collision) {
+        args.on_collision = parse_value_enum::<CollisionPolicy>(on_collision, "on-collision")?;
+    }
+    if let (false, Some(case)) = (
+        is_explicit(matches, "output_extension_case"),
+        &config.output_extension_case,
+    ) {
+        args.output_extension_case = parse_value_enum::<ExtensionCase>(case, "output-extension-case")?;
+    }
+    if let (false, Some(min_savings)) = (is_explicit(matches, "min_savings"), &config.min_savings) {
+        args.min_savings = Some(min_savings_validator(min_savings)?);
+    }
+    if let (false, Some(mode)) = (is_explicit(matches, "chmod_files"), &config.chmod_files) {
+        args.chmod_files = Some(file_mode_validator(mode)?);
+    }
+    if let (false, Some(mode)) = (is_explicit(matches, "chmod_dirs"), &config.chmod_dirs) {
+        args.chmod_dirs = Some(file_mode_validator(mode)?);
+    }
+
+    let flags = [
+        ("jpeg_baseline", config.jpeg_baseline, &mut args.jpeg_baseline),
+        ("zopfli", config.zopfli, &mut args.zopfli),
+        ("raw_preview", config.raw_preview, &mut args.raw_preview),
+        ("exif", config.exif, &mut args.exif),
+        ("keep_dates", config.keep_dates, &mut args.keep_dates),
+        ("strip_icc", config.strip_icc, &mut args.strip_icc),
+        ("software_tag", config.software_tag, &mut args.software_tag),
+        ("recursive", config.recursive, &mut args.recursive),
+        ("follow_symlinks", config.follow_symlinks, &mut args.follow_symlinks),
+        ("use_ignore", config.use_ignore, &mut args.use_ignore),
+        ("keep_structure", config.keep_structure, &mut args.keep_structure),
+        ("trash_original", config.trash_original, &mut args.trash_original),
+        ("dedupe", config.dedupe, &mut args.dedupe),
+        ("newer", config.newer, &mut args.newer),
+        ("verify", config.verify, &mut args.verify),
+        (
+            "check_extension_only",
+            config.check_extension_only,
+            &mut args.check_extension_only,
+        ),
+    ];
+    for (id, config_value, arg_value) in flags {
+        if let (false, Some(value)) = (is_explicit(matches, id), config_value) {
+            *arg_value = value;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use caesiumclt::options::MinSavingsThreshold;
+
+    fn to_args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_find_config_arg() {
+        let args = to_args(&["caesiumclt", "-q", "80", "--config", "custom.toml", "file.jpg"]);
+        assert_eq!(find_config_arg(&args), Some(PathBuf::from("custom.toml")));
+
+        let args = to_args(&["caesiumclt", "--config=other.toml", "file.jpg"]);
+        assert_eq!(find_config_arg(&args), Some(PathBuf::from("other.toml")));
+
+        let args = to_args(&["caesiumclt", "-q", "80", "file.jpg"]);
+        assert_eq!(find_config_arg(&args), None);
+
+        let args = to_args(&["caesiumclt", "-q", "80", "--", "--config"]);
+        assert_eq!(find_config_arg(&args), None);
+    }
+
+    #[test]
+    fn test_parse_config() {
+        let config = parse_config(
+            r#"
+            quality = 70
+            format = "webp"
+            output = "/config/output"
+            max-size = "100KB"
+            exif = true
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.quality, Some(70));
+        assert_eq!(config.format, Some("webp".to_string()));
+        assert_eq!(config.output, Some(PathBuf::from("/config/output")));
+        assert_eq!(config.max_size, Some("100KB".to_string()));
+        assert_eq!(config.exif, Some(true));
+
+        assert!(parse_config("quality = 101").is_err());
+        assert!(parse_config("png-opt-level = 7").is_err());
+        assert!(parse_config("avif-speed = 0").is_err());
+        assert!(parse_config("webp-near-lossless = 101").is_err());
+        assert!(parse_config("webp-effort = 7").is_err());
+        assert!(parse_config("png-quality = 101").is_err());
+        assert!(parse_config("target-ssim = 1.5").is_err());
+        assert!(parse_config("bpp-target = 0").is_err());
+        assert!(parse_config("max-iterations = 0").is_err());
+        assert!(parse_config("max-size-tolerance = 101.0").is_err());
+        assert!(parse_config("max-size-iterations = 21").is_err());
+        assert!(parse_config("unknown-option = 1").is_err());
+        assert!(parse_config("quality = \"high\"").is_err());
+    }
+
+    #[test]
+    fn test_expand_arg_files() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let arg_file = temp_dir.path().join("fixture-args.txt");
+        fs::write(
+            &arg_file,
+            "-q 60 --keep-structure\n-o \"my output\"\n'photos/with space.jpg'\n\nphotos/b.png\n",
+        )
+        .unwrap();
+
+        let raw_args = to_args(&["caesiumclt", &format!("@{}", arg_file.display()), "c.webp"]);
+        let args = parse_args(expand_arg_files(raw_args).unwrap(), None).unwrap();
+        assert_eq!(args.compression.quality, Some(vec![60]));
+        assert!(args.keep_structure);
+        assert_eq!(args.output_destination.output, Some(PathBuf::from("my output")));
+        assert_eq!(args.files, vec!["photos/with space.jpg", "photos/b.png", "c.webp"]);
+
+        // Not an arguments file
+        let raw_args = to_args(&["caesiumclt", "@2x.png", "--", &format!("@{}", arg_file.display())]);
+        assert_eq!(expand_arg_files(raw_args.clone()).unwrap(), raw_args);
+
+        fs::write(&arg_file, "-o \"unterminated").unwrap();
+        let raw_args = to_args(&["caesiumclt", &format!("@{}", arg_file.display())]);
+        assert!(expand_arg_files(raw_args).is_err());
+    }
+
+    #[test]
+    fn test_load_config() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let config_path = temp_dir.path().join("config.toml");
+        fs::write(&config_path, "quality = 60\n").unwrap();
+
+        let config = load_config(Some(config_path)).unwrap().unwrap();
+        assert_eq!(config.quality, Some(60));
+
+        assert!(load_config(Some(temp_dir.path().join("missing.toml"))).is_err());
+    }
+
+    #[test]
+    fn test_parse_args_without_config() {
+        let args = parse_args(["caesiumclt", "-q", "80", "-o", "/output", "file.jpg"], None).unwrap();
+        assert_eq!(args.compression.quality, Some(vec![80]));
+        assert_eq!(args.output_destination.output, Some(PathBuf::from("/output")));
+
+        assert!(parse_args(["caesiumclt", "file.jpg"], None).is_err());
+    }
+
+    #[test]
+    fn test_parse_args_config_fills_defaults() {
+        let config = parse_config(
+            r#"
+            quality = 60
+            output = "/config/output"
+            format = "png"
+            threads = 2
+            overwrite = "never"
+            jpeg-chroma-subsampling = "4:2:0"
+            tiff-compression = "lzw"
+            webp-quality = 50
+            min-savings = "10%"
+            keep-dates = true
+            chmod-files = "0644"
+            "#,
+        )
+        .unwrap();
+
+        let args = parse_args(["caesiumclt", "file.jpg"], Some(&config)).unwrap();
+        assert_eq!(args.compression.quality, Some(vec![60]));
+        assert_eq!(args.output_destination.output, Some(PathBuf::from("/config/output")));
+        assert_eq!(args.format, OutputFormat::Png);
+        assert_eq!(args.threads, Threads::Count(2));
+        assert_eq!(args.overwrite, OverwritePolicy::Never);
+        assert_eq!(
+            args.jpeg_chroma_subsampling,
+            JpegChromaSubsampling::ChromaSubsampling420
+        );
+        assert_eq!(args.tiff_compression, TiffCompression::Lzw);
+        assert_eq!(args.format_quality.webp_quality, Some(50));
+        assert_eq!(args.min_savings, Some(MinSavingsThreshold::Percentage(10.0)));
+        assert!(args.keep_dates);
+        assert_eq!(args.chmod_files, Some(0o644));
+        assert_eq!(args.chmod_dirs, None);
+        assert!(!args.exif);
+    }
+
+    #[test]
+    fn test_parse_args_cli_overrides_config() {
+        let config = parse_config(
+            r#"
+            quality = 60
+            output = "/config/output"
+            format = "png"
+            threads = 2
+            "#,
+        )
+        .unwrap();
+
+        let args = parse_args(
+            [
+                "caesiumclt",
+                "-q",
+                "90",
+                "--format",
+                "webp",
+                "--threads",
+                "4",
+                "file.jpg",
+            ],
+            Some(&config),
+        )
+        .unwrap();
+        assert_eq!(args.compression.quality, Some(vec![90]));
+        assert_eq!(args.format, OutputFormat::Webp);
+        assert_eq!(args.threads, Threads::Count(4));
+        assert_eq!(args.output_destination.output, Some(PathBuf::from("/config/output")));
+
+        // A flag from the same exclusive group replaces the config value entirely
+        let args = parse_args(
+            ["caesiumclt", "--lossless", "--same-folder-as-input", "file.jpg"],
+            Some(&config),
+        )
+        .unwrap();
+        assert_eq!(args.compression.quality, None);
+        assert!(args.compression.lossless);
+        assert_eq!(args.output_destination.output, None);
+        assert!(args.output_destination.same_folder_as_input);
+    }
+
+    #[test]
+    fn test_parse_args_webp_near_lossless() {
+        let config = parse_config(
+            r#"
+            quality = 80
+            webp-near-lossless = 60
+            "#,
+        )
+        .unwrap();
+
+        let args = parse_args(["caesiumclt", "-o", "/output", "file.jpg"], Some(&config)).unwrap();
+        assert_eq!(args.compression.quality, Some(vec![80]));
+        assert_eq!(args.webp_near_lossless, Some(60));
+
+        let args = parse_args(
+            ["caesiumclt", "--webp-near-lossless", "20", "-o", "/output", "file.jpg"],
+            Some(&config),
+        )
+        .unwrap();
+        assert_eq!(args.compression.quality, Some(vec![80]));
+        assert_eq!(args.webp_near_lossless, Some(20));
+
+        // --lossless can't be combined with it, so it replaces the level too
+        let args = parse_args(["caesiumclt", "--lossless", "-o", "/output", "file.jpg"], Some(&config)).unwrap();
+        assert!(args.compression.lossless);
+        assert_eq!(args.webp_near_lossless, None);
+    }
+
+    #[test]
+    fn test_parse_args_fit() {
+        let parse = |args: &[&str]| {
+            parse_args(
+                [&["caesiumclt", "-q", "80"][..], args, &["-o", "out", "file.jpg"]].concat(),
+                None,
+            )
+        };
+
+        let args = parse(&[
+            "--width",
+            "800",
+            "--height",
+            "600",
+            "--fit",
+            "cover",
+            "--gravity",
+            "north",
+        ])
+        .unwrap();
+        assert_eq!(args.resize.fit, Some(Fit::Mode(FitMode::Cover)));
+        assert!(parse(&[
+            "--width",
+            "800",
+            "--height",
+            "600",
+            "--fit",
+            "pad",
+            "--pad-color",
+            "#fff0"
+        ])
+        .is_err());
+
+        let err = parse(&["--fit", "1920x1080", "--width", "100"]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ArgumentConflict);
+        let err = parse(&["--width", "800", "--fit", "pad"]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::MissingRequiredArgument);
+        let err = parse(&[
+            "--width",
+            "800",
+            "--height",
+            "600",
+            "--fit",
+            "cover",
+            "--pad-color",
+            "#ffffff",
+        ])
+        .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::MissingRequiredArgument);
+        let err = parse(&["--gravity", "north"]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::MissingRequiredArgument);
+        let err = parse(&[
+            "--width",
+            "800",
+            "--height",
+            "600",
+            "--fit",
+            "contain",
+            "--gravity",
+            "north",
+        ])
+        .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::MissingRequiredArgument);
+        assert!(parse(&["--crop", "100x100", "--gravity", "north"]).is_ok());
+    }
+
+    #[test]
+    fn test_parse_args_total_budget() {
+        let config = parse_config(
+            r#"
+            total-budget = "500MB"
+            "#,
+        )
+        .unwrap();
+
+        let args = parse_args(["caesiumclt", "-o", "out", "file.jpg"], Some(&config)).unwrap();
+        assert_eq!(args.compression.total_budget, Some(500_000_000));
+
+        // Another compression mode on the command line replaces the budget
+        let args = parse_args(["caesiumclt", "-q", "80", "-o", "out", "file.jpg"], Some(&config)).unwrap();
+        assert_eq!(args.compression.total_budget, None);
+
+        let err = parse_args(["caesiumclt", "--sizes", "480", "-o", "out", "file.jpg"], Some(&config)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ArgumentConflict);
+    }
+
+    #[test]
+    fn test_parse_args_target_metric() {
+        let config = parse_config(
+            r#"
+            quality = 80
+            "#,
+        )
+        .unwrap();
+
+        let parse = |value: &str| {
+            parse_args(
+                [
+                    "caesiumclt",
+                    "--target-metric",
+                    "ssim",
+                    "--target-value",
+                    value,
+                    "-o",
+                    "out",
+                    "file.jpg",
+                ],
+                Some(&config),
+            )
+        };
+        // The value replaces the compression mode of the config file, and runs the SSIM search
+        let args = parse("0.95").unwrap();
+        assert_eq!(args.compression.target_ssim, Some(0.95));
+        assert_eq!(args.compression.target_value, None);
+        assert_eq!(args.compression.quality, None);
+
+        let err = parse("1.5").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidValue);
+    }
+
+    #[test]
+    fn test_parse_args_max_size_search() {
+        let config = parse_config(
+            r#"
+            max-size = "100KB"
+            max-size-tolerance = 5.0
+            max-size-iterations = 4
+            "#,
+        )
+        .unwrap();
+
+        let args = parse_args(["caesiumclt", "-o", "out", "file.jpg"], Some(&config)).unwrap();
+        assert_eq!(args.compression.max_size, Some(100_000));
+        assert_eq!(args.max_size_tolerance, Some(5.0));
+        assert_eq!(args.max_size_iterations, Some(4));
+
+        let args = parse_args(
+            [
+                "caesiumclt",
+                "--max-size",
+                "1MB",
+                "--max-size-iterations",
+                "10",
+                "-o",
+                "out",
+                "file.jpg",
+            ],
+            Some(&config),
+        )
+        .unwrap();
+        assert_eq!(args.compression.max_size, Some(1_000_000));
+        assert_eq!(args.max_size_tolerance, Some(5.0));
+        assert_eq!(args.max_size_iterations, Some(10));
+    }
+
+    #[test]
+    fn test_parse_args_quality_for() {
+        let config = parse_config(
+            r#"
+            quality = 80
+            output = "/config/output"
+            quality-for = ["hero/=95", "*.png=60"]
+            "#,
+        )
+        .unwrap();
+        let globs = |args: &CommandLineArgs| -> Vec<(String, u32)> {
+            args.quality_for
+                .iter()
+                .map(|rule| (rule.glob.clone(), rule.quality))
+                .collect()
+        };
+
+        let args = parse_args(["caesiumclt", "file.jpg"], Some(&config)).unwrap();
+        assert_eq!(globs(&args), [("hero/".to_string(), 95), ("*.png".to_string(), 60)]);
+
+        let args = parse_args(["caesiumclt", "--quality-for", "thumbs/=40", "file.jpg"], Some(&config)).unwrap();
+        assert_eq!(globs(&args), [("thumbs/".to_string(), 40)]);
+
+        let config = parse_config(r#"quality-for = ["hero/"]"#).unwrap();
+        assert!(parse_args(["caesiumclt", "-q", "80", "-o", "/output", "file.jpg"], Some(&config)).is_err());
+    }
+
+    #[test]
+    fn test_parse_args_stdin() {
+        let args = parse_args(["caesiumclt", "-q", "80", "--format", "png", "-"], None).unwrap();
+        assert!(args.is_stdin_input());
+        assert_eq!(args.output_destination.output, None);
+
+        assert!(parse_args(["caesiumclt", "-q", "80", "--format", "png", "file.jpg"], None).is_err());
+        assert!(parse_args(["caesiumclt", "--format", "png", "-"], None).is_err());
+    }
+
+    #[test]
+    fn test_parse_args_missing_required_with_config() {
+        let config = parse_config("format = \"png\"").unwrap();
+        assert!(parse_args(["caesiumclt", "-o", "/output", "file.jpg"], Some(&config)).is_err());
+        assert!(parse_args(["caesiumclt", "-q", "80", "file.jpg"], Some(&config)).is_err());
+        assert!(parse_args(["caesiumclt", "-q", "80", "-o", "/output", "file.jpg"], Some(&config)).is_ok());
+    }
+
+    #[test]
+    fn test_parse_args_sync_requires_output() {
+        assert!(parse_args(["caesiumclt", "-q", "80", "-o", "/output", "--sync", "file.jpg"], None).is_ok());
+        assert!(parse_args(
+            ["caesiumclt", "-q", "80", "--same-folder-as-input", "--sync", "file.jpg"],
+            None
+        )
+        .is_err());
+
+        let config = parse_config("output = \"/output\"").unwrap();
+        assert!(parse_args(["caesiumclt", "-q", "80", "--sync", "file.jpg"], Some(&config)).is_ok());
+    }
+
+    #[test]
+    fn test_parse_args_flatten_with_config_keep_structure() {
+        let config = parse_config("keep-structure = true").unwrap();
+        let err = parse_args(
+            ["caesiumclt", "-q", "80", "-o", "/output", "--flatten", "file.jpg"],
+            Some(&config),
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ArgumentConflict);
+        assert!(parse_args(
+            ["caesiumclt", "-q", "80", "-o", "/output", "--flatten", "file.jpg"],
+            None
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_parse_args_lossless_jpeg() {
+        let base = ["caesiumclt", "--lossless", "-o", "/output"];
+        let parse = |extra: &[&str]| parse_args(base.iter().chain(extra).chain(&["file.png"]), None);
+
+        let err = parse(&["--format", "jpeg"]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ArgumentConflict);
+        assert!(parse(&["--convert", "png=jpg"]).is_err());
+        assert!(parse(&["--format", "webp"]).is_ok());
+        assert!(parse(&[]).is_ok());
+
+        let config = parse_config("format = \"jpeg\"").unwrap();
+        assert!(parse_args(base.iter().chain(&["file.png"]), Some(&config)).is_err());
+    }
+
+    #[test]
+    fn test_parse_args_count_only() {
+        let args = parse_args(["caesiumclt", "--count-only", "-R", "photos"], None).unwrap();
+        assert!(args.count_only);
+        assert!(args.recursive);
+        assert!(parse_args(["caesiumclt", "--count-only", "--list-only", "photos"], None).is_err());
+    }
+
+    #[test]
+    fn test_parse_args_optimize() {
+        let config = parse_config("optimize = true\noutput = \"/output\"").unwrap();
+        let args = parse_args(["caesiumclt", "file.jpg"], Some(&config)).unwrap();
+        assert!(args.compression.optimize);
+        assert_eq!(args.compression.quality, None);
+
+        // A compression option on the command line replaces it
+        let args = parse_args(["caesiumclt", "-q", "80", "file.jpg"], Some(&config)).unwrap();
+        assert!(!args.compression.optimize);
+
+        let config = parse_config("optimize = true\noutput = \"/output\"\nformat = \"webp\"").unwrap();
+        let err = parse_args(["caesiumclt", "file.jpg"], Some(&config)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ArgumentConflict);
+    }
+
+    #[test]
+    fn test_parse_args_config_conflicts() {
+        let parse = |config: &str, extra: &[&str]| {
+            let config = parse_config(&format!("output = \"/output\"\n{config}")).unwrap();
+            parse_args(["caesiumclt"].iter().chain(extra).chain(&["file.jpg"]), Some(&config))
+        };
+        let assert_conflict = |config: &str, extra: &[&str]| {
+            let err = parse(config, extra).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::ArgumentConflict, "{config}");
+        };
+
+        // The compression modes are exclusive in the config file too
+        assert_conflict("quality = 80\nmax-size = \"100KB\"", &[]);
+        assert_conflict("lossless = true\ntarget-ssim = 0.95", &[]);
+        assert_conflict("jpeg-quality = 70", &["--lossless"]);
+        assert_conflict("quality-for = [\"hero/=95\"]\nbpp-target = 1.5", &[]);
+        assert_conflict("lossless = true\nwebp-near-lossless = 60", &[]);
+        assert_conflict("optimize = true\nwebp-near-lossless = 60", &[]);
+        assert_conflict("max-size = \"100KB\"\nwebp-effort = 4", &[]);
+        let err = parse("max-size-tolerance = 5.0", &["-q", "80"]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::MissingRequiredArgument);
+
+        assert!(parse("quality = 80\njpeg-quality = 70\nwebp-effort = 4", &[]).is_ok());
+        // A flag on the command line replaces the config values it conflicts with
+        assert!(parse("webp-near-lossless = 60", &["--optimize"]).is_ok());
+    }
+
+    #[test]
+    fn test_parse_args_invalid_config_value() {
+        let config = parse_config("quality = 80\noutput = \"/output\"\nformat = \"bmp\"").unwrap();
+        assert!(parse_args(["caesiumclt", "file.jpg"], Some(&config)).is_err());
+    }
+}
@@ -0,0 +1,412 @@
+//! Reading and writing the resolution metadata of JPEG (JFIF), PNG (pHYs) and TIFF files.
+
+use std::error::Error;
+
+const INCH_IN_METERS: f64 = 0.0254;
+pub(crate) const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+const TIFF_X_RESOLUTION: u16 = 282;
+const TIFF_Y_RESOLUTION: u16 = 283;
+const TIFF_RESOLUTION_UNIT: u16 = 296;
+const TIFF_SHORT: u16 = 3;
+const TIFF_RATIONAL: u16 = 5;
+const TIFF_INCH: u16 = 2;
+const TIFF_CENTIMETER: u16 = 3;
+
+/// Horizontal and vertical resolution, in dots per inch
+pub type Dpi = (u32, u32);
+
+/// Whether `set_dpi` can write the resolution of this format
+pub fn supports_dpi(mime_type: Option<&str>) -> bool {
+    matches!(mime_type, Some("image/jpeg" | "image/png" | "image/tiff"))
+}
+
+/// Resolution stored in the image, if any and if it is a physical one
+pub fn read_dpi(buffer: &[u8]) -> Option<Dpi> {
+    match infer::get(buffer)?.mime_type() {
+        "image/jpeg" => read_jpeg_dpi(buffer),
+        "image/png" => read_png_dpi(buffer),
+        "image/tiff" => read_tiff_dpi(buffer),
+        _ => None,
+    }
+}
+
+/// Returns a copy of the image with its resolution set to `dpi`, in both directions.
+/// Pixels are left untouched.
+pub fn set_dpi(buffer: &[u8], dpi: u32) -> Result<Vec<u8>, Box<dyn Error>> {
+    match infer::get(buffer).map(|t| t.mime_type()) {
+        Some("image/jpeg") => set_jpeg_dpi(buffer, u16::try_from(dpi).map_err(|_| "DPI too high for JPEG")?),
+        Some("image/png") => set_png_dpi(buffer, dpi),
+        Some("image/tiff") => set_tiff_dpi(buffer, dpi),
+        _ => Err("Unsupported format for DPI metadata".into()),
+    }
+}
+
+/// Offset of the units byte of the JFIF segment, followed by the X and Y densities
+fn find_jfif_density(buffer: &[u8]) -> Option<usize> {
+    let mut position = 2;
+    while position + 4 <= buffer.len() && buffer[position] == 0xFF {
+        let marker = buffer[position + 1];
+        // Start of scan or end of image: no more metadata segments
+        if marker == 0xDA || marker == 0xD9 {
+            return None;
+        }
+        let length = u16::from_be_bytes([buffer[position + 2], buffer[position + 3]]) as usize;
+        if marker == 0xE0
+            && length >= 16
+            && buffer.get(position + 4..position + 9) == Some(b"JFIF\0")
+            && position + 16 <= buffer.len()
+        {
+            return Some(position + 11);
+        }
+        position += 2 + length;
+    }
+
+    None
+}
+
+fn read_jpeg_dpi(buffer: &[u8]) -> Option<Dpi> {
+    let offset = find_jfif_density(buffer)?;
+    let x = u16::from_be_bytes([buffer[offset + 1], buffer[offset + 2]]) as f64;
+    let y = u16::from_be_bytes([buffer[offset + 3], buffer[offset + 4]]) as f64;
+    match buffer[offset] {
+        1 => Some((x as u32, y as u32)),
+        2 => Some(((x * 2.54).round() as u32, (y * 2.54).round() as u32)),
+        _ => None,
+    }
+}
+
+fn set_jpeg_dpi(buffer: &[u8], dpi: u16) -> Result<Vec<u8>, Box<dyn Error>> {
+    if !buffer.starts_with(&[0xFF, 0xD8]) {
+        return Err("Invalid JPEG data".into());
+    }
+
+    let [high, low] = dpi.to_be_bytes();
+    let density = [1, high, low, high, low];
+    if let Some(offset) = find_jfif_density(buffer) {
+        let mut output = buffer.to_vec();
+        output[offset..offset + density.len()].copy_from_slice(&density);
+        return Ok(output);
+    }
+
+    // The JFIF segment must directly follow the start of image marker
+    let mut output = Vec::with_capacity(buffer.len() + 18);
+    output.extend_from_slice(&buffer[..2]);
+    output.extend_from_slice(&[0xFF, 0xE0, 0x00, 0x10]);
+    output.extend_from_slice(b"JFIF\0");
+    output.extend_from_slice(&[1, 2]);
+    output.extend_from_slice(&density);
+    output.extend_from_slice(&[0, 0]);
+    output.extend_from_slice(&buffer[2..]);
+    Ok(output)
+}
+
+/// Start, end and type of every chunk
+pub(crate) fn png_chunks(buffer: &[u8]) -> Result<Vec<(usize, usize, &[u8])>, Box<dyn Error>> {
+    if !buffer.starts_with(PNG_SIGNATURE) {
+        return Err("Invalid PNG data".into());
+    }
+
+    let mut chunks = vec![];
+    let mut position = PNG_SIGNATURE.len();
+    while position + 8 <= buffer.len() {
+        let length = u32::from_be_bytes(buffer[position..position + 4].try_into()?) as usize;
+        // Length, type, data and CRC
+        let end = position + 12 + length;
+        if end > buffer.len() {
+            return Err("Truncated PNG chunk".into());
+        }
+        chunks.push((position, end, &buffer[position + 4..position + 8]));
+        position = end;
+    }
+
+    Ok(chunks)
+}
+
+fn read_png_dpi(buffer: &[u8]) -> Option<Dpi> {
+    let (start, _, _) = png_chunks(buffer)
+        .ok()?
+        .into_iter()
+        .find(|(_, _, kind)| *kind == b"pHYs")?;
+    let data = buffer.get(start + 8..start + 17)?;
+    if data[8] != 1 {
+        return None;
+    }
+
+    let to_dpi = |bytes: &[u8]| {
+        (u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64 * INCH_IN_METERS).round() as u32
+    };
+    Some((to_dpi(&data[0..4]), to_dpi(&data[4..8])))
+}
+
+fn set_png_dpi(buffer: &[u8], dpi: u32) -> Result<Vec<u8>, Box<dyn Error>> {
+    let pixels_per_meter = (dpi as f64 / INCH_IN_METERS).round() as u32;
+    let mut data = Vec::with_capacity(9);
+    data.extend_from_slice(&pixels_per_meter.to_be_bytes());
+    data.extend_from_slice(&pixels_per_meter.to_be_bytes());
+    data.push(1);
+    let physical_chunk = png_chunk(b"pHYs", &data);
+
+    let mut output = Vec::with_capacity(buffer.len() + physical_chunk.len());
+    output.extend_from_slice(PNG_SIGNATURE);
+    let mut inserted = false;
+    for (start, end, kind) in png_chunks(buffer)? {
+        if kind == b"pHYs" {
+            continue;
+        }
+        // pHYs must come before the image data
+        if kind == b"IDAT" && !inserted {
+            output.extend_from_slice(&physical_chunk);
+            inserted = true;
+        }
+        output.extend_from_slice(&buffer[start..end]);
+    }
+
+    if !inserted {
+        return Err("PNG without image data".into());
+    }
+    Ok(output)
+}
+
+pub(crate) fn png_chunk(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut chunk = Vec::with_capacity(data.len() + 12);
+    chunk.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    chunk.extend_from_slice(kind);
+    chunk.extend_from_slice(data);
+    chunk.extend_from_slice(&crc32(&chunk[4..]).to_be_bytes());
+    chunk
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Byte order of a TIFF file
+#[derive(Copy, Clone)]
+struct TiffEndian {
+    big: bool,
+}
+
+impl TiffEndian {
+    fn of(buffer: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let endian = match buffer.get(0..2) {
+            Some(b"II") => TiffEndian { big: false },
+            Some(b"MM") => TiffEndian { big: true },
+            _ => return Err("Invalid TIFF data".into()),
+        };
+        // BigTIFF uses 43 and 64 bits offsets
+        if endian.u16_at(buffer, 2)? != 42 {
+            return Err("Unsupported TIFF variant".into());
+        }
+        Ok(endian)
+    }
+
+    fn u16_at(&self, buffer: &[u8], offset: usize) -> Result<u16, Box<dyn Error>> {
+        let bytes: [u8; 2] = buffer.get(offset..offset + 2).ok_or("Truncated TIFF")?.try_into()?;
+        Ok(if self.big {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    }
+
+    fn u32_at(&self, buffer: &[u8], offset: usize) -> Result<u32, Box<dyn Error>> {
+        let bytes: [u8; 4] = buffer.get(offset..offset + 4).ok_or("Truncated TIFF")?.try_into()?;
+        Ok(if self.big {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    fn u16_bytes(&self, value: u16) -> [u8; 2] {
+        if self.big {
+            value.to_be_bytes()
+        } else {
+            value.to_le_bytes()
+        }
+    }
+
+    fn u32_bytes(&self, value: u32) -> [u8; 4] {
+        if self.big {
+            value.to_be_bytes()
+        } else {
+            value.to_le_bytes()
+        }
+    }
+}
+
+/// Offset of the first IFD and its 12 bytes entries
+fn tiff_entries(buffer: &[u8], endian: TiffEndian) -> Result<(usize, Vec<&[u8]>), Box<dyn Error>> {
+    let ifd_offset = endian.u32_at(buffer, 4)? as usize;
+    let count = endian.u16_at(buffer, ifd_offset)? as usize;
+    let entries = (0..count)
+        .map(|i| {
+            let start = ifd_offset + 2 + i * 12;
+            buffer.get(start..start + 12).ok_or("Truncated TIFF")
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok((ifd_offset, entries))
+}
+
+fn read_tiff_dpi(buffer: &[u8]) -> Option<Dpi> {
+    let endian = TiffEndian::of(buffer).ok()?;
+    let (_, entries) = tiff_entries(buffer, endian).ok()?;
+    let find = |tag: u16| entries.iter().find(|entry| endian.u16_at(entry, 0).ok() == Some(tag));
+    let rational = |tag: u16| -> Option<f64> {
+        let entry = find(tag)?;
+        if endian.u16_at(entry, 2).ok()? != TIFF_RATIONAL {
+            return None;
+        }
+        let offset = endian.u32_at(entry, 8).ok()? as usize;
+        let numerator = endian.u32_at(buffer, offset).ok()? as f64;
+        let denominator = endian.u32_at(buffer, offset + 4).ok()? as f64;
+        (denominator > 0.0).then_some(numerator / denominator)
+    };
+
+    // Inches when the unit is missing
+    let unit = find(TIFF_RESOLUTION_UNIT)
+        .and_then(|entry| endian.u16_at(entry, 8).ok())
+        .unwrap_or(TIFF_INCH);
+    let scale = match unit {
+        TIFF_INCH => 1.0,
+        TIFF_CENTIMETER => 2.54,
+        _ => return None,
+    };
+    Some((
+        (rational(TIFF_X_RESOLUTION)? * scale).round() as u32,
+        (rational(TIFF_Y_RESOLUTION)? * scale).round() as u32,
+    ))
+}
+
+/// Writes a new first IFD at the end of the file, with the resolution entries replaced.
+/// The other entries are copied as is, as the data they point to does not move.
+fn set_tiff_dpi(buffer: &[u8], dpi: u32) -> Result<Vec<u8>, Box<dyn Error>> {
+    let endian = TiffEndian::of(buffer)?;
+    let (ifd_offset, entries) = tiff_entries(buffer, endian)?;
+    let next_ifd = endian.u32_at(buffer, ifd_offset + 2 + entries.len() * 12)?;
+
+    let mut output = buffer.to_vec();
+    // Values and IFDs start on a word boundary
+    if output.len() % 2 == 1 {
+        output.push(0);
+    }
+    let rational_offset = u32::try_from(output.len())?;
+    output.extend_from_slice(&endian.u32_bytes(dpi));
+    output.extend_from_slice(&endian.u32_bytes(1));
+
+    let entry = |tag: u16, field_type: u16, value: [u8; 4]| {
+        let mut entry = Vec::with_capacity(12);
+        entry.extend_from_slice(&endian.u16_bytes(tag));
+        entry.extend_from_slice(&endian.u16_bytes(field_type));
+        entry.extend_from_slice(&endian.u32_bytes(1));
+        entry.extend_from_slice(&value);
+        entry
+    };
+    let [unit_0, unit_1] = endian.u16_bytes(TIFF_INCH);
+    let mut new_entries: Vec<Vec<u8>> = entries
+        .iter()
+        .filter(|entry| {
+            !matches!(
+                endian.u16_at(entry, 0),
+                Ok(TIFF_X_RESOLUTION | TIFF_Y_RESOLUTION | TIFF_RESOLUTION_UNIT)
+            )
+        })
+        .map(|entry| entry.to_vec())
+        .collect();
+    new_entries.push(entry(
+        TIFF_X_RESOLUTION,
+        TIFF_RATIONAL,
+        endian.u32_bytes(rational_offset),
+    ));
+    new_entries.push(entry(
+        TIFF_Y_RESOLUTION,
+        TIFF_RATIONAL,
+        endian.u32_bytes(rational_offset),
+    ));
+    new_entries.push(entry(TIFF_RESOLUTION_UNIT, TIFF_SHORT, [unit_0, unit_1, 0, 0]));
+    // Entries must be sorted by tag
+    new_entries.sort_by_key(|entry| endian.u16_at(entry, 0).unwrap_or(u16::MAX));
+
+    let new_ifd_offset = u32::try_from(output.len())?;
+    output.extend_from_slice(&endian.u16_bytes(u16::try_from(new_entries.len())?));
+    for entry in new_entries {
+        output.extend_from_slice(&entry);
+    }
+    output.extend_from_slice(&endian.u32_bytes(next_ifd));
+    output[4..8].copy_from_slice(&endian.u32_bytes(new_ifd_offset));
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageFormat, RgbImage};
+    use std::io::Cursor;
+
+    fn encode(format: ImageFormat) -> Vec<u8> {
+        let mut bytes = vec![];
+        RgbImage::from_fn(16, 8, |x, y| image::Rgb([x as u8 * 16, y as u8 * 32, 128]))
+            .write_to(&mut Cursor::new(&mut bytes), format)
+            .unwrap();
+        bytes
+    }
+
+    #[test]
+    fn test_set_and_read_dpi() {
+        for format in [ImageFormat::Jpeg, ImageFormat::Png, ImageFormat::Tiff] {
+            let original = encode(format);
+            for dpi in [72, 300, 600] {
+                let output = set_dpi(&original, dpi).unwrap();
+                assert_eq!(read_dpi(&output), Some((dpi, dpi)), "{format:?}");
+
+                // The image is still valid and unchanged
+                let decoded = image::load_from_memory(&output).unwrap();
+                assert_eq!((decoded.width(), decoded.height()), (16, 8));
+
+                // Setting it again replaces the previous value
+                let output = set_dpi(&output, 96).unwrap();
+                assert_eq!(read_dpi(&output), Some((96, 96)), "{format:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_jfif_segment_is_inserted() {
+        let jpeg = encode(ImageFormat::Jpeg);
+        let offset = find_jfif_density(&jpeg).unwrap() - 11;
+        // Remove the JFIF segment
+        let length = u16::from_be_bytes([jpeg[offset + 2], jpeg[offset + 3]]) as usize;
+        let stripped: Vec<u8> = [&jpeg[..offset], &jpeg[offset + 2 + length..]].concat();
+        assert_eq!(read_dpi(&stripped), None);
+
+        let output = set_dpi(&stripped, 72).unwrap();
+        assert_eq!(read_dpi(&output), Some((72, 72)));
+        assert_eq!(output.len(), stripped.len() + 18);
+    }
+
+    #[test]
+    fn test_unsupported_formats() {
+        assert!(set_dpi(&encode(ImageFormat::WebP), 72).is_err());
+        assert!(set_dpi(&encode(ImageFormat::Gif), 72).is_err());
+        assert!(set_dpi(&encode(ImageFormat::Jpeg), 70000).is_err());
+        assert!(!supports_dpi(Some("image/webp")));
+        assert!(supports_dpi(Some("image/tiff")));
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+    }
+}
@@ -0,0 +1,344 @@
+//! Optimization of GIF files by rewriting their palettes, which libcaesium can't do in memory.
+//!
+//! Every frame keeps its pixels: the palette entries no frame uses are dropped and duplicated colors merged, so the
+//! LZW codes can be shorter, and frames share a single global palette when their colors fit in one. Static GIFs can
+//! also be quantized to fewer colors, which is lossy.
+
+use color_quant::NeuQuant;
+use gif::{ColorOutput, DecodeOptions, Encoder, Frame, Repeat};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::io::Cursor;
+
+const MAX_COLORS: usize = 256;
+
+/// A decoded GIF, with the indexed pixels of each frame as stored in the file
+struct IndexedGif {
+    width: u16,
+    height: u16,
+    global_palette: Option<Vec<u8>>,
+    repeat: Repeat,
+    frames: Vec<Frame<'static>>,
+}
+
+/// Rewrites the palettes of `buffer`. With `max_colors` below 256, a static GIF is first quantized to that many
+/// colors. The input is returned as is when the output would not be smaller.
+pub fn optimize_gif(buffer: &[u8], max_colors: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut gif = decode(buffer)?;
+    if max_colors < MAX_COLORS && gif.frames.len() == 1 {
+        let palette = frame_palette(&gif.frames[0], gif.global_palette.as_deref())?.to_vec();
+        quantize(&mut gif.frames[0], &palette, max_colors);
+    }
+
+    let output = encode(repalettize(gif)?)?;
+    Ok(match output.len() < buffer.len() {
+        true => output,
+        false => buffer.to_vec(),
+    })
+}
+
+/// Number of colors of a static GIF compressed at `quality`, halved every 12.5 points below 100
+pub fn gif_colors(quality: u32) -> usize {
+    let bits = (quality.clamp(1, 100) * 8).div_ceil(100);
+    1 << bits.max(1)
+}
+
+fn decode(buffer: &[u8]) -> Result<IndexedGif, Box<dyn Error>> {
+    let mut options = DecodeOptions::new();
+    options.set_color_output(ColorOutput::Indexed);
+    let mut decoder = options.read_info(Cursor::new(buffer))?;
+    let mut frames = Vec::new();
+    while let Some(frame) = decoder.read_next_frame()? {
+        frames.push(frame.clone());
+    }
+    if frames.is_empty() {
+        return Err("GIF without any frame".into());
+    }
+
+    Ok(IndexedGif {
+        width: decoder.width(),
+        height: decoder.height(),
+        global_palette: decoder.global_palette().map(<[u8]>::to_vec),
+        repeat: decoder.repeat(),
+        frames,
+    })
+}
+
+fn encode(gif: IndexedGif) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut output = Vec::new();
+    {
+        let global_palette = gif.global_palette.as_deref().unwrap_or_default();
+        let mut encoder = Encoder::new(&mut output, gif.width, gif.height, global_palette)?;
+        // Without a loop count the animation plays once, writing one would make it loop
+        if gif.frames.len() > 1 && gif.repeat != Repeat::Finite(0) {
+            encoder.set_repeat(gif.repeat)?;
+        }
+        for frame in &gif.frames {
+            encoder.write_frame(frame)?;
+        }
+    }
+    Ok(output)
+}
+
+fn frame_palette<'a>(frame: &'a Frame, global_palette: Option<&'a [u8]>) -> Result<&'a [u8], Box<dyn Error>> {
+    frame
+        .palette
+        .as_deref()
+        .or(global_palette)
+        .ok_or_else(|| "GIF frame without a palette".into())
+}
+
+/// Replaces the palette of each frame with the colors its pixels use, once each, in a global palette when all the
+/// frames fit in one. The transparent pixels keep an entry of their own.
+fn repalettize(mut gif: IndexedGif) -> Result<IndexedGif, Box<dyn Error>> {
+    let mut compacted = Vec::with_capacity(gif.frames.len());
+    for frame in &gif.frames {
+        let palette = frame_palette(frame, gif.global_palette.as_deref())?;
+        compacted.push(compact_palette(frame, palette));
+    }
+
+    let shared_colors = shared_palette(&gif.frames, &compacted);
+    let shared_lookup = shared_colors.as_deref().map(index_lookup);
+    for (frame, (colors, pixels)) in gif.frames.iter_mut().zip(compacted) {
+        let transparent = frame.transparent.map(|_| transparent_index(&colors));
+        match (&shared_colors, &shared_lookup) {
+            (Some(shared_colors), Some(lookup)) => {
+                let shared_transparent = transparent_index(shared_colors);
+                frame.buffer = pixels
+                    .into_iter()
+                    .map(|pixel| match Some(pixel) == transparent {
+                        true => shared_transparent,
+                        false => lookup[&colors[pixel as usize]],
+                    })
+                    .collect();
+                frame.transparent = transparent.map(|_| shared_transparent);
+                frame.palette = None;
+            }
+            _ => {
+                frame.buffer = Cow::Owned(pixels);
+                frame.palette = Some(with_transparency(&colors, transparent.is_some()));
+                frame.transparent = transparent;
+            }
+        }
+    }
+    gif.global_palette = shared_colors.map(|colors| {
+        let has_transparency = gif.frames.iter().any(|frame| frame.transparent.is_some());
+        with_transparency(&colors, has_transparency)
+    });
+
+    Ok(gif)
+}
+
+/// Distinct opaque colors used by the frame, and its pixels indexing them. The transparent pixels index the entry
+/// right after the colors.
+fn compact_palette(frame: &Frame, palette: &[u8]) -> (Vec<[u8; 3]>, Vec<u8>) {
+    let mut colors = Vec::new();
+    let mut lookup = HashMap::new();
+    let mut remapped: [Option<u8>; MAX_COLORS] = [None; MAX_COLORS];
+    let opaque_pixels: Vec<Option<u8>> = frame
+        .buffer
+        .iter()
+        .map(|&index| {
+            if Some(index) == frame.transparent {
+                return None;
+            }
+            let pixel = remapped[index as usize].get_or_insert_with(|| {
+                let start = index as usize * 3;
+                let color: [u8; 3] = palette
+                    .get(start..start + 3)
+                    .and_then(|color| color.try_into().ok())
+                    .unwrap_or_default();
+                *lookup.entry(color).or_insert_with(|| {
+                    colors.push(color);
+                    (colors.len() - 1) as u8
+                })
+            });
+            Some(*pixel)
+        })
+        .collect();
+
+    let transparent = transparent_index(&colors);
+    let pixels = opaque_pixels
+        .into_iter()
+        .map(|pixel| pixel.unwrap_or(transparent))
+        .collect();
+    (colors, pixels)
+}
+
+/// The colors of all the frames, when they fit in a single palette along with an entry for transparency
+fn shared_palette(frames: &[Frame], compacted: &[(Vec<[u8; 3]>, Vec<u8>)]) -> Option<Vec<[u8; 3]>> {
+    let mut colors = Vec::new();
+    let mut seen = HashSet::new();
+    for color in compacted.iter().flat_map(|(frame_colors, _)| frame_colors) {
+        if seen.insert(*color) {
+            colors.push(*color);
+        }
+    }
+    let has_transparency = frames.iter().any(|frame| frame.transparent.is_some());
+    (colors.len() + has_transparency as usize <= MAX_COLORS).then_some(colors)
+}
+
+fn index_lookup(colors: &[[u8; 3]]) -> HashMap<[u8; 3], u8> {
+    colors
+        .iter()
+        .enumerate()
+        .map(|(index, color)| (*color, index as u8))
+        .collect()
+}
+
+fn transparent_index(colors: &[[u8; 3]]) -> u8 {
+    colors.len().min(MAX_COLORS - 1) as u8
+}
+
+fn with_transparency(colors: &[[u8; 3]], has_transparency: bool) -> Vec<u8> {
+    let mut palette: Vec<u8> = colors.iter().flatten().copied().collect();
+    if has_transparency {
+        palette.extend_from_slice(&[0, 0, 0]);
+    }
+    palette
+}
+
+/// Maps the opaque pixels of the frame to at most `max_colors` colors, the transparent ones are left as they are
+fn quantize(frame: &mut Frame, palette: &[u8], max_colors: usize) {
+    let color_of = |index: u8| {
+        let start = index as usize * 3;
+        match palette.get(start..start + 3) {
+            Some(&[r, g, b]) => [r, g, b, 255],
+            _ => [0, 0, 0, 255],
+        }
+    };
+    let opaque = |index: &&u8| Some(**index) != frame.transparent;
+    let rgba: Vec<u8> = frame
+        .buffer
+        .iter()
+        .filter(opaque)
+        .flat_map(|&index| color_of(index))
+        .collect();
+    let max_colors = (max_colors - frame.transparent.is_some() as usize).max(2);
+    let distinct_colors: HashSet<&[u8]> = rgba.chunks_exact(4).collect();
+    if distinct_colors.len() <= max_colors {
+        return;
+    }
+
+    let quantizer = NeuQuant::new(10, max_colors, &rgba);
+    let mut quantized_palette = quantizer.color_map_rgb();
+    let transparent = frame.transparent.map(|_| (quantized_palette.len() / 3) as u8);
+    let pixels = frame
+        .buffer
+        .iter()
+        .map(|&index| match Some(index) == frame.transparent {
+            true => transparent.unwrap_or_default(),
+            false => quantizer.index_of(&color_of(index)) as u8,
+        })
+        .collect();
+
+    if transparent.is_some() {
+        quantized_palette.extend_from_slice(&[0, 0, 0]);
+    }
+    frame.palette = Some(quantized_palette);
+    frame.transparent = transparent;
+    frame.buffer = Cow::Owned(pixels);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::AnimationDecoder;
+
+    /// Writes `frames` of indexed pixels, each with its own palette of 256 entries of which few are used
+    fn write_gif(width: u16, height: u16, frames: &[(Vec<u8>, Option<u8>)], repeat: Option<Repeat>) -> Vec<u8> {
+        let palette: Vec<u8> = (0..=255u8).flat_map(|index| [index, 255 - index, index / 2]).collect();
+        let mut output = Vec::new();
+        {
+            let mut encoder = Encoder::new(&mut output, width, height, &[]).unwrap();
+            if let Some(repeat) = repeat {
+                encoder.set_repeat(repeat).unwrap();
+            }
+            for (pixels, transparent) in frames {
+                let mut frame =
+                    Frame::from_palette_pixels(width, height, pixels.clone(), palette.clone(), *transparent);
+                frame.delay = 10;
+                encoder.write_frame(&frame).unwrap();
+            }
+        }
+        output
+    }
+
+    fn decode_frames(buffer: &[u8]) -> Vec<image::RgbaImage> {
+        let decoder = image::codecs::gif::GifDecoder::new(Cursor::new(buffer)).unwrap();
+        decoder
+            .into_frames()
+            .map(|frame| frame.unwrap().into_buffer())
+            .collect()
+    }
+
+    #[test]
+    fn test_optimize_static_gif() {
+        let pixels: Vec<u8> = (0..64 * 64).map(|i| [10, 200][(i / 64 + i % 64) % 2]).collect();
+        let input = write_gif(64, 64, &[(pixels, None)], None);
+
+        let output = optimize_gif(&input, MAX_COLORS).unwrap();
+        assert!(output.len() < input.len());
+        assert_eq!(decode_frames(&output), decode_frames(&input));
+        // Two colors only need the smallest palette
+        assert_eq!(decode(&output).unwrap().global_palette.unwrap().len(), 2 * 3);
+
+        // Nothing left to gain, the input is kept
+        assert_eq!(optimize_gif(&output, MAX_COLORS).unwrap(), output);
+    }
+
+    #[test]
+    fn test_optimize_animated_gif() {
+        let frames: Vec<(Vec<u8>, Option<u8>)> = (0..3u8)
+            .map(|frame| {
+                let pixels = (0..32 * 32u32)
+                    .map(|i| [frame, frame + 100, 7][(i % 3) as usize])
+                    .collect();
+                (pixels, Some(7))
+            })
+            .collect();
+        let input = write_gif(32, 32, &frames, Some(Repeat::Infinite));
+
+        let output = optimize_gif(&input, MAX_COLORS).unwrap();
+        assert!(output.len() < input.len());
+        assert_eq!(decode_frames(&output), decode_frames(&input));
+        let optimized = decode(&output).unwrap();
+        assert_eq!(optimized.repeat, Repeat::Infinite);
+        assert!(optimized
+            .frames
+            .iter()
+            .all(|frame| frame.palette.is_none() && frame.delay == 10));
+        // Six colors and transparency, padded to a power of two in the file
+        assert_eq!(optimized.global_palette.unwrap().len(), 8 * 3);
+
+        // Animations are not quantized, whatever the number of colors
+        let output = optimize_gif(&input, 2).unwrap();
+        assert_eq!(decode_frames(&output), decode_frames(&input));
+    }
+
+    #[test]
+    fn test_quantize_static_gif() {
+        let pixels: Vec<u8> = (0..64 * 64u32).map(|i| (i % 64 * 4) as u8).collect();
+        let input = write_gif(64, 64, &[(pixels, None)], None);
+
+        let output = optimize_gif(&input, 16).unwrap();
+        assert!(output.len() < input.len());
+        let colors: HashSet<[u8; 4]> = decode_frames(&output)[0].pixels().map(|pixel| pixel.0).collect();
+        assert!(colors.len() <= 16, "{} colors", colors.len());
+    }
+
+    #[test]
+    fn test_gif_colors() {
+        assert_eq!(gif_colors(100), 256);
+        assert_eq!(gif_colors(80), 128);
+        assert_eq!(gif_colors(50), 16);
+        assert_eq!(gif_colors(1), 2);
+        assert_eq!(gif_colors(0), 2);
+    }
+
+    #[test]
+    fn test_optimize_invalid_gif() {
+        assert!(optimize_gif(b"GIF89a", MAX_COLORS).is_err());
+    }
+}
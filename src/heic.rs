@@ -0,0 +1,115 @@
+//! Decoding of the HEIC/HEIF photos taken by phones, which can't be written back, only converted.
+//!
+//! The decoder is libheif, linked when the `heic` feature is enabled. Without it, HEIC files are still recognized and
+//! reported as unsupported.
+
+use std::error::Error;
+
+/// Pixels of a HEIC image, with its orientation applied
+pub struct DecodedHeic {
+    /// The pixels, encoded as a PNG
+    pub png: Vec<u8>,
+    /// EXIF data of the photo, starting with its TIFF header
+    pub exif: Option<Vec<u8>>,
+}
+
+/// Brands of the HEVC coded images and sequences
+const HEIC_BRANDS: [&[u8]; 8] = [b"heic", b"heix", b"heim", b"heis", b"hevc", b"hevx", b"hevm", b"hevs"];
+
+/// Whether `buffer` is a HEIC image, from the brands of its file type box. AVIF files share the HEIF container with
+/// other brands and are not matched.
+pub fn is_heic(buffer: &[u8]) -> bool {
+    if buffer.len() < 12 || &buffer[4..8] != b"ftyp" {
+        return false;
+    }
+
+    // The major brand, then the minor version and the compatible brands, which a partial read may leave out
+    let box_size = u32::from_be_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]) as usize;
+    let compatible_brands = buffer.get(16..box_size.min(buffer.len())).unwrap_or_default();
+    std::iter::once(&buffer[8..12])
+        .chain(compatible_brands.chunks_exact(4))
+        .any(|brand| HEIC_BRANDS.contains(&brand))
+}
+
+/// Decodes the primary image of a HEIC file
+#[cfg(feature = "heic")]
+pub fn decode_heic(buffer: &[u8]) -> Result<DecodedHeic, Box<dyn Error>> {
+    use libheif_rs::{ColorSpace, HeifContext, LibHeif, RgbChroma};
+    use std::io::Cursor;
+
+    let context = HeifContext::read_from_bytes(buffer)?;
+    let handle = context.primary_image_handle()?;
+    let has_alpha = handle.has_alpha_channel();
+    let chroma = if has_alpha { RgbChroma::Rgba } else { RgbChroma::Rgb };
+    // The rotation and mirroring of the file are applied by libheif
+    let image = LibHeif::new().decode(&handle, ColorSpace::Rgb(chroma), None)?;
+    let plane = image.planes().interleaved.ok_or("Missing HEIC pixels")?;
+
+    let row_length = plane.width as usize * if has_alpha { 4 } else { 3 };
+    let pixels: Vec<u8> = plane
+        .data
+        .chunks(plane.stride)
+        .take(plane.height as usize)
+        .flat_map(|row| &row[..row_length])
+        .copied()
+        .collect();
+    let decoded = if has_alpha {
+        image::RgbaImage::from_raw(plane.width, plane.height, pixels).map(image::DynamicImage::from)
+    } else {
+        image::RgbImage::from_raw(plane.width, plane.height, pixels).map(image::DynamicImage::from)
+    }
+    .ok_or("Truncated HEIC pixels")?;
+    let mut png = Vec::new();
+    decoded.write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)?;
+
+    // The Exif item starts with the offset of the TIFF header
+    let exif = handle
+        .metadata_block_ids(b"Exif")
+        .into_iter()
+        .find_map(|id| handle.metadata(id).ok())
+        .and_then(|block| {
+            let offset = u32::from_be_bytes(block.get(..4)?.try_into().ok()?) as usize;
+            block.get(4 + offset..).map(<[u8]>::to_vec)
+        });
+
+    Ok(DecodedHeic { png, exif })
+}
+
+/// Decodes the primary image of a HEIC file
+#[cfg(not(feature = "heic"))]
+pub fn decode_heic(_buffer: &[u8]) -> Result<DecodedHeic, Box<dyn Error>> {
+    Err("HEIC support is not included in this build, it needs the `heic` feature".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The file type box of a HEIC file, enough to recognize it
+    fn heic_header(brand: &[u8; 4]) -> Vec<u8> {
+        let mut header = vec![0, 0, 0, 0x18];
+        header.extend_from_slice(b"ftyp");
+        header.extend_from_slice(brand);
+        header.extend_from_slice(&[0, 0, 0, 0]);
+        header.extend_from_slice(b"mif1");
+        header.extend_from_slice(brand);
+        header
+    }
+
+    #[test]
+    fn test_is_heic() {
+        assert!(is_heic(&heic_header(b"heic")));
+        assert!(is_heic(&heic_header(b"heix")));
+        assert!(!is_heic(&heic_header(b"avif")));
+        // Only the first bytes are read by the scan
+        assert!(is_heic(&heic_header(b"heic")[..16]));
+        assert!(!is_heic(&std::fs::read("samples/j0.JPG").unwrap()));
+    }
+
+    #[cfg(not(feature = "heic"))]
+    #[test]
+    fn test_decode_heic_without_feature() {
+        let error = decode_heic(&heic_header(b"heic")).err().unwrap();
+        assert!(error.to_string().contains("`heic` feature"));
+    }
+}
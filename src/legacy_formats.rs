@@ -0,0 +1,150 @@
+//! BMP, TGA and ICO inputs, found among legacy assets. They can't be written: they are decoded and converted to PNG,
+//! or to the selected format.
+
+use image::ImageFormat;
+use std::error::Error;
+use std::io::Cursor;
+
+const TGA_HEADER_LENGTH: usize = 18;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LegacyFormat {
+    Bmp,
+    Tga,
+    Ico,
+}
+
+impl LegacyFormat {
+    fn image_format(self) -> ImageFormat {
+        match self {
+            LegacyFormat::Bmp => ImageFormat::Bmp,
+            LegacyFormat::Tga => ImageFormat::Tga,
+            LegacyFormat::Ico => ImageFormat::Ico,
+        }
+    }
+}
+
+/// Format of `buffer`, from its header. TGA files have no signature, their header fields are checked instead, so
+/// they are only looked for in files of no other known type.
+pub fn detect_legacy_format(buffer: &[u8]) -> Option<LegacyFormat> {
+    if is_bmp(buffer) {
+        Some(LegacyFormat::Bmp)
+    } else if is_ico(buffer) {
+        Some(LegacyFormat::Ico)
+    } else if infer::get(buffer).is_none() && is_tga(buffer) {
+        Some(LegacyFormat::Tga)
+    } else {
+        None
+    }
+}
+
+/// Decodes the image, the largest one of an icon, and returns its pixels encoded as a PNG
+pub fn legacy_to_png(buffer: &[u8], format: LegacyFormat) -> Result<Vec<u8>, Box<dyn Error>> {
+    let image = image::load_from_memory_with_format(buffer, format.image_format())?;
+    let mut output = Vec::new();
+    image.write_to(&mut Cursor::new(&mut output), ImageFormat::Png)?;
+    Ok(output)
+}
+
+fn is_bmp(buffer: &[u8]) -> bool {
+    // The size of the info header tells its version, from the 12 bytes of OS/2 bitmaps to the 124 of version 5
+    buffer.len() >= 18
+        && buffer.starts_with(b"BM")
+        && matches!(
+            u32::from_le_bytes([buffer[14], buffer[15], buffer[16], buffer[17]]),
+            12 | 16 | 40 | 52 | 56 | 64 | 108 | 124
+        )
+}
+
+fn is_ico(buffer: &[u8]) -> bool {
+    // Reserved, type 1 for icons, then the number of images and the reserved byte of the first entry
+    buffer.len() >= 10
+        && buffer.starts_with(&[0, 0, 1, 0])
+        && u16::from_le_bytes([buffer[4], buffer[5]]) > 0
+        && buffer[9] == 0
+}
+
+fn is_tga(buffer: &[u8]) -> bool {
+    let Some(header) = buffer.get(..TGA_HEADER_LENGTH) else {
+        return false;
+    };
+    let color_map_type = header[1];
+    let image_type = header[2];
+    let width = u16::from_le_bytes([header[12], header[13]]);
+    let height = u16::from_le_bytes([header[14], header[15]]);
+    let pixel_depth = header[16];
+    let descriptor = header[17];
+
+    let color_mapped = matches!(image_type, 1 | 9);
+    let valid_image_type = matches!(image_type, 1 | 2 | 3 | 9 | 10 | 11);
+    // The color map fields are set exactly when the image has one
+    let valid_color_map = match color_map_type {
+        0 => !color_mapped,
+        1 => color_mapped && matches!(header[7], 15 | 16 | 24 | 32),
+        _ => false,
+    };
+    valid_image_type
+        && valid_color_map
+        && width > 0
+        && height > 0
+        && matches!(pixel_depth, 8 | 15 | 16 | 24 | 32)
+        // The interleaving bits are unused
+        && descriptor & 0xC0 == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(image: &image::DynamicImage, format: ImageFormat) -> Vec<u8> {
+        let mut output = Vec::new();
+        image.write_to(&mut Cursor::new(&mut output), format).unwrap();
+        output
+    }
+
+    fn fixture() -> image::DynamicImage {
+        let mut image = image::RgbImage::new(16, 8);
+        image.put_pixel(0, 0, image::Rgb([255, 0, 0]));
+        image.put_pixel(15, 7, image::Rgb([0, 0, 255]));
+        image::DynamicImage::from(image)
+    }
+
+    #[test]
+    fn test_detect_legacy_format() {
+        let fixture = fixture();
+        assert_eq!(
+            detect_legacy_format(&encode(&fixture, ImageFormat::Bmp)),
+            Some(LegacyFormat::Bmp)
+        );
+        assert_eq!(
+            detect_legacy_format(&encode(&fixture, ImageFormat::Tga)),
+            Some(LegacyFormat::Tga)
+        );
+        assert_eq!(
+            detect_legacy_format(&encode(&fixture, ImageFormat::Ico)),
+            Some(LegacyFormat::Ico)
+        );
+        // Only the header is read by the scan
+        assert_eq!(
+            detect_legacy_format(&encode(&fixture, ImageFormat::Tga)[..18]),
+            Some(LegacyFormat::Tga)
+        );
+
+        assert_eq!(detect_legacy_format(&std::fs::read("samples/p0.png").unwrap()), None);
+        assert_eq!(detect_legacy_format(&std::fs::read("samples/j0.JPG").unwrap()), None);
+        assert_eq!(detect_legacy_format(b"Some text that is not an image at all"), None);
+        assert_eq!(detect_legacy_format(&[0; 32]), None);
+    }
+
+    #[test]
+    fn test_legacy_to_png() {
+        let fixture = fixture();
+        for format in [LegacyFormat::Bmp, LegacyFormat::Tga, LegacyFormat::Ico] {
+            let png = legacy_to_png(&encode(&fixture, format.image_format()), format).unwrap();
+            let decoded = image::load_from_memory_with_format(&png, ImageFormat::Png).unwrap();
+            assert_eq!(decoded.to_rgba8(), fixture.to_rgba8(), "{format:?}");
+        }
+
+        assert!(legacy_to_png(b"BM", LegacyFormat::Bmp).is_err());
+    }
+}
@@ -0,0 +1,30 @@
+//! Compression engine behind the `caesiumclt` command line tool.
+//!
+//! [`compress_files`] compresses a list of images with the given [`CompressionOptions`], without printing anything.
+//! Progress can be followed through an optional [`ProgressCallback`].
+
+pub mod animation;
+pub mod archive;
+pub mod cmyk;
+pub mod comment;
+pub mod compressor;
+pub mod dpi;
+pub mod gif_palette;
+pub mod heic;
+pub mod legacy_formats;
+pub mod memory_budget;
+pub mod metadata;
+pub mod options;
+pub mod pdf;
+pub mod quality_rules;
+pub mod raw_preview;
+pub mod rename_map;
+pub mod similarity;
+pub mod svg;
+pub mod tiff_pages;
+pub mod tiff_stream;
+
+pub use compressor::{
+    compress_files, CompressionOptions, CompressionResult, CompressionStatus, ProgressCallback, ProgressEvent,
+    RunningTotals,
+};
@@ -1,16 +1,35 @@
-use crate::compressor::{start_compression, CompressionOptions, CompressionResult, CompressionStatus};
-use crate::options::{CommandLineArgs, JpegChromaSubsampling};
-use crate::scan_files::scan_files;
+use crate::capabilities::probe_capabilities;
+use crate::config::{expand_arg_files, find_config_arg, load_config, parse_args};
+use crate::progress_json::ProgressJsonWriter;
+use crate::scan_files::{nested_output_folder, scan_files, ScanSettings};
+use crate::sync::{check_sync_output_folder, sync_output_folder};
 use bytesize::ByteSize;
 use caesium::parameters::ChromaSubsampling;
-use clap::Parser;
-use colored::Colorize;
+use caesiumclt::archive::{check_temp_dir, ZipOutput};
+use caesiumclt::compressor::{
+    compress_stream, in_place_inputs, plan_outputs, savings_percent, start_compression, CompressionOptions,
+    CompressionResult, CompressionStatus, OverwriteAnswer, OverwritePrompt, PlannedOutput, ProgressEvent, Variant,
+};
+use caesiumclt::memory_budget::MemoryBudget;
+use caesiumclt::metadata::{GPS_TAG_GROUP, THUMBNAIL_TAG_GROUP};
+use caesiumclt::options::{
+    ColorMode, CommandLineArgs, Fit, Gravity, JpegChromaSubsampling, OutputFormat, OverwritePolicy, ProgressUnit,
+    Threads,
+};
+use caesiumclt::rename_map::load_rename_map;
+use colored::{ColoredString, Colorize};
 use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use log::debug;
+use rayon::{ThreadPool, ThreadPoolBuildError};
 use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::io::{self, IsTerminal, Write};
 use std::num::NonZero;
 use std::path::{Path, PathBuf};
 use std::process::exit;
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 #[derive(Serialize)]
 struct JsonSummary {
@@ -33,17 +52,56 @@ struct JsonOutput<'a> {
     summary: JsonSummary,
 }
 
-mod compressor;
-mod options;
+/// One output of an input in the `--manifest` file
+#[derive(Serialize, Debug, PartialEq)]
+struct ManifestOutput {
+    path: String,
+    width: usize,
+    height: usize,
+    size: u64,
+}
+
+#[derive(Serialize)]
+struct JsonScanCount {
+    files: usize,
+    bytes: u64,
+}
+
+mod capabilities;
+mod config;
+mod progress_json;
 mod scan_files;
+mod sync;
 
-const PROGRESS_UPDATE_INTERVAL: Duration = Duration::from_secs(1);
 const FALLBACK_THREAD_COUNT: usize = 1;
+const ADAPTIVE_MEDIUM_FILE_SIZE: u64 = 4 * 1024 * 1024;
+const ADAPTIVE_LARGE_FILE_SIZE: u64 = 20 * 1024 * 1024;
+const FAILED_FILES_EXIT_CODE: i32 = 1;
+const SKIPPED_FILES_EXIT_CODE: i32 = 2;
 
 fn main() {
-    let args = CommandLineArgs::parse_from(wild::args());
+    // Internal diagnostics, enabled with RUST_LOG (e.g. RUST_LOG=debug), always on stderr
+    env_logger::Builder::from_env(env_logger::Env::default())
+        .target(env_logger::Target::Stderr)
+        .init();
+    let raw_args = match expand_arg_files(wild::args().collect()) {
+        Ok(raw_args) => raw_args,
+        Err(e) => {
+            eprintln!("{e}");
+            exit(-1);
+        }
+    };
+    let config = match load_config(find_config_arg(&raw_args)) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e}");
+            exit(-1);
+        }
+    };
+    let args = parse_args(raw_args, config.as_ref()).unwrap_or_else(|e| e.exit());
+    let start_time = Instant::now();
 
-    if args.files.is_empty() {
+    if args.files.is_empty() && !args.count_only {
         if args.json {
             write_json_output(&[], args.dry_run, Some("No files to compress"));
         } else {
@@ -52,28 +110,104 @@ fn main() {
         return;
     }
 
-    let threads_number = get_parallelism_count(
-        args.threads,
-        std::thread::available_parallelism()
-            .unwrap_or_else(|_| NonZero::new(FALLBACK_THREAD_COUNT).expect("1 is never zero"))
-            .get(),
-    );
+    if args.is_stdin_input() && !args.count_only {
+        compress_stdin(&args);
+        return;
+    }
 
-    rayon::ThreadPoolBuilder::new()
-        .num_threads(threads_number)
-        .build_global()
-        .unwrap_or_default();
+    // The probe file would be the only thing a dry run writes
+    if let Err(e) = args
+        .tmpdir
+        .as_deref()
+        .filter(|_| !args.dry_run)
+        .map(check_temp_dir)
+        .transpose()
+    {
+        if args.json {
+            write_json_output(&[], args.dry_run, Some(&e));
+        } else {
+            eprintln!("{e}");
+        }
+        exit(-1);
+    }
 
-    let quiet = args.quiet || args.verbose == 0;
+    if let Some(Err(e)) = args
+        .output_destination
+        .output
+        .as_deref()
+        .filter(|_| args.sync)
+        .map(|output| check_sync_output_folder(output, &args.files))
+    {
+        if args.json {
+            write_json_output(&[], args.dry_run, Some(&e));
+        } else {
+            eprintln!("{e}");
+        }
+        exit(-1);
+    }
+
+    if cfg!(not(unix)) && (args.chmod_files.is_some() || args.chmod_dirs.is_some()) {
+        eprintln!("--chmod-files and --chmod-dirs are only supported on Unix, permissions will not be changed");
+    }
+
+    let available_threads = std::thread::available_parallelism()
+        .unwrap_or_else(|_| NonZero::new(FALLBACK_THREAD_COUNT).expect("1 is never zero"))
+        .get();
+    let start_thread_pool = |threads_number: usize| match build_thread_pool(threads_number) {
+        Ok(thread_pool) => {
+            debug!("Using {threads_number} threads");
+            thread_pool
+        }
+        Err(e) => {
+            let message = format!("Unable to start {threads_number} threads: {e}");
+            if args.json {
+                write_json_output(&[], args.dry_run, Some(&message));
+            } else {
+                eprintln!("{message}");
+            }
+            exit(-1);
+        }
+    };
+    let thread_pool = start_thread_pool(match args.threads {
+        Threads::Count(requested_threads) => get_parallelism_count(requested_threads, available_threads),
+        // Only for the scan, the compression threads depend on the files found
+        Threads::Adaptive => available_threads,
+    });
+
+    let quiet = args.quiet || args.verbose == 0 || args.stats_only;
     let verbose = if quiet { 0 } else { args.verbose };
-    let (base_path, input_files) = scan_files(
-        &args.files,
-        args.recursive,
-        quiet || args.json,
-        args.check_extension_only,
-    );
+    let no_color = env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+    colored::control::set_override(should_colorize(args.color, quiet, io::stdout().is_terminal(), no_color));
+    // The outputs of a previous run would be compressed again
+    let excluded_directory = match (&args.output_destination.output, args.recursive) {
+        (Some(output), true) => nested_output_folder(&args.files, output),
+        _ => None,
+    };
+    if let Some(output) = excluded_directory.as_ref().filter(|_| !quiet && !args.json) {
+        eprintln!(
+            "{} The output folder {} is inside an input folder, it is left out of the scan",
+            "Warning:".yellow().bold(),
+            output.display()
+        );
+    }
+    let scan_settings = ScanSettings {
+        recursive: args.recursive,
+        check_extension_only: args.check_extension_only,
+        follow_symlinks: args.follow_symlinks,
+        use_ignore: args.use_ignore,
+        max_depth: args.max_depth,
+        excluded_directory,
+        raw_preview: args.raw_preview,
+    };
+    let (base_path, input_files) = thread_pool.install(|| scan_files(&args.files, quiet || args.json, &scan_settings));
+    if args.count_only {
+        println!("{}", format_scan_count(&input_files, args.json));
+        return;
+    }
     let base_path = match base_path {
         Some(bp) => bp,
+        // Only missing inputs, reported as errors without writing anything
+        None if !input_files.is_empty() => PathBuf::new(),
         None => {
             if args.json {
                 write_json_output(
@@ -88,27 +222,269 @@ fn main() {
         }
     };
     let total_files = input_files.len();
+    debug!("Scan found {total_files} files, base path {}", base_path.display());
+    let thread_pool = match args.threads {
+        Threads::Adaptive => {
+            let total_bytes = input_files
+                .iter()
+                .filter_map(|input_file| input_file.metadata().ok())
+                .map(|metadata| metadata.len())
+                .sum();
+            start_thread_pool(adaptive_thread_count(total_files, total_bytes, available_threads))
+        }
+        Threads::Count(_) => thread_pool,
+    };
+
+    let rename_map = match args.rename_map.as_deref().map(load_rename_map).transpose() {
+        Ok(rename_map) => rename_map.map(Arc::new),
+        Err(e) => {
+            if args.json {
+                write_json_output(&[], args.dry_run, Some(&e));
+            } else {
+                eprintln!("{e}");
+            }
+            exit(-1);
+        }
+    };
 
-    let progress_target = if args.json {
-        ProgressDrawTarget::stderr()
+    if args.list_only {
+        let mut compression_options = build_compression_options(&args, &base_path);
+        compression_options.rename_map = rename_map;
+        // The archive is only created when an entry is added, its path is all that is needed here
+        compression_options.output_zip = args
+            .output_destination
+            .output_zip
+            .as_ref()
+            .and_then(|path| ZipOutput::new(path.clone(), OverwritePolicy::All).ok())
+            .map(Arc::new);
+        for planned_output in thread_pool.install(|| plan_outputs(&input_files, &compression_options)) {
+            println!("{}", format_planned_output(&planned_output));
+        }
+        return;
+    }
+
+    let mut progress_json = if args.progress_json {
+        Some(ProgressJsonWriter::stderr())
+    } else if let Some(fd) = args.progress_json_fd {
+        match ProgressJsonWriter::from_fd(fd) {
+            Ok(writer) => Some(writer),
+            Err(e) => {
+                eprintln!("Unable to write progress to file descriptor {fd}: {e}");
+                exit(-1);
+            }
+        }
     } else {
-        ProgressDrawTarget::stdout()
+        None
+    };
+    if let Some(writer) = progress_json.as_mut() {
+        writer.scan_complete(total_files);
+    }
+
+    // The progress bar would be mixed with the JSON lines on stderr
+    let progress_target = match (args.json, args.progress_json) {
+        (true, true) => ProgressDrawTarget::hidden(),
+        (true, false) => ProgressDrawTarget::stderr(),
+        _ => ProgressDrawTarget::stdout(),
     };
-    let (multi_progress, progress_bar) = setup_progress_bar(total_files, verbose, progress_target);
-    let compression_options = build_compression_options(&args, &base_path);
-    let compression_results = start_compression(
-        &input_files,
-        &compression_options,
-        &multi_progress,
-        &progress_bar,
-        args.dry_run,
+    let mut compression_options = build_compression_options(&args, &base_path);
+    compression_options.rename_map = rename_map;
+    if guards_in_place_overwrite(&args) {
+        let in_place = thread_pool.install(|| in_place_inputs(&input_files, &compression_options));
+        let interactive = io::stdin().is_terminal() && io::stderr().is_terminal();
+        if !in_place.is_empty() && !confirm_in_place_overwrite(in_place.len(), interactive, quiet) {
+            exit(-1);
+        }
+    }
+    // Each input is compressed once per variant
+    let progress_steps = progress_steps(&input_files, args.progress_by);
+    let progress_len = progress_steps.iter().sum::<u64>() * compression_options.variants.len().max(1) as u64;
+    let (multi_progress, progress_bar) = setup_progress_bar(
+        progress_len,
+        args.progress_by,
+        verbose,
+        progress_target,
+        args.progress_template.as_deref(),
+        Duration::from_millis(args.progress_interval),
     );
+    let overwrite_prompt = match args.overwrite {
+        // Nothing is overwritten, so nothing is asked
+        _ if args.dry_run => None,
+        OverwritePolicy::Ask if io::stdin().is_terminal() && io::stderr().is_terminal() => {
+            let multi_progress = multi_progress.clone();
+            Some(Arc::new(OverwritePrompt::new(move |path| {
+                ask_overwrite(&multi_progress, path)
+            })))
+        }
+        OverwritePolicy::Ask => {
+            eprintln!("--overwrite ask needs an interactive terminal, existing files will not be overwritten");
+            None
+        }
+        _ => None,
+    };
+    let output_zip = match &args.output_destination.output_zip {
+        Some(path) => match ZipOutput::new(
+            path.clone(),
+            resolve_archive_policy(args.overwrite, path, overwrite_prompt.as_deref()),
+        ) {
+            Ok(zip_output) => Some(Arc::new(match &args.tmpdir {
+                Some(tmpdir) => zip_output.with_temp_dir(tmpdir),
+                None => zip_output,
+            })),
+            Err(e) => {
+                if args.json {
+                    write_json_output(&[], args.dry_run, Some(&e));
+                } else {
+                    eprintln!("{e}");
+                }
+                exit(-1);
+            }
+        },
+        None => None,
+    };
+
+    compression_options.output_zip = output_zip.clone();
+    compression_options.overwrite_prompt = overwrite_prompt;
+    let compression_results = thread_pool.install(|| {
+        compress_with_progress(
+            &input_files,
+            &compression_options,
+            &multi_progress,
+            &progress_bar,
+            &progress_steps,
+            progress_json.as_mut(),
+            args.dry_run,
+        )
+    });
     progress_bar.finish();
+    if let Some(Err(e)) = output_zip.map(|zip_output| zip_output.finish()) {
+        eprintln!("{e}");
+    }
+    if let (true, Some(output_folder)) = (args.sync, &compression_options.output_folder) {
+        let report = sync_output_folder(
+            output_folder,
+            &compression_results,
+            &input_files,
+            &compression_options,
+            &scan_settings,
+            args.keep_structure,
+            args.dry_run,
+        );
+        if verbose > 0 && !args.json {
+            let action = if args.dry_run { "Would delete" } else { "Deleted" };
+            for path in &report.removed {
+                println!("{action} {}", path.display());
+            }
+        }
+        for error in &report.errors {
+            eprintln!("{error}");
+        }
+    }
+    if let (Some(path), false) = (&args.srcset, args.dry_run) {
+        if let Err(e) = write_json_file(path, &build_srcsets(&compression_results)) {
+            eprintln!("Unable to write the srcset file {}: {e}", path.display());
+        }
+    }
+    if let (Some(path), false) = (&args.manifest, args.dry_run) {
+        if let Err(e) = write_json_file(path, &build_manifest(&compression_results)) {
+            eprintln!("Unable to write the manifest file {}: {e}", path.display());
+        }
+    }
+    let elapsed = start_time.elapsed();
 
     if args.json {
         write_json_output(&compression_results, args.dry_run, None);
+    } else if args.stats_only {
+        println!("{}", format_stats_line(&compression_results));
     } else {
-        write_recap_message(&compression_results, verbose);
+        write_recap_message(&compression_results, verbose, elapsed, args.histogram);
+    }
+
+    let exit_code = get_exit_code(&compression_results, args.strict);
+    if exit_code != 0 {
+        exit(exit_code);
+    }
+}
+
+/// Fails when any file could not be compressed, e.g. a missing input
+/// With `strict`, skipped files fail the run too, with their own status so that errors can still be told apart
+fn get_exit_code(compression_results: &[CompressionResult], strict: bool) -> i32 {
+    let stats = CompressionStats::from_results(compression_results);
+    if stats.errors > 0 {
+        FAILED_FILES_EXIT_CODE
+    } else if strict && stats.skipped > 0 {
+        SKIPPED_FILES_EXIT_CODE
+    } else {
+        0
+    }
+}
+
+/// Whether the originals overwritten in place must be confirmed first: they can't be recovered unless trashed, and
+/// `never` and `ask` already keep or confirm each of them
+fn guards_in_place_overwrite(args: &CommandLineArgs) -> bool {
+    args.output_destination.same_folder_as_input
+        && !args.force
+        && !args.dry_run
+        && !args.trash_original
+        && !matches!(args.overwrite, OverwritePolicy::Never | OverwritePolicy::Ask)
+}
+
+/// Asks on the terminal before overwriting `count` originals in place. Elsewhere, e.g. in scripts, only warns.
+fn confirm_in_place_overwrite(count: usize, interactive: bool, quiet: bool) -> bool {
+    let warning = format!(
+        "{count} original files will be overwritten in place and CANNOT be recovered. Use --suffix or --format to \
+         write beside them, --trash-original to keep a copy, or --force to skip this check."
+    );
+    if !interactive {
+        if !quiet {
+            eprintln!("{} {warning}", "Warning:".yellow().bold());
+        }
+        return true;
+    }
+
+    eprint!("{} {warning}\nContinue? [y/N] ", "Warning:".yellow().bold());
+    let _ = io::stderr().flush();
+    let mut reply = String::new();
+    io::stdin().read_line(&mut reply).is_ok() && matches!(reply.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+/// Asks on the terminal whether `path` can be overwritten, hiding the progress bars meanwhile
+fn ask_overwrite(multi_progress: &MultiProgress, path: &Path) -> OverwriteAnswer {
+    multi_progress.suspend(|| {
+        eprint!("Overwrite {}? [y/N/a(ll)/q(uit)] ", path.display());
+        let _ = io::stderr().flush();
+        let mut reply = String::new();
+        match io::stdin().read_line(&mut reply) {
+            Ok(_) => OverwriteAnswer::parse(&reply),
+            Err(_) => OverwriteAnswer::No,
+        }
+    })
+}
+
+/// The archive is written as a whole, so `ask` is answered once before starting
+fn resolve_archive_policy(policy: OverwritePolicy, path: &Path, prompt: Option<&OverwritePrompt>) -> OverwritePolicy {
+    match (policy, prompt) {
+        (OverwritePolicy::Ask, Some(prompt)) if path.exists() => match prompt.confirm(path) {
+            OverwriteAnswer::Yes | OverwriteAnswer::All => OverwritePolicy::All,
+            OverwriteAnswer::No | OverwriteAnswer::Quit => OverwritePolicy::Never,
+        },
+        (policy, _) => policy,
+    }
+}
+
+fn compress_stdin(args: &CommandLineArgs) {
+    if args.format == OutputFormat::Original {
+        eprintln!("--format must be set when reading from stdin");
+        exit(-1);
+    }
+
+    let compression_options = build_compression_options(args, Path::new(""));
+    if !compression_options.variants.is_empty() {
+        eprintln!("Only one --quality, and no --sizes or --widths, can be used when reading from stdin");
+        exit(-1);
+    }
+    if let Err(e) = compress_stream(&mut io::stdin().lock(), &mut io::stdout().lock(), &compression_options) {
+        eprintln!("{e}");
+        exit(-1);
     }
 }
 
@@ -153,11 +529,7 @@ impl CompressionStats {
     }
 
     fn savings_percent(&self) -> f64 {
-        if self.total_original_size > 0 {
-            (self.savings_bytes() as f64 / self.total_original_size as f64) * 100.0
-        } else {
-            0.0
-        }
+        savings_percent(self.total_original_size, self.total_compressed_size)
     }
 }
 
@@ -186,7 +558,156 @@ fn write_json_output(compression_results: &[CompressionResult], dry_run: bool, e
     println!("{}", build_json_output_string(compression_results, dry_run, error));
 }
 
-fn write_recap_message(compression_results: &[CompressionResult], verbose: u8) {
+/// Number of scanned files, with their total size in bytes with `json`. Missing inputs count as files of 0 bytes, as
+/// they are reported as errors.
+fn format_scan_count(input_files: &[PathBuf], json: bool) -> String {
+    if !json {
+        return input_files.len().to_string();
+    }
+
+    let scan_count = JsonScanCount {
+        files: input_files.len(),
+        bytes: input_files
+            .iter()
+            .filter_map(|input_file| input_file.metadata().ok())
+            .map(|metadata| metadata.len())
+            .sum(),
+    };
+    serde_json::to_string(&scan_count).unwrap_or_else(|e| format!("{{\"error\":\"JSON serialization failed: {e}}}"))
+}
+
+/// Totals of the run on a single line of `key=value` pairs, for `--stats-only`
+fn format_stats_line(compression_results: &[CompressionResult]) -> String {
+    let stats = CompressionStats::from_results(compression_results);
+    format!(
+        "files={} success={} skipped={} errors={} original={} compressed={} saved_pct={:.1}",
+        compression_results.len(),
+        stats.success,
+        stats.skipped,
+        stats.errors,
+        stats.total_original_size,
+        stats.total_compressed_size,
+        stats.savings_percent()
+    )
+}
+
+fn format_planned_output(planned_output: &PlannedOutput) -> String {
+    let input_path = planned_output.input_path.display();
+    let Some(output_path) = &planned_output.output_path else {
+        return format!("{input_path} -> skipped: {}", planned_output.message);
+    };
+    let format = match planned_output.format {
+        Some(format) => format!("{format:?}").to_lowercase(),
+        None => "unsupported".to_string(),
+    };
+
+    format!(
+        "{input_path} -> {} ({format}, {})",
+        output_path.display(),
+        planned_output.compression
+    )
+}
+
+fn format_throughput(total_files: usize, total_bytes: u64, elapsed: Duration) -> String {
+    let seconds = elapsed.as_secs_f64();
+    let (files_per_second, mb_per_second) = if seconds > 0.0 {
+        (total_files as f64 / seconds, total_bytes as f64 / 1_000_000.0 / seconds)
+    } else {
+        (0.0, 0.0)
+    };
+
+    format!("Processed {total_files} files in {seconds:.1}s ({files_per_second:.1} files/s, {mb_per_second:.1} MB/s)")
+}
+
+/// `--color always` wins over `NO_COLOR`, as flags take precedence over the environment
+fn should_colorize(color: ColorMode, quiet: bool, is_terminal: bool, no_color: bool) -> bool {
+    match color {
+        ColorMode::Always => true,
+        ColorMode::Never => false,
+        ColorMode::Auto => !quiet && is_terminal && !no_color,
+    }
+}
+
+/// Formats the size change of an output against its original, e.g. `-1.2 MB` and `42.00% saved` when it shrank,
+/// or `+5.0 KB` and `5.00% larger` when it grew. The percentage is always positive, the wording gives the direction.
+fn format_savings(original_size: u64, compressed_size: u64) -> (String, String) {
+    let savings_size = original_size as i64 - compressed_size as i64;
+    let sign = match savings_size {
+        0 => "",
+        1.. => "-",
+        _ => "+",
+    };
+    let size = format!("{sign}{}", ByteSize::b(savings_size.unsigned_abs()));
+
+    let percentage = if original_size == 0 {
+        "n/a".to_string()
+    } else if savings_size >= 0 {
+        format!("{:.2}% saved", savings_percent(original_size, compressed_size))
+    } else {
+        format!("{:.2}% larger", -savings_percent(original_size, compressed_size))
+    };
+
+    (size, percentage)
+}
+
+/// Progress bar message with the savings of the files processed so far
+fn format_saved_so_far(original_size: u64, compressed_size: u64) -> String {
+    let (size, percentage) = format_savings(original_size, compressed_size);
+    format!("Saved so far: {size} [{percentage}]")
+}
+
+fn colorize_savings(original_size: u64, compressed_size: u64) -> (ColoredString, ColoredString) {
+    let (size, percentage) = format_savings(original_size, compressed_size);
+    if compressed_size <= original_size {
+        (size.green(), percentage.green())
+    } else {
+        (size.red(), percentage.red())
+    }
+}
+
+/// Labels of the savings histogram buckets, in the order of `savings_histogram`
+const HISTOGRAM_BUCKETS: [&str; 5] = ["grew", "0-10%", "10-25%", "25-50%", "50%+"];
+const HISTOGRAM_WIDTH: usize = 40;
+
+/// Counts the successfully compressed files per savings bucket, see `HISTOGRAM_BUCKETS`.
+/// Skipped and failed files have no meaningful savings and are left out.
+fn savings_histogram(compression_results: &[CompressionResult]) -> [usize; HISTOGRAM_BUCKETS.len()] {
+    let mut buckets = [0; HISTOGRAM_BUCKETS.len()];
+    for result in compression_results {
+        if !matches!(result.status, CompressionStatus::Success) {
+            continue;
+        }
+
+        let bucket = if result.compressed_size > result.original_size {
+            0
+        } else {
+            match savings_percent(result.original_size, result.compressed_size) {
+                p if p < 10.0 => 1,
+                p if p < 25.0 => 2,
+                p if p < 50.0 => 3,
+                _ => 4,
+            }
+        };
+        buckets[bucket] += 1;
+    }
+
+    buckets
+}
+
+/// One ASCII line per bucket, with bars scaled to the biggest bucket
+fn format_histogram(buckets: &[usize; HISTOGRAM_BUCKETS.len()]) -> Vec<String> {
+    let max_count = buckets.iter().copied().max().unwrap_or(0).max(1);
+    HISTOGRAM_BUCKETS
+        .iter()
+        .zip(buckets)
+        .map(|(label, &count)| {
+            let bar = "#".repeat((count * HISTOGRAM_WIDTH).div_ceil(max_count));
+            format!("{label:>7} | {bar:<HISTOGRAM_WIDTH$} {count}")
+        })
+        .collect()
+}
+
+fn write_recap_message(compression_results: &[CompressionResult], verbose: u8, elapsed: Duration, histogram: bool) {
     if compression_results.is_empty() {
         return;
     }
@@ -206,25 +727,8 @@ fn write_recap_message(compression_results: &[CompressionResult], verbose: u8) {
                 continue;
             }
 
-            let savings_size = result.original_size as i64 - result.compressed_size as i64;
-            let savings_percent = if result.original_size > 0 {
-                (savings_size as f64 / result.original_size as f64) * 100.0
-            } else {
-                0.0
-            };
-
-            let savings_size_abs = savings_size.unsigned_abs();
-            let (formatted_savings_size, formatted_savings_percentage) = if savings_size >= 0 {
-                (
-                    format!("-{}", ByteSize::b(savings_size_abs)).green(),
-                    format!("-{savings_percent:.2}%").green(),
-                )
-            } else {
-                (
-                    format!("+{}", ByteSize::b(savings_size_abs)).red(),
-                    format!("+{:.2}%", -savings_percent).red(),
-                )
-            };
+            let (formatted_savings_size, formatted_savings_percentage) =
+                colorize_savings(result.original_size, result.compressed_size);
 
             let status_message = match result.status {
                 CompressionStatus::Success => "Success".green(),
@@ -255,20 +759,8 @@ fn write_recap_message(compression_results: &[CompressionResult], verbose: u8) {
     }
 
     if verbose > 0 {
-        let total_saved = stats.savings_bytes();
-        let total_saved_percent = stats.savings_percent();
-        let total_saved_abs = total_saved.unsigned_abs();
-        let (formatted_total_saved_size, formatted_total_saved_percentage) = if total_saved >= 0 {
-            (
-                format!("-{}", ByteSize::b(total_saved_abs)).green(),
-                format!("-{total_saved_percent:.2}%").green(),
-            )
-        } else {
-            (
-                format!("+{}", ByteSize::b(total_saved_abs)).red(),
-                format!("+{:.2}%", -total_saved_percent).red(),
-            )
-        };
+        let (formatted_total_saved_size, formatted_total_saved_percentage) =
+            colorize_savings(total_original_size, total_compressed_size);
 
         println!(
             "Compressed {} files ({} success, {} skipped, {} errors)\n{} -> {} [{} | {}]",
@@ -281,9 +773,47 @@ fn write_recap_message(compression_results: &[CompressionResult], verbose: u8) {
             formatted_total_saved_size,
             formatted_total_saved_percentage
         );
+        println!(
+            "{}",
+            format_throughput(compression_results.len(), total_original_size, elapsed)
+        );
+
+        if histogram {
+            println!("\nSavings distribution ({total_success} compressed files)");
+            for line in format_histogram(&savings_histogram(compression_results)) {
+                println!("{line}");
+            }
+        }
+
+        // Explains throughput differences between hosts
+        if verbose > 2 {
+            println!("\n{}", probe_capabilities());
+        }
     }
 }
 
+/// A dedicated pool rather than the global one, which can't be configured once it exists
+fn build_thread_pool(threads: usize) -> Result<ThreadPool, ThreadPoolBuildError> {
+    rayon::ThreadPoolBuilder::new().num_threads(threads).build()
+}
+
+/// Threads used by `--threads adaptive`: all the available ones for small files, where the work is short and the
+/// memory low, fewer as the average file grows, as each one holds a large decoded image. Never more than the files.
+fn adaptive_thread_count(file_count: usize, total_bytes: u64, available_threads: usize) -> usize {
+    let Some(average_size) = total_bytes.checked_div(file_count as u64) else {
+        return 1;
+    };
+    let threads = if average_size >= ADAPTIVE_LARGE_FILE_SIZE {
+        available_threads / 4
+    } else if average_size >= ADAPTIVE_MEDIUM_FILE_SIZE {
+        available_threads / 2
+    } else {
+        available_threads
+    };
+
+    threads.min(file_count).max(1)
+}
+
 fn get_parallelism_count(requested_threads: u32, available_threads: usize) -> usize {
     match requested_threads {
         0 => available_threads,
@@ -291,9 +821,76 @@ fn get_parallelism_count(requested_threads: u32, available_threads: usize) -> us
     }
 }
 
-fn setup_progress_bar(len: usize, verbose: u8, target: ProgressDrawTarget) -> (MultiProgress, ProgressBar) {
+/// How much each input advances the progress bar: one, or its size read before compressing, as the file may be
+/// overwritten
+fn progress_steps(input_files: &[PathBuf], unit: ProgressUnit) -> Vec<u64> {
+    input_files
+        .iter()
+        .map(|input_file| match unit {
+            ProgressUnit::Files => 1,
+            ProgressUnit::Bytes => input_file.metadata().map(|metadata| metadata.len()).unwrap_or(0),
+        })
+        .collect()
+}
+
+/// Shows a spinner for each file being compressed on top of the overall progress bar,
+/// and forwards the events to the JSON progress stream if requested.
+/// `progress_steps` are the increments of the bar for each of `input_files`.
+fn compress_with_progress(
+    input_files: &[PathBuf],
+    options: &CompressionOptions,
+    multi_progress: &MultiProgress,
+    progress_bar: &ProgressBar,
+    progress_steps: &[u64],
+    mut progress_json: Option<&mut ProgressJsonWriter>,
+    dry_run: bool,
+) -> Vec<CompressionResult> {
+    let spinner_style = ProgressStyle::default_spinner()
+        .tick_strings(&["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏"])
+        .template("{spinner:.cyan} {msg}")
+        .unwrap_or(ProgressStyle::default_spinner());
+    let mut spinners: Vec<(PathBuf, ProgressBar)> = vec![];
+    let steps: HashMap<&Path, u64> = input_files
+        .iter()
+        .map(PathBuf::as_path)
+        .zip(progress_steps.iter().copied())
+        .collect();
+
+    let mut on_progress = |event: ProgressEvent| {
+        if let Some(writer) = progress_json.as_deref_mut() {
+            writer.progress(&event);
+        }
+        match event {
+            ProgressEvent::Started { path } => {
+                let spinner = multi_progress.add(ProgressBar::new_spinner());
+                spinner.set_style(spinner_style.clone());
+                spinner.set_message(format!("{}", path.display()));
+                spinner.enable_steady_tick(Duration::from_millis(100));
+                spinners.push((path.to_path_buf(), spinner));
+            }
+            ProgressEvent::Finished { path, totals, .. } => {
+                if let Some(index) = spinners.iter().position(|(spinner_path, _)| spinner_path == path) {
+                    spinners.swap_remove(index).1.finish_and_clear();
+                }
+                progress_bar.set_message(format_saved_so_far(totals.original_size(), totals.compressed_size()));
+                progress_bar.inc(steps.get(path).copied().unwrap_or(0));
+            }
+        }
+    };
+
+    start_compression(input_files, options, dry_run, Some(&mut on_progress))
+}
+
+fn setup_progress_bar(
+    len: u64,
+    unit: ProgressUnit,
+    verbose: u8,
+    target: ProgressDrawTarget,
+    template: Option<&str>,
+    interval: Duration,
+) -> (MultiProgress, ProgressBar) {
     let multi_progress = MultiProgress::new();
-    let progress_bar = multi_progress.add(ProgressBar::new(len as u64));
+    let progress_bar = multi_progress.add(ProgressBar::new(len));
 
     if verbose == 0 {
         multi_progress.set_draw_target(ProgressDrawTarget::hidden());
@@ -301,42 +898,245 @@ fn setup_progress_bar(len: usize, verbose: u8, target: ProgressDrawTarget) -> (M
     }
 
     multi_progress.set_draw_target(target);
-    progress_bar.set_style(
-        ProgressStyle::default_bar()
-            .template("[{elapsed_precise}] [{wide_bar:.cyan/blue}] {pos}/{len}\n{msg}")
-            .unwrap_or(ProgressStyle::default_bar())
-            .progress_chars("#>-"),
-    );
-    progress_bar.enable_steady_tick(PROGRESS_UPDATE_INTERVAL);
+    let style = progress_style(unit, template).unwrap_or_else(|e| {
+        eprintln!("{} {e}", "Warning:".yellow().bold());
+        progress_style(unit, None).unwrap_or(ProgressStyle::default_bar())
+    });
+    progress_bar.set_style(style.progress_chars("#>-"));
+    progress_bar.enable_steady_tick(interval);
     (multi_progress, progress_bar)
 }
 
+/// Style of the progress bar, from `template` when set, or the default template of `unit`
+fn progress_style(unit: ProgressUnit, template: Option<&str>) -> Result<ProgressStyle, String> {
+    let default_template = match unit {
+        ProgressUnit::Files => "[{elapsed_precise}] [{wide_bar:.cyan/blue}] {pos}/{len}\n{msg}",
+        ProgressUnit::Bytes => "[{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})\n{msg}",
+    };
+    ProgressStyle::default_bar()
+        .template(template.unwrap_or(default_template))
+        .map_err(|e| format!("Invalid progress template, using the default one: {e}"))
+}
+
 fn build_compression_options(args: &CommandLineArgs, base_path: &Path) -> CompressionOptions {
+    let (exif, strip_icc) = resolve_metadata_flags(args);
     CompressionOptions {
-        quality: args.compression.quality,
+        quality: args
+            .compression
+            .quality
+            .as_ref()
+            .and_then(|qualities| qualities.first().copied()),
+        quality_rules: args.quality_for.clone(),
+        format_qualities: args.format_quality.qualities(),
         lossless: args.compression.lossless,
+        optimize: args.compression.optimize,
+        webp_near_lossless: args.webp_near_lossless,
+        webp_effort: args.webp_effort,
         output_folder: args.output_destination.output.clone(),
+        output_zip: None,
         same_folder_as_input: args.output_destination.same_folder_as_input,
         overwrite_policy: args.overwrite,
+        overwrite_prompt: None,
         format: args.format,
+        extension_case: args.output_extension_case,
+        format_conversions: args.convert.clone(),
         suffix: args.suffix.clone(),
         keep_structure: args.keep_structure,
+        flatten: args.flatten.then(|| args.number_format.clone().unwrap_or_default()),
+        file_mode: args.chmod_files,
+        dir_mode: args.chmod_dirs,
         width: args.resize.width,
         height: args.resize.height,
         long_edge: args.resize.long_edge,
         short_edge: args.resize.short_edge,
+        fit: match args.resize.fit {
+            Some(Fit::Within(width, height)) => Some((width, height)),
+            _ => None,
+        },
+        fit_mode: match args.resize.fit {
+            Some(Fit::Mode(mode)) => Some(mode),
+            _ => None,
+        },
+        pad_color: args.resize.pad_color.unwrap_or([0, 0, 0, 255]),
+        scale: args.resize.scale,
+        dpi: args.dpi,
+        resample_dpi: args.resize.resample_dpi,
+        comment: args.comment.clone(),
+        software_tag: args.software_tag,
         max_size: args.compression.max_size,
+        target_ssim: args.compression.target_ssim,
+        bpp_target: args.compression.bpp_target,
+        total_budget: args.compression.total_budget,
+        max_iterations: args.max_iterations,
+        max_size_tolerance: args.max_size_tolerance,
+        max_size_iterations: args.max_size_iterations,
         keep_dates: args.keep_dates,
-        exif: args.exif,
+        collision_policy: args.on_collision,
+        trash_original: args.trash_original,
+        dedupe: args.dedupe,
+        newer: args.newer,
+        verify: args.verify,
+        retries: args.retries,
+        exif,
         png_opt_level: args.png_opt_level,
         jpeg_chroma_subsampling: parse_jpeg_chroma_subsampling(args.jpeg_chroma_subsampling),
         jpeg_baseline: args.jpeg_baseline,
         zopfli: args.zopfli,
+        avif_speed: args.avif_speed,
+        tiff_compression: args.tiff_compression,
+        raw_preview: args.raw_preview,
         base_path: PathBuf::from(base_path),
         no_upscale: args.resize.no_upscale,
-        strip_icc: args.strip_icc,
+        resize_filter: args.resize.resize_filter,
+        rotate: args.rotate.filter(|angle| *angle != 0),
+        flip: args.flip,
+        crop: args.crop,
+        gravity: args.gravity.unwrap_or(Gravity::Center),
+        strip_icc,
+        strip_tags: build_strip_tags(&args.strip_tags, args.strip_gps, args.strip_thumbnail),
         min_savings: args.min_savings,
+        timeout: args.timeout.filter(|t| *t > 0).map(Duration::from_secs),
+        deadline: None,
+        fail_fast: args.fail_fast,
+        memory_budget: args
+            .max_memory
+            .map(|max_memory| Arc::new(MemoryBudget::new(max_memory))),
+        // Read from a file, set by the caller
+        rename_map: None,
+        variants: build_variants(
+            args.compression.quality.as_deref().unwrap_or_default(),
+            &args.resize.sizes,
+            &args.resize.widths,
+        ),
+    }
+}
+
+/// One output per quality, named with a `_q<quality>` suffix, when several are given, and one per size or width, named
+/// with a `-<size>w` suffix. With both, every size is written at every quality.
+fn build_variants(qualities: &[u32], sizes: &[u32], widths: &[u32]) -> Vec<Variant> {
+    let mut quality_variants: Vec<Variant> = vec![];
+    for &quality in qualities {
+        if !quality_variants.iter().any(|variant| variant.quality == Some(quality)) {
+            quality_variants.push(Variant {
+                suffix: format!("_q{quality}"),
+                quality: Some(quality),
+                ..Default::default()
+            });
+        }
+    }
+    if quality_variants.len() < 2 {
+        quality_variants = vec![Variant::default()];
+    }
+
+    let mut size_variants: Vec<Variant> = vec![];
+    let ladder = sizes
+        .iter()
+        .map(|&size| Variant {
+            suffix: format!("-{size}w"),
+            long_edge: Some(size),
+            ..Default::default()
+        })
+        .chain(widths.iter().map(|&width| Variant {
+            suffix: format!("-{width}w"),
+            width: Some(width),
+            ..Default::default()
+        }));
+    for size_variant in ladder {
+        if !size_variants.contains(&size_variant) {
+            size_variants.push(size_variant);
+        }
+    }
+    if size_variants.is_empty() {
+        size_variants = vec![Variant::default()];
+    }
+
+    let variants: Vec<Variant> = size_variants
+        .iter()
+        .flat_map(|size_variant| {
+            quality_variants.iter().map(|quality_variant| Variant {
+                suffix: format!("{}{}", size_variant.suffix, quality_variant.suffix),
+                quality: quality_variant.quality,
+                long_edge: size_variant.long_edge,
+                width: size_variant.width,
+            })
+        })
+        .collect();
+    if variants == [Variant::default()] {
+        vec![]
+    } else {
+        variants
+    }
+}
+
+/// The srcset attribute of each input written with `--sizes`, listing its outputs with their actual width.
+/// Outputs that were not written, or not into a folder, are left out.
+fn build_srcsets(compression_results: &[CompressionResult]) -> BTreeMap<String, String> {
+    let mut srcsets: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for result in compression_results {
+        if !matches!(result.status, CompressionStatus::Success) {
+            continue;
+        }
+        if let Ok(size) = imagesize::size(&result.output_path) {
+            srcsets
+                .entry(result.original_path.clone())
+                .or_default()
+                .push(format!("{} {}w", result.output_path, size.width));
+        }
     }
+    srcsets
+        .into_iter()
+        .map(|(input, candidates)| (input, candidates.join(", ")))
+        .collect()
+}
+
+/// The outputs written for each input, with their dimensions and sizes. Outputs that were not written, or not into a
+/// folder, are left out.
+fn build_manifest(compression_results: &[CompressionResult]) -> BTreeMap<String, Vec<ManifestOutput>> {
+    let mut manifest: BTreeMap<String, Vec<ManifestOutput>> = BTreeMap::new();
+    for result in compression_results {
+        if !matches!(result.status, CompressionStatus::Success) {
+            continue;
+        }
+        if let Ok(size) = imagesize::size(&result.output_path) {
+            manifest
+                .entry(result.original_path.clone())
+                .or_default()
+                .push(ManifestOutput {
+                    path: result.output_path.clone(),
+                    width: size.width,
+                    height: size.height,
+                    size: result.compressed_size,
+                });
+        }
+    }
+    manifest
+}
+
+fn write_json_file<T: Serialize>(path: &Path, value: &T) -> io::Result<()> {
+    let json = serde_json::to_string_pretty(value).map_err(io::Error::other)?;
+    std::fs::write(path, json + "\n")
+}
+
+/// Whether EXIF is kept and ICC stripped. `--keep-all` and `--strip-all` set both, and the individual flags take
+/// precedence over them, e.g. `--strip-all --keep-icc` keeps only the ICC profile.
+fn resolve_metadata_flags(args: &CommandLineArgs) -> (bool, bool) {
+    let exif = args.exif || args.keep_all;
+    let strip_icc = if args.keep_icc || args.keep_all {
+        args.strip_icc
+    } else {
+        args.strip_icc || args.strip_all
+    };
+    (exif, strip_icc)
+}
+
+fn build_strip_tags(strip_tags: &[String], strip_gps: bool, strip_thumbnail: bool) -> Vec<String> {
+    let mut tags = strip_tags.to_vec();
+    for (enabled, group) in [(strip_gps, GPS_TAG_GROUP), (strip_thumbnail, THUMBNAIL_TAG_GROUP)] {
+        if enabled && !tags.iter().any(|t| t.eq_ignore_ascii_case(group)) {
+            tags.push(group.to_string());
+        }
+    }
+    tags
 }
 
 fn parse_jpeg_chroma_subsampling(arg: JpegChromaSubsampling) -> ChromaSubsampling {
@@ -352,8 +1152,10 @@ fn parse_jpeg_chroma_subsampling(arg: JpegChromaSubsampling) -> ChromaSubsamplin
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::options::{
-        Compression, JpegChromaSubsampling, OutputDestination, OutputFormat, OverwritePolicy, Resize,
+    use caesiumclt::compressor::CompressionErrorKind;
+    use caesiumclt::options::{
+        CollisionPolicy, Compression, ExtensionCase, FitMode, FormatQuality, JpegChromaSubsampling,
+        MinSavingsThreshold, OutputDestination, OutputFormat, OverwritePolicy, Resize, ResizeFilter, TiffCompression,
     };
     use std::path::PathBuf;
 
@@ -378,16 +1180,138 @@ mod tests {
         assert_eq!(result, 0);
     }
 
+    #[test]
+    fn test_adaptive_thread_count() {
+        const MB: u64 = 1024 * 1024;
+
+        // Many small files use every core
+        assert_eq!(adaptive_thread_count(1000, 1000 * 200 * 1024, 8), 8);
+        // Medium files, e.g. camera JPEGs
+        assert_eq!(adaptive_thread_count(200, 200 * 6 * MB, 8), 4);
+        // Large files, e.g. TIFF scans
+        assert_eq!(adaptive_thread_count(50, 50 * 40 * MB, 16), 4);
+        assert_eq!(adaptive_thread_count(50, 50 * 40 * MB, 2), 1);
+        // No more threads than files
+        assert_eq!(adaptive_thread_count(3, 3 * 100 * 1024, 8), 3);
+        assert_eq!(adaptive_thread_count(0, 0, 8), 1);
+    }
+
+    #[test]
+    fn test_build_thread_pool() {
+        let thread_pool = build_thread_pool(2).unwrap();
+        assert_eq!(thread_pool.current_num_threads(), 2);
+
+        let input_files = vec![
+            PathBuf::from("samples/j0.JPG"),
+            PathBuf::from("samples/p0.png"),
+            PathBuf::from("samples/w0.webp"),
+            PathBuf::from("samples/t0.tif"),
+        ];
+        let mut args = create_test_args();
+        args.output_destination.output = Some(PathBuf::from("output"));
+        let options = build_compression_options(&args, Path::new("samples"));
+
+        // Events are sent from the threads compressing the files
+        let mut workers: Vec<(Option<usize>, usize)> = vec![];
+        let mut on_progress = |_: ProgressEvent| {
+            workers.push((rayon::current_thread_index(), rayon::current_num_threads()));
+        };
+        let results = thread_pool.install(|| start_compression(&input_files, &options, true, Some(&mut on_progress)));
+
+        assert_eq!(results.len(), input_files.len());
+        assert_eq!(workers.len(), input_files.len() * 2);
+        assert!(workers
+            .iter()
+            .all(|(index, threads)| index.is_some_and(|i| i < 2) && *threads == 2));
+    }
+
     #[test]
     fn test_setup_progress_bar() {
         // Test with verbose = 0 (hidden regardless of target)
-        let (_multi, progress_bar) = setup_progress_bar(10, 0, ProgressDrawTarget::stdout());
+        let (_multi, progress_bar) = setup_progress_bar(
+            10,
+            ProgressUnit::Files,
+            0,
+            ProgressDrawTarget::stdout(),
+            None,
+            Duration::from_secs(1),
+        );
         assert!(progress_bar.is_hidden());
         assert_eq!(progress_bar.length(), Some(10));
 
         // Test with different lengths
-        let (_multi, progress_bar) = setup_progress_bar(0, 1, ProgressDrawTarget::stdout());
+        let (_multi, progress_bar) = setup_progress_bar(
+            0,
+            ProgressUnit::Files,
+            1,
+            ProgressDrawTarget::stdout(),
+            None,
+            Duration::from_secs(1),
+        );
         assert_eq!(progress_bar.length(), Some(0));
+
+        // An invalid template falls back to the default one
+        let (_multi, progress_bar) = setup_progress_bar(
+            5,
+            ProgressUnit::Files,
+            1,
+            ProgressDrawTarget::hidden(),
+            Some("{bar:x}"),
+            Duration::from_millis(100),
+        );
+        assert_eq!(progress_bar.length(), Some(5));
+    }
+
+    #[test]
+    fn test_progress_style() {
+        assert!(progress_style(ProgressUnit::Files, None).is_ok());
+        assert!(progress_style(ProgressUnit::Bytes, None).is_ok());
+        assert!(progress_style(ProgressUnit::Files, Some("{wide_bar} {pos}/{len} {msg}")).is_ok());
+        let error = progress_style(ProgressUnit::Files, Some("{bar:x}")).unwrap_err();
+        assert!(error.starts_with("Invalid progress template"), "{error}");
+        assert!(progress_style(ProgressUnit::Files, Some("{pos}}")).is_err());
+    }
+
+    #[test]
+    fn test_progress_by_bytes() {
+        let input_files = vec![
+            PathBuf::from("samples/j0.JPG"),
+            PathBuf::from("samples/p0.png"),
+            PathBuf::from("samples/w0.webp"),
+            PathBuf::from("samples/missing.jpg"),
+        ];
+        assert_eq!(progress_steps(&input_files, ProgressUnit::Files), vec![1, 1, 1, 1]);
+        let steps = progress_steps(&input_files, ProgressUnit::Bytes);
+        let sizes: Vec<u64> = input_files[..3]
+            .iter()
+            .map(|f| std::fs::metadata(f).unwrap().len())
+            .collect();
+        assert_eq!(steps, [sizes.as_slice(), &[0]].concat());
+
+        let total_bytes = steps.iter().sum();
+        let (multi_progress, progress_bar) = setup_progress_bar(
+            total_bytes,
+            ProgressUnit::Bytes,
+            0,
+            ProgressDrawTarget::hidden(),
+            None,
+            Duration::from_secs(1),
+        );
+        let mut args = create_test_args();
+        args.output_destination.output = Some(PathBuf::from("output"));
+        let options = build_compression_options(&args, Path::new("samples"));
+        compress_with_progress(
+            &input_files,
+            &options,
+            &multi_progress,
+            &progress_bar,
+            &steps,
+            None,
+            true,
+        );
+
+        assert_eq!(progress_bar.length(), Some(total_bytes));
+        assert_eq!(progress_bar.position(), total_bytes);
     }
 
     #[test]
@@ -429,16 +1353,154 @@ mod tests {
         assert_eq!(options.base_path, PathBuf::from(base_path));
     }
 
+    #[test]
+    fn test_build_variants() {
+        assert!(build_variants(&[], &[], &[]).is_empty());
+        assert!(build_variants(&[80], &[], &[]).is_empty());
+        assert!(build_variants(&[80, 80], &[], &[]).is_empty());
+
+        let variants = build_variants(&[60, 95, 60], &[], &[]);
+        assert_eq!(
+            variants,
+            vec![
+                Variant {
+                    suffix: "_q60".to_string(),
+                    quality: Some(60),
+                    long_edge: None,
+                    width: None,
+                },
+                Variant {
+                    suffix: "_q95".to_string(),
+                    quality: Some(95),
+                    long_edge: None,
+                    width: None,
+                },
+            ]
+        );
+
+        let mut args = create_test_args();
+        args.compression.quality = Some(vec![60, 95]);
+        let options = build_compression_options(&args, Path::new("/test/base"));
+        assert_eq!(options.quality, Some(60));
+        assert_eq!(options.variants, variants);
+
+        // A single size is still written with its suffix
+        assert_eq!(
+            build_variants(&[80], &[480, 480], &[]),
+            vec![Variant {
+                suffix: "-480w".to_string(),
+                quality: None,
+                long_edge: Some(480),
+                width: None,
+            }]
+        );
+
+        let suffixes: Vec<String> = build_variants(&[60, 95], &[480, 960], &[])
+            .into_iter()
+            .map(|variant| variant.suffix)
+            .collect();
+        assert_eq!(suffixes, ["-480w_q60", "-480w_q95", "-960w_q60", "-960w_q95"]);
+
+        // Widths give the same names, resizing the width instead of the longest edge
+        assert_eq!(
+            build_variants(&[80], &[], &[320, 640]),
+            vec![
+                Variant {
+                    suffix: "-320w".to_string(),
+                    quality: None,
+                    long_edge: None,
+                    width: Some(320),
+                },
+                Variant {
+                    suffix: "-640w".to_string(),
+                    quality: None,
+                    long_edge: None,
+                    width: Some(640),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_build_srcsets() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut args = create_test_args();
+        args.resize.width = None;
+        args.resize.height = None;
+        args.resize.sizes = vec![100, 200];
+        args.output_destination.output = Some(temp_dir.path().to_path_buf());
+        args.keep_structure = false;
+        args.format = OutputFormat::Original;
+        let input_files = vec![std::path::absolute("samples/j0.JPG").unwrap()];
+        let options = build_compression_options(&args, &std::path::absolute("samples").unwrap());
+        let results = start_compression(&input_files, &options, false, None);
+
+        let srcsets = build_srcsets(&results);
+        let output = |size: u32| temp_dir.path().join(format!("j0_compressed-{size}w.jpg"));
+        let width = |size: u32| imagesize::size(output(size)).unwrap().width;
+        assert_eq!(
+            srcsets.get(&input_files[0].display().to_string()),
+            Some(&format!(
+                "{} {}w, {} {}w",
+                output(100).display(),
+                width(100),
+                output(200).display(),
+                width(200)
+            ))
+        );
+
+        // Each output of --widths has exactly its width
+        args.resize.sizes = vec![];
+        args.resize.widths = vec![100, 200];
+        let options = build_compression_options(&args, &std::path::absolute("samples").unwrap());
+        start_compression(&input_files, &options, false, None);
+        assert_eq!((width(100), width(200)), (100, 200));
+    }
+
+    #[test]
+    fn test_build_manifest() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut args = create_test_args();
+        args.resize.width = None;
+        args.resize.height = None;
+        args.compression.max_size = None;
+        args.resize.widths = vec![100, 200];
+        args.output_destination.output = Some(temp_dir.path().to_path_buf());
+        args.keep_structure = false;
+        args.format = OutputFormat::Webp;
+        let input_files = vec![
+            std::path::absolute("samples/j0.JPG").unwrap(),
+            std::path::absolute("samples/missing.jpg").unwrap(),
+        ];
+        let options = build_compression_options(&args, &std::path::absolute("samples").unwrap());
+        let results = start_compression(&input_files, &options, false, None);
+
+        let manifest = build_manifest(&results);
+        // Inputs without outputs are left out
+        assert_eq!(manifest.len(), 1);
+        let outputs = &manifest[&input_files[0].display().to_string()];
+        let output = |width: u32| temp_dir.path().join(format!("j0_compressed-{width}w.webp"));
+        assert_eq!(
+            outputs.iter().map(|output| output.path.clone()).collect::<Vec<_>>(),
+            [output(100).display().to_string(), output(200).display().to_string()]
+        );
+        for (output, width) in outputs.iter().zip([100, 200]) {
+            assert_eq!(output.width, width);
+            assert!(output.height > 0);
+            assert_eq!(output.size, std::fs::metadata(&output.path).unwrap().len());
+        }
+    }
+
     #[test]
     fn test_write_recap_message_empty_results() {
         // Test with empty results - should return early without printing
         let results: Vec<CompressionResult> = vec![];
 
         // This test mainly ensures the function doesn't panic with empty input
-        write_recap_message(&results, 0);
-        write_recap_message(&results, 1);
-        write_recap_message(&results, 2);
-        write_recap_message(&results, 3);
+        write_recap_message(&results, 0, Duration::ZERO, true);
+        write_recap_message(&results, 1, Duration::ZERO, true);
+        write_recap_message(&results, 2, Duration::ZERO, true);
+        write_recap_message(&results, 3, Duration::ZERO, true);
     }
 
     #[test]
@@ -450,6 +1512,7 @@ mod tests {
                 original_size: 1000,
                 compressed_size: 800,
                 status: CompressionStatus::Success,
+                error_kind: None,
                 message: "".to_string(),
             },
             CompressionResult {
@@ -458,6 +1521,7 @@ mod tests {
                 original_size: 2000,
                 compressed_size: 1500,
                 status: CompressionStatus::Skipped,
+                error_kind: None,
                 message: "File skipped".to_string(),
             },
             CompressionResult {
@@ -466,21 +1530,58 @@ mod tests {
                 original_size: 500,
                 compressed_size: 0,
                 status: CompressionStatus::Error,
+                error_kind: Some(CompressionErrorKind::Encode),
                 message: "Compression failed".to_string(),
             },
         ];
 
         // Test with verbose = 0 (should not print detailed results)
-        write_recap_message(&results, 0);
+        write_recap_message(&results, 0, Duration::ZERO, true);
 
         // Test with verbose = 1 (should print summary only)
-        write_recap_message(&results, 1);
+        write_recap_message(&results, 1, Duration::ZERO, true);
 
         // Test with verbose = 2 (should print some details)
-        write_recap_message(&results, 2);
+        write_recap_message(&results, 2, Duration::ZERO, true);
 
         // Test with verbose = 3 (should print all details)
-        write_recap_message(&results, 3);
+        write_recap_message(&results, 3, Duration::ZERO, true);
+    }
+
+    #[test]
+    fn test_savings_histogram() {
+        let result = |original_size: u64, compressed_size: u64, status: CompressionStatus| CompressionResult {
+            original_path: "test.jpg".to_string(),
+            output_path: "out.jpg".to_string(),
+            original_size,
+            compressed_size,
+            status,
+            error_kind: None,
+            message: "".to_string(),
+        };
+        let results = vec![
+            result(1000, 1100, CompressionStatus::Success),
+            result(1000, 1000, CompressionStatus::Success),
+            result(1000, 950, CompressionStatus::Success),
+            result(1000, 900, CompressionStatus::Success),
+            result(1000, 750, CompressionStatus::Success),
+            result(1000, 500, CompressionStatus::Success),
+            result(1000, 100, CompressionStatus::Success),
+            result(0, 0, CompressionStatus::Success),
+            result(1000, 100, CompressionStatus::Skipped),
+            result(1000, 0, CompressionStatus::Error),
+        ];
+
+        let buckets = savings_histogram(&results);
+        assert_eq!(buckets, [1, 3, 1, 1, 2]);
+        assert_eq!(savings_histogram(&[]), [0; 5]);
+
+        let lines = format_histogram(&buckets);
+        assert_eq!(lines.len(), HISTOGRAM_BUCKETS.len());
+        assert!(lines.iter().all(|line| line.is_ascii()));
+        assert!(lines[1].ends_with(&format!("{} 3", "#".repeat(HISTOGRAM_WIDTH))));
+        assert_eq!(lines[0].matches('#').count(), 14);
+        assert!(format_histogram(&[0; 5]).iter().all(|line| !line.contains('#')));
     }
 
     #[test]
@@ -491,51 +1592,129 @@ mod tests {
             original_size: 0, // Test zero division case
             compressed_size: 0,
             status: CompressionStatus::Success,
+            error_kind: None,
             message: "".to_string(),
         }];
 
         // Should not panic with zero original sizes
-        write_recap_message(&results, 3);
+        write_recap_message(&results, 3, Duration::ZERO, true);
     }
 
     // Helper function to create test CommandLineArgs
     fn create_test_args() -> CommandLineArgs {
         CommandLineArgs {
             compression: Compression {
-                quality: Some(80),
+                quality: Some(vec![80]),
                 lossless: false,
+                optimize: false,
                 max_size: Some(1024),
+                target_ssim: None,
+                target_value: None,
+                bpp_target: None,
+                total_budget: None,
+            },
+            quality_for: vec![],
+            format_quality: FormatQuality {
+                jpeg_quality: None,
+                png_quality: None,
+                webp_quality: None,
             },
             resize: Resize {
                 width: Some(800),
                 height: Some(600),
                 long_edge: None,
                 short_edge: None,
+                fit: None,
+                pad_color: None,
+                scale: None,
+                resample_dpi: false,
+                sizes: vec![],
+                widths: vec![],
                 no_upscale: false,
+                resize_filter: ResizeFilter::Lanczos3,
             },
+            rotate: None,
+            flip: None,
+            crop: None,
+            gravity: None,
             output_destination: OutputDestination {
                 output: Some(PathBuf::from("/output")),
                 same_folder_as_input: false,
+                output_zip: None,
             },
             format: OutputFormat::Jpeg,
+            convert: vec![],
             png_opt_level: 5,
+            avif_speed: 6,
+            tiff_compression: TiffCompression::Deflate,
             jpeg_chroma_subsampling: JpegChromaSubsampling::ChromaSubsampling420,
             jpeg_baseline: true,
             zopfli: true,
+            webp_near_lossless: None,
+            webp_effort: None,
+            raw_preview: false,
             exif: true,
             keep_dates: true,
             suffix: Some("_compressed".to_string()),
+            rename_map: None,
             recursive: true,
+            max_depth: None,
+            follow_symlinks: false,
+            use_ignore: false,
             keep_structure: true,
+            flatten: false,
+            number_format: None,
+            srcset: None,
+            manifest: None,
             dry_run: false,
-            threads: 4,
+            strict: false,
+            fail_fast: false,
+            list_only: false,
+            count_only: false,
+            stats_only: false,
+            threads: Threads::Count(4),
+            timeout: None,
+            max_memory: None,
+            retries: 0,
+            max_iterations: 8,
+            target_metric: None,
+            max_size_tolerance: None,
+            max_size_iterations: None,
             overwrite: OverwritePolicy::All,
+            on_collision: CollisionPolicy::Rename,
+            output_extension_case: ExtensionCase::Lower,
+            tmpdir: None,
+            trash_original: false,
+            force: false,
+            chmod_files: None,
+            chmod_dirs: None,
+            sync: false,
+            dedupe: false,
+            newer: false,
+            verify: false,
             min_savings: None,
             quiet: false,
             verbose: 2,
+            histogram: false,
             json: false,
+            progress_json: false,
+            progress_json_fd: None,
+            color: ColorMode::Auto,
+            progress_by: ProgressUnit::Files,
+            progress_template: None,
+            progress_interval: 1000,
+            config: None,
             files: vec!["test1.jpg".to_string(), "test2.png".to_string()],
             strip_icc: false,
+            keep_icc: false,
+            keep_all: false,
+            strip_all: false,
+            dpi: None,
+            comment: None,
+            software_tag: false,
+            strip_tags: vec![],
+            strip_gps: false,
+            strip_thumbnail: false,
             check_extension_only: false,
         }
     }
@@ -572,6 +1751,162 @@ mod tests {
         assert!(options.no_upscale);
     }
 
+    #[test]
+    fn test_build_compression_options_fit() {
+        let mut args = create_test_args();
+        args.resize.fit = Some(Fit::Within(1920, 1080));
+        let options = build_compression_options(&args, Path::new("/base"));
+        assert_eq!(options.fit, Some((1920, 1080)));
+        assert_eq!(options.fit_mode, None);
+        assert_eq!(options.pad_color, [0, 0, 0, 255]);
+
+        args.resize.fit = Some(Fit::Mode(FitMode::Pad));
+        args.resize.pad_color = Some([255, 255, 255, 255]);
+        let options = build_compression_options(&args, Path::new("/base"));
+        assert_eq!(options.fit, None);
+        assert_eq!(options.fit_mode, Some(FitMode::Pad));
+        assert_eq!(options.pad_color, [255, 255, 255, 255]);
+    }
+
+    #[test]
+    fn test_resolve_metadata_flags() {
+        // (keep_all, strip_all, exif, keep_icc, strip_icc) => (exif, strip_icc)
+        let cases = [
+            ((false, false, false, false, false), (false, false)),
+            ((false, false, true, false, false), (true, false)),
+            ((false, false, false, false, true), (false, true)),
+            ((true, false, false, false, false), (true, false)),
+            ((true, false, false, false, true), (true, true)),
+            ((false, true, false, false, false), (false, true)),
+            ((false, true, false, true, false), (false, false)),
+            ((false, true, true, false, false), (true, true)),
+            ((false, true, true, true, false), (true, false)),
+        ];
+        for ((keep_all, strip_all, exif, keep_icc, strip_icc), expected) in cases {
+            let mut args = create_test_args();
+            args.keep_all = keep_all;
+            args.strip_all = strip_all;
+            args.exif = exif;
+            args.keep_icc = keep_icc;
+            args.strip_icc = strip_icc;
+            assert_eq!(
+                resolve_metadata_flags(&args),
+                expected,
+                "keep_all {keep_all}, strip_all {strip_all}, exif {exif}, keep_icc {keep_icc}, strip_icc {strip_icc}"
+            );
+
+            let options = build_compression_options(&args, Path::new("/base"));
+            assert_eq!((options.exif, options.strip_icc), expected);
+        }
+    }
+
+    #[test]
+    fn test_guards_in_place_overwrite() {
+        let mut args = create_test_args();
+        assert!(!guards_in_place_overwrite(&args));
+
+        args.output_destination.output = None;
+        args.output_destination.same_folder_as_input = true;
+        assert!(guards_in_place_overwrite(&args));
+        args.overwrite = OverwritePolicy::Bigger;
+        assert!(guards_in_place_overwrite(&args));
+
+        let disables: [fn(&mut CommandLineArgs); 5] = [
+            |args| args.force = true,
+            |args| args.dry_run = true,
+            |args| args.trash_original = true,
+            |args| args.overwrite = OverwritePolicy::Never,
+            |args| args.overwrite = OverwritePolicy::Ask,
+        ];
+        for disable in disables {
+            let mut args = create_test_args();
+            args.output_destination.output = None;
+            args.output_destination.same_folder_as_input = true;
+            disable(&mut args);
+            assert!(!guards_in_place_overwrite(&args));
+        }
+
+        // Only warned about outside of a terminal
+        assert!(confirm_in_place_overwrite(2, false, true));
+    }
+
+    #[test]
+    fn test_build_strip_tags() {
+        assert!(build_strip_tags(&[], false, false).is_empty());
+        assert_eq!(build_strip_tags(&[], true, false), vec!["GPS".to_string()]);
+        assert_eq!(
+            build_strip_tags(&["MakerNote".to_string()], true, false),
+            vec!["MakerNote".to_string(), "GPS".to_string()]
+        );
+        assert_eq!(
+            build_strip_tags(&["gps".to_string()], true, false),
+            vec!["gps".to_string()]
+        );
+        assert_eq!(
+            build_strip_tags(&[], true, true),
+            vec!["GPS".to_string(), "Thumbnail".to_string()]
+        );
+        assert_eq!(
+            build_strip_tags(&["THUMBNAIL".to_string()], false, true),
+            vec!["THUMBNAIL".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_should_colorize() {
+        assert!(should_colorize(ColorMode::Auto, false, true, false));
+        assert!(!should_colorize(ColorMode::Auto, false, false, false));
+        assert!(!should_colorize(ColorMode::Auto, false, true, true));
+        assert!(!should_colorize(ColorMode::Auto, true, true, false));
+
+        assert!(should_colorize(ColorMode::Always, false, false, false));
+        assert!(should_colorize(ColorMode::Always, false, true, true));
+        assert!(should_colorize(ColorMode::Always, true, false, true));
+
+        assert!(!should_colorize(ColorMode::Never, false, true, false));
+        assert!(!should_colorize(ColorMode::Never, true, false, true));
+    }
+
+    #[test]
+    fn test_format_savings() {
+        // Shrink
+        assert_eq!(
+            format_savings(1000, 580),
+            ("-420 B".to_string(), "42.00% saved".to_string())
+        );
+        // Grow
+        assert_eq!(
+            format_savings(1000, 1050),
+            ("+50 B".to_string(), "5.00% larger".to_string())
+        );
+        // Unchanged
+        assert_eq!(
+            format_savings(1000, 1000),
+            ("0 B".to_string(), "0.00% saved".to_string())
+        );
+        // Zero-size original
+        assert_eq!(format_savings(0, 0), ("0 B".to_string(), "n/a".to_string()));
+        assert_eq!(format_savings(0, 100), ("+100 B".to_string(), "n/a".to_string()));
+
+        assert_eq!(format_saved_so_far(1000, 580), "Saved so far: -420 B [42.00% saved]");
+    }
+
+    #[test]
+    fn test_format_throughput() {
+        assert_eq!(
+            format_throughput(120, 350_000_000, Duration::from_secs(10)),
+            "Processed 120 files in 10.0s (12.0 files/s, 35.0 MB/s)"
+        );
+        assert_eq!(
+            format_throughput(3, 1_500_000, Duration::from_millis(500)),
+            "Processed 3 files in 0.5s (6.0 files/s, 3.0 MB/s)"
+        );
+        assert_eq!(
+            format_throughput(5, 1000, Duration::ZERO),
+            "Processed 5 files in 0.0s (0.0 files/s, 0.0 MB/s)"
+        );
+    }
+
     #[test]
     fn test_compression_stats_from_results() {
         let results = vec![
@@ -581,6 +1916,7 @@ mod tests {
                 original_size: 1000,
                 compressed_size: 800,
                 status: CompressionStatus::Success,
+                error_kind: None,
                 message: "".to_string(),
             },
             CompressionResult {
@@ -589,6 +1925,7 @@ mod tests {
                 original_size: 2000,
                 compressed_size: 2000,
                 status: CompressionStatus::Skipped,
+                error_kind: None,
                 message: "".to_string(),
             },
             CompressionResult {
@@ -597,6 +1934,7 @@ mod tests {
                 original_size: 500,
                 compressed_size: 0,
                 status: CompressionStatus::Error,
+                error_kind: Some(CompressionErrorKind::Encode),
                 message: "".to_string(),
             },
         ];
@@ -611,6 +1949,263 @@ mod tests {
         assert!((stats.savings_percent() - 20.0).abs() < 0.01);
     }
 
+    #[test]
+    fn test_keep_structure_with_explicit_files() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let inputs = [
+            "samples/level_1_0/j1.jpg",
+            "samples/level_1_0/level_2_0/p2.png",
+            "samples/level_1_1/w1.webp",
+        ]
+        .map(String::from);
+        let (base_path, input_files) = scan_files(&inputs, true, &ScanSettings::default());
+        let base_path = base_path.unwrap();
+        assert_eq!(base_path, std::path::absolute("samples").unwrap());
+
+        let mut args = create_test_args();
+        args.recursive = false;
+        args.keep_structure = true;
+        args.suffix = None;
+        args.format = OutputFormat::Original;
+        args.output_destination.output = Some(temp_dir.path().to_path_buf());
+        let options = build_compression_options(&args, &base_path);
+        let results = start_compression(&input_files, &options, true, None);
+
+        let outputs: Vec<PathBuf> = results.iter().map(|r| PathBuf::from(&r.output_path)).collect();
+        assert_eq!(
+            outputs,
+            vec![
+                temp_dir.path().join("level_1_0/j1.jpg"),
+                temp_dir.path().join("level_1_0/level_2_0/p2.png"),
+                temp_dir.path().join("level_1_1/w1.webp"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tiff_inputs() {
+        use tiff::encoder::{colortype, TiffEncoder};
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let input_dir = temp_dir.path().join("scans");
+        std::fs::create_dir(&input_dir).unwrap();
+        let mut pages = Vec::new();
+        {
+            let mut encoder = TiffEncoder::new(io::Cursor::new(&mut pages)).unwrap();
+            for value in [0, 100] {
+                encoder
+                    .write_image::<colortype::Gray8>(32, 16, &[value; 32 * 16])
+                    .unwrap();
+            }
+        }
+        std::fs::write(input_dir.join("pages.tif"), &pages).unwrap();
+        image::RgbImage::new(8, 8).save(input_dir.join("single.tiff")).unwrap();
+
+        let (base_path, input_files) = scan_files(
+            &[input_dir.to_string_lossy().to_string()],
+            true,
+            &ScanSettings::default(),
+        );
+        assert_eq!(
+            input_files,
+            vec![input_dir.join("pages.tif"), input_dir.join("single.tiff")]
+        );
+
+        let mut args = create_test_args();
+        args.compression.quality = None;
+        args.compression.max_size = None;
+        args.compression.lossless = true;
+        args.suffix = None;
+        args.format = OutputFormat::Original;
+        args.output_destination.output = Some(temp_dir.path().join("out"));
+        let options = build_compression_options(&args, &base_path.clone().unwrap());
+        let results = start_compression(&input_files, &options, false, None);
+        assert_eq!(results[0].message, "Recompressed 2 pages");
+        assert!(results
+            .iter()
+            .all(|result| matches!(result.status, CompressionStatus::Success)));
+
+        // Converting keeps the pages of multi-page files by skipping them
+        args.convert = vec![(OutputFormat::Tiff, OutputFormat::Png)];
+        let options = build_compression_options(&args, &base_path.unwrap());
+        let results = start_compression(&input_files, &options, false, None);
+        assert!(matches!(results[0].status, CompressionStatus::Skipped));
+        assert!(matches!(results[1].status, CompressionStatus::Success));
+        assert!(results[1].output_path.ends_with("single.png"));
+    }
+
+    #[test]
+    fn test_format_stats_line() {
+        let result = |original_size: u64, compressed_size: u64, status: CompressionStatus| CompressionResult {
+            original_path: "in.jpg".to_string(),
+            output_path: "out.jpg".to_string(),
+            original_size,
+            compressed_size,
+            status,
+            error_kind: None,
+            message: String::new(),
+        };
+        let results = vec![
+            result(1000, 800, CompressionStatus::Success),
+            result(2000, 1500, CompressionStatus::Success),
+            result(2000, 2000, CompressionStatus::Skipped),
+            result(500, 0, CompressionStatus::Error),
+        ];
+        assert_eq!(
+            format_stats_line(&results),
+            "files=4 success=2 skipped=1 errors=1 original=5500 compressed=4300 saved_pct=21.8"
+        );
+        assert_eq!(
+            format_stats_line(&[]),
+            "files=0 success=0 skipped=0 errors=0 original=0 compressed=0 saved_pct=0.0"
+        );
+    }
+
+    #[test]
+    fn test_format_scan_count() {
+        let inputs = ["samples".to_string()];
+        let count = |settings: ScanSettings| format_scan_count(&scan_files(&inputs, true, &settings).1, false);
+
+        // TIFF files are not picked up by the scan
+        assert_eq!(count(ScanSettings::default()), "3");
+        let recursive = || ScanSettings {
+            recursive: true,
+            ..Default::default()
+        };
+        assert_eq!(count(recursive()), "7");
+        assert_eq!(
+            count(ScanSettings {
+                max_depth: Some(1),
+                ..recursive()
+            }),
+            "5"
+        );
+        let excluded_directory = Some(std::fs::canonicalize("samples/level_1_0").unwrap());
+        assert_eq!(
+            count(ScanSettings {
+                excluded_directory,
+                ..recursive()
+            }),
+            "4"
+        );
+
+        let (_, input_files) = scan_files(&inputs, true, &ScanSettings::default());
+        let bytes: u64 = ["j0.JPG", "p0.png", "w0.webp"]
+            .iter()
+            .map(|file| std::fs::metadata(Path::new("samples").join(file)).unwrap().len())
+            .sum();
+        assert_eq!(
+            format_scan_count(&input_files, true),
+            format!("{{\"files\":3,\"bytes\":{bytes}}}")
+        );
+        assert_eq!(format_scan_count(&[], true), "{\"files\":0,\"bytes\":0}");
+    }
+
+    #[test]
+    fn test_list_only_mapping() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let (base_path, mut input_files) = scan_files(
+            &["samples/level_1_0".to_string()],
+            true,
+            &ScanSettings {
+                recursive: true,
+                ..Default::default()
+            },
+        );
+        input_files.sort();
+
+        let mut args = create_test_args();
+        args.keep_structure = true;
+        args.suffix = None;
+        args.format = OutputFormat::Original;
+        args.compression.quality = Some(vec![60]);
+        args.output_destination.output = Some(temp_dir.path().to_path_buf());
+        let options = build_compression_options(&args, &base_path.unwrap());
+        let lines: Vec<String> = plan_outputs(&input_files, &options)
+            .iter()
+            .map(format_planned_output)
+            .collect();
+
+        let input = |path: &str| std::path::absolute(Path::new("samples/level_1_0").join(path)).unwrap();
+        let output = |path: &str| temp_dir.path().join(path);
+        assert_eq!(
+            lines,
+            vec![
+                format!(
+                    "{} -> {} (jpeg, quality 60)",
+                    input("j1.jpg").display(),
+                    output("j1.jpg").display()
+                ),
+                format!(
+                    "{} -> {} (gif, quality 60)",
+                    input("level_2_0/level_3_0/g1.gif").display(),
+                    output("level_2_0/level_3_0/g1.gif").display()
+                ),
+                format!(
+                    "{} -> {} (png, quality 60)",
+                    input("level_2_0/p2.png").display(),
+                    output("level_2_0/p2.png").display()
+                ),
+            ]
+        );
+        // Nothing is written
+        assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_missing_inputs_are_reported() {
+        let existing = "samples/j0.JPG".to_string();
+        let missing = "samples/missing.jpg".to_string();
+        let (base_path, input_files) = scan_files(&[existing, missing.clone()], true, &ScanSettings::default());
+        assert_eq!(input_files.len(), 2);
+
+        let mut args = create_test_args();
+        args.output_destination.output = Some(PathBuf::from("output"));
+        let options = build_compression_options(&args, &base_path.unwrap());
+        let results = start_compression(&input_files, &options, true, None);
+
+        assert!(matches!(results[0].status, CompressionStatus::Success));
+        assert_eq!(results[1].original_path, missing);
+        assert!(matches!(results[1].status, CompressionStatus::Error));
+        assert_eq!(results[1].message, "File not found");
+        assert_eq!(get_exit_code(&results, false), FAILED_FILES_EXIT_CODE);
+        assert_eq!(get_exit_code(&results[..1], false), 0);
+        assert_eq!(get_exit_code(&[], false), 0);
+    }
+
+    #[test]
+    fn test_strict_exit_code() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let input_files = vec![
+            std::path::absolute("samples/j0.JPG").unwrap(),
+            std::path::absolute("samples/p0.png").unwrap(),
+        ];
+        let mut args = create_test_args();
+        args.output_destination.output = Some(temp_dir.path().to_path_buf());
+        let options = build_compression_options(&args, Path::new("samples"));
+        let clean_results = start_compression(&input_files, &options, true, None);
+        assert!(clean_results
+            .iter()
+            .all(|r| matches!(r.status, CompressionStatus::Success)));
+        assert_eq!(get_exit_code(&clean_results, false), 0);
+        assert_eq!(get_exit_code(&clean_results, true), 0);
+
+        // No file can save 100%
+        args.min_savings = Some(MinSavingsThreshold::Percentage(100.0));
+        let options = build_compression_options(&args, Path::new("samples"));
+        let skipped_results = start_compression(&input_files, &options, false, None);
+        assert!(skipped_results
+            .iter()
+            .all(|r| matches!(r.status, CompressionStatus::Skipped)));
+        assert_eq!(get_exit_code(&skipped_results, false), 0);
+        assert_eq!(get_exit_code(&skipped_results, true), SKIPPED_FILES_EXIT_CODE);
+
+        // Errors keep their own status
+        let mut results = skipped_results;
+        results[0].status = CompressionStatus::Error;
+        assert_eq!(get_exit_code(&results, true), FAILED_FILES_EXIT_CODE);
+    }
+
     #[test]
     fn test_compression_stats_empty() {
         let stats = CompressionStats::from_results(&[]);
@@ -631,6 +2226,7 @@ mod tests {
             original_size: 800,
             compressed_size: 1000,
             status: CompressionStatus::Success,
+            error_kind: None,
             message: "".to_string(),
         }];
 
@@ -647,6 +2243,7 @@ mod tests {
             original_size: 1000,
             compressed_size: 600,
             status: CompressionStatus::Success,
+            error_kind: None,
             message: "".to_string(),
         }];
 
@@ -695,6 +2292,7 @@ mod tests {
                 original_size: 100,
                 compressed_size: 80,
                 status: CompressionStatus::Success,
+                error_kind: None,
                 message: "".to_string(),
             },
             CompressionResult {
@@ -703,6 +2301,7 @@ mod tests {
                 original_size: 100,
                 compressed_size: 100,
                 status: CompressionStatus::Skipped,
+                error_kind: None,
                 message: "min savings not met".to_string(),
             },
             CompressionResult {
@@ -711,6 +2310,7 @@ mod tests {
                 original_size: 100,
                 compressed_size: 0,
                 status: CompressionStatus::Error,
+                error_kind: Some(CompressionErrorKind::Encode),
                 message: "read error".to_string(),
             },
         ];
@@ -721,6 +2321,8 @@ mod tests {
         assert_eq!(parsed["files"][0]["status"], "success");
         assert_eq!(parsed["files"][1]["status"], "skipped");
         assert_eq!(parsed["files"][2]["status"], "error");
+        assert!(parsed["files"][0]["error_kind"].is_null());
+        assert_eq!(parsed["files"][2]["error_kind"], "encode");
         assert_eq!(parsed["summary"]["success"], 1);
         assert_eq!(parsed["summary"]["skipped"], 1);
         assert_eq!(parsed["summary"]["errors"], 1);
@@ -0,0 +1,140 @@
+use std::path::Path;
+use std::sync::{Condvar, Mutex};
+
+/// Bytes per pixel assumed for a decoded image (RGBA, 8 bits per channel)
+const BYTES_PER_PIXEL: u64 = 4;
+
+/// Caps the estimated memory of the images being decoded at the same time.
+/// Workers wait for enough of the budget to be released before starting a decode, even when a thread is free.
+pub struct MemoryBudget {
+    limit: u64,
+    in_use: Mutex<u64>,
+    released: Condvar,
+}
+
+/// Part of the budget held while an image is being compressed, given back when dropped
+pub struct MemoryPermit<'a> {
+    budget: &'a MemoryBudget,
+    bytes: u64,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: u64) -> Self {
+        Self {
+            limit,
+            in_use: Mutex::new(0),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Blocks until `bytes` fit in the budget.
+    /// An image bigger than the whole budget is still admitted, alone, so that it can't wait forever.
+    pub fn acquire(&self, bytes: u64) -> MemoryPermit<'_> {
+        let mut in_use = self.in_use.lock().unwrap_or_else(|e| e.into_inner());
+        while !can_admit(*in_use, bytes, self.limit) {
+            in_use = self.released.wait(in_use).unwrap_or_else(|e| e.into_inner());
+        }
+        *in_use += bytes;
+
+        MemoryPermit { budget: self, bytes }
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    pub fn in_use(&self) -> u64 {
+        *self.in_use.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Drop for MemoryPermit<'_> {
+    fn drop(&mut self) {
+        let mut in_use = self.budget.in_use.lock().unwrap_or_else(|e| e.into_inner());
+        *in_use -= self.bytes;
+        self.budget.released.notify_all();
+    }
+}
+
+fn can_admit(in_use: u64, requested: u64, limit: u64) -> bool {
+    in_use == 0 || in_use.saturating_add(requested) <= limit
+}
+
+/// Memory needed to decode the image, estimated from the dimensions in its header.
+/// Unreadable headers count as 0, the decoder reports the error.
+pub fn estimate_decode_memory(path: &Path) -> u64 {
+    imagesize::size(path)
+        .map(|size| size.width as u64 * size.height as u64 * BYTES_PER_PIXEL)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_can_admit() {
+        // 4000x3000 RGBA images against a 100MB budget
+        let image = 4000 * 3000 * BYTES_PER_PIXEL;
+        let limit = 100_000_000;
+        assert!(can_admit(0, image, limit));
+        assert!(can_admit(image, image, limit));
+        assert!(!can_admit(2 * image, image, limit));
+
+        // Too big for the budget, only admitted alone
+        assert!(can_admit(0, 2 * limit, limit));
+        assert!(!can_admit(1, 2 * limit, limit));
+        assert!(can_admit(limit, 0, limit));
+    }
+
+    #[test]
+    fn test_acquire_and_release() {
+        let budget = MemoryBudget::new(100);
+        let first = budget.acquire(60);
+        let second = budget.acquire(40);
+        assert_eq!(budget.in_use(), 100);
+        drop(first);
+        assert_eq!(budget.in_use(), 40);
+        drop(second);
+        assert_eq!(budget.in_use(), 0);
+
+        let oversized = budget.acquire(500);
+        assert_eq!(budget.in_use(), 500);
+        drop(oversized);
+        assert_eq!(budget.in_use(), 0);
+    }
+
+    #[test]
+    fn test_budget_is_never_exceeded() {
+        let budget = Arc::new(MemoryBudget::new(100));
+        let peak = Arc::new(AtomicU64::new(0));
+        let workers: Vec<_> = [30, 50, 70, 20, 40, 60, 10, 90]
+            .into_iter()
+            .map(|bytes| {
+                let (budget, peak) = (budget.clone(), peak.clone());
+                thread::spawn(move || {
+                    let _permit = budget.acquire(bytes);
+                    peak.fetch_max(budget.in_use(), Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(10));
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+
+        assert!(peak.load(Ordering::SeqCst) <= 100);
+        assert_eq!(budget.in_use(), 0);
+    }
+
+    #[test]
+    fn test_estimate_decode_memory() {
+        let estimate = estimate_decode_memory(Path::new("samples/j0.JPG"));
+        assert_eq!(estimate, 2000 * 3000 * BYTES_PER_PIXEL);
+        assert_eq!(estimate_decode_memory(Path::new("samples/missing.jpg")), 0);
+    }
+}
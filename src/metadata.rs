@@ -0,0 +1,414 @@
+use exif::experimental::Writer;
+use exif::{Context, Field, In, Tag};
+use std::error::Error;
+use std::io::Cursor;
+
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+const APP1_MARKER: u8 = 0xE1;
+const APP2_MARKER: u8 = 0xE2;
+const ICC_HEADER: &[u8] = b"ICC_PROFILE\0";
+/// Bytes of profile in an APP2 segment, after its length, header, sequence number and number of segments
+const ICC_CHUNK_LENGTH: usize = MAX_SEGMENT_LENGTH - 2 - ICC_HEADER.len() - 2;
+const SOS_MARKER: u8 = 0xDA;
+const MAX_SEGMENT_LENGTH: usize = u16::MAX as usize;
+
+/// Tag group name matching every GPS field
+pub const GPS_TAG_GROUP: &str = "GPS";
+/// Tag group name matching the embedded thumbnail and the fields describing it (IFD1)
+pub const THUMBNAIL_TAG_GROUP: &str = "Thumbnail";
+
+/// Removes the given EXIF tags from a JPEG, keeping every other metadata field.
+/// Tags are matched by name (case-insensitive); `GPS` matches the whole GPS IFD and `Thumbnail` the whole IFD1.
+pub fn strip_exif_tags(jpeg: &[u8], tags: &[String]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let Some((start, end)) = find_exif_segment(jpeg)? else {
+        return Ok(jpeg.to_vec());
+    };
+
+    let tiff = &jpeg[start + 4 + EXIF_HEADER.len()..end];
+    let exif = exif::Reader::new().read_raw(tiff.to_vec())?;
+    let little_endian = tiff.starts_with(b"II");
+
+    let mut writer = Writer::new();
+    let mut kept_fields = 0;
+    for field in exif.fields() {
+        if is_stripped(field, tags) {
+            continue;
+        }
+        writer.push_field(field);
+        if !is_structural(field) {
+            kept_fields += 1;
+        }
+    }
+
+    let strip_thumbnail = tags.iter().any(|tag| tag.eq_ignore_ascii_case(THUMBNAIL_TAG_GROUP));
+    if let (false, Some(thumbnail)) = (strip_thumbnail, get_thumbnail(&exif)) {
+        writer.set_jpeg(thumbnail, In::THUMBNAIL);
+    }
+
+    let mut output = Vec::with_capacity(jpeg.len());
+    output.extend_from_slice(&jpeg[..start]);
+    if kept_fields > 0 {
+        let mut new_tiff = Cursor::new(Vec::new());
+        writer.write(&mut new_tiff, little_endian)?;
+        let new_tiff = new_tiff.into_inner();
+
+        let segment_length = 2 + EXIF_HEADER.len() + new_tiff.len();
+        if segment_length > MAX_SEGMENT_LENGTH {
+            return Err("EXIF data too large".into());
+        }
+        output.extend_from_slice(&[0xFF, APP1_MARKER]);
+        output.extend_from_slice(&(segment_length as u16).to_be_bytes());
+        output.extend_from_slice(EXIF_HEADER);
+        output.extend_from_slice(&new_tiff);
+    }
+    output.extend_from_slice(&jpeg[end..]);
+
+    Ok(output)
+}
+
+/// Writes `tiff`, the EXIF data of another image, into a JPEG. An existing EXIF segment is replaced, otherwise the new
+/// one follows the JFIF segment, if any.
+pub fn insert_exif(jpeg: &[u8], tiff: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let segment_length = 2 + EXIF_HEADER.len() + tiff.len();
+    if segment_length > MAX_SEGMENT_LENGTH {
+        return Err("EXIF data too large".into());
+    }
+    let (start, end) = match find_exif_segment(jpeg)? {
+        Some(range) => range,
+        None => {
+            let after_jfif = match jpeg.get(2..4) {
+                Some([0xFF, 0xE0]) if jpeg.len() >= 6 => 4 + u16::from_be_bytes([jpeg[4], jpeg[5]]) as usize,
+                _ => 2,
+            };
+            (after_jfif.min(jpeg.len()), after_jfif.min(jpeg.len()))
+        }
+    };
+
+    let mut output = Vec::with_capacity(jpeg.len() + segment_length + 2);
+    output.extend_from_slice(&jpeg[..start]);
+    output.extend_from_slice(&[0xFF, APP1_MARKER]);
+    output.extend_from_slice(&(segment_length as u16).to_be_bytes());
+    output.extend_from_slice(EXIF_HEADER);
+    output.extend_from_slice(tiff);
+    output.extend_from_slice(&jpeg[end..]);
+    Ok(output)
+}
+
+fn is_stripped(field: &Field, tags: &[String]) -> bool {
+    let name = field.tag.to_string();
+    tags.iter().any(|tag| {
+        (tag.eq_ignore_ascii_case(GPS_TAG_GROUP) && field.tag.context() == Context::Gps)
+            || (tag.eq_ignore_ascii_case(THUMBNAIL_TAG_GROUP) && field.ifd_num == In::THUMBNAIL)
+            || tag.eq_ignore_ascii_case(&name)
+    })
+}
+
+/// IFD pointers and thumbnail offsets are regenerated by the writer and do not count as metadata
+fn is_structural(field: &Field) -> bool {
+    matches!(
+        field.tag,
+        Tag::ExifIFDPointer
+            | Tag::GPSInfoIFDPointer
+            | Tag::InteropIFDPointer
+            | Tag::JPEGInterchangeFormat
+            | Tag::JPEGInterchangeFormatLength
+    )
+}
+
+fn get_thumbnail(exif: &exif::Exif) -> Option<&[u8]> {
+    let offset = exif
+        .get_field(Tag::JPEGInterchangeFormat, In::THUMBNAIL)?
+        .value
+        .get_uint(0)? as usize;
+    let length = exif
+        .get_field(Tag::JPEGInterchangeFormatLength, In::THUMBNAIL)?
+        .value
+        .get_uint(0)? as usize;
+
+    exif.buf().get(offset..offset.checked_add(length)?)
+}
+
+/// Returns the EXIF data of a JPEG, in the form `insert_exif` takes
+pub fn extract_exif(jpeg: &[u8]) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    Ok(find_exif_segment(jpeg)?.map(|(start, end)| jpeg[start + 4 + EXIF_HEADER.len()..end].to_vec()))
+}
+
+/// Returns the ICC profile of a JPEG, joined from its APP2 segments
+pub fn extract_icc_profile(jpeg: &[u8]) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    let mut chunks: Vec<(u8, &[u8])> = jpeg_segments(jpeg)?
+        .into_iter()
+        .filter(|&(marker, start, end)| marker == APP2_MARKER && is_icc_segment(&jpeg[start..end]))
+        .map(|(_, start, end)| {
+            let data = &jpeg[start + 4 + ICC_HEADER.len()..end];
+            (data[0], &data[2..])
+        })
+        .collect();
+    if chunks.is_empty() {
+        return Ok(None);
+    }
+
+    chunks.sort_by_key(|&(sequence_number, _)| sequence_number);
+    let chunks: Vec<&[u8]> = chunks.into_iter().map(|(_, chunk)| chunk).collect();
+    Ok(Some(chunks.concat()))
+}
+
+/// Writes an ICC profile into a JPEG, split into as many APP2 segments as needed after the JFIF and EXIF segments.
+/// An existing profile is replaced.
+pub fn insert_icc_profile(jpeg: &[u8], icc: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let chunk_count = u8::try_from(icc.len().div_ceil(ICC_CHUNK_LENGTH)).map_err(|_| "ICC profile too large")?;
+    let segments = jpeg_segments(jpeg)?;
+    let mut insert_at = 2;
+    for &(marker, start, end) in &segments {
+        if start != insert_at || !matches!(marker, 0xE0 | APP1_MARKER) {
+            break;
+        }
+        insert_at = end;
+    }
+    let existing: Vec<(usize, usize)> = segments
+        .iter()
+        .filter(|&&(marker, start, end)| marker == APP2_MARKER && is_icc_segment(&jpeg[start..end]))
+        .map(|&(_, start, end)| (start, end))
+        .collect();
+
+    let mut output = Vec::with_capacity(jpeg.len() + icc.len() + chunk_count as usize * 18);
+    output.extend_from_slice(&jpeg[..insert_at]);
+    for (index, chunk) in icc.chunks(ICC_CHUNK_LENGTH).enumerate() {
+        let segment_length = 2 + ICC_HEADER.len() + 2 + chunk.len();
+        output.extend_from_slice(&[0xFF, APP2_MARKER]);
+        output.extend_from_slice(&(segment_length as u16).to_be_bytes());
+        output.extend_from_slice(ICC_HEADER);
+        output.extend_from_slice(&[index as u8 + 1, chunk_count]);
+        output.extend_from_slice(chunk);
+    }
+    let mut position = insert_at;
+    for (start, end) in existing {
+        output.extend_from_slice(&jpeg[position..start]);
+        position = end;
+    }
+    output.extend_from_slice(&jpeg[position..]);
+    Ok(output)
+}
+
+fn is_icc_segment(segment: &[u8]) -> bool {
+    segment.len() >= 6 + ICC_HEADER.len() && segment[4..].starts_with(ICC_HEADER)
+}
+
+/// Returns the byte range of the APP1 Exif segment, marker included
+fn find_exif_segment(jpeg: &[u8]) -> Result<Option<(usize, usize)>, Box<dyn Error>> {
+    Ok(jpeg_segments(jpeg)?
+        .into_iter()
+        .find(|&(marker, start, end)| marker == APP1_MARKER && jpeg[start + 4..end].starts_with(EXIF_HEADER))
+        .map(|(_, start, end)| (start, end)))
+}
+
+/// Returns the marker and the byte range, marker included, of the segments before the image data
+fn jpeg_segments(jpeg: &[u8]) -> Result<Vec<(u8, usize, usize)>, Box<dyn Error>> {
+    if !jpeg.starts_with(&[0xFF, 0xD8]) {
+        return Err("Not a JPEG file".into());
+    }
+
+    let mut segments = vec![];
+    let mut position = 2;
+    while position + 4 <= jpeg.len() {
+        if jpeg[position] != 0xFF {
+            return Err("Invalid JPEG marker".into());
+        }
+        let marker = jpeg[position + 1];
+        if marker == SOS_MARKER {
+            break;
+        }
+        if marker == 0x01 || (0xD0..=0xD7).contains(&marker) {
+            position += 2;
+            continue;
+        }
+
+        let length = u16::from_be_bytes([jpeg[position + 2], jpeg[position + 3]]) as usize;
+        let end = position + 2 + length;
+        if length < 2 || end > jpeg.len() {
+            return Err("Invalid JPEG segment length".into());
+        }
+        segments.push((marker, position, end));
+        position = end;
+    }
+
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exif::Value;
+    use image::RgbImage;
+
+    fn build_jpeg_with_exif(fields: &[Field]) -> Vec<u8> {
+        build_jpeg_with_exif_thumbnail(fields, None)
+    }
+
+    fn build_jpeg_with_exif_thumbnail(fields: &[Field], thumbnail: Option<&[u8]>) -> Vec<u8> {
+        let mut jpeg = Vec::new();
+        RgbImage::new(8, 8)
+            .write_to(&mut Cursor::new(&mut jpeg), image::ImageFormat::Jpeg)
+            .unwrap();
+
+        let mut writer = Writer::new();
+        for field in fields {
+            writer.push_field(field);
+        }
+        if let Some(thumbnail) = thumbnail {
+            writer.set_jpeg(thumbnail, In::THUMBNAIL);
+        }
+        let mut tiff = Cursor::new(Vec::new());
+        writer.write(&mut tiff, false).unwrap();
+        let tiff = tiff.into_inner();
+
+        let mut output = jpeg[..2].to_vec();
+        output.extend_from_slice(&[0xFF, APP1_MARKER]);
+        output.extend_from_slice(&((2 + EXIF_HEADER.len() + tiff.len()) as u16).to_be_bytes());
+        output.extend_from_slice(EXIF_HEADER);
+        output.extend_from_slice(&tiff);
+        output.extend_from_slice(&jpeg[2..]);
+        output
+    }
+
+    fn ascii_field(tag: Tag, value: &str) -> Field {
+        Field {
+            tag,
+            ifd_num: In::PRIMARY,
+            value: Value::Ascii(vec![value.as_bytes().to_vec()]),
+        }
+    }
+
+    fn read_exif(jpeg: &[u8]) -> Option<exif::Exif> {
+        exif::Reader::new().read_from_container(&mut Cursor::new(jpeg)).ok()
+    }
+
+    fn test_fields() -> Vec<Field> {
+        vec![
+            ascii_field(Tag::Make, "Caesium"),
+            ascii_field(Tag::Model, "Test Camera"),
+            ascii_field(Tag::BodySerialNumber, "123456"),
+            ascii_field(Tag::GPSLatitudeRef, "N"),
+            ascii_field(Tag::GPSLongitudeRef, "E"),
+        ]
+    }
+
+    #[test]
+    fn test_strip_gps_tags() {
+        let jpeg = build_jpeg_with_exif(&test_fields());
+        let exif = read_exif(&jpeg).unwrap();
+        assert!(exif.get_field(Tag::GPSLatitudeRef, In::PRIMARY).is_some());
+
+        let stripped = strip_exif_tags(&jpeg, &[String::from("gps")]).unwrap();
+        let exif = read_exif(&stripped).unwrap();
+        assert!(exif.get_field(Tag::GPSLatitudeRef, In::PRIMARY).is_none());
+        assert!(exif.get_field(Tag::GPSLongitudeRef, In::PRIMARY).is_none());
+        assert!(exif.get_field(Tag::Make, In::PRIMARY).is_some());
+        assert!(exif.get_field(Tag::Model, In::PRIMARY).is_some());
+        assert!(exif.get_field(Tag::BodySerialNumber, In::PRIMARY).is_some());
+        assert!(image::load_from_memory(&stripped).is_ok());
+    }
+
+    #[test]
+    fn test_strip_named_tags() {
+        let jpeg = build_jpeg_with_exif(&test_fields());
+
+        let stripped = strip_exif_tags(&jpeg, &[String::from("BodySerialNumber"), String::from("model")]).unwrap();
+        let exif = read_exif(&stripped).unwrap();
+        assert!(exif.get_field(Tag::BodySerialNumber, In::PRIMARY).is_none());
+        assert!(exif.get_field(Tag::Model, In::PRIMARY).is_none());
+        assert!(exif.get_field(Tag::Make, In::PRIMARY).is_some());
+        assert!(exif.get_field(Tag::GPSLatitudeRef, In::PRIMARY).is_some());
+    }
+
+    #[test]
+    fn test_strip_thumbnail() {
+        let mut thumbnail = Vec::new();
+        RgbImage::new(4, 4)
+            .write_to(&mut Cursor::new(&mut thumbnail), image::ImageFormat::Jpeg)
+            .unwrap();
+        let mut fields = test_fields();
+        fields.push(Field {
+            tag: Tag::Compression,
+            ifd_num: In::THUMBNAIL,
+            value: Value::Short(vec![6]),
+        });
+        let jpeg = build_jpeg_with_exif_thumbnail(&fields, Some(&thumbnail));
+        let exif = read_exif(&jpeg).unwrap();
+        assert_eq!(get_thumbnail(&exif), Some(thumbnail.as_slice()));
+
+        // Stripping other tags keeps the thumbnail
+        let stripped = strip_exif_tags(&jpeg, &[String::from("GPS")]).unwrap();
+        let exif = read_exif(&stripped).unwrap();
+        assert_eq!(get_thumbnail(&exif), Some(thumbnail.as_slice()));
+
+        let stripped = strip_exif_tags(&jpeg, &[String::from("thumbnail")]).unwrap();
+        assert!(stripped.len() <= jpeg.len() - thumbnail.len());
+        let exif = read_exif(&stripped).unwrap();
+        assert_eq!(get_thumbnail(&exif), None);
+        assert!(exif.fields().all(|field| field.ifd_num != In::THUMBNAIL));
+        assert!(exif.get_field(Tag::Make, In::PRIMARY).is_some());
+        assert!(exif.get_field(Tag::Model, In::PRIMARY).is_some());
+        assert!(exif.get_field(Tag::GPSLatitudeRef, In::PRIMARY).is_some());
+        assert!(image::load_from_memory(&stripped).is_ok());
+    }
+
+    #[test]
+    fn test_strip_all_tags_removes_segment() {
+        let jpeg = build_jpeg_with_exif(&[ascii_field(Tag::GPSLatitudeRef, "N")]);
+
+        let stripped = strip_exif_tags(&jpeg, &[String::from("GPS")]).unwrap();
+        assert_eq!(find_exif_segment(&stripped).unwrap(), None);
+        assert!(image::load_from_memory(&stripped).is_ok());
+    }
+
+    #[test]
+    fn test_insert_exif() {
+        let mut jpeg = Vec::new();
+        RgbImage::new(8, 8)
+            .write_to(&mut Cursor::new(&mut jpeg), image::ImageFormat::Jpeg)
+            .unwrap();
+        let source = build_jpeg_with_exif(&test_fields());
+        let (start, end) = find_exif_segment(&source).unwrap().unwrap();
+        let tiff = &source[start + 4 + EXIF_HEADER.len()..end];
+
+        let with_exif = insert_exif(&jpeg, tiff).unwrap();
+        let exif = read_exif(&with_exif).unwrap();
+        assert!(exif.get_field(Tag::Model, In::PRIMARY).is_some());
+        assert!(image::load_from_memory(&with_exif).is_ok());
+
+        // A second insertion replaces the first segment
+        let replaced = insert_exif(&with_exif, tiff).unwrap();
+        assert_eq!(replaced, with_exif);
+        assert!(insert_exif(b"not a jpeg", tiff).is_err());
+    }
+
+    #[test]
+    fn test_icc_profile() {
+        let mut jpeg = Vec::new();
+        RgbImage::new(8, 8)
+            .write_to(&mut Cursor::new(&mut jpeg), image::ImageFormat::Jpeg)
+            .unwrap();
+        assert_eq!(extract_icc_profile(&jpeg).unwrap(), None);
+
+        // Split into two segments
+        let icc: Vec<u8> = (0..ICC_CHUNK_LENGTH + 100).map(|i| i as u8).collect();
+        let with_icc = insert_icc_profile(&jpeg, &icc).unwrap();
+        assert_eq!(extract_icc_profile(&with_icc).unwrap(), Some(icc));
+        assert!(image::load_from_memory(&with_icc).is_ok());
+
+        let replaced = insert_icc_profile(&with_icc, b"profile").unwrap();
+        assert_eq!(extract_icc_profile(&replaced).unwrap(), Some(b"profile".to_vec()));
+        assert_eq!(replaced.len(), jpeg.len() + 4 + ICC_HEADER.len() + 2 + 7);
+    }
+
+    #[test]
+    fn test_strip_without_exif() {
+        let mut jpeg = Vec::new();
+        RgbImage::new(8, 8)
+            .write_to(&mut Cursor::new(&mut jpeg), image::ImageFormat::Jpeg)
+            .unwrap();
+
+        assert_eq!(strip_exif_tags(&jpeg, &[String::from("GPS")]).unwrap(), jpeg);
+        assert!(strip_exif_tags(b"not a jpeg", &[String::from("GPS")]).is_err());
+    }
+}
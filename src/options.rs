@@ -1,3 +1,4 @@
+use crate::quality_rules::QualityRule;
 use bytesize::ByteSize;
 use clap::{Args, Parser, ValueEnum};
 use std::path::PathBuf;
@@ -10,6 +11,48 @@ pub enum MinSavingsThreshold {
     Bytes(u64),
 }
 
+/// Number of parallel jobs given with `--threads`
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Threads {
+    /// Fixed count, 0 meaning all the available processors
+    Count(u32),
+    /// Picked from the number and sizes of the scanned files (experimental)
+    Adaptive,
+}
+
+/// Names of the `--flatten` outputs, parsed from a template like `{:04}` or `img_{:03}`
+#[derive(Clone, PartialEq, Debug)]
+pub struct NumberFormat {
+    prefix: String,
+    width: usize,
+    zero_pad: bool,
+    suffix: String,
+}
+
+impl NumberFormat {
+    pub fn format(&self, number: usize) -> String {
+        let width = self.width;
+        let number = if self.zero_pad {
+            format!("{number:0width$}")
+        } else {
+            format!("{number:width$}")
+        };
+        format!("{}{number}{}", self.prefix, self.suffix)
+    }
+}
+
+/// `{:04}`, e.g. `0001`
+impl Default for NumberFormat {
+    fn default() -> Self {
+        Self {
+            prefix: String::new(),
+            width: 4,
+            zero_pad: true,
+            suffix: String::new(),
+        }
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
 pub enum OverwritePolicy {
     /// Always overwrite existing files
@@ -18,6 +61,128 @@ pub enum OverwritePolicy {
     Never,
     /// Overwrite only if the existing file is bigger
     Bigger,
+    /// Overwrite only if the existing file is smaller
+    Smaller,
+    /// Ask before overwriting each existing file (never overwrite when not attached to a terminal)
+    Ask,
+}
+
+impl OverwritePolicy {
+    /// Whether an existing file of `existing_size` bytes must be kept instead of being replaced by `new_size` bytes
+    pub fn keeps_existing(&self, existing_size: u64, new_size: u64) -> bool {
+        match self {
+            OverwritePolicy::All => false,
+            OverwritePolicy::Never => true,
+            OverwritePolicy::Bigger => existing_size <= new_size,
+            OverwritePolicy::Smaller => existing_size >= new_size,
+            // The answer is given before compressing, this only applies when nobody can be asked
+            OverwritePolicy::Ask => true,
+        }
+    }
+
+    /// Whether the decision depends on the size of the existing file
+    pub fn compares_size(&self) -> bool {
+        matches!(self, OverwritePolicy::Bigger | OverwritePolicy::Smaller)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Debug)]
+pub enum CollisionPolicy {
+    /// Add a counter to the filename, e.g. `photo_1.jpg`
+    Rename,
+    /// Fail the file
+    Error,
+    /// Let the last file overwrite the previous ones
+    Overwrite,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Debug)]
+pub enum ExtensionCase {
+    /// Lowercase extensions, e.g. `photo.jpg`
+    Lower,
+    /// Uppercase extensions, e.g. `photo.JPG`
+    Upper,
+    /// Keep the case of the input extension
+    Preserve,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Debug)]
+pub enum Flip {
+    /// Mirror the image left to right
+    #[value(name = "h", alias = "horizontal")]
+    Horizontal,
+    /// Mirror the image top to bottom
+    #[value(name = "v", alias = "vertical")]
+    Vertical,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Debug)]
+pub enum FitMode {
+    /// Fill the box, cropping the parts of the image outside of it
+    Cover,
+    /// Scale the image down to fit within the box
+    Contain,
+    /// Fit within the box, then fill the rest of it with --pad-color
+    Pad,
+}
+
+/// How --fit resizes the image
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Fit {
+    /// Scale the image down to fit within a WIDTHxHEIGHT box
+    Within(u32, u32),
+    /// Fit the image to the box of --width and --height
+    Mode(FitMode),
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Debug)]
+pub enum Gravity {
+    Center,
+    North,
+    South,
+    East,
+    West,
+    Northeast,
+    Northwest,
+    Southeast,
+    Southwest,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Debug)]
+pub enum ResizeFilter {
+    /// Sharpest, the filter of libcaesium
+    Lanczos3,
+    /// Slightly softer and faster
+    Catmullrom,
+    /// Bilinear, soft and fast
+    Triangle,
+    /// Keeps hard pixel edges, e.g. for pixel art
+    Nearest,
+}
+
+/// Metric reached by --target-value
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Debug)]
+pub enum TargetMetric {
+    /// Structural similarity to the original [0-1], the same search as --target-ssim
+    Ssim,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Debug)]
+pub enum ProgressUnit {
+    /// Advance by one for each file
+    Files,
+    /// Advance by the size of each input file, for a steadier ETA when sizes vary
+    Bytes,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Debug)]
+pub enum ColorMode {
+    /// Color the output when printing to a terminal, unless `NO_COLOR` is set
+    Auto,
+    /// Always color the output
+    Always,
+    /// Never color the output
+    Never,
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
@@ -27,6 +192,9 @@ pub enum OutputFormat {
     Gif,
     Webp,
     Tiff,
+    Avif,
+    Jxl,
+    Auto,
     Original,
 }
 
@@ -44,15 +212,47 @@ pub enum JpegChromaSubsampling {
     Auto,
 }
 
+/// Compressions the `tiff` encoder can write. It reads JPEG and Zstandard TIFF files but can't write them.
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Debug)]
+pub enum TiffCompression {
+    None,
+    Lzw,
+    Deflate,
+}
+
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 pub struct CommandLineArgs {
     #[command(flatten)]
     pub compression: Compression,
 
+    /// Use a different quality for the files matching a glob, relative to the input folder, e.g. 'hero/=95' or
+    /// '*.png=60' (can be repeated, the first matching glob wins, other files use --quality)
+    #[arg(long, value_name = "GLOB=QUALITY", value_parser = quality_rule_validator, conflicts_with_all = &["lossless", "optimize", "max_size", "target_ssim", "target_value", "bpp_target", "total_budget"])]
+    pub quality_for: Vec<QualityRule>,
+
+    #[command(flatten)]
+    pub format_quality: FormatQuality,
+
     #[command(flatten)]
     pub resize: Resize,
 
+    /// Rotate the image clockwise by this angle [0, 90, 180, 270], after applying its EXIF orientation
+    #[arg(long, value_name = "DEGREES", value_parser = rotate_validator)]
+    pub rotate: Option<u16>,
+
+    /// Mirror the image horizontally (h) or vertically (v), after the rotation
+    #[arg(long, value_enum)]
+    pub flip: Option<Flip>,
+
+    /// Crop a WIDTHxHEIGHT region of the image (e.g. 800x800), after the rotation and before resizing
+    #[arg(long, value_name = "WIDTHxHEIGHT", value_parser = dimensions_validator)]
+    pub crop: Option<(u32, u32)>,
+
+    /// Side or corner of the image kept by --crop or --fit cover, or where --fit pad places it [default: center]
+    #[arg(long, value_enum)]
+    pub gravity: Option<Gravity>,
+
     #[command(flatten)]
     pub output_destination: OutputDestination,
 
@@ -60,10 +260,22 @@ pub struct CommandLineArgs {
     #[arg(long, value_enum, default_value = "original")]
     pub format: OutputFormat,
 
+    /// Convert only the files of a given format, e.g. 'png=webp' (can be repeated, other formats are kept)
+    #[arg(long, value_name = "FROM=TO", value_parser = format_conversion_validator, conflicts_with = "format")]
+    pub convert: Vec<(OutputFormat, OutputFormat)>,
+
     /// PNG optimization level [0-6], higher values provide better compression
     #[arg(long, default_value = "3", value_parser = png_opt_level_validator)]
     pub png_opt_level: u8,
 
+    /// AVIF encoder speed [1-10], lower values are slower but give smaller files
+    #[arg(long, value_name = "SPEED", default_value = "6", value_parser = avif_speed_validator)]
+    pub avif_speed: u8,
+
+    /// Compression used inside TIFF files, 'none' and 'lzw' are the most widely readable
+    #[arg(long, value_enum, default_value = "deflate")]
+    pub tiff_compression: TiffCompression,
+
     /// Chroma subsampling for JPEG files
     #[arg(long, value_enum, default_value = "auto")]
     pub jpeg_chroma_subsampling: JpegChromaSubsampling,
@@ -76,37 +288,164 @@ pub struct CommandLineArgs {
     #[arg(long)]
     pub zopfli: bool,
 
+    /// Encode WebP outputs in near-lossless mode at this level [0-100], lower values give smaller files (100 is
+    /// lossless). Other formats use the compression options
+    #[arg(long, value_parser = webp_near_lossless_validator, conflicts_with_all = &["lossless", "max_size", "target_ssim", "target_value", "bpp_target", "total_budget"])]
+    pub webp_near_lossless: Option<u8>,
+
+    /// WebP encoder effort [0-6], higher values are slower but give smaller files
+    #[arg(long, value_name = "EFFORT", value_parser = webp_effort_validator, conflicts_with_all = &["max_size", "target_ssim", "target_value", "bpp_target", "total_budget"])]
+    pub webp_effort: Option<u8>,
+
+    /// Compress the largest JPEG preview embedded in CR2, NEF and ARW files instead of skipping them. The previews are
+    /// written as JPEG unless --format is set
+    #[arg(long)]
+    pub raw_preview: bool,
+
     /// Keep EXIF metadata during compression
     #[arg(short, long)]
     pub exif: bool,
 
+    /// Remove an EXIF tag by name when keeping metadata (e.g. MakerNote, BodySerialNumber, GPS for all GPS tags,
+    /// Thumbnail for the embedded thumbnail). Can be repeated. JPEG only
+    #[arg(long = "strip-tag", value_name = "TAG")]
+    pub strip_tags: Vec<String>,
+
+    /// Remove GPS location tags when keeping metadata (same as --strip-tag GPS)
+    #[arg(long)]
+    pub strip_gps: bool,
+
+    /// Remove the embedded EXIF thumbnail when keeping metadata (same as --strip-tag Thumbnail)
+    #[arg(long)]
+    pub strip_thumbnail: bool,
+
     /// Preserve original file timestamps
     #[arg(long)]
     pub keep_dates: bool,
 
     /// Strips ICC profile info on JPG files, ignoring the -e flag
-    #[arg(long)]
+    #[arg(long, conflicts_with = "keep_icc")]
     pub strip_icc: bool,
 
+    /// Keep the ICC profile of JPG files, even with --strip-all
+    #[arg(long)]
+    pub keep_icc: bool,
+
+    /// Keep all the metadata the output format supports (EXIF, ICC, XMP, IPTC), same as -e
+    #[arg(long, conflicts_with = "strip_all")]
+    pub keep_all: bool,
+
+    /// Remove all the metadata, ICC profile included. -e and --keep-icc still keep their part
+    #[arg(long)]
+    pub strip_all: bool,
+
+    /// Set the resolution metadata of JPEG, PNG and TIFF outputs, in dots per inch [1-65535]
+    #[arg(long, value_parser = dpi_validator)]
+    pub dpi: Option<u32>,
+
+    /// Write a comment in the metadata of JPEG, PNG and WebP outputs, kept even without -e
+    #[arg(long)]
+    pub comment: Option<String>,
+
+    /// Write the name and version of caesium-clt in the metadata of JPEG, PNG and WebP outputs
+    #[arg(long)]
+    pub software_tag: bool,
+
     /// Add suffix to output filenames
     #[arg(long)]
     pub suffix: Option<String>,
 
+    /// CSV file of input_path,output_name lines giving the output names of the listed inputs, without extension
+    #[arg(long, value_name = "CSV", conflicts_with = "number_format")]
+    pub rename_map: Option<PathBuf>,
+
     /// Scan subfolders recursively when input is a directory
     #[arg(short = 'R', long)]
     pub recursive: bool,
 
-    /// Preserve directory structure (requires -R/--recursive)
+    /// Levels of subfolders scanned with -R, 0 meaning only the files of the input folders
+    #[arg(long)]
+    pub max_depth: Option<usize>,
+
+    /// Follow symbolic links to directories while scanning (cycles are detected and skipped)
+    #[arg(long)]
+    pub follow_symlinks: bool,
+
+    /// Skip the files and folders matched by .gitignore and .ignore files while scanning folders
+    #[arg(long)]
+    pub use_ignore: bool,
+
+    /// Preserve the directory structure of the inputs, relative to the folder containing all of them
     #[arg(short = 'S', long)]
     pub keep_structure: bool,
 
+    /// Write all the outputs in the output folder with sequential names (0001.jpg, 0002.jpg...), in sorted input order
+    #[arg(long, conflicts_with = "keep_structure")]
+    pub flatten: bool,
+
+    /// Template of the --flatten names, with one {} placeholder for the number (e.g. {:04}, img_{:03})
+    #[arg(long, value_name = "FORMAT", value_parser = number_format_validator, requires = "flatten")]
+    pub number_format: Option<NumberFormat>,
+
+    /// Write a JSON file mapping each input to the srcset attribute listing its --sizes or --widths outputs
+    #[arg(long, value_name = "FILE")]
+    pub srcset: Option<PathBuf>,
+
+    /// Write a JSON file mapping each input to its outputs, with their width, height and size in bytes
+    #[arg(long, value_name = "FILE")]
+    pub manifest: Option<PathBuf>,
+
     /// Simulate compression without writing files
     #[arg(long, short, default_value = "false")]
     pub dry_run: bool,
 
-    /// Number of parallel jobs (0 = auto-detect, max = available processors)
+    /// Print the output path, format and quality of each input, then exit without compressing anything
+    #[arg(long, conflicts_with_all = &["json", "dry_run"])]
+    pub list_only: bool,
+
+    /// Print the number of files found by the scan, then exit without compressing anything. With --json, their total
+    /// size in bytes is printed too. No compression option or output is needed
+    #[arg(long, conflicts_with_all = &["list_only", "dry_run"])]
+    pub count_only: bool,
+
+    /// Print only a line of space-separated key=value totals once done, e.g. 'files=3 success=2 skipped=1 errors=0
+    /// original=5000 compressed=3000 saved_pct=40.0', without progress bar or per-file output
+    #[arg(long, conflicts_with_all = &["json", "list_only", "count_only", "histogram"])]
+    pub stats_only: bool,
+
+    /// Number of parallel jobs (0 = auto-detect, max = available processors), or 'adaptive' to pick it from the sizes
+    /// of the files (experimental)
+    #[arg(long, default_value = "0", value_parser = threads_validator)]
+    pub threads: Threads,
+
+    /// Maximum time in seconds allowed to compress a single file (0 = no timeout)
+    #[arg(long)]
+    pub timeout: Option<u64>,
+
+    /// Maximum memory used by the images decoded at the same time, in bytes or human-readable format (e.g., 2GB)
+    #[arg(long, value_parser = max_size_validator)]
+    pub max_memory: Option<u64>,
+
+    /// Number of times a file is retried after a read or write error, with an increasing delay between attempts
     #[arg(long, default_value = "0")]
-    pub threads: u32,
+    pub retries: u32,
+
+    /// Maximum number of encodings tried per file with --target-ssim, --target-value or --bpp-target
+    #[arg(long, default_value = "8", value_parser = max_iterations_validator)]
+    pub max_iterations: u32,
+
+    /// Perceptual metric measured against the original by --target-value
+    #[arg(long, value_enum, value_name = "METRIC", requires = "target_value")]
+    pub target_metric: Option<TargetMetric>,
+
+    /// Stop the --max-size search as soon as an output is at most this percentage [0-100] under the maximum size,
+    /// instead of searching the closest size (lower values are more accurate, higher values are faster)
+    #[arg(long, value_name = "PERCENT", value_parser = max_size_tolerance_validator, requires = "max_size")]
+    pub max_size_tolerance: Option<f64>,
+
+    /// Maximum number of encodings tried per file with --max-size [1-20]
+    #[arg(long, value_name = "N", value_parser = max_iterations_validator, requires = "max_size")]
+    pub max_size_iterations: Option<u32>,
 
     /// Trust file extensions instead of reading magic bytes (significantly faster on large directories)
     #[arg(long, default_value = "false")]
@@ -116,6 +455,51 @@ pub struct CommandLineArgs {
     #[arg(short = 'O', long, value_enum, default_value = "all")]
     pub overwrite: OverwritePolicy,
 
+    /// Policy for handling several input files that would be written to the same output file
+    #[arg(long, value_enum, default_value = "rename")]
+    pub on_collision: CollisionPolicy,
+
+    /// Case of the output file extensions, the file names are left as is
+    #[arg(long, value_enum, default_value = "lower")]
+    pub output_extension_case: ExtensionCase,
+
+    /// Directory of the temporary files, e.g. the archive being written with --output-zip (default: the output
+    /// directory, to stay on the same file system)
+    #[arg(long, value_name = "PATH")]
+    pub tmpdir: Option<PathBuf>,
+
+    /// Move the original file to the system trash before overwriting it in place
+    #[arg(long)]
+    pub trash_original: bool,
+
+    /// Overwrite the original files in place with --same-folder-as-input without asking for confirmation
+    #[arg(long)]
+    pub force: bool,
+
+    /// Permissions of the written files, in octal (e.g. 0644), regardless of the umask. Unix only
+    #[arg(long, value_name = "MODE", value_parser = file_mode_validator)]
+    pub chmod_files: Option<u32>,
+
+    /// Permissions of the created output directories, in octal (e.g. 0755), regardless of the umask. Unix only
+    #[arg(long, value_name = "MODE", value_parser = file_mode_validator)]
+    pub chmod_dirs: Option<u32>,
+
+    /// Delete the files of the output folder that do not match any input, making it a mirror of the inputs
+    #[arg(long, requires = "output")]
+    pub sync: bool,
+
+    /// Compress files with identical content only once and copy the result to the other outputs
+    #[arg(long)]
+    pub dedupe: bool,
+
+    /// Skip the files whose output already exists and is at least as recent as the input
+    #[arg(long)]
+    pub newer: bool,
+
+    /// Read each output back after writing it and fail the file if it differs from the compressed image
+    #[arg(long)]
+    pub verify: bool,
+
     /// Minimum compression savings required to write an output file.
     /// Use percentage (e.g., '10%', '1.5%'), absolute size (e.g., '100KB', '1MB'), or plain number as bytes
     #[arg(long, value_parser = min_savings_validator)]
@@ -129,28 +513,136 @@ pub struct CommandLineArgs {
     #[arg(long, default_value = "1", group = "verbosity", value_parser = verbosity_validator)]
     pub verbose: u8,
 
+    /// Exit with a non-zero status when files are skipped too, not only when they fail
+    #[arg(long)]
+    pub strict: bool,
+
+    /// Stop at the first file that fails, skipping the remaining ones
+    #[arg(long)]
+    pub fail_fast: bool,
+
+    /// Print a histogram of the savings of the compressed files after the totals
+    #[arg(long, conflicts_with = "json")]
+    pub histogram: bool,
+
     /// Output results as structured JSON
     #[arg(long, group = "verbosity")]
     pub json: bool,
 
-    /// Input files or directories to process
+    /// Write progress events to stderr as newline-delimited JSON
+    #[arg(long, conflicts_with = "progress_json_fd")]
+    pub progress_json: bool,
+
+    /// Write progress events as newline-delimited JSON to this already open file descriptor
+    #[arg(long, value_name = "FD")]
+    pub progress_json_fd: Option<i32>,
+
+    /// What the progress bar counts
+    #[arg(long, value_enum, value_name = "UNIT", default_value = "files")]
+    pub progress_by: ProgressUnit,
+
+    /// Layout of the progress bar, in the indicatif template syntax, e.g. '{wide_bar} {pos}/{len}\n{msg}'. An
+    /// invalid template is replaced by the default one
+    #[arg(long, value_name = "TEMPLATE")]
+    pub progress_template: Option<String>,
+
+    /// Milliseconds between two refreshes of the progress bar [10-60000]
+    #[arg(long, value_name = "MS", default_value = "1000", value_parser = progress_interval_validator)]
+    pub progress_interval: u64,
+
+    /// When to color the output
+    #[arg(long, value_enum, value_name = "WHEN", default_value = "auto")]
+    pub color: ColorMode,
+
+    /// Read default options from a TOML file (default: ./caesium-clt.toml, if present)
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+
+    /// Input files or directories to process ('-' to read from stdin and write to stdout)
     pub files: Vec<String>,
 }
 
+/// Input path reading the image from stdin and writing the result to stdout
+pub const STDIN_PATH: &str = "-";
+
+impl CommandLineArgs {
+    pub fn is_stdin_input(&self) -> bool {
+        self.files.len() == 1 && self.files[0] == STDIN_PATH
+    }
+}
+
+/// Compression mode: exactly one of these must be passed explicitly.
+/// `lossless` being `false` when absent does not satisfy the group.
 #[derive(Args, Debug)]
 #[group(required = true, multiple = false)]
 pub struct Compression {
-    /// Compression quality [0-100], higher values mean better quality
-    #[arg(short, long, value_parser = quality_validator)]
-    pub quality: Option<u32>,
+    /// Compression quality [0-100], higher values mean better quality. A comma-separated list (e.g. 60,80,95) writes
+    /// one output per quality, named with a _q60 suffix
+    #[arg(short, long, value_parser = quality_validator, value_delimiter = ',')]
+    pub quality: Option<Vec<u32>>,
 
     /// Use lossless compression (may increase file size for some formats)
     #[arg(long)]
     pub lossless: bool,
 
+    /// Optimize each file in its own format without changing its pixels, e.g. JPEG files keep their quality. Lossy
+    /// WebP files are skipped
+    #[arg(long, visible_alias = "quality-auto", conflicts_with_all = &["Resize", "rotate", "flip", "crop", "format", "convert", "webp_near_lossless"])]
+    pub optimize: bool,
+
     /// Target maximum file size in bytes or human-readable format (e.g., 100KB, 0.5MB)
     #[arg(long, value_parser = max_size_validator)]
-    pub max_size: Option<usize>,
+    pub max_size: Option<u64>,
+
+    /// Search, for each file, the lowest quality reaching this structural similarity (SSIM) [0-1] to the original
+    #[arg(long, value_parser = target_ssim_validator)]
+    pub target_ssim: Option<f64>,
+
+    /// Search, for each file, the lowest quality reaching this value of --target-metric, e.g. `--target-metric ssim
+    /// --target-value 0.95`
+    #[arg(long, value_name = "VALUE", requires = "target_metric")]
+    pub target_value: Option<f64>,
+
+    /// Search, for each file, the quality giving the closest size to this number of bits per pixel of the output
+    /// (e.g. 1.5), to keep images of different resolutions visually uniform
+    #[arg(long, value_name = "BPP", value_parser = bpp_target_validator)]
+    pub bpp_target: Option<f64>,
+
+    /// Maximum size of all the outputs together, in bytes or human-readable format (e.g., 500MB). Each file gets a share
+    /// proportional to its number of pixels as its maximum size
+    #[arg(long, value_name = "SIZE", value_parser = max_size_validator, conflicts_with_all = &["sizes", "widths"])]
+    pub total_budget: Option<u64>,
+}
+
+/// Qualities replacing --quality for the outputs of one format, e.g. when converting a folder of mixed formats
+#[derive(Args, Debug)]
+#[group(required = false, multiple = true)]
+pub struct FormatQuality {
+    /// Compression quality of the JPEG outputs [0-100], instead of --quality
+    #[arg(long, value_parser = quality_validator, conflicts_with_all = &["lossless", "optimize", "max_size", "target_ssim", "target_value", "bpp_target", "total_budget"])]
+    pub jpeg_quality: Option<u32>,
+
+    /// Compression quality of the PNG outputs [0-100], instead of --quality
+    #[arg(long, value_parser = quality_validator, conflicts_with_all = &["lossless", "optimize", "max_size", "target_ssim", "target_value", "bpp_target", "total_budget"])]
+    pub png_quality: Option<u32>,
+
+    /// Compression quality of the WebP outputs [0-100], instead of --quality
+    #[arg(long, value_parser = quality_validator, conflicts_with_all = &["lossless", "optimize", "max_size", "target_ssim", "target_value", "bpp_target", "total_budget"])]
+    pub webp_quality: Option<u32>,
+}
+
+impl FormatQuality {
+    /// The qualities that are set, with the output format they apply to
+    pub fn qualities(&self) -> Vec<(OutputFormat, u32)> {
+        [
+            (OutputFormat::Jpeg, self.jpeg_quality),
+            (OutputFormat::Png, self.png_quality),
+            (OutputFormat::Webp, self.webp_quality),
+        ]
+        .into_iter()
+        .filter_map(|(format, quality)| Some((format, quality?)))
+        .collect()
+    }
 }
 
 #[derive(Args, Debug)]
@@ -165,16 +657,47 @@ pub struct Resize {
     pub height: Option<u32>,
 
     /// Size in pixels for the longest edge of the image
-    #[arg(long, conflicts_with_all = &["width", "height", "short_edge"])]
+    #[arg(long, conflicts_with_all = &["width", "height", "short_edge", "fit"])]
     pub long_edge: Option<u32>,
 
     /// Size in pixels for the shortest edge of the image
-    #[arg(long, conflicts_with_all = &["width", "height", "long_edge"])]
+    #[arg(long, conflicts_with_all = &["width", "height", "long_edge", "fit"])]
     pub short_edge: Option<u32>,
 
+    /// Scale the image down to fit within a WIDTHxHEIGHT box (e.g. 1920x1080), preserving the aspect ratio, or fit it
+    /// to the box of --width and --height with cover, contain or pad
+    #[arg(long, value_name = "WIDTHxHEIGHT|MODE", value_parser = fit_validator, conflicts_with_all = &["long_edge", "short_edge"])]
+    pub fit: Option<Fit>,
+
+    /// Color filling the box around the image with --fit pad, as #RRGGBB or #RRGGBBAA [default: #000000]
+    #[arg(long, value_name = "COLOR", value_parser = color_validator)]
+    pub pad_color: Option<[u8; 4]>,
+
+    /// Scale the image down to a percentage of its dimensions (e.g. 50%), preserving the aspect ratio
+    #[arg(long, value_name = "PERCENT", value_parser = scale_validator, conflicts_with_all = &["width", "height", "long_edge", "short_edge", "fit", "resample_dpi", "sizes", "widths"])]
+    pub scale: Option<f64>,
+
+    /// Resize the image to keep its physical size at the --dpi resolution, based on its embedded resolution
+    #[arg(long, requires = "dpi", conflicts_with_all = &["width", "height", "long_edge", "short_edge", "fit"])]
+    pub resample_dpi: bool,
+
+    /// Write one output per size, scaling the longest edge down to it and naming it with a -<size>w suffix (e.g.
+    /// 480,960,1920). Images are never upscaled.
+    #[arg(long, value_delimiter = ',', value_parser = clap::value_parser!(u32).range(1..), conflicts_with_all = &["width", "height", "long_edge", "short_edge", "fit", "resample_dpi"])]
+    pub sizes: Vec<u32>,
+
+    /// Write one output per width, scaling the image down to it and naming it with a -<width>w suffix (e.g.
+    /// 320,640,1280). Images are never upscaled.
+    #[arg(long, value_delimiter = ',', value_parser = clap::value_parser!(u32).range(1..), conflicts_with_all = &["width", "height", "long_edge", "short_edge", "fit", "resample_dpi", "sizes"])]
+    pub widths: Vec<u32>,
+
     /// Prevents upscaling of the image when resizing
     #[arg(long)]
     pub no_upscale: bool,
+
+    /// Filter used to resize the image
+    #[arg(long, value_enum, default_value = "lanczos3")]
+    pub resize_filter: ResizeFilter,
 }
 
 #[derive(Args, Debug)]
@@ -187,6 +710,10 @@ pub struct OutputDestination {
     /// Use input file's directory as output (WARNING: may overwrite originals)
     #[arg(long, group = "output_destination", default_value = "false")]
     pub same_folder_as_input: bool,
+
+    /// Write all outputs into a single ZIP archive
+    #[arg(long, group = "output_destination")]
+    pub output_zip: Option<PathBuf>,
 }
 
 /// Validates quality values are within the valid range [0-100]
@@ -194,6 +721,86 @@ fn quality_validator(val: &str) -> Result<u32, String> {
     validate_range(val, 0, 100, "Quality")
 }
 
+/// Parses a `GLOB=QUALITY` rule. The glob may contain `=`, the quality is after the last one.
+pub fn quality_rule_validator(val: &str) -> Result<QualityRule, String> {
+    let (glob, quality) = val
+        .rsplit_once('=')
+        .ok_or_else(|| format!("'{val}' must be written as GLOB=QUALITY, e.g. hero/=95"))?;
+    QualityRule::new(glob, quality_validator(quality.trim())?)
+}
+
+/// Validates SSIM targets are within the valid range [0-1]
+pub fn target_ssim_validator(val: &str) -> Result<f64, String> {
+    validate_range(val, 0.0, 1.0, "Target SSIM")
+}
+
+/// Validates rotations are right angles
+fn rotate_validator(val: &str) -> Result<u16, String> {
+    match val.parse() {
+        Ok(angle @ (0 | 90 | 180 | 270)) => Ok(angle),
+        _ => Err(format!("Rotation must be 0, 90, 180 or 270, got '{val}'")),
+    }
+}
+
+/// Validates bits per pixel targets are within the valid range [0.01-24]
+pub fn bpp_target_validator(val: &str) -> Result<f64, String> {
+    validate_range(val, 0.01, 24.0, "Target bits per pixel")
+}
+
+/// Validates progress bar refresh intervals are within the valid range [10-60000] milliseconds
+fn progress_interval_validator(val: &str) -> Result<u64, String> {
+    validate_range(val, 10, 60000, "Progress interval")
+}
+
+/// Validates the number of quality search iterations is within the valid range [1-20]
+fn max_iterations_validator(val: &str) -> Result<u32, String> {
+    validate_range(val, 1, 20, "Max iterations")
+}
+
+/// Validates max size tolerances are within the valid range [0-100]
+pub fn max_size_tolerance_validator(val: &str) -> Result<f64, String> {
+    validate_range(val, 0.0, 100.0, "Max size tolerance")
+}
+
+/// Validates resolutions are within the valid range [1-65535]
+pub fn dpi_validator(val: &str) -> Result<u32, String> {
+    validate_range(val, 1, 65535, "DPI")
+}
+
+/// Validates and parses Unix permissions written in octal, e.g. `0644`, `644` or `0o644`
+pub fn file_mode_validator(val: &str) -> Result<u32, String> {
+    let digits = val.trim().trim_start_matches("0o");
+    match u32::from_str_radix(digits, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!("'{val}' is not a valid octal mode, e.g. 0644")),
+    }
+}
+
+/// Validates and parses `--number-format` templates: `{}`, `{:N}` or `{:0N}`, with optional text around it
+fn number_format_validator(val: &str) -> Result<NumberFormat, String> {
+    let invalid = || format!("'{val}' must contain one placeholder like {{:04}}, e.g. img_{{:04}}");
+    let (prefix, rest) = val.split_once('{').ok_or_else(invalid)?;
+    let (spec, suffix) = rest.split_once('}').ok_or_else(invalid)?;
+    if [prefix, suffix].iter().any(|text| text.contains(['{', '}', '/', '\\'])) {
+        return Err(invalid());
+    }
+
+    let (width, zero_pad) = match spec.strip_prefix(':') {
+        None if spec.is_empty() => (0, false),
+        Some(width) if !width.is_empty() && width.chars().all(|c| c.is_ascii_digit()) => {
+            (width.parse::<usize>().map_err(|_| invalid())?, width.starts_with('0'))
+        }
+        _ => return Err(invalid()),
+    };
+
+    Ok(NumberFormat {
+        prefix: prefix.to_string(),
+        width,
+        zero_pad,
+        suffix: suffix.to_string(),
+    })
+}
+
 /// Validates verbosity levels are within the valid range [0-3]
 fn verbosity_validator(val: &str) -> Result<u8, String> {
     validate_range(val, 0, 3, "Verbosity")
@@ -204,6 +811,21 @@ fn png_opt_level_validator(val: &str) -> Result<u8, String> {
     validate_range(val, 0, 6, "PNG optimization level")
 }
 
+/// Validates AVIF encoder speeds are within the valid range [1-10]
+fn avif_speed_validator(val: &str) -> Result<u8, String> {
+    validate_range(val, 1, 10, "AVIF speed")
+}
+
+/// Validates WebP near-lossless levels are within the valid range [0-100]
+fn webp_near_lossless_validator(val: &str) -> Result<u8, String> {
+    validate_range(val, 0, 100, "WebP near-lossless level")
+}
+
+/// Validates WebP encoder efforts are within the valid range [0-6]
+fn webp_effort_validator(val: &str) -> Result<u8, String> {
+    validate_range(val, 0, 6, "WebP effort")
+}
+
 /// Generic validator for numeric ranges
 fn validate_range<T>(val: &str, min: T, max: T, field_name: &str) -> Result<T, String>
 where
@@ -220,15 +842,106 @@ where
 }
 
 /// Validates and parses max_size values (supports both raw bytes and human-readable formats)
-fn max_size_validator(val: &str) -> Result<usize, String> {
+pub fn max_size_validator(val: &str) -> Result<u64, String> {
     val.parse::<ByteSize>()
-        .map(|bs| bs.as_u64() as usize)
+        .map(|size| size.as_u64())
         .map_err(|e| format!("Invalid size format: {e}"))
 }
 
+/// Validates and parses a box size written as `WIDTHxHEIGHT`, or a fit mode
+fn fit_validator(val: &str) -> Result<Fit, String> {
+    if let Ok(mode) = FitMode::from_str(val, true) {
+        return Ok(Fit::Mode(mode));
+    }
+    dimensions_validator(val)
+        .map(|(width, height)| Fit::Within(width, height))
+        .map_err(|e| format!("{e}, or be cover, contain or pad"))
+}
+
+/// Validates and parses colors written as `#RRGGBB` or `#RRGGBBAA`, the `#` being optional
+fn color_validator(val: &str) -> Result<[u8; 4], String> {
+    let hex = val.trim().trim_start_matches('#');
+    let channels = (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|channel| u8::from_str_radix(channel, 16).ok())
+        })
+        .collect::<Option<Vec<u8>>>();
+    match channels.as_deref() {
+        Some(&[red, green, blue]) => Ok([red, green, blue, 255]),
+        Some(&[red, green, blue, alpha]) => Ok([red, green, blue, alpha]),
+        _ => Err(format!("'{val}' must be written as #RRGGBB or #RRGGBBAA, e.g. #ffffff")),
+    }
+}
+
+/// Validates and parses a size written as `WIDTHxHEIGHT`, e.g. `1920x1080`
+fn dimensions_validator(val: &str) -> Result<(u32, u32), String> {
+    let invalid = || format!("'{val}' must be written as WIDTHxHEIGHT, e.g. 1920x1080");
+    let (width, height) = val
+        .to_lowercase()
+        .split_once('x')
+        .map(|(w, h)| (w.trim().to_string(), h.trim().to_string()))
+        .ok_or_else(invalid)?;
+    match (width.parse::<u32>(), height.parse::<u32>()) {
+        (Ok(width), Ok(height)) if width > 0 && height > 0 => Ok((width, height)),
+        _ => Err(invalid()),
+    }
+}
+
+/// Validates scales written as a percentage (0-100], with or without the % sign
+fn scale_validator(val: &str) -> Result<f64, String> {
+    let percent = val.trim().strip_suffix('%').unwrap_or(val.trim());
+    let scale = percent
+        .trim()
+        .parse::<f64>()
+        .map_err(|_| format!("'{val}' is not a valid percentage"))?;
+    if scale > 0.0 && scale <= 100.0 {
+        Ok(scale)
+    } else {
+        Err(format!("Scale must be greater than 0% and at most 100%, but got {val}"))
+    }
+}
+
+/// Validates and parses format conversions written as `from=to`, e.g. `png=webp`
+pub fn format_conversion_validator(val: &str) -> Result<(OutputFormat, OutputFormat), String> {
+    let (from, to) = val
+        .split_once('=')
+        .ok_or_else(|| format!("'{val}' must be written as FROM=TO, e.g. png=webp"))?;
+    let parse_format = |name: &str| {
+        let name = match name.trim().to_lowercase().as_str() {
+            "jpg" => "jpeg".to_string(),
+            other => other.to_string(),
+        };
+        match OutputFormat::from_str(&name, true) {
+            Ok(OutputFormat::Original) | Err(_) => Err(format!("'{name}' is not a valid format")),
+            Ok(format) => Ok(format),
+        }
+    };
+
+    // AVIF and JPEG XL files can be written but not read
+    match parse_format(from)? {
+        OutputFormat::Avif => Err("'avif' can only be converted to, e.g. png=avif".to_string()),
+        OutputFormat::Jxl => Err("'jxl' can only be converted to, e.g. jpeg=jxl".to_string()),
+        OutputFormat::Auto => Err("'auto' can only be converted to, e.g. png=auto".to_string()),
+        from => Ok((from, parse_format(to)?)),
+    }
+}
+
+/// Parses a thread count or `adaptive`
+fn threads_validator(val: &str) -> Result<Threads, String> {
+    if val.eq_ignore_ascii_case("adaptive") {
+        return Ok(Threads::Adaptive);
+    }
+
+    val.parse::<u32>()
+        .map(Threads::Count)
+        .map_err(|_| format!("Invalid thread count: '{val}'. Use a number or 'adaptive'"))
+}
+
 /// Validates and parses min_savings values
 /// Supports: "10%" or "1.5%" for percentage, "100KB" for bytes, or plain numbers as bytes
-fn min_savings_validator(val: &str) -> Result<MinSavingsThreshold, String> {
+pub fn min_savings_validator(val: &str) -> Result<MinSavingsThreshold, String> {
     let trimmed = val.trim();
 
     if trimmed.is_empty() {
@@ -270,6 +983,374 @@ mod tests {
         assert!(quality_validator("abc").is_err());
     }
 
+    #[test]
+    fn test_stats_only() {
+        let args = parse(&["-q", "80", "-o", "out", "--stats-only", "a.jpg"]).unwrap();
+        assert!(args.stats_only);
+        assert!(parse(&["-q", "80", "-o", "out", "--stats-only", "--json", "a.jpg"]).is_err());
+        assert!(parse(&["-q", "80", "-o", "out", "--stats-only", "--histogram", "a.jpg"]).is_err());
+    }
+
+    #[test]
+    fn test_progress_interval_validator() {
+        assert_eq!(progress_interval_validator("250").unwrap(), 250);
+        assert!(progress_interval_validator("10").is_ok());
+        assert!(progress_interval_validator("60000").is_ok());
+        assert!(progress_interval_validator("5").is_err());
+        assert!(progress_interval_validator("60001").is_err());
+        assert!(progress_interval_validator("1s").is_err());
+
+        let args = parse(&["-q", "80", "-o", "out", "a.jpg"]).unwrap();
+        assert_eq!(args.progress_interval, 1000);
+        assert_eq!(args.progress_template, None);
+        let args = parse(&[
+            "-q",
+            "80",
+            "--progress-interval",
+            "100",
+            "--progress-template",
+            "{pos}/{len}",
+            "-o",
+            "out",
+            "a.jpg",
+        ])
+        .unwrap();
+        assert_eq!(args.progress_interval, 100);
+        assert_eq!(args.progress_template.as_deref(), Some("{pos}/{len}"));
+    }
+
+    #[test]
+    fn test_threads_validator() {
+        assert_eq!(threads_validator("0"), Ok(Threads::Count(0)));
+        assert_eq!(threads_validator("8"), Ok(Threads::Count(8)));
+        assert_eq!(threads_validator("adaptive"), Ok(Threads::Adaptive));
+        assert_eq!(threads_validator("Adaptive"), Ok(Threads::Adaptive));
+        assert!(threads_validator("-1").is_err());
+        assert!(threads_validator("auto").is_err());
+    }
+
+    #[test]
+    fn test_resize_filter() {
+        let args = parse(&["-q", "80", "-o", "out", "file.jpg"]).unwrap();
+        assert_eq!(args.resize.resize_filter, ResizeFilter::Lanczos3);
+
+        let args = parse(&["-q", "80", "--resize-filter", "nearest", "-o", "out", "file.jpg"]).unwrap();
+        assert_eq!(args.resize.resize_filter, ResizeFilter::Nearest);
+        let args = parse(&["-q", "80", "--resize-filter", "catmullrom", "-o", "out", "file.jpg"]).unwrap();
+        assert_eq!(args.resize.resize_filter, ResizeFilter::Catmullrom);
+
+        assert!(parse(&["-q", "80", "--resize-filter", "bicubic", "-o", "out", "file.jpg"]).is_err());
+    }
+
+    #[test]
+    fn test_scale_validator() {
+        assert_eq!(scale_validator("50%").unwrap(), 50.0);
+        assert_eq!(scale_validator("33.3").unwrap(), 33.3);
+        assert_eq!(scale_validator("100%").unwrap(), 100.0);
+        assert!(scale_validator("0%").is_err());
+        assert!(scale_validator("150%").is_err());
+        assert!(scale_validator("half").is_err());
+
+        let args = parse(&["-q", "80", "--scale", "50%", "-o", "out", "file.jpg"]).unwrap();
+        assert_eq!(args.resize.scale, Some(50.0));
+
+        for conflicting in [
+            &["--width", "100"][..],
+            &["--long-edge", "100"],
+            &["--fit", "100x100"],
+            &["--sizes", "100"],
+        ] {
+            let args = [
+                &["-q", "80", "--scale", "50%", "-o", "out"][..],
+                conflicting,
+                &["file.jpg"],
+            ]
+            .concat();
+            let err = parse(&args).unwrap_err();
+            assert_eq!(err.kind(), clap::error::ErrorKind::ArgumentConflict, "{conflicting:?}");
+        }
+    }
+
+    #[test]
+    fn test_crop() {
+        let args = parse(&["-q", "80", "--crop", "800x600", "-o", "out", "file.jpg"]).unwrap();
+        assert_eq!(args.crop, Some((800, 600)));
+        assert_eq!(args.gravity, None);
+
+        let args = parse(&[
+            "-q",
+            "80",
+            "--crop",
+            "800x600",
+            "--gravity",
+            "southwest",
+            "-o",
+            "out",
+            "file.jpg",
+        ])
+        .unwrap();
+        assert_eq!(args.gravity, Some(Gravity::Southwest));
+
+        let err = parse(&["--optimize", "--crop", "800x600", "-o", "out", "file.jpg"]).unwrap_err();
+        assert_eq!(err.kind(), clap::error::ErrorKind::ArgumentConflict);
+    }
+
+    #[test]
+    fn test_fit_validator() {
+        assert_eq!(fit_validator("1920x1080").unwrap(), Fit::Within(1920, 1080));
+        assert_eq!(fit_validator("800X600").unwrap(), Fit::Within(800, 600));
+        assert_eq!(fit_validator("pad").unwrap(), Fit::Mode(FitMode::Pad));
+        assert_eq!(fit_validator("Cover").unwrap(), Fit::Mode(FitMode::Cover));
+        assert!(fit_validator("1920").is_err());
+        assert!(fit_validator("0x100").is_err());
+        assert!(fit_validator("axb").is_err());
+        assert!(fit_validator("stretch").is_err());
+        assert_eq!(dimensions_validator("800x800").unwrap(), (800, 800));
+
+        let args = parse(&["-q", "80", "--fit", "1920x1080", "-o", "out", "file.jpg"]).unwrap();
+        assert_eq!(args.resize.fit, Some(Fit::Within(1920, 1080)));
+
+        let args = parse(&[
+            "-q",
+            "80",
+            "--width",
+            "800",
+            "--height",
+            "600",
+            "--fit",
+            "pad",
+            "--pad-color",
+            "#ffffff",
+            "-o",
+            "out",
+            "file.jpg",
+        ])
+        .unwrap();
+        assert_eq!(args.resize.fit, Some(Fit::Mode(FitMode::Pad)));
+        assert_eq!(args.resize.pad_color, Some([255, 255, 255, 255]));
+
+        let err = parse(&[
+            "-q",
+            "80",
+            "--fit",
+            "pad",
+            "--long-edge",
+            "100",
+            "-o",
+            "out",
+            "file.jpg",
+        ])
+        .unwrap_err();
+        assert_eq!(err.kind(), clap::error::ErrorKind::ArgumentConflict);
+    }
+
+    #[test]
+    fn test_color_validator() {
+        assert_eq!(color_validator("#ff8000").unwrap(), [255, 128, 0, 255]);
+        assert_eq!(color_validator("FFFFFF00").unwrap(), [255, 255, 255, 0]);
+        assert!(color_validator("#fff").is_err());
+        assert!(color_validator("#gggggg").is_err());
+        assert!(color_validator("").is_err());
+    }
+
+    #[test]
+    fn test_dpi_validator() {
+        assert_eq!(dpi_validator("300").unwrap(), 300);
+        assert!(dpi_validator("0").is_err());
+        assert!(dpi_validator("65536").is_err());
+
+        let args = parse(&["-q", "80", "--dpi", "150", "--resample-dpi", "-o", "out", "file.jpg"]).unwrap();
+        assert_eq!(args.dpi, Some(150));
+        assert!(args.resize.resample_dpi);
+
+        let err = parse(&["-q", "80", "--resample-dpi", "-o", "out", "file.jpg"]).unwrap_err();
+        assert_eq!(err.kind(), clap::error::ErrorKind::MissingRequiredArgument);
+
+        let err = parse(&[
+            "-q",
+            "80",
+            "--dpi",
+            "150",
+            "--resample-dpi",
+            "--width",
+            "100",
+            "-o",
+            "out",
+            "file.jpg",
+        ])
+        .unwrap_err();
+        assert_eq!(err.kind(), clap::error::ErrorKind::ArgumentConflict);
+    }
+
+    #[test]
+    fn test_file_mode_validator() {
+        assert_eq!(file_mode_validator("0644").unwrap(), 0o644);
+        assert_eq!(file_mode_validator("755").unwrap(), 0o755);
+        assert_eq!(file_mode_validator("0o2775").unwrap(), 0o2775);
+        assert!(file_mode_validator("0888").is_err());
+        assert!(file_mode_validator("17777").is_err());
+        assert!(file_mode_validator("rw-r--r--").is_err());
+        assert!(file_mode_validator("").is_err());
+    }
+
+    #[test]
+    fn test_number_format_validator() {
+        assert_eq!(number_format_validator("{:04}").unwrap(), NumberFormat::default());
+        assert_eq!(number_format_validator("{:04}").unwrap().format(7), "0007");
+        assert_eq!(
+            number_format_validator("img_{:03}_web").unwrap().format(12),
+            "img_012_web"
+        );
+        assert_eq!(number_format_validator("{}").unwrap().format(12345), "12345");
+        assert_eq!(number_format_validator("{:3}").unwrap().format(5), "  5");
+        assert_eq!(number_format_validator("{:02}").unwrap().format(123), "123");
+        assert!(number_format_validator("0001").is_err());
+        assert!(number_format_validator("{:x}").is_err());
+        assert!(number_format_validator("{}{}").is_err());
+        assert!(number_format_validator("a/{}").is_err());
+
+        let args = parse(&[
+            "-q",
+            "80",
+            "--flatten",
+            "--number-format",
+            "{:03}",
+            "-o",
+            "out",
+            "file.jpg",
+        ])
+        .unwrap();
+        assert!(args.flatten);
+        assert_eq!(args.number_format.unwrap().format(1), "001");
+
+        let err = parse(&["-q", "80", "--flatten", "-S", "-o", "out", "file.jpg"]).unwrap_err();
+        assert_eq!(err.kind(), clap::error::ErrorKind::ArgumentConflict);
+        let err = parse(&["-q", "80", "--number-format", "{:03}", "-o", "out", "file.jpg"]).unwrap_err();
+        assert_eq!(err.kind(), clap::error::ErrorKind::MissingRequiredArgument);
+    }
+
+    #[test]
+    fn test_quality_rule_validator() {
+        let rule = quality_rule_validator("hero/=95").unwrap();
+        assert_eq!((rule.glob.as_str(), rule.quality), ("hero/", 95));
+        let rule = quality_rule_validator("a=b.png = 60").unwrap();
+        assert_eq!((rule.glob.as_str(), rule.quality), ("a=b.png", 60));
+        assert!(quality_rule_validator("hero/").is_err());
+        assert!(quality_rule_validator("hero/=101").is_err());
+        assert!(quality_rule_validator("=95").is_err());
+
+        let args = parse(&[
+            "-q",
+            "80",
+            "--quality-for",
+            "hero/=95",
+            "--quality-for",
+            "*.png=60",
+            "-o",
+            "out",
+            "a.jpg",
+        ])
+        .unwrap();
+        let rules: Vec<_> = args
+            .quality_for
+            .iter()
+            .map(|rule| (rule.glob.as_str(), rule.quality))
+            .collect();
+        assert_eq!(rules, [("hero/", 95), ("*.png", 60)]);
+        assert!(parse(&["--lossless", "--quality-for", "hero/=95", "-o", "out", "a.jpg"]).is_err());
+    }
+
+    #[test]
+    fn test_format_quality() {
+        let args = parse(&[
+            "-q",
+            "80",
+            "--jpeg-quality",
+            "75",
+            "--webp-quality",
+            "60",
+            "-o",
+            "out",
+            "a.jpg",
+        ])
+        .unwrap();
+        assert_eq!(
+            args.format_quality.qualities(),
+            [(OutputFormat::Jpeg, 75), (OutputFormat::Webp, 60)]
+        );
+        assert!(parse(&["-q", "80", "--png-quality", "101", "-o", "out", "a.jpg"]).is_err());
+        assert!(parse(&["--lossless", "--webp-quality", "60", "-o", "out", "a.jpg"]).is_err());
+        assert!(parse(&["--max-size", "100KB", "--jpeg-quality", "60", "-o", "out", "a.jpg"]).is_err());
+    }
+
+    #[test]
+    fn test_format_conversion_validator() {
+        assert_eq!(
+            format_conversion_validator("png=webp").unwrap(),
+            (OutputFormat::Png, OutputFormat::Webp)
+        );
+        assert_eq!(
+            format_conversion_validator("TIFF=jpg").unwrap(),
+            (OutputFormat::Tiff, OutputFormat::Jpeg)
+        );
+        assert!(format_conversion_validator("png").is_err());
+        assert!(format_conversion_validator("png=bmp").is_err());
+        assert!(format_conversion_validator("original=png").is_err());
+        assert!(format_conversion_validator("png=original").is_err());
+
+        let args = parse(&[
+            "-q",
+            "80",
+            "--convert",
+            "png=webp",
+            "--convert",
+            "tiff=png",
+            "-o",
+            "out",
+            "file.png",
+        ])
+        .unwrap();
+        assert_eq!(
+            args.convert,
+            vec![
+                (OutputFormat::Png, OutputFormat::Webp),
+                (OutputFormat::Tiff, OutputFormat::Png)
+            ]
+        );
+        assert_eq!(args.format, OutputFormat::Original);
+
+        let err = parse(&[
+            "-q",
+            "80",
+            "--convert",
+            "png=webp",
+            "--format",
+            "jpeg",
+            "-o",
+            "out",
+            "file.png",
+        ])
+        .unwrap_err();
+        assert_eq!(err.kind(), clap::error::ErrorKind::ArgumentConflict);
+    }
+
+    #[test]
+    fn test_target_ssim_validator() {
+        assert_eq!(target_ssim_validator("0.95").unwrap(), 0.95);
+        assert_eq!(target_ssim_validator("1").unwrap(), 1.0);
+        assert!(target_ssim_validator("1.1").is_err());
+        assert!(target_ssim_validator("-0.1").is_err());
+        assert!(target_ssim_validator("abc").is_err());
+        assert_eq!(rotate_validator("270").unwrap(), 270);
+        assert!(rotate_validator("45").is_err());
+        assert!(rotate_validator("-90").is_err());
+        assert_eq!(bpp_target_validator("1.5").unwrap(), 1.5);
+        assert!(bpp_target_validator("0").is_err());
+        assert!(bpp_target_validator("25").is_err());
+        assert!(max_iterations_validator("0").is_err());
+        assert!(max_iterations_validator("8").is_ok());
+    }
+
     #[test]
     fn test_verbosity_validator() {
         assert!(verbosity_validator("0").is_ok());
@@ -286,6 +1367,45 @@ mod tests {
         assert!(png_opt_level_validator("7").is_err());
     }
 
+    #[test]
+    fn test_avif_speed_validator() {
+        assert_eq!(avif_speed_validator("1"), Ok(1));
+        assert_eq!(avif_speed_validator("10"), Ok(10));
+        assert!(avif_speed_validator("0").is_err());
+        assert!(avif_speed_validator("11").is_err());
+
+        let args = parse(&["-q", "60", "--format", "avif", "-o", "out", "a.jpg"]).unwrap();
+        assert_eq!(args.format, OutputFormat::Avif);
+        assert_eq!(args.avif_speed, 6);
+        assert_eq!(
+            format_conversion_validator("png=avif"),
+            Ok((OutputFormat::Png, OutputFormat::Avif))
+        );
+        assert!(format_conversion_validator("avif=png").is_err());
+        assert_eq!(
+            format_conversion_validator("jpg=jxl"),
+            Ok((OutputFormat::Jpeg, OutputFormat::Jxl))
+        );
+        assert!(format_conversion_validator("jxl=jpeg").is_err());
+    }
+
+    #[test]
+    fn test_webp_near_lossless_validator() {
+        assert_eq!(webp_near_lossless_validator("0"), Ok(0));
+        assert_eq!(webp_near_lossless_validator("60"), Ok(60));
+        assert_eq!(webp_near_lossless_validator("100"), Ok(100));
+        assert!(webp_near_lossless_validator("101").is_err());
+    }
+
+    #[test]
+    fn test_webp_effort_validator() {
+        assert_eq!(webp_effort_validator("0"), Ok(0));
+        assert_eq!(webp_effort_validator("6"), Ok(6));
+        assert!(webp_effort_validator("7").is_err());
+        assert!(parse(&["-q", "80", "--webp-effort", "6", "-o", "out", "a.jpg"]).is_ok());
+        assert!(parse(&["--max-size", "100KB", "--webp-effort", "6", "-o", "out", "a.jpg"]).is_err());
+    }
+
     #[test]
     fn test_validate_range() {
         // Test with u32
@@ -311,11 +1431,32 @@ mod tests {
         let all = OverwritePolicy::All;
         let never = OverwritePolicy::Never;
         let bigger = OverwritePolicy::Bigger;
+        let smaller = OverwritePolicy::Smaller;
 
         // Verify they're different
         assert_ne!(format!("{all:?}"), format!("{:?}", never));
         assert_ne!(format!("{all:?}"), format!("{:?}", bigger));
         assert_ne!(format!("{never:?}"), format!("{:?}", bigger));
+        assert_ne!(format!("{bigger:?}"), format!("{:?}", smaller));
+    }
+
+    #[test]
+    fn test_overwrite_policy_keeps_existing() {
+        // Existing file larger than the new output
+        assert!(!OverwritePolicy::All.keeps_existing(200, 100));
+        assert!(OverwritePolicy::Never.keeps_existing(200, 100));
+        assert!(!OverwritePolicy::Bigger.keeps_existing(200, 100));
+        assert!(OverwritePolicy::Smaller.keeps_existing(200, 100));
+
+        // Existing file smaller than the new output
+        assert!(!OverwritePolicy::All.keeps_existing(100, 200));
+        assert!(OverwritePolicy::Never.keeps_existing(100, 200));
+        assert!(OverwritePolicy::Bigger.keeps_existing(100, 200));
+        assert!(!OverwritePolicy::Smaller.keeps_existing(100, 200));
+
+        // Same size, nothing to gain
+        assert!(OverwritePolicy::Bigger.keeps_existing(100, 100));
+        assert!(OverwritePolicy::Smaller.keeps_existing(100, 100));
     }
 
     #[test]
@@ -364,6 +1505,157 @@ mod tests {
         assert_ne!(format!("{cs411:?}"), format!("{:?}", auto));
     }
 
+    fn parse(args: &[&str]) -> Result<CommandLineArgs, clap::Error> {
+        CommandLineArgs::try_parse_from(["caesiumclt"].iter().chain(args))
+    }
+
+    #[test]
+    fn test_compression_group() {
+        let args = parse(&["-q", "0", "-o", "out", "file.jpg"]).unwrap();
+        assert_eq!(args.compression.quality, Some(vec![0]));
+        assert!(!args.compression.lossless);
+        assert_eq!(args.compression.max_size, None);
+
+        let args = parse(&["--lossless", "-o", "out", "file.jpg"]).unwrap();
+        assert_eq!(args.compression.quality, None);
+        assert!(args.compression.lossless);
+        assert_eq!(args.compression.max_size, None);
+
+        let args = parse(&["--max-size", "100KB", "-o", "out", "file.jpg"]).unwrap();
+        assert_eq!(args.compression.quality, None);
+        assert!(!args.compression.lossless);
+        assert_eq!(args.compression.max_size, Some(100_000));
+
+        let args = parse(&["--target-ssim", "0.95", "-o", "out", "file.jpg"]).unwrap();
+        assert_eq!(args.compression.quality, None);
+        assert_eq!(args.compression.target_ssim, Some(0.95));
+        assert_eq!(args.max_iterations, 8);
+
+        let err = parse(&["-o", "out", "file.jpg"]).unwrap_err();
+        assert_eq!(err.kind(), clap::error::ErrorKind::MissingRequiredArgument);
+
+        let err = parse(&["-q", "80", "--lossless", "-o", "out", "file.jpg"]).unwrap_err();
+        assert_eq!(err.kind(), clap::error::ErrorKind::ArgumentConflict);
+
+        let err = parse(&["-q", "80", "--max-size", "1MB", "-o", "out", "file.jpg"]).unwrap_err();
+        assert_eq!(err.kind(), clap::error::ErrorKind::ArgumentConflict);
+
+        let err = parse(&["-q", "80", "--target-ssim", "0.9", "-o", "out", "file.jpg"]).unwrap_err();
+        assert_eq!(err.kind(), clap::error::ErrorKind::ArgumentConflict);
+
+        let args = parse(&[
+            "--target-metric",
+            "ssim",
+            "--target-value",
+            "0.95",
+            "-o",
+            "out",
+            "file.jpg",
+        ])
+        .unwrap();
+        assert_eq!(args.target_metric, Some(TargetMetric::Ssim));
+        assert_eq!(args.compression.target_value, Some(0.95));
+        assert!(parse(&[
+            "--target-metric",
+            "butteraugli",
+            "--target-value",
+            "2",
+            "-o",
+            "out",
+            "file.jpg"
+        ])
+        .is_err());
+        let err = parse(&["--target-value", "0.95", "-o", "out", "file.jpg"]).unwrap_err();
+        assert_eq!(err.kind(), clap::error::ErrorKind::MissingRequiredArgument);
+        let err = parse(&[
+            "--target-ssim",
+            "0.9",
+            "--target-value",
+            "0.9",
+            "--target-metric",
+            "ssim",
+            "-o",
+            "out",
+            "file.jpg",
+        ])
+        .unwrap_err();
+        assert_eq!(err.kind(), clap::error::ErrorKind::ArgumentConflict);
+
+        let args = parse(&["--optimize", "-o", "out", "file.jpg"]).unwrap();
+        assert_eq!(args.compression.quality, None);
+        assert!(args.compression.optimize);
+        assert!(
+            parse(&["--quality-auto", "-o", "out", "file.jpg"])
+                .unwrap()
+                .compression
+                .optimize
+        );
+        let err = parse(&["--optimize", "--lossless", "-o", "out", "file.jpg"]).unwrap_err();
+        assert_eq!(err.kind(), clap::error::ErrorKind::ArgumentConflict);
+        for conflicting in [&["--format", "webp"][..], &["--width", "100"], &["--rotate", "90"]] {
+            let args = [&["--optimize", "-o", "out"][..], conflicting, &["file.jpg"]].concat();
+            assert!(parse(&args).is_err(), "{conflicting:?}");
+        }
+
+        assert!(parse(&["--lossless=false", "-o", "out", "file.jpg"]).is_err());
+    }
+
+    #[test]
+    fn test_total_budget() {
+        let args = parse(&["--total-budget", "500MB", "-o", "out", "file.jpg"]).unwrap();
+        assert_eq!(args.compression.total_budget, Some(500_000_000));
+        assert_eq!(args.compression.quality, None);
+
+        let err = parse(&["-q", "80", "--total-budget", "500MB", "-o", "out", "file.jpg"]).unwrap_err();
+        assert_eq!(err.kind(), clap::error::ErrorKind::ArgumentConflict);
+        let err = parse(&["--total-budget", "500MB", "--sizes", "480,960", "-o", "out", "file.jpg"]).unwrap_err();
+        assert_eq!(err.kind(), clap::error::ErrorKind::ArgumentConflict);
+        let err = parse(&["--total-budget", "500MB", "--webp-effort", "4", "-o", "out", "file.jpg"]).unwrap_err();
+        assert_eq!(err.kind(), clap::error::ErrorKind::ArgumentConflict);
+    }
+
+    #[test]
+    fn test_max_size_search_options() {
+        let args = parse(&[
+            "--max-size",
+            "100KB",
+            "--max-size-tolerance",
+            "5",
+            "--max-size-iterations",
+            "4",
+            "-o",
+            "out",
+            "file.jpg",
+        ])
+        .unwrap();
+        assert_eq!(args.max_size_tolerance, Some(5.0));
+        assert_eq!(args.max_size_iterations, Some(4));
+
+        let args = parse(&["--max-size", "100KB", "-o", "out", "file.jpg"]).unwrap();
+        assert_eq!(args.max_size_tolerance, None);
+        assert_eq!(args.max_size_iterations, None);
+
+        let err = parse(&["-q", "80", "--max-size-tolerance", "5", "-o", "out", "file.jpg"]).unwrap_err();
+        assert_eq!(err.kind(), clap::error::ErrorKind::MissingRequiredArgument);
+        let err = parse(&["-q", "80", "--max-size-iterations", "4", "-o", "out", "file.jpg"]).unwrap_err();
+        assert_eq!(err.kind(), clap::error::ErrorKind::MissingRequiredArgument);
+
+        assert!(max_size_tolerance_validator("0").is_ok());
+        assert!(max_size_tolerance_validator("2.5").is_ok());
+        assert!(max_size_tolerance_validator("101").is_err());
+        assert!(max_size_tolerance_validator("-1").is_err());
+        assert!(parse(&[
+            "--max-size",
+            "1MB",
+            "--max-size-iterations",
+            "0",
+            "-o",
+            "out",
+            "file.jpg"
+        ])
+        .is_err());
+    }
+
     #[test]
     fn test_max_size_validator() {
         // Test raw byte numbers
@@ -377,6 +1669,10 @@ mod tests {
         assert_eq!(max_size_validator("1MiB").unwrap(), 1_048_576);
         assert_eq!(max_size_validator("0.3GB").unwrap(), 300_000_000);
         assert_eq!(max_size_validator("0.5GiB").unwrap(), 536_870_912);
+        assert_eq!(max_size_validator("1.2MiB").unwrap(), 1_258_291);
+        assert_eq!(max_size_validator("500KB").unwrap(), 500_000);
+        // Past the 32-bit range
+        assert_eq!(max_size_validator("5GB").unwrap(), 5_000_000_000);
 
         // Test invalid formats
         assert!(max_size_validator("invalid").is_err());
@@ -0,0 +1,495 @@
+//! Recompression of the images embedded in PDF files, such as the pages of scanned documents.
+//!
+//! The document is left as it is but for the streams of its images: JPEG images are recompressed, and 8-bit gray or
+//! RGB images stored with the Flate filter are either deflated again losslessly or converted to JPEG. An image is
+//! only replaced when its new stream is smaller and has the same color components.
+
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::{Compression, Crc};
+use lopdf::{Dictionary, Document, Object, ObjectId, Stream};
+use std::collections::HashSet;
+use std::error::Error;
+use std::io::{Read, Write};
+
+const PDF_SIGNATURE: &[u8] = b"%PDF-";
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+/// The PNG predictors of Flate streams, one filter type byte starting each row
+const PNG_PREDICTORS: i64 = 10;
+
+/// The document with its recompressed images, and how many of its images were replaced
+pub struct PdfRecompression {
+    pub output: Vec<u8>,
+    pub images: usize,
+    pub recompressed: usize,
+}
+
+/// An image stream that can be recompressed, and how
+enum ImageStream {
+    Jpeg { components: u8 },
+    Flate { width: u32, height: u32, components: u8 },
+}
+
+pub fn is_pdf(buffer: &[u8]) -> bool {
+    buffer.starts_with(PDF_SIGNATURE)
+}
+
+/// Recompresses the images of a PDF. JPEG images, and the Flate ones when `lossless` is not set, are given to
+/// `compress_jpeg` as a JPEG or PNG file, which returns them encoded as a JPEG. With `lossless`, Flate images are
+/// deflated again instead, keeping their pixels.
+pub fn recompress_pdf_images<F>(
+    buffer: &[u8],
+    lossless: bool,
+    mut compress_jpeg: F,
+) -> Result<PdfRecompression, Box<dyn Error>>
+where
+    F: FnMut(&[u8]) -> Option<Vec<u8>>,
+{
+    let mut document = Document::load_mem(buffer)?;
+    if document.trailer.get(b"Encrypt").is_ok() {
+        return Err("Encrypted PDF files aren't supported".into());
+    }
+
+    // Soft masks are the transparency of another image
+    let soft_masks: HashSet<ObjectId> = document
+        .objects
+        .values()
+        .filter_map(|object| object.as_stream().ok()?.dict.get(b"SMask").ok()?.as_reference().ok())
+        .collect();
+    let images: Vec<(ObjectId, Option<ImageStream>)> = document
+        .objects
+        .iter()
+        .filter_map(|(id, object)| {
+            let stream = object.as_stream().ok()?;
+            let is_image = stream.dict.get(b"Subtype").and_then(Object::as_name).ok() == Some(b"Image".as_slice());
+            is_image.then(|| (*id, image_stream(&document, stream)))
+        })
+        .collect();
+
+    let mut recompressed = 0;
+    for (id, image) in &images {
+        let Some(image) = image else {
+            continue;
+        };
+        let stream = document.get_object(*id)?.as_stream()?;
+        let replacement = match image {
+            ImageStream::Jpeg { components } => compress_jpeg(&stream.content)
+                .filter(|jpeg| jpeg_components(jpeg) == Some(*components))
+                .map(|jpeg| (jpeg, None)),
+            ImageStream::Flate {
+                width,
+                height,
+                components,
+            } if lossless => flate_to_png(stream, *width, *height, *components)
+                .and_then(|png| optimize_png(&png))
+                .ok()
+                .and_then(|png| png_data(&png, *width, *height, *components))
+                .map(|data| (data, Some(png_predictor_parameters(*width, *components)))),
+            // The pixels of soft masks and of color key masked images are kept exact
+            ImageStream::Flate { .. } if soft_masks.contains(id) || stream.dict.has(b"Mask") => None,
+            ImageStream::Flate {
+                width,
+                height,
+                components,
+            } => flate_to_png(stream, *width, *height, *components)
+                .ok()
+                .and_then(|png| compress_jpeg(&png))
+                .filter(|jpeg| jpeg_components(jpeg) == Some(*components))
+                .map(|jpeg| (jpeg, None)),
+        };
+
+        let Some((content, decode_parameters)) =
+            replacement.filter(|(content, _)| content.len() < stream.content.len())
+        else {
+            continue;
+        };
+        let stream = document.get_object_mut(*id)?.as_stream_mut()?;
+        let filter = match decode_parameters {
+            Some(_) => "FlateDecode",
+            None => "DCTDecode",
+        };
+        stream.dict.set("Filter", Object::Name(filter.as_bytes().to_vec()));
+        match decode_parameters {
+            Some(decode_parameters) => stream.dict.set("DecodeParms", decode_parameters),
+            None => {
+                stream.dict.remove(b"DecodeParms");
+            }
+        }
+        stream.set_content(content);
+        recompressed += 1;
+    }
+
+    // Nothing is gained by writing the document again
+    let output = match recompressed {
+        0 => buffer.to_vec(),
+        _ => {
+            let mut output = Vec::new();
+            document.save_to(&mut output)?;
+            output
+        }
+    };
+
+    Ok(PdfRecompression {
+        output,
+        images: images.len(),
+        recompressed,
+    })
+}
+
+/// How the image can be recompressed, `None` for the ones left as they are: image masks, other filters, bit depths
+/// and color spaces, including CMYK whose JPEGs may be stored inverted
+fn image_stream(document: &Document, stream: &Stream) -> Option<ImageStream> {
+    let dict = &stream.dict;
+    if dict.get(b"ImageMask").and_then(Object::as_bool).unwrap_or(false) {
+        return None;
+    }
+    let filter = match dict.get(b"Filter").ok()? {
+        Object::Name(name) => name.as_slice(),
+        Object::Array(filters) if filters.len() == 1 => filters[0].as_name().ok()?,
+        _ => return None,
+    };
+    let components = color_components(document, dict.get(b"ColorSpace").ok()?)?;
+
+    match filter {
+        b"DCTDecode" => {
+            let jpeg_components = jpeg_components(&stream.content)?;
+            (jpeg_components == components).then_some(ImageStream::Jpeg { components })
+        }
+        b"FlateDecode" if dict.get(b"BitsPerComponent").and_then(Object::as_i64).ok()? == 8 => {
+            let width = u32::try_from(dict.get(b"Width").and_then(Object::as_i64).ok()?).ok()?;
+            let height = u32::try_from(dict.get(b"Height").and_then(Object::as_i64).ok()?).ok()?;
+            (width > 0 && height > 0).then_some(ImageStream::Flate {
+                width,
+                height,
+                components,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Number of components of the gray and RGB color spaces, including the ICC based ones
+fn color_components(document: &Document, color_space: &Object) -> Option<u8> {
+    let color_space = match color_space {
+        Object::Reference(id) => document.get_object(*id).ok()?,
+        color_space => color_space,
+    };
+    match color_space {
+        Object::Name(name) if name == b"DeviceGray" => Some(1),
+        Object::Name(name) if name == b"DeviceRGB" => Some(3),
+        Object::Array(family) if family.len() == 2 && family[0].as_name().ok()? == b"ICCBased" => {
+            let profile = document
+                .get_object(family[1].as_reference().ok()?)
+                .ok()?
+                .as_stream()
+                .ok()?;
+            match profile.dict.get(b"N").and_then(Object::as_i64).ok()? {
+                1 => Some(1),
+                3 => Some(3),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Number of components of a baseline or progressive JPEG
+fn jpeg_components(jpeg: &[u8]) -> Option<u8> {
+    let mut position = 2;
+    if !jpeg.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+    while let Some(header) = jpeg.get(position..position + 4).filter(|header| header[0] == 0xFF) {
+        let length = u16::from_be_bytes([header[2], header[3]]) as usize;
+        match header[1] {
+            // Precision, height and width come before the number of components
+            0xC0..=0xC2 => return jpeg.get(position + 9).copied(),
+            0xDA => return None,
+            _ => position += 2 + length,
+        }
+    }
+
+    None
+}
+
+/// The pixels of a Flate image as a PNG file. The data of the PNG predictors already is the one of a PNG image.
+fn flate_to_png(stream: &Stream, width: u32, height: u32, components: u8) -> Result<Vec<u8>, Box<dyn Error>> {
+    let parameter = |name: &[u8]| {
+        stream
+            .dict
+            .get(b"DecodeParms")
+            .and_then(Object::as_dict)
+            .and_then(|parameters| parameters.get(name))
+            .and_then(Object::as_i64)
+            .ok()
+    };
+    let row_length = width as usize * components as usize;
+    let data = match parameter(b"Predictor").unwrap_or(1) {
+        1 => {
+            let mut pixels = Vec::new();
+            ZlibDecoder::new(stream.content.as_slice()).read_to_end(&mut pixels)?;
+            if pixels.len() < row_length * height as usize {
+                return Err("Truncated image data".into());
+            }
+            // Rows without any filter
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::fast());
+            for row in pixels.chunks_exact(row_length).take(height as usize) {
+                encoder.write_all(&[0])?;
+                encoder.write_all(row)?;
+            }
+            encoder.finish()?
+        }
+        predictor
+            if predictor >= PNG_PREDICTORS
+                && parameter(b"Colors").unwrap_or(1) == components as i64
+                && parameter(b"BitsPerComponent").unwrap_or(8) == 8
+                && parameter(b"Columns").unwrap_or(1) == width as i64 =>
+        {
+            stream.content.clone()
+        }
+        _ => return Err("Unsupported Flate predictor".into()),
+    };
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    // Bit depth, gray or RGB color type, compression, filter and interlace methods
+    header.extend_from_slice(&[8, if components == 1 { 0 } else { 2 }, 0, 0, 0]);
+    let mut png = PNG_SIGNATURE.to_vec();
+    write_png_chunk(&mut png, b"IHDR", &header);
+    write_png_chunk(&mut png, b"IDAT", &data);
+    write_png_chunk(&mut png, b"IEND", &[]);
+    Ok(png)
+}
+
+fn write_png_chunk(png: &mut Vec<u8>, chunk_type: &[u8; 4], data: &[u8]) {
+    let mut crc = Crc::new();
+    crc.update(chunk_type);
+    crc.update(data);
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    png.extend_from_slice(chunk_type);
+    png.extend_from_slice(data);
+    png.extend_from_slice(&crc.sum().to_be_bytes());
+}
+
+/// Deflates the image data again, without any of the reductions that would change the color space of the image
+fn optimize_png(png: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut options = oxipng::Options::from_preset(2);
+    options.bit_depth_reduction = false;
+    options.color_type_reduction = false;
+    options.palette_reduction = false;
+    options.grayscale_reduction = false;
+    Ok(oxipng::optimize_from_memory(png, &options)?)
+}
+
+/// The image data of a PNG, a Flate stream of rows starting with their filter type, when the image is still
+/// `width` by `height` pixels of `components` 8-bit components, without interlacing
+fn png_data(png: &[u8], width: u32, height: u32, components: u8) -> Option<Vec<u8>> {
+    let color_type = if components == 1 { 0 } else { 2 };
+    let mut data = Vec::new();
+    let mut position = PNG_SIGNATURE.len();
+    while let Some(header) = png.get(position..position + 8) {
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let chunk = png.get(position + 8..position + 8 + length)?;
+        match &header[4..8] {
+            b"IHDR" => {
+                let mut expected_header = width.to_be_bytes().to_vec();
+                expected_header.extend_from_slice(&height.to_be_bytes());
+                expected_header.extend_from_slice(&[8, color_type, 0, 0, 0]);
+                if chunk != expected_header {
+                    return None;
+                }
+            }
+            b"IDAT" => data.extend_from_slice(chunk),
+            b"IEND" => break,
+            _ => {}
+        }
+        // Chunk length, type, data and CRC
+        position += 12 + length;
+    }
+
+    (!data.is_empty()).then_some(data)
+}
+
+/// The decode parameters of image data whose rows start with their PNG filter type
+fn png_predictor_parameters(width: u32, components: u8) -> Object {
+    let mut parameters = Dictionary::new();
+    parameters.set("Predictor", Object::Integer(15));
+    parameters.set("Colors", Object::Integer(components as i64));
+    parameters.set("BitsPerComponent", Object::Integer(8));
+    parameters.set("Columns", Object::Integer(width as i64));
+    Object::Dictionary(parameters)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, ImageFormat};
+    use lopdf::dictionary;
+    use std::io::Cursor;
+
+    fn gradient(components: u8) -> DynamicImage {
+        let image = image::RgbImage::from_fn(64, 48, |x, y| image::Rgb([(x * 4) as u8, (y * 5) as u8, 128]));
+        match components {
+            1 => DynamicImage::from(DynamicImage::from(image).to_luma8()),
+            _ => DynamicImage::from(image),
+        }
+    }
+
+    fn encode(image: &DynamicImage, format: ImageFormat) -> Vec<u8> {
+        let mut output = Vec::new();
+        image.write_to(&mut Cursor::new(&mut output), format).unwrap();
+        output
+    }
+
+    fn jpeg(image: &DynamicImage, quality: u8) -> Vec<u8> {
+        let mut output = Vec::new();
+        image::codecs::jpeg::JpegEncoder::new_with_quality(&mut output, quality)
+            .encode_image(image)
+            .unwrap();
+        output
+    }
+
+    /// A single page document drawing the image of the given filter and content
+    fn pdf(image: &DynamicImage, filter: &str, content: Vec<u8>) -> Vec<u8> {
+        let mut document = Document::with_version("1.5");
+        let color_space = match image.color().channel_count() {
+            1 => "DeviceGray",
+            _ => "DeviceRGB",
+        };
+        let image_id = document.add_object(Stream::new(
+            dictionary! {
+                "Type" => "XObject",
+                "Subtype" => "Image",
+                "Width" => image.width() as i64,
+                "Height" => image.height() as i64,
+                "ColorSpace" => color_space,
+                "BitsPerComponent" => 8,
+                "Filter" => filter,
+            },
+            content,
+        ));
+        let contents_id = document.add_object(Stream::new(dictionary! {}, b"q 64 0 0 48 0 0 cm /Im0 Do Q".to_vec()));
+        let pages_id = document.new_object_id();
+        let page_id = document.add_object(dictionary! {
+            "Type" => "Page",
+            "Parent" => pages_id,
+            "MediaBox" => vec![0.into(), 0.into(), 64.into(), 48.into()],
+            "Contents" => contents_id,
+            "Resources" => dictionary! { "XObject" => dictionary! { "Im0" => image_id } },
+        });
+        document.objects.insert(
+            pages_id,
+            Object::Dictionary(dictionary! { "Type" => "Pages", "Kids" => vec![page_id.into()], "Count" => 1 }),
+        );
+        let catalog_id = document.add_object(dictionary! { "Type" => "Catalog", "Pages" => pages_id });
+        document.trailer.set("Root", catalog_id);
+
+        let mut output = Vec::new();
+        document.save_to(&mut output).unwrap();
+        output
+    }
+
+    fn deflate(data: &[u8]) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::none());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    /// The only image of a document, and its pixels when it is stored with the Flate filter
+    fn read_image(pdf: &[u8]) -> (Stream, Option<DynamicImage>) {
+        let document = Document::load_mem(pdf).unwrap();
+        let stream = document
+            .objects
+            .values()
+            .filter_map(|object| object.as_stream().ok())
+            .find(|stream| stream.dict.get(b"Subtype").and_then(Object::as_name).ok() == Some(b"Image".as_slice()))
+            .unwrap()
+            .clone();
+        let pixels = match image_stream(&document, &stream) {
+            Some(ImageStream::Flate {
+                width,
+                height,
+                components,
+            }) => {
+                let png = flate_to_png(&stream, width, height, components).unwrap();
+                Some(image::load_from_memory_with_format(&png, ImageFormat::Png).unwrap())
+            }
+            _ => None,
+        };
+        (stream, pixels)
+    }
+
+    #[test]
+    fn test_is_pdf() {
+        let image = gradient(3);
+        assert!(is_pdf(&pdf(&image, "DCTDecode", jpeg(&image, 90))));
+        assert!(!is_pdf(&std::fs::read("samples/p0.png").unwrap()));
+    }
+
+    #[test]
+    fn test_recompress_jpeg_images() {
+        let image = gradient(3);
+        let input = pdf(&image, "DCTDecode", jpeg(&image, 100));
+        let result = recompress_pdf_images(&input, false, |file| {
+            let decoded = image::load_from_memory_with_format(file, ImageFormat::Jpeg).unwrap();
+            Some(jpeg(&decoded, 50))
+        })
+        .unwrap();
+        assert_eq!((result.images, result.recompressed), (1, 1));
+        assert!(result.output.len() < input.len());
+        let (stream, _) = read_image(&result.output);
+        assert_eq!(jpeg_components(&stream.content), Some(3));
+        assert_eq!(
+            stream.dict.get(b"Filter").and_then(Object::as_name).unwrap(),
+            b"DCTDecode"
+        );
+
+        // A larger image, or one of other components, is not used
+        let result = recompress_pdf_images(&input, false, |_| Some(jpeg(&image, 100).repeat(2))).unwrap();
+        assert_eq!((result.recompressed, result.output), (0, input.clone()));
+        let result = recompress_pdf_images(&input, false, |_| Some(jpeg(&gradient(1), 10))).unwrap();
+        assert_eq!(result.recompressed, 0);
+    }
+
+    #[test]
+    fn test_recompress_flate_images() {
+        for components in [1, 3] {
+            let image = gradient(components);
+            let input = pdf(&image, "FlateDecode", deflate(image.as_bytes()));
+
+            // Deflated again, with the same pixels
+            let result = recompress_pdf_images(&input, true, |_| panic!("Converted losslessly")).unwrap();
+            assert_eq!(result.recompressed, 1);
+            let (stream, pixels) = read_image(&result.output);
+            assert_eq!(
+                stream.dict.get(b"Filter").and_then(Object::as_name).unwrap(),
+                b"FlateDecode"
+            );
+            assert_eq!(pixels.unwrap().as_bytes(), image.as_bytes());
+
+            // Converted to JPEG
+            let result = recompress_pdf_images(&input, false, |file| {
+                let decoded = image::load_from_memory_with_format(file, ImageFormat::Png).unwrap();
+                assert_eq!(decoded.as_bytes(), image.as_bytes());
+                Some(jpeg(&decoded, 80))
+            })
+            .unwrap();
+            assert_eq!(result.recompressed, 1);
+            let (stream, _) = read_image(&result.output);
+            assert_eq!(
+                stream.dict.get(b"Filter").and_then(Object::as_name).unwrap(),
+                b"DCTDecode"
+            );
+            assert!(stream.dict.get(b"DecodeParms").is_err());
+            assert_eq!(jpeg_components(&stream.content), Some(components));
+        }
+    }
+
+    #[test]
+    fn test_recompress_pdf_images_errors() {
+        assert!(recompress_pdf_images(b"%PDF-1.4\nnot a document", false, |_| None).is_err());
+        let png = encode(&gradient(3), ImageFormat::Png);
+        assert!(recompress_pdf_images(&png, false, |_| None).is_err());
+    }
+}
@@ -0,0 +1,143 @@
+use caesiumclt::{CompressionResult, ProgressEvent};
+use serde::Serialize;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// One line of the progress stream, tagged by its `event` field
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum ProgressLine<'a> {
+    ScanComplete {
+        total_files: usize,
+    },
+    Started {
+        path: &'a Path,
+    },
+    Finished {
+        #[serde(flatten)]
+        result: &'a CompressionResult,
+    },
+}
+
+/// Writes the progress events as newline-delimited JSON, flushing after every line so that the consumer
+/// can follow them live
+pub struct ProgressJsonWriter {
+    output: Box<dyn Write + Send>,
+}
+
+impl ProgressJsonWriter {
+    pub fn new(output: Box<dyn Write + Send>) -> Self {
+        Self { output }
+    }
+
+    pub fn stderr() -> Self {
+        Self::new(Box::new(io::stderr()))
+    }
+
+    /// Writes to an already open file descriptor, e.g. one end of a pipe inherited from the parent process
+    pub fn from_fd(fd: i32) -> io::Result<Self> {
+        match fd {
+            1 => Ok(Self::new(Box::new(io::stdout()))),
+            2 => Ok(Self::stderr()),
+            _ => open_fd(fd).map(|file| Self::new(Box::new(file))),
+        }
+    }
+
+    pub fn scan_complete(&mut self, total_files: usize) {
+        self.write_line(&ProgressLine::ScanComplete { total_files });
+    }
+
+    pub fn progress(&mut self, event: &ProgressEvent) {
+        let line = match event {
+            ProgressEvent::Started { path } => ProgressLine::Started { path },
+            ProgressEvent::Finished { result, .. } => ProgressLine::Finished { result },
+        };
+        self.write_line(&line);
+    }
+
+    /// A consumer going away must not stop the compression, so write errors are ignored
+    fn write_line(&mut self, line: &ProgressLine) {
+        if let Ok(json) = serde_json::to_string(line) {
+            let _ = writeln!(self.output, "{json}").and_then(|_| self.output.flush());
+        }
+    }
+}
+
+#[cfg(unix)]
+fn open_fd(fd: i32) -> io::Result<File> {
+    use std::os::fd::FromRawFd;
+
+    if fd < 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid file descriptor"));
+    }
+    // SAFETY: the descriptor is handed over by the parent process for this purpose and is not used elsewhere
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+#[cfg(not(unix))]
+fn open_fd(_fd: i32) -> io::Result<File> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "only 1 (stdout) and 2 (stderr) are supported on this platform",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use caesiumclt::compressor::RunningTotals;
+    use caesiumclt::CompressionStatus;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_progress_lines() {
+        let buffer = SharedBuffer::default();
+        let mut writer = ProgressJsonWriter::new(Box::new(buffer.clone()));
+        let path = Path::new("samples/j0.JPG");
+        let result = CompressionResult {
+            original_path: "samples/j0.JPG".to_string(),
+            output_path: "out/j0.JPG".to_string(),
+            original_size: 1000,
+            compressed_size: 600,
+            status: CompressionStatus::Success,
+            error_kind: None,
+            message: String::new(),
+        };
+
+        writer.scan_complete(1);
+        writer.progress(&ProgressEvent::Started { path });
+        let totals = RunningTotals::default();
+        writer.progress(&ProgressEvent::Finished {
+            path,
+            result: &result,
+            totals: &totals,
+        });
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<serde_json::Value> = output.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["event"], "scan_complete");
+        assert_eq!(lines[0]["total_files"], 1);
+        assert_eq!(lines[1]["event"], "started");
+        assert_eq!(lines[1]["path"], "samples/j0.JPG");
+        assert_eq!(lines[2]["event"], "finished");
+        assert_eq!(lines[2]["output_path"], "out/j0.JPG");
+        assert_eq!(lines[2]["original_size"], 1000);
+        assert_eq!(lines[2]["compressed_size"], 600);
+        assert_eq!(lines[2]["status"], "success");
+    }
+}
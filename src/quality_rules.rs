@@ -0,0 +1,94 @@
+//! Qualities given to the files matching a glob, overriding the global quality.
+
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::path::Path;
+
+/// A `GLOB=QUALITY` rule. The glob follows the .gitignore syntax and is matched against the path of the file
+/// relative to the base folder of the inputs: `*.png` matches at any depth, `hero/` everything under a hero folder
+/// and `/banner.jpg` only at the top.
+#[derive(Clone, Debug)]
+pub struct QualityRule {
+    pub glob: String,
+    pub quality: u32,
+    matcher: Gitignore,
+}
+
+impl QualityRule {
+    pub fn new(glob: &str, quality: u32) -> Result<Self, String> {
+        let glob = glob.trim();
+        if glob.is_empty() || glob.starts_with('!') {
+            return Err(format!("'{glob}' is not a valid glob"));
+        }
+        let mut builder = GitignoreBuilder::new("");
+        builder
+            .add_line(None, glob)
+            .map_err(|e| format!("'{glob}' is not a valid glob: {e}"))?;
+        let matcher = builder
+            .build()
+            .map_err(|e| format!("'{glob}' is not a valid glob: {e}"))?;
+
+        Ok(Self {
+            glob: glob.to_string(),
+            quality,
+            matcher,
+        })
+    }
+
+    /// Whether the rule matches the file, or one of its parent folders
+    pub fn matches(&self, relative_path: &Path) -> bool {
+        self.matcher
+            .matched_path_or_any_parents(relative_path, false)
+            .is_ignore()
+    }
+}
+
+/// Quality of the first rule matching the file, in the order they are given
+pub fn quality_for(rules: &[QualityRule], relative_path: &Path) -> Option<u32> {
+    rules
+        .iter()
+        .find(|rule| rule.matches(relative_path))
+        .map(|rule| rule.quality)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build_rules(rules: &[(&str, u32)]) -> Vec<QualityRule> {
+        rules
+            .iter()
+            .map(|(glob, quality)| QualityRule::new(glob, *quality).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_quality_for_non_overlapping_rules() {
+        let rules = build_rules(&[("hero/", 95), ("*.png", 60)]);
+        assert_eq!(quality_for(&rules, Path::new("hero/banner.jpg")), Some(95));
+        assert_eq!(quality_for(&rules, Path::new("shop/hero/shoe.jpg")), Some(95));
+        assert_eq!(quality_for(&rules, Path::new("icons/logo.png")), Some(60));
+        assert_eq!(quality_for(&rules, Path::new("shop/shoe.jpg")), None);
+        assert_eq!(quality_for(&[], Path::new("shop/shoe.jpg")), None);
+    }
+
+    #[test]
+    fn test_quality_for_overlapping_rules() {
+        let rules = build_rules(&[("/thumbs/*.png", 40), ("thumbs/", 70), ("*.png", 90)]);
+        assert_eq!(quality_for(&rules, Path::new("thumbs/a.png")), Some(40));
+        assert_eq!(quality_for(&rules, Path::new("thumbs/a.jpg")), Some(70));
+        assert_eq!(quality_for(&rules, Path::new("thumbs/small/a.png")), Some(70));
+        assert_eq!(quality_for(&rules, Path::new("a.png")), Some(90));
+
+        // The first matching rule wins, even when a later one is more specific
+        let rules = build_rules(&[("*.png", 90), ("/thumbs/*.png", 40)]);
+        assert_eq!(quality_for(&rules, Path::new("thumbs/a.png")), Some(90));
+    }
+
+    #[test]
+    fn test_quality_rule_new() {
+        assert_eq!(QualityRule::new(" **/hero/* ", 95).unwrap().glob, "**/hero/*");
+        assert!(QualityRule::new("", 95).is_err());
+        assert!(QualityRule::new("!*.png", 95).is_err());
+        assert!(QualityRule::new("a[", 95).is_err());
+    }
+}
@@ -0,0 +1,311 @@
+//! Full size JPEG previews embedded in camera RAW files, compressed in place of the RAW data.
+//!
+//! CR2, NEF and ARW files are TIFF files: their IFDs point to the raw sensor data and to JPEG previews of several
+//! sizes, the largest of which is kept. Only the orientation of the RAW file is carried over to the preview.
+
+use crate::metadata::insert_exif;
+use std::collections::HashSet;
+use std::error::Error;
+use std::path::Path;
+
+/// Extensions of the RAW files whose previews are extracted
+pub const RAW_EXTENSIONS: [&str; 3] = ["cr2", "nef", "arw"];
+
+const TAG_COMPRESSION: u16 = 0x0103;
+const TAG_STRIP_OFFSETS: u16 = 0x0111;
+const TAG_ORIENTATION: u16 = 0x0112;
+const TAG_STRIP_BYTE_COUNTS: u16 = 0x0117;
+const TAG_SUB_IFDS: u16 = 0x014A;
+const TAG_JPEG_OFFSET: u16 = 0x0201;
+const TAG_JPEG_LENGTH: u16 = 0x0202;
+/// Old style and new style JPEG compression of the strips
+const JPEG_COMPRESSIONS: [u32; 2] = [6, 7];
+/// Bound on the IFDs followed, against files whose offsets loop
+const MAX_IFDS: usize = 64;
+
+/// Whether the extension of `path` is the one of a supported RAW format
+pub fn is_raw_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| RAW_EXTENSIONS.contains(&extension.to_lowercase().as_str()))
+}
+
+/// The largest JPEG preview of a RAW file, with the orientation of the RAW file when it has no EXIF data of its own
+pub fn extract_raw_preview(buffer: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let tiff = Tiff::new(buffer).ok_or("Not a TIFF based RAW file")?;
+    let mut orientation = None;
+    let mut previews = Vec::new();
+    let mut pending_ifds = vec![tiff.read_u32(4).ok_or("Truncated TIFF header")? as usize];
+    let mut visited_ifds = HashSet::new();
+    while let Some(offset) = pending_ifds.pop() {
+        if offset == 0 || visited_ifds.len() >= MAX_IFDS || !visited_ifds.insert(offset) {
+            continue;
+        }
+        let Some(ifd) = tiff.read_ifd(offset) else {
+            continue;
+        };
+        // The orientation of the photo is the one of the first IFD
+        if visited_ifds.len() == 1 {
+            orientation = ifd.value(TAG_ORIENTATION);
+        }
+        pending_ifds.extend(ifd.sub_ifds.iter().map(|&offset| offset as usize));
+        pending_ifds.extend((ifd.next > 0).then_some(ifd.next as usize));
+
+        let jpeg_interchange = ifd.value(TAG_JPEG_OFFSET).zip(ifd.value(TAG_JPEG_LENGTH));
+        let jpeg_strip = match ifd.value(TAG_COMPRESSION) {
+            Some(compression) if JPEG_COMPRESSIONS.contains(&compression) => {
+                ifd.value(TAG_STRIP_OFFSETS).zip(ifd.value(TAG_STRIP_BYTE_COUNTS))
+            }
+            _ => None,
+        };
+        previews.extend(
+            [jpeg_interchange, jpeg_strip]
+                .into_iter()
+                .flatten()
+                .filter_map(|(offset, length)| {
+                    buffer.get(offset as usize..(offset as usize).checked_add(length as usize)?)
+                })
+                .filter_map(|jpeg| Some((jpeg_dimensions(jpeg)?, jpeg))),
+        );
+    }
+
+    let (_, preview) = previews
+        .into_iter()
+        .max_by_key(|((width, height), jpeg)| (*width as u32 * *height as u32, jpeg.len()))
+        .ok_or("No embedded JPEG preview found")?;
+    match orientation.filter(|&orientation| orientation > 1 && !has_exif(preview)) {
+        Some(orientation) => insert_exif(preview, &orientation_exif(orientation as u16)),
+        None => Ok(preview.to_vec()),
+    }
+}
+
+/// A TIFF structure holding only an orientation tag, to be embedded as EXIF data
+fn orientation_exif(orientation: u16) -> Vec<u8> {
+    let mut exif = b"II*\0".to_vec();
+    exif.extend_from_slice(&8u32.to_le_bytes());
+    exif.extend_from_slice(&1u16.to_le_bytes());
+    // Tag, SHORT type, one value, and the value padded to four bytes
+    exif.extend_from_slice(&TAG_ORIENTATION.to_le_bytes());
+    exif.extend_from_slice(&3u16.to_le_bytes());
+    exif.extend_from_slice(&1u32.to_le_bytes());
+    exif.extend_from_slice(&orientation.to_le_bytes());
+    exif.extend_from_slice(&[0, 0]);
+    exif.extend_from_slice(&0u32.to_le_bytes());
+    exif
+}
+
+/// Width and height of a baseline or progressive JPEG. The lossless JPEG of the raw data of some cameras is left out.
+fn jpeg_dimensions(jpeg: &[u8]) -> Option<(u16, u16)> {
+    jpeg_segments(jpeg)
+        .find(|(marker, _)| matches!(marker, 0xC0..=0xC2))
+        .and_then(|(_, segment)| {
+            let height = u16::from_be_bytes([*segment.get(1)?, *segment.get(2)?]);
+            let width = u16::from_be_bytes([*segment.get(3)?, *segment.get(4)?]);
+            (width > 0 && height > 0).then_some((width, height))
+        })
+}
+
+fn has_exif(jpeg: &[u8]) -> bool {
+    jpeg_segments(jpeg).any(|(marker, segment)| marker == 0xE1 && segment.starts_with(b"Exif\0\0"))
+}
+
+/// Markers and payloads of the segments of a JPEG, up to its scan
+fn jpeg_segments(jpeg: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    let mut position = if jpeg.starts_with(&[0xFF, 0xD8]) { 2 } else { jpeg.len() };
+    std::iter::from_fn(move || {
+        let header = jpeg.get(position..position + 4).filter(|header| header[0] == 0xFF)?;
+        let marker = header[1];
+        let length = u16::from_be_bytes([header[2], header[3]]) as usize;
+        let segment = jpeg.get(position + 4..position + 2 + length)?;
+        position += 2 + length;
+        // Nothing follows the start of scan but the compressed data
+        (marker != 0xDA).then_some((marker, segment))
+    })
+}
+
+/// The entries of an IFD needed to find the previews
+struct Ifd {
+    values: Vec<(u16, u32)>,
+    sub_ifds: Vec<u32>,
+    next: u32,
+}
+
+impl Ifd {
+    fn value(&self, tag: u16) -> Option<u32> {
+        self.values
+            .iter()
+            .find(|(entry_tag, _)| *entry_tag == tag)
+            .map(|(_, value)| *value)
+    }
+}
+
+struct Tiff<'a> {
+    buffer: &'a [u8],
+    little_endian: bool,
+}
+
+impl<'a> Tiff<'a> {
+    fn new(buffer: &'a [u8]) -> Option<Self> {
+        let little_endian = match buffer.get(..4)? {
+            b"II*\0" => true,
+            b"MM\0*" => false,
+            _ => return None,
+        };
+        Some(Self { buffer, little_endian })
+    }
+
+    fn read_u16(&self, offset: usize) -> Option<u16> {
+        let bytes = self.buffer.get(offset..offset + 2)?.try_into().ok()?;
+        Some(match self.little_endian {
+            true => u16::from_le_bytes(bytes),
+            false => u16::from_be_bytes(bytes),
+        })
+    }
+
+    fn read_u32(&self, offset: usize) -> Option<u32> {
+        let bytes = self.buffer.get(offset..offset + 4)?.try_into().ok()?;
+        Some(match self.little_endian {
+            true => u32::from_le_bytes(bytes),
+            false => u32::from_be_bytes(bytes),
+        })
+    }
+
+    /// Reads the single SHORT or LONG values of an IFD, and the offsets of its sub-IFDs
+    fn read_ifd(&self, offset: usize) -> Option<Ifd> {
+        let entry_count = self.read_u16(offset)? as usize;
+        let mut ifd = Ifd {
+            values: Vec::with_capacity(entry_count),
+            sub_ifds: Vec::new(),
+            next: 0,
+        };
+        for entry in 0..entry_count {
+            let entry_offset = offset + 2 + entry * 12;
+            let tag = self.read_u16(entry_offset)?;
+            let field_type = self.read_u16(entry_offset + 2)?;
+            let count = self.read_u32(entry_offset + 4)? as usize;
+            let value_offset = entry_offset + 8;
+            match (field_type, count) {
+                // SHORT
+                (3, 1) => ifd.values.push((tag, self.read_u16(value_offset)? as u32)),
+                // LONG or IFD
+                (4 | 13, 1) => {
+                    let value = self.read_u32(value_offset)?;
+                    ifd.values.push((tag, value));
+                    if tag == TAG_SUB_IFDS {
+                        ifd.sub_ifds.push(value);
+                    }
+                }
+                // Several sub-IFDs, whose offsets are stored elsewhere
+                (4 | 13, _) if tag == TAG_SUB_IFDS => {
+                    let array_offset = self.read_u32(value_offset)? as usize;
+                    let offsets = (0..count.min(MAX_IFDS)).filter_map(|index| self.read_u32(array_offset + index * 4));
+                    ifd.sub_ifds.extend(offsets);
+                }
+                _ => {}
+            }
+        }
+        ifd.next = self.read_u32(offset + 2 + entry_count * 12).unwrap_or(0);
+        Some(ifd)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn jpeg(width: u32, height: u32) -> Vec<u8> {
+        let image = image::RgbImage::from_pixel(width, height, image::Rgb([200, 40, 40]));
+        let mut output = Vec::new();
+        image
+            .write_to(&mut Cursor::new(&mut output), image::ImageFormat::Jpeg)
+            .unwrap();
+        output
+    }
+
+    /// A little endian RAW file: a first IFD with the orientation and a small preview, and a sub-IFD with a large one
+    /// stored as a JPEG strip, like NEF files
+    fn raw_file(orientation: u16, small: &[u8], large: &[u8]) -> Vec<u8> {
+        fn entry(tag: u16, field_type: u16, value: u32) -> Vec<u8> {
+            let mut entry = tag.to_le_bytes().to_vec();
+            entry.extend_from_slice(&field_type.to_le_bytes());
+            entry.extend_from_slice(&1u32.to_le_bytes());
+            entry.extend_from_slice(&value.to_le_bytes());
+            entry
+        }
+        let ifd_length = |entries: u32| 2 + entries * 12 + 4;
+        let ifd0_offset = 8;
+        let sub_ifd_offset = ifd0_offset + ifd_length(4);
+        let small_offset = sub_ifd_offset + ifd_length(3);
+        let large_offset = small_offset + small.len() as u32;
+
+        let mut raw = b"II*\0".to_vec();
+        raw.extend_from_slice(&ifd0_offset.to_le_bytes());
+        raw.extend_from_slice(&4u16.to_le_bytes());
+        raw.extend(entry(TAG_ORIENTATION, 3, orientation as u32));
+        raw.extend(entry(TAG_SUB_IFDS, 4, sub_ifd_offset));
+        raw.extend(entry(TAG_JPEG_OFFSET, 4, small_offset));
+        raw.extend(entry(TAG_JPEG_LENGTH, 4, small.len() as u32));
+        raw.extend_from_slice(&0u32.to_le_bytes());
+        raw.extend_from_slice(&3u16.to_le_bytes());
+        raw.extend(entry(TAG_COMPRESSION, 3, 6));
+        raw.extend(entry(TAG_STRIP_OFFSETS, 4, large_offset));
+        raw.extend(entry(TAG_STRIP_BYTE_COUNTS, 4, large.len() as u32));
+        raw.extend_from_slice(&0u32.to_le_bytes());
+        raw.extend_from_slice(small);
+        raw.extend_from_slice(large);
+        raw
+    }
+
+    #[test]
+    fn test_extract_raw_preview() {
+        let (small, large) = (jpeg(16, 8), jpeg(64, 32));
+        let preview = extract_raw_preview(&raw_file(1, &small, &large)).unwrap();
+        assert_eq!(preview, large);
+
+        // The orientation is carried over
+        let preview = extract_raw_preview(&raw_file(6, &small, &large)).unwrap();
+        assert_eq!(jpeg_dimensions(&preview), Some((64, 32)));
+        let exif = exif::Reader::new()
+            .read_from_container(&mut Cursor::new(&preview))
+            .unwrap();
+        let orientation = exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY).unwrap();
+        assert_eq!(orientation.value.get_uint(0), Some(6));
+
+        // A lossless JPEG of the raw data is not a preview
+        let mut lossless = large.clone();
+        let mut position = 2;
+        while lossless[position + 1] != 0xC0 {
+            position += 2 + u16::from_be_bytes([lossless[position + 2], lossless[position + 3]]) as usize;
+        }
+        lossless[position + 1] = 0xC3;
+        assert_eq!(extract_raw_preview(&raw_file(1, &small, &lossless)).unwrap(), small);
+    }
+
+    #[test]
+    fn test_extract_raw_preview_errors() {
+        assert!(extract_raw_preview(&std::fs::read("samples/j0.JPG").unwrap()).is_err());
+        assert!(extract_raw_preview(b"II*\0").is_err());
+        // A TIFF without any JPEG in it
+        let mut tiff = Vec::new();
+        image::RgbImage::new(4, 4)
+            .write_to(&mut Cursor::new(&mut tiff), image::ImageFormat::Tiff)
+            .unwrap();
+        assert!(extract_raw_preview(&tiff).is_err());
+        // An IFD pointing to itself
+        let mut looping = b"II*\0".to_vec();
+        looping.extend_from_slice(&8u32.to_le_bytes());
+        looping.extend_from_slice(&0u16.to_le_bytes());
+        looping.extend_from_slice(&8u32.to_le_bytes());
+        assert!(extract_raw_preview(&looping).is_err());
+    }
+
+    #[test]
+    fn test_is_raw_file() {
+        assert!(is_raw_file(Path::new("DSC_0001.NEF")));
+        assert!(is_raw_file(Path::new("IMG_0001.cr2")));
+        assert!(is_raw_file(Path::new("photos/_DSC0001.ARW")));
+        assert!(!is_raw_file(Path::new("scan.tif")));
+        assert!(!is_raw_file(Path::new("nef")));
+    }
+}
@@ -0,0 +1,158 @@
+//! Output names read from a CSV file of `input_path,output_name` pairs, overriding the names derived from the inputs.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Extensions dropped from the output names, which get the extension of the output format instead
+const IMAGE_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png", "webp", "gif", "tif", "tiff"];
+
+/// Output names without extension, by canonical input path
+pub type RenameMap = HashMap<PathBuf, String>;
+
+/// Reads the map from a CSV file. Relative input paths are relative to the folder of the file.
+/// Fields can be quoted with `"`, e.g. to contain a comma. An `input_path,output_name` header line is skipped.
+pub fn load_rename_map(path: &Path) -> Result<RenameMap, String> {
+    let content =
+        fs::read_to_string(path).map_err(|e| format!("Unable to read the rename map {}: {e}", path.display()))?;
+    let base_folder = path.parent().unwrap_or(Path::new(""));
+    parse_rename_map(&content, base_folder).map_err(|e| format!("Invalid rename map {}: {e}", path.display()))
+}
+
+/// Parses and validates the CSV content: every input must exist, and appear once, and no two inputs can get the
+/// same name
+pub fn parse_rename_map(content: &str, base_folder: &Path) -> Result<RenameMap, String> {
+    let mut rename_map = RenameMap::new();
+    let mut names = HashSet::new();
+    for (index, line) in content.lines().enumerate() {
+        let line_number = index + 1;
+        let line = line.trim();
+        if line.is_empty() || (line_number == 1 && line.eq_ignore_ascii_case("input_path,output_name")) {
+            continue;
+        }
+
+        let fields = split_csv_line(line).ok_or_else(|| format!("line {line_number}: unterminated quote"))?;
+        let [input, name] = fields.as_slice() else {
+            return Err(format!(
+                "line {line_number}: expected input_path,output_name, found {} fields",
+                fields.len()
+            ));
+        };
+        let name = strip_image_extension(name.trim());
+        if name.is_empty() || name.contains(['/', '\\']) || name == "." || name == ".." {
+            return Err(format!("line {line_number}: '{name}' is not a valid file name"));
+        }
+
+        let input_path = base_folder.join(input.trim());
+        let input_path =
+            fs::canonicalize(&input_path).map_err(|e| format!("line {line_number}: {}: {e}", input_path.display()))?;
+        if !names.insert(name.to_string()) {
+            return Err(format!(
+                "line {line_number}: the output name '{name}' is used more than once"
+            ));
+        }
+        if rename_map.insert(input_path.clone(), name.to_string()).is_some() {
+            return Err(format!(
+                "line {line_number}: {} is listed more than once",
+                input_path.display()
+            ));
+        }
+    }
+
+    Ok(rename_map)
+}
+
+/// Splits a CSV line on commas, except within double quotes, where `""` is a quote.
+/// Returns `None` if a quote is not closed.
+fn split_csv_line(line: &str) -> Option<Vec<String>> {
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, in_quotes) {
+            ('"', true) if chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            ('"', _) => in_quotes = !in_quotes,
+            (',', false) => fields.push(std::mem::take(&mut field)),
+            (c, _) => field.push(c),
+        }
+    }
+    if in_quotes {
+        return None;
+    }
+    fields.push(field);
+
+    Some(fields)
+}
+
+fn strip_image_extension(name: &str) -> &str {
+    match name.rsplit_once('.') {
+        Some((stem, extension))
+            if !stem.is_empty()
+                && IMAGE_EXTENSIONS
+                    .iter()
+                    .any(|image_extension| image_extension.eq_ignore_ascii_case(extension)) =>
+        {
+            stem
+        }
+        _ => name,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_csv_line() {
+        assert_eq!(split_csv_line("a.jpg,b").unwrap(), ["a.jpg", "b"]);
+        assert_eq!(split_csv_line("\"a,1.jpg\",b").unwrap(), ["a,1.jpg", "b"]);
+        assert_eq!(split_csv_line("\"say \"\"hi\"\"\",b").unwrap(), ["say \"hi\"", "b"]);
+        assert_eq!(split_csv_line("a.jpg").unwrap(), ["a.jpg"]);
+        assert!(split_csv_line("\"a.jpg,b").is_none());
+    }
+
+    #[test]
+    fn test_strip_image_extension() {
+        assert_eq!(strip_image_extension("shoe.JPG"), "shoe");
+        assert_eq!(strip_image_extension("shoe.webp"), "shoe");
+        assert_eq!(strip_image_extension("v1.2-shoe"), "v1.2-shoe");
+        assert_eq!(strip_image_extension(".png"), ".png");
+    }
+
+    #[test]
+    fn test_parse_rename_map() {
+        let samples = Path::new("samples");
+        let rename_map = parse_rename_map(
+            "input_path,output_name\nj0.JPG, red-shoe.jpg\n\n\"p0.png\",blue",
+            samples,
+        )
+        .unwrap();
+        assert_eq!(rename_map.len(), 2);
+        assert_eq!(
+            rename_map.get(&fs::canonicalize("samples/j0.JPG").unwrap()).unwrap(),
+            "red-shoe"
+        );
+        assert_eq!(
+            rename_map.get(&fs::canonicalize("samples/p0.png").unwrap()).unwrap(),
+            "blue"
+        );
+
+        let error = parse_rename_map("missing.jpg,a", samples).unwrap_err();
+        assert!(error.starts_with("line 1: "), "{error}");
+        let error = parse_rename_map("j0.JPG,a\np0.png,a.png", samples).unwrap_err();
+        assert!(
+            error.contains("line 2: the output name 'a' is used more than once"),
+            "{error}"
+        );
+        let error = parse_rename_map("j0.JPG,a\n./j0.JPG,b", samples).unwrap_err();
+        assert!(error.contains("listed more than once"), "{error}");
+        assert!(parse_rename_map("j0.JPG,a/b", samples).is_err());
+        assert!(parse_rename_map("j0.JPG,", samples).is_err());
+        assert!(parse_rename_map("j0.JPG,a,b", samples).is_err());
+        assert!(parse_rename_map("j0.JPG", samples).is_err());
+    }
+}
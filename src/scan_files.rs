@@ -1,15 +1,57 @@
+use std::collections::HashSet;
+use std::fs;
 use std::path::{absolute, Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
+use caesiumclt::heic::is_heic;
+use caesiumclt::legacy_formats::detect_legacy_format;
+use caesiumclt::pdf::is_pdf;
+use caesiumclt::raw_preview::is_raw_file;
+use caesiumclt::svg::{is_svg, SVG_HEADER_LENGTH};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
 use indicatif::ProgressStyle;
 use indicatif::{ProgressBar, ProgressDrawTarget, ProgressIterator};
-use walkdir::WalkDir;
+use rayon::prelude::*;
+
+#[derive(Default)]
+pub struct ScanSettings {
+    pub recursive: bool,
+    pub check_extension_only: bool,
+    pub follow_symlinks: bool,
+    pub use_ignore: bool,
+    /// Levels of subfolders scanned with `recursive`, 0 being only the files of the input folder
+    pub max_depth: Option<usize>,
+    /// Canonical path of a folder left out of the scan, see `nested_output_folder`
+    pub excluded_directory: Option<PathBuf>,
+    /// Include the RAW files, whose previews are compressed
+    pub raw_preview: bool,
+}
+
+/// Ignore files read in every scanned directory, the later taking precedence
+const IGNORE_FILES: [&str; 2] = [".gitignore", ".ignore"];
 
 fn has_supported_extension(path: &Path) -> bool {
     match path.extension().and_then(|e| e.to_str()) {
         Some(ext) => {
             let ext_lower = ext.to_lowercase();
-            matches!(ext_lower.as_str(), "jpg" | "jpeg" | "png" | "webp" | "gif")
+            matches!(
+                ext_lower.as_str(),
+                "jpg"
+                    | "jpeg"
+                    | "png"
+                    | "webp"
+                    | "gif"
+                    | "tif"
+                    | "tiff"
+                    | "heic"
+                    | "heif"
+                    | "bmp"
+                    | "tga"
+                    | "ico"
+                    | "svg"
+                    | "pdf"
+            )
         }
         None => false,
     }
@@ -19,78 +61,198 @@ fn read_first_bytes(path: &Path, count: usize) -> Option<Vec<u8>> {
     use std::fs::File;
     use std::io::Read;
 
-    let mut file = File::open(path).ok()?;
-    let mut buffer = vec![0; count];
-    match file.read_exact(&mut buffer) {
-        Ok(_) => Some(buffer),
-        Err(_) => None,
-    }
+    let file = File::open(path).ok()?;
+    let mut buffer = Vec::with_capacity(count);
+    file.take(count as u64).read_to_end(&mut buffer).ok()?;
+    Some(buffer)
 }
 
 fn is_filetype_supported(path: &Path) -> bool {
-    let buffer = match read_first_bytes(path, 16) {
+    let buffer = match read_first_bytes(path, SVG_HEADER_LENGTH) {
         Some(b) => b,
         None => return false,
     };
+    if is_svg(&buffer) || is_pdf(&buffer) {
+        return true;
+    }
+    // Enough for the header of a TGA file, which has no signature
+    if buffer.len() < 18 {
+        return false;
+    }
 
     infer::image::is_jpeg(&buffer)
         || infer::image::is_png(&buffer)
         || infer::image::is_webp(&buffer)
         || infer::image::is_gif(&buffer)
+        || infer::image::is_tiff(&buffer)
+        || is_heic(&buffer)
+        || detect_legacy_format(&buffer).is_some()
 }
 
-fn is_valid_file(path: &Path, check_extension_only: bool) -> bool {
-    if check_extension_only {
+pub fn is_valid_file(path: &Path, settings: &ScanSettings) -> bool {
+    // RAW files are TIFF files to their magic bytes, only their extension tells them apart
+    if is_raw_file(path) {
+        return settings.raw_preview;
+    }
+    if settings.check_extension_only {
         return has_supported_extension(path);
     }
 
     is_filetype_supported(path)
 }
 
-pub fn scan_files(
-    args: &[String],
-    recursive: bool,
-    quiet: bool,
-    check_extension_only: bool,
-) -> (Option<PathBuf>, Vec<PathBuf>) {
+pub fn scan_files(args: &[String], quiet: bool, settings: &ScanSettings) -> (Option<PathBuf>, Vec<PathBuf>) {
     if args.is_empty() {
         return (None, vec![]);
     }
     let mut files: Vec<PathBuf> = vec![];
     let mut base_path: Option<PathBuf> = None;
+    let visited_directories: Mutex<HashSet<PathBuf>> = Mutex::new(HashSet::new());
     let progress_bar = init_progress_bar(quiet);
 
     for path in args.iter().progress_with(progress_bar) {
         let input = PathBuf::from(path);
         if input.exists() && input.is_dir() {
-            let mut walk_dir = WalkDir::new(&input).follow_links(false);
-            if !recursive {
-                walk_dir = walk_dir.max_depth(1);
-            }
-            for entry in walk_dir.into_iter().filter_map(|e| e.ok()) {
-                if entry.file_type().is_file() {
-                    let path = entry.into_path();
-                    if is_valid_file(&path, check_extension_only) {
-                        base_path = match compute_base_path(&path, base_path.clone()) {
-                            Some(p) => Some(p),
-                            None => continue,
-                        };
-                        files.push(path);
-                    }
-                }
+            // The walk order depends on thread scheduling, sorting keeps the output deterministic
+            let mut directory_files = walk_directory(&input, settings, &visited_directories, &[], 0);
+            directory_files.sort();
+            for path in directory_files {
+                base_path = match compute_base_path(&path, base_path.clone()) {
+                    Some(p) => Some(p),
+                    None => continue,
+                };
+                files.push(path);
             }
-        } else if input.is_file() && is_valid_file(&input, check_extension_only) {
+        } else if input.is_file() && is_valid_file(&input, settings) {
             base_path = match compute_base_path(&input, base_path.clone()) {
                 Some(p) => Some(p),
                 None => continue,
             };
             files.push(input);
+        } else if !input.is_file() || fs::File::open(&input).is_err() {
+            // Inputs listed explicitly but missing or unreadable are kept, so that they are reported as errors
+            files.push(input);
         }
     }
 
     (base_path, files)
 }
 
+/// The output folder when it is inside one of the input folders, but not one of them, e.g. `photos/compressed` for
+/// `photos`. A recursive scan would otherwise pick up the outputs of a previous run as inputs.
+/// Returns its canonical path, `None` when it doesn't exist yet.
+pub fn nested_output_folder(inputs: &[String], output_folder: &Path) -> Option<PathBuf> {
+    let output_folder = fs::canonicalize(output_folder).ok()?;
+    inputs
+        .iter()
+        .filter_map(|input| fs::canonicalize(input).ok())
+        .filter(|input| input.is_dir())
+        .any(|input| output_folder != input && output_folder.starts_with(&input))
+        .then_some(output_folder)
+}
+
+/// Lists the supported files in `directory`, scanning subfolders and checking file types in parallel.
+/// `ignore_rules` holds the rules of the parent directories, the innermost last, and `depth` is the number of
+/// folders between `directory` and the input one.
+fn walk_directory(
+    directory: &Path,
+    settings: &ScanSettings,
+    visited: &Mutex<HashSet<PathBuf>>,
+    ignore_rules: &[Arc<Gitignore>],
+    depth: usize,
+) -> Vec<PathBuf> {
+    let Ok(canonical_directory) = fs::canonicalize(directory) else {
+        return vec![];
+    };
+    if settings.excluded_directory.as_ref() == Some(&canonical_directory) {
+        return vec![];
+    }
+    // Directories reachable through several symlinks (or through a cycle) are only scanned once
+    let first_visit = visited
+        .lock()
+        .is_ok_and(|mut visited| visited.insert(canonical_directory));
+    if !first_visit {
+        return vec![];
+    }
+
+    let entries: Vec<fs::DirEntry> = match fs::read_dir(directory) {
+        Ok(entries) => entries.filter_map(|e| e.ok()).collect(),
+        Err(_) => return vec![],
+    };
+
+    let mut ignore_rules = ignore_rules.to_vec();
+    if settings.use_ignore {
+        if let Some(rules) = load_ignore_rules(directory) {
+            ignore_rules.push(Arc::new(rules));
+        }
+    }
+
+    entries
+        .into_par_iter()
+        .flat_map_iter(|entry| {
+            let path = entry.path();
+            let Ok(file_type) = entry.file_type() else {
+                return vec![];
+            };
+
+            let is_dir = file_type.is_dir() || (file_type.is_symlink() && settings.follow_symlinks && path.is_dir());
+            if is_ignored(&path, is_dir, &ignore_rules) {
+                return vec![];
+            }
+
+            if is_dir {
+                let within_depth = settings.max_depth.is_none_or(|max_depth| depth < max_depth);
+                return if settings.recursive && within_depth {
+                    walk_directory(&path, settings, visited, &ignore_rules, depth + 1)
+                } else {
+                    vec![]
+                };
+            }
+
+            // Symlinked files are compressed even when directory symlinks are not followed
+            let is_file = file_type.is_file() || (file_type.is_symlink() && path.is_file());
+            if is_file && is_valid_file(&path, settings) {
+                vec![path]
+            } else {
+                vec![]
+            }
+        })
+        .collect()
+}
+
+fn load_ignore_rules(directory: &Path) -> Option<Gitignore> {
+    let ignore_files: Vec<PathBuf> = IGNORE_FILES
+        .iter()
+        .map(|name| directory.join(name))
+        .filter(|path| path.is_file())
+        .collect();
+    if ignore_files.is_empty() {
+        return None;
+    }
+
+    // Invalid lines are skipped, the valid ones still apply
+    let mut builder = GitignoreBuilder::new(directory);
+    for ignore_file in ignore_files {
+        builder.add(ignore_file);
+    }
+    builder.build().ok()
+}
+
+/// The innermost directory with a matching rule decides, as with git
+fn is_ignored(path: &Path, is_dir: bool, ignore_rules: &[Arc<Gitignore>]) -> bool {
+    for rules in ignore_rules.iter().rev() {
+        let matched = rules.matched(path, is_dir);
+        if matched.is_ignore() {
+            return true;
+        }
+        if matched.is_whitelist() {
+            return false;
+        }
+    }
+
+    false
+}
+
 fn compute_base_path(path: &Path, base_path: Option<PathBuf>) -> Option<PathBuf> {
     if !path.exists() {
         return None;
@@ -176,10 +338,16 @@ mod tests {
         assert!(has_supported_extension(Path::new("test.webp")));
         assert!(has_supported_extension(Path::new("test.gif")));
 
-        assert!(!has_supported_extension(Path::new("test.tiff")));
-        assert!(!has_supported_extension(Path::new("test.tif")));
+        assert!(has_supported_extension(Path::new("test.tiff")));
+        assert!(has_supported_extension(Path::new("SCAN.TIF")));
         assert!(!has_supported_extension(Path::new("test.txt")));
         assert!(!has_supported_extension(Path::new("test.avif")));
+        assert!(has_supported_extension(Path::new("IMG_0042.HEIC")));
+        assert!(has_supported_extension(Path::new("sprite.tga")));
+        assert!(has_supported_extension(Path::new("TILES.BMP")));
+        assert!(has_supported_extension(Path::new("favicon.ico")));
+        assert!(has_supported_extension(Path::new("logo.svg")));
+        assert!(has_supported_extension(Path::new("Scan 2024-01.PDF")));
         assert!(!has_supported_extension(Path::new("test")));
     }
 
@@ -190,6 +358,10 @@ mod tests {
             image::ImageFormat::Png,
             image::ImageFormat::WebP,
             image::ImageFormat::Gif,
+            image::ImageFormat::Bmp,
+            image::ImageFormat::Tga,
+            image::ImageFormat::Ico,
+            image::ImageFormat::Tiff,
         ];
 
         for supported_file in supported_file_types {
@@ -204,7 +376,7 @@ mod tests {
             assert!(is_filetype_supported(temp_file.path()));
         }
 
-        let unsupported_file_types = [image::ImageFormat::Tiff, image::ImageFormat::Avif];
+        let unsupported_file_types = [image::ImageFormat::Avif];
 
         for unsupported_file in unsupported_file_types {
             let mut temp_file = NamedTempFile::new().unwrap();
@@ -217,6 +389,20 @@ mod tests {
 
             assert!(!is_filetype_supported(temp_file.path()));
         }
+
+        // SVG files are found from their root element, after the prolog
+        let mut temp_file = NamedTempFile::new().unwrap();
+        temp_file
+            .write_all(b"<?xml version=\"1.0\"?>\n<!-- Icon -->\n<svg xmlns=\"http://www.w3.org/2000/svg\"/>")
+            .unwrap();
+        assert!(is_filetype_supported(temp_file.path()));
+        let mut temp_file = NamedTempFile::new().unwrap();
+        temp_file.write_all(b"<?xml version=\"1.0\"?>\n<html/>").unwrap();
+        assert!(!is_filetype_supported(temp_file.path()));
+
+        let mut temp_file = NamedTempFile::new().unwrap();
+        temp_file.write_all(b"%PDF-1.7\n%\xE2\xE3\xCF\xD3\n").unwrap();
+        assert!(is_filetype_supported(temp_file.path()));
     }
 
     #[test]
@@ -363,35 +549,368 @@ mod tests {
 
         // Test with recursive = false, quiet = true, check_extension_only = false
         let args = vec![temp_path.to_string_lossy().to_string()];
-        let (base_path, files) = scan_files(&args, false, true, false);
+        let (base_path, files) = scan_files(&args, true, &ScanSettings::default());
         assert!(!base_path.unwrap().as_os_str().is_empty());
         assert_eq!(files.len(), 3); // Should find 3 image files (jpg, png, and the extensionless one)
 
         // Test with recursive = false, quiet = true, check_extension_only = true
         let args = vec![temp_path.to_string_lossy().to_string()];
-        let (base_path, files) = scan_files(&args, false, true, true);
+        let (base_path, files) = scan_files(
+            &args,
+            true,
+            &ScanSettings {
+                check_extension_only: true,
+                ..Default::default()
+            },
+        );
         assert!(!base_path.unwrap().as_os_str().is_empty());
         assert_eq!(files.len(), 2); // Should find ONLY the 2 files with extensions
 
         // Test with empty args
         let args: Vec<String> = vec![];
-        let (base_path, files) = scan_files(&args, false, true, false);
+        let (base_path, files) = scan_files(&args, true, &ScanSettings::default());
         assert!(base_path.is_none());
         assert_eq!(files.len(), 0);
 
-        // Test with a non-existent path
+        // Test with a non-existent path, kept to be reported
         let args = vec!["/non/existent/path".to_string()];
-        let (base_path, files) = scan_files(&args, false, true, false);
+        let (base_path, files) = scan_files(&args, true, &ScanSettings::default());
         assert!(base_path.is_none());
-        assert_eq!(files.len(), 0);
+        assert_eq!(files, vec![PathBuf::from("/non/existent/path")]);
+
+        // Test mixing an existing file and a missing one
+        let missing_path = temp_path.join("missing.jpg");
+        let args = vec![
+            jpeg_path.to_string_lossy().to_string(),
+            missing_path.to_string_lossy().to_string(),
+        ];
+        let (base_path, files) = scan_files(&args, true, &ScanSettings::default());
+        assert_eq!(base_path.unwrap(), temp_path);
+        assert_eq!(files, vec![jpeg_path.clone(), missing_path]);
+
+        // Existing files of unsupported types are still ignored
+        let args = vec![txt_path.to_string_lossy().to_string()];
+        let (_, files) = scan_files(&args, true, &ScanSettings::default());
+        assert!(files.is_empty());
 
         // Test with a file path directly
         let args = vec![jpeg_path.to_string_lossy().to_string()];
-        let (base_path, files) = scan_files(&args, false, true, false);
+        let (base_path, files) = scan_files(&args, true, &ScanSettings::default());
         assert!(!base_path.unwrap().as_os_str().is_empty());
         assert_eq!(files.len(), 1);
     }
 
+    #[test]
+    fn test_scan_files_raw_preview() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let temp_path = temp_dir.path();
+        // A TIFF header is all the scan reads
+        std::fs::write(temp_path.join("DSC_0001.NEF"), b"II*\0\x08\0\0\0").unwrap();
+        std::fs::write(temp_path.join("notes.txt"), b"Not a RAW file").unwrap();
+
+        let args = vec![temp_path.to_string_lossy().to_string()];
+        let (_, files) = scan_files(&args, true, &ScanSettings::default());
+        assert!(files.is_empty());
+
+        for check_extension_only in [false, true] {
+            let (_, files) = scan_files(
+                &args,
+                true,
+                &ScanSettings {
+                    check_extension_only,
+                    raw_preview: true,
+                    ..Default::default()
+                },
+            );
+            assert_eq!(files, vec![temp_path.join("DSC_0001.NEF")]);
+        }
+    }
+
+    #[test]
+    fn test_scan_files_tiff() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let temp_path = temp_dir.path();
+        let mut bytes: Vec<u8> = Vec::new();
+        RgbImage::new(4, 4)
+            .write_to(&mut Cursor::new(&mut bytes), image::ImageFormat::Tiff)
+            .unwrap();
+        fs::write(temp_path.join("scan.tif"), &bytes).unwrap();
+        fs::write(temp_path.join("archive.TIFF"), &bytes).unwrap();
+        // Same magic bytes, only picked up as RAW files
+        fs::write(temp_path.join("DSC_0001.NEF"), &bytes).unwrap();
+
+        let args = vec![temp_path.to_string_lossy().to_string()];
+        for check_extension_only in [false, true] {
+            let settings = ScanSettings {
+                check_extension_only,
+                ..Default::default()
+            };
+            let (_, files) = scan_files(&args, true, &settings);
+            assert_eq!(files, vec![temp_path.join("archive.TIFF"), temp_path.join("scan.tif")]);
+        }
+
+        let args = vec![temp_path.join("scan.tif").to_string_lossy().to_string()];
+        let (_, files) = scan_files(&args, true, &ScanSettings::default());
+        assert_eq!(files, vec![temp_path.join("scan.tif")]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_scan_files_symlinks() {
+        use std::os::unix::fs::symlink;
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path().join("root");
+        let nested = root.join("nested");
+        fs::create_dir_all(&nested).unwrap();
+
+        let mut bytes: Vec<u8> = Vec::new();
+        RgbImage::new(1, 1)
+            .write_to(&mut Cursor::new(&mut bytes), image::ImageFormat::Png)
+            .unwrap();
+        fs::write(nested.join("image.png"), &bytes).unwrap();
+
+        // A cycle back to the root and a symlinked file outside the tree
+        symlink(&root, nested.join("loop")).unwrap();
+        let outside = temp_dir.path().join("outside.png");
+        fs::write(&outside, &bytes).unwrap();
+        symlink(&outside, root.join("linked.png")).unwrap();
+
+        let args = vec![root.to_string_lossy().to_string()];
+
+        let (_, files) = scan_files(
+            &args,
+            true,
+            &ScanSettings {
+                recursive: true,
+                ..Default::default()
+            },
+        );
+        assert_eq!(files.len(), 2);
+        assert!(files.contains(&nested.join("image.png")));
+        assert!(files.contains(&root.join("linked.png")));
+
+        let (_, files) = scan_files(
+            &args,
+            true,
+            &ScanSettings {
+                recursive: true,
+                follow_symlinks: true,
+                ..Default::default()
+            },
+        );
+        assert_eq!(files.len(), 2);
+        assert!(files.contains(&nested.join("image.png")));
+        assert!(files.contains(&root.join("linked.png")));
+    }
+
+    #[test]
+    fn test_scan_files_use_ignore() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path();
+        let ignored = root.join("build");
+        let nested = root.join("assets").join("icons");
+        fs::create_dir_all(&ignored).unwrap();
+        fs::create_dir_all(&nested).unwrap();
+
+        let mut bytes: Vec<u8> = Vec::new();
+        RgbImage::new(1, 1)
+            .write_to(&mut Cursor::new(&mut bytes), image::ImageFormat::Png)
+            .unwrap();
+        for path in [
+            root.join("kept.png"),
+            root.join("draft.png"),
+            ignored.join("generated.png"),
+            nested.join("icon.png"),
+            nested.join("draft.png"),
+        ] {
+            fs::write(path, &bytes).unwrap();
+        }
+        fs::write(root.join(".gitignore"), "build/\n*draft*\n").unwrap();
+        // Nested rules take precedence over the parent ones
+        fs::write(root.join("assets").join(".ignore"), "!icons/draft.png\n").unwrap();
+
+        let args = vec![root.to_string_lossy().to_string()];
+
+        let (_, files) = scan_files(
+            &args,
+            true,
+            &ScanSettings {
+                recursive: true,
+                ..Default::default()
+            },
+        );
+        assert_eq!(files.len(), 5);
+
+        let (_, files) = scan_files(
+            &args,
+            true,
+            &ScanSettings {
+                recursive: true,
+                use_ignore: true,
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            files,
+            vec![nested.join("draft.png"), nested.join("icon.png"), root.join("kept.png")]
+        );
+    }
+
+    #[test]
+    fn test_scan_files_excludes_nested_output_folder() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path();
+        let output = root.join("compressed");
+        let nested = root.join("album");
+        fs::create_dir_all(&output).unwrap();
+        fs::create_dir_all(&nested).unwrap();
+
+        let mut bytes: Vec<u8> = Vec::new();
+        RgbImage::new(1, 1)
+            .write_to(&mut Cursor::new(&mut bytes), image::ImageFormat::Png)
+            .unwrap();
+        for path in [root.join("a.png"), nested.join("b.png"), output.join("a.png")] {
+            fs::write(path, &bytes).unwrap();
+        }
+
+        let args = vec![root.to_string_lossy().to_string()];
+        let excluded_directory = nested_output_folder(&args, &output);
+        assert_eq!(excluded_directory, Some(fs::canonicalize(&output).unwrap()));
+        let settings = ScanSettings {
+            recursive: true,
+            excluded_directory,
+            ..Default::default()
+        };
+        let (_, files) = scan_files(&args, true, &settings);
+        assert_eq!(files, vec![root.join("a.png"), nested.join("b.png")]);
+
+        // Outputs written to the input folder itself, elsewhere or not yet created are not nested
+        assert_eq!(nested_output_folder(&args, root), None);
+        assert_eq!(
+            nested_output_folder(&[nested.to_string_lossy().to_string()], &output),
+            None
+        );
+        assert_eq!(nested_output_folder(&args, &root.join("missing")), None);
+    }
+
+    #[test]
+    fn test_scan_files_matches_serial_walk() {
+        let root = absolute(PathBuf::from("samples")).unwrap();
+        let args = vec![root.to_string_lossy().to_string()];
+
+        for check_extension_only in [false, true] {
+            let mut expected: Vec<PathBuf> = walkdir::WalkDir::new(&root)
+                .into_iter()
+                .filter_map(|e| e.ok())
+                .filter(|e| {
+                    let settings = ScanSettings {
+                        check_extension_only,
+                        ..Default::default()
+                    };
+                    e.file_type().is_file() && is_valid_file(e.path(), &settings)
+                })
+                .map(|e| e.into_path())
+                .collect();
+            expected.sort();
+
+            let (base_path, files) = scan_files(
+                &args,
+                true,
+                &ScanSettings {
+                    recursive: true,
+                    check_extension_only,
+                    ..Default::default()
+                },
+            );
+            assert_eq!(base_path.unwrap(), root);
+            assert_eq!(files, expected);
+            assert!(files
+                .iter()
+                .any(|f| f.ends_with("level_1_0/level_2_0/level_3_0/g1.gif")));
+        }
+
+        // Without recursion only the top level is listed
+        let (_, files) = scan_files(&args, true, &ScanSettings::default());
+        assert!(!files.is_empty());
+        assert!(files.iter().all(|f| f.parent() == Some(root.as_path())));
+    }
+
+    #[test]
+    fn test_scan_files_max_depth() {
+        let root = absolute(PathBuf::from("samples")).unwrap();
+        let args = vec![root.to_string_lossy().to_string()];
+        let relative_files = |max_depth| {
+            let (_, files) = scan_files(
+                &args,
+                true,
+                &ScanSettings {
+                    recursive: true,
+                    max_depth,
+                    ..Default::default()
+                },
+            );
+            files
+                .into_iter()
+                .map(|f| f.strip_prefix(&root).unwrap().to_path_buf())
+                .collect::<Vec<PathBuf>>()
+        };
+
+        assert_eq!(
+            relative_files(Some(1)),
+            [
+                "j0.JPG",
+                "level_1_0/j1.jpg",
+                "level_1_1/w1.webp",
+                "p0.png",
+                "t0.tif",
+                "w0.webp"
+            ]
+            .map(PathBuf::from)
+        );
+        assert_eq!(
+            relative_files(Some(0)),
+            ["j0.JPG", "p0.png", "t0.tif", "w0.webp"].map(PathBuf::from)
+        );
+        assert!(relative_files(Some(2)).contains(&PathBuf::from("level_1_0/level_2_0/p2.png")));
+        assert!(!relative_files(Some(2)).contains(&PathBuf::from("level_1_0/level_2_0/level_3_0/g1.gif")));
+        assert_eq!(relative_files(Some(3)), relative_files(None));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_scan_files_max_depth_through_symlinks() {
+        use std::os::unix::fs::symlink;
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path().join("root");
+        let target = temp_dir.path().join("target");
+        fs::create_dir_all(target.join("nested")).unwrap();
+        fs::create_dir_all(&root).unwrap();
+
+        let mut bytes: Vec<u8> = Vec::new();
+        RgbImage::new(1, 1)
+            .write_to(&mut Cursor::new(&mut bytes), image::ImageFormat::Png)
+            .unwrap();
+        fs::write(target.join("linked.png"), &bytes).unwrap();
+        fs::write(target.join("nested").join("deep.png"), &bytes).unwrap();
+        // A followed symlink counts as one level, as a folder would
+        symlink(&target, root.join("link")).unwrap();
+
+        let args = vec![root.to_string_lossy().to_string()];
+        let (_, files) = scan_files(
+            &args,
+            true,
+            &ScanSettings {
+                recursive: true,
+                follow_symlinks: true,
+                max_depth: Some(1),
+                ..Default::default()
+            },
+        );
+        assert_eq!(files, vec![root.join("link").join("linked.png")]);
+    }
+
     #[test]
     fn test_init_progress_bar() {
         // Test with quiet = true
@@ -0,0 +1,108 @@
+use image::imageops::FilterType;
+use image::GrayImage;
+use std::error::Error;
+
+const WINDOW_SIZE: u32 = 8;
+const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+/// Decodes an encoded image to its luma channel
+pub fn decode_luma(buffer: &[u8]) -> Result<GrayImage, Box<dyn Error>> {
+    Ok(image::load_from_memory(buffer)?.to_luma8())
+}
+
+/// Structural similarity of `compressed` against the already decoded `original`, from 0 to 1 (identical).
+/// The original is scaled to the compressed size when the image has been resized.
+pub fn ssim_against(original: &GrayImage, compressed: &[u8]) -> Result<f64, Box<dyn Error>> {
+    let compressed = decode_luma(compressed)?;
+    if original.dimensions() == compressed.dimensions() {
+        return Ok(ssim(original, &compressed));
+    }
+
+    let scaled = image::imageops::resize(original, compressed.width(), compressed.height(), FilterType::Lanczos3);
+    Ok(ssim(&scaled, &compressed))
+}
+
+/// Mean SSIM over non-overlapping 8x8 windows of two images of the same size
+pub fn ssim(a: &GrayImage, b: &GrayImage) -> f64 {
+    let (width, height) = a.dimensions();
+    let window_width = WINDOW_SIZE.min(width);
+    let window_height = WINDOW_SIZE.min(height);
+    if window_width == 0 || window_height == 0 || a.dimensions() != b.dimensions() {
+        return 0.0;
+    }
+
+    let mut total = 0.0;
+    let mut windows = 0;
+    for y in (0..=height - window_height).step_by(window_height as usize) {
+        for x in (0..=width - window_width).step_by(window_width as usize) {
+            total += window_ssim(a, b, x, y, window_width, window_height);
+            windows += 1;
+        }
+    }
+
+    total / windows as f64
+}
+
+fn window_ssim(a: &GrayImage, b: &GrayImage, x: u32, y: u32, width: u32, height: u32) -> f64 {
+    let count = (width * height) as f64;
+    let pixels = || {
+        (y..y + height).flat_map(move |py| {
+            (x..x + width).map(move |px| (a.get_pixel(px, py)[0] as f64, b.get_pixel(px, py)[0] as f64))
+        })
+    };
+
+    let (sum_a, sum_b) = pixels().fold((0.0, 0.0), |(sa, sb), (pa, pb)| (sa + pa, sb + pb));
+    let (mean_a, mean_b) = (sum_a / count, sum_b / count);
+    let (variance_a, variance_b, covariance) = pixels().fold((0.0, 0.0, 0.0), |(va, vb, cov), (pa, pb)| {
+        let (da, db) = (pa - mean_a, pb - mean_b);
+        (va + da * da, vb + db * db, cov + da * db)
+    });
+    let (variance_a, variance_b, covariance) = (variance_a / count, variance_b / count, covariance / count);
+
+    ((2.0 * mean_a * mean_b + C1) * (2.0 * covariance + C2))
+        / ((mean_a * mean_a + mean_b * mean_b + C1) * (variance_a + variance_b + C2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Luma;
+
+    fn gradient(width: u32, height: u32) -> GrayImage {
+        GrayImage::from_fn(width, height, |x, y| Luma([((x * 7 + y * 3) % 256) as u8]))
+    }
+
+    #[test]
+    fn test_ssim_identical() {
+        let image = gradient(64, 48);
+        assert!((ssim(&image, &image) - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_ssim_decreases_with_noise() {
+        let image = gradient(64, 48);
+        let noisy = |amount: u8| {
+            GrayImage::from_fn(64, 48, |x, y| {
+                let value = image.get_pixel(x, y)[0];
+                Luma([if (x + y) % 2 == 0 {
+                    value.saturating_add(amount)
+                } else {
+                    value.saturating_sub(amount)
+                }])
+            })
+        };
+
+        let light = ssim(&image, &noisy(4));
+        let heavy = ssim(&image, &noisy(40));
+        assert!(light < 1.0);
+        assert!(heavy < light);
+    }
+
+    #[test]
+    fn test_ssim_small_and_mismatched_images() {
+        let small = gradient(3, 5);
+        assert!((ssim(&small, &small) - 1.0).abs() < 1e-9);
+        assert_eq!(ssim(&gradient(8, 8), &gradient(16, 8)), 0.0);
+    }
+}
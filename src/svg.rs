@@ -0,0 +1,547 @@
+//! SVG files, minified as text: comments, editor data, whitespace between tags and attributes set to their default
+//! value are removed, and the numbers of the geometry are rounded to fewer decimals.
+//!
+//! The markup is rewritten token by token, not parsed into a tree, so anything this module doesn't rewrite, such as
+//! stylesheets, scripts or the text of the drawing, is copied as it is.
+
+use std::error::Error;
+
+/// Start of the file in which the root element is looked for, after the XML declaration and comments
+pub const SVG_HEADER_LENGTH: usize = 1024;
+
+const UTF8_BOM: &[u8] = &[0xEF, 0xBB, 0xBF];
+
+/// Namespaces of the data saved by drawing applications, which browsers ignore
+const EDITOR_NAMESPACES: [&str; 4] = ["inkscape", "sodipodi", "sketch", "serif"];
+
+/// Elements whose whitespace is part of their content
+const TEXT_ELEMENTS: [&str; 8] = [
+    "text",
+    "tspan",
+    "textPath",
+    "title",
+    "desc",
+    "style",
+    "script",
+    "foreignObject",
+];
+
+/// Attributes holding numbers, lengths or lists of them
+const NUMERIC_ATTRIBUTES: [&str; 24] = [
+    "d",
+    "points",
+    "transform",
+    "gradientTransform",
+    "patternTransform",
+    "viewBox",
+    "x",
+    "y",
+    "x1",
+    "y1",
+    "x2",
+    "y2",
+    "cx",
+    "cy",
+    "r",
+    "rx",
+    "ry",
+    "fx",
+    "fy",
+    "dx",
+    "dy",
+    "width",
+    "height",
+    "stroke-width",
+];
+
+/// Presentation attributes set to their initial value. Apart from `opacity` and `display`, they are inherited, so
+/// they are only dropped when no ancestor could set them to another value.
+const DEFAULT_ATTRIBUTES: [(&str, &str, bool); 12] = [
+    ("opacity", "1", false),
+    ("display", "inline", false),
+    ("fill-opacity", "1", true),
+    ("stroke-opacity", "1", true),
+    ("fill-rule", "nonzero", true),
+    ("clip-rule", "nonzero", true),
+    ("stroke-dasharray", "none", true),
+    ("stroke-dashoffset", "0", true),
+    ("stroke-linecap", "butt", true),
+    ("stroke-linejoin", "miter", true),
+    ("stroke-miterlimit", "4", true),
+    ("visibility", "visible", true),
+];
+
+/// Whether `buffer` starts with an SVG document, i.e. its root element, once the prolog is skipped
+pub fn is_svg(buffer: &[u8]) -> bool {
+    let mut rest = buffer.strip_prefix(UTF8_BOM).unwrap_or(buffer);
+    loop {
+        rest = &rest[rest.iter().take_while(|byte| byte.is_ascii_whitespace()).count()..];
+        let length = if rest.starts_with(b"<?") {
+            find(rest, b"?>").map(|end| end + 2)
+        } else if rest.starts_with(b"<!--") {
+            find(rest, b"-->").map(|end| end + 3)
+        } else if rest.starts_with(b"<!") {
+            declaration_length(rest)
+        } else {
+            break;
+        };
+        match length {
+            Some(length) => rest = &rest[length..],
+            None => return false,
+        }
+    }
+
+    rest.starts_with(b"<svg")
+        && rest
+            .get(4)
+            .is_some_and(|byte| byte.is_ascii_whitespace() || b"/>".contains(byte))
+}
+
+/// Decimals kept in the numbers of the geometry, from 1 at the lowest qualities up to 5 at quality 100
+pub fn svg_precision(quality: u32) -> usize {
+    1 + quality.min(100) as usize / 25
+}
+
+/// Minifies an SVG document, rounding its numbers to `precision` decimals, or keeping their value without one.
+/// The `metadata` elements are kept with `keep_metadata`.
+pub fn minify_svg(buffer: &[u8], precision: Option<usize>, keep_metadata: bool) -> Result<Vec<u8>, Box<dyn Error>> {
+    let source = std::str::from_utf8(buffer.strip_prefix(UTF8_BOM).unwrap_or(buffer))?;
+    // The inherited values set by a stylesheet are unknown, the default ones are then all kept
+    let has_stylesheet = source.contains("<style");
+    let mut output = String::with_capacity(source.len());
+    let mut elements: Vec<Element> = Vec::new();
+    // Depth of the removed element being skipped, with all its content
+    let mut skipped_depth = 0;
+    let mut position = 0;
+
+    while position < source.len() {
+        let rest = &source[position..];
+        if rest.starts_with("<!--") {
+            position += rest.find("-->").ok_or("Unterminated comment")? + 3;
+        } else if rest.starts_with("<![CDATA[") {
+            let length = rest.find("]]>").ok_or("Unterminated CDATA section")? + 3;
+            if skipped_depth == 0 {
+                output.push_str(&rest[..length]);
+            }
+            position += length;
+        } else if rest.starts_with("<?") {
+            let length = rest.find("?>").ok_or("Unterminated processing instruction")? + 2;
+            if skipped_depth == 0 {
+                output.push_str(&rest[..length]);
+            }
+            position += length;
+        } else if rest.starts_with("<!") {
+            let length = declaration_length(rest.as_bytes()).ok_or("Unterminated declaration")?;
+            output.push_str(&rest[..length]);
+            position += length;
+        } else if let Some(tag) = rest.strip_prefix("</") {
+            let length = tag.find('>').ok_or("Unterminated closing tag")?;
+            if skipped_depth > 0 {
+                skipped_depth -= 1;
+            } else {
+                elements.pop();
+                output.push_str("</");
+                output.push_str(tag[..length].trim_end());
+                output.push('>');
+            }
+            position += length + 3;
+        } else if rest.starts_with('<') {
+            let tag = parse_tag(rest)?;
+            position += tag.length;
+            if skipped_depth > 0 || is_editor_name(tag.name) || (tag.name == "metadata" && !keep_metadata) {
+                if !tag.self_closing {
+                    skipped_depth += 1;
+                }
+                continue;
+            }
+
+            let parent = elements.last();
+            let mut element = Element {
+                keeps_whitespace: parent.is_some_and(|parent| parent.keeps_whitespace)
+                    || TEXT_ELEMENTS.contains(&tag.name),
+                styled: has_stylesheet || parent.is_some_and(|parent| parent.styled),
+                inherited: Vec::new(),
+            };
+            output.push('<');
+            output.push_str(tag.name);
+            for (name, quote, value) in &tag.attributes {
+                match *name {
+                    "xml:space" => element.keeps_whitespace |= *value == "preserve",
+                    "style" | "class" => element.styled |= !value.trim().is_empty(),
+                    _ => {}
+                }
+                if is_redundant_attribute(name, value, &elements) {
+                    continue;
+                }
+                let value = match NUMERIC_ATTRIBUTES.contains(name) {
+                    true => minify_numbers(value, precision, *name == "d"),
+                    false => value.to_string(),
+                };
+                output.push(' ');
+                output.push_str(name);
+                output.push('=');
+                output.push(*quote);
+                output.push_str(&value);
+                output.push(*quote);
+            }
+            element.inherited = tag.attributes.iter().map(|(name, _, _)| name.to_string()).collect();
+            if tag.self_closing {
+                output.push_str("/>");
+            } else {
+                output.push('>');
+                elements.push(element);
+            }
+        } else {
+            let length = rest.find('<').unwrap_or(rest.len());
+            let text = &rest[..length];
+            let keeps_whitespace = elements.last().is_some_and(|element| element.keeps_whitespace);
+            if skipped_depth == 0 && (keeps_whitespace || !text.trim().is_empty()) {
+                output.push_str(text);
+            }
+            position += length;
+        }
+    }
+
+    Ok(output.into_bytes())
+}
+
+/// An open element, with what its children inherit from it
+struct Element {
+    keeps_whitespace: bool,
+    /// Whether CSS may set the inherited properties, from a stylesheet or a `style` or `class` attribute
+    styled: bool,
+    /// Names of the attributes set on the element
+    inherited: Vec<String>,
+}
+
+/// A start tag, with its attributes and the quote of their value
+struct Tag<'a> {
+    name: &'a str,
+    attributes: Vec<(&'a str, char, &'a str)>,
+    self_closing: bool,
+    length: usize,
+}
+
+fn parse_tag(source: &str) -> Result<Tag<'_>, Box<dyn Error>> {
+    let is_delimiter = |c: char| c.is_ascii_whitespace() || matches!(c, '/' | '>' | '=');
+    let name_length = source[1..].find(is_delimiter).ok_or("Unterminated tag")?;
+    let name = &source[1..1 + name_length];
+    if name.is_empty() {
+        return Err("Tag without a name".into());
+    }
+
+    let mut attributes = Vec::new();
+    let mut position = 1 + name_length;
+    loop {
+        position += source[position..].len() - source[position..].trim_start().len();
+        let rest = &source[position..];
+        if rest.starts_with("/>") || rest.starts_with('>') {
+            let self_closing = rest.starts_with('/');
+            return Ok(Tag {
+                name,
+                attributes,
+                self_closing,
+                length: position + if self_closing { 2 } else { 1 },
+            });
+        }
+
+        let attribute_length = rest.find(is_delimiter).ok_or("Unterminated tag")?;
+        let attribute = &rest[..attribute_length];
+        let value = rest[attribute_length..].trim_start();
+        let value = value
+            .strip_prefix('=')
+            .map(str::trim_start)
+            .ok_or_else(|| format!("Attribute {attribute} of <{name}> without a value"))?;
+        let quote = match value.chars().next() {
+            Some(quote @ ('"' | '\'')) => quote,
+            _ => return Err(format!("Unquoted value of the attribute {attribute} of <{name}>").into()),
+        };
+        let value_length = value[1..].find(quote).ok_or("Unterminated attribute value")?;
+        attributes.push((attribute, quote, &value[1..1 + value_length]));
+        position = source.len() - value.len() + value_length + 2;
+    }
+}
+
+/// Whether the attribute can be dropped without changing the drawing
+fn is_redundant_attribute(name: &str, value: &str, ancestors: &[Element]) -> bool {
+    if is_editor_name(name) {
+        return true;
+    }
+    if matches!(name, "id" | "class" | "style") && value.trim().is_empty() {
+        return true;
+    }
+
+    DEFAULT_ATTRIBUTES
+        .iter()
+        .find(|(default_name, _, _)| *default_name == name)
+        .is_some_and(|(_, default_value, inherited)| {
+            value.trim() == *default_value
+                && (!inherited
+                    || !ancestors
+                        .iter()
+                        .any(|ancestor| ancestor.styled || ancestor.inherited.iter().any(|set| set == name)))
+        })
+}
+
+/// Whether the element or attribute belongs to an editor namespace, or declares it
+fn is_editor_name(name: &str) -> bool {
+    let prefix = match name.strip_prefix("xmlns:") {
+        Some(declared) => declared,
+        None => name.split_once(':').map_or("", |(prefix, _)| prefix),
+    };
+    EDITOR_NAMESPACES.contains(&prefix)
+}
+
+/// Rewrites the numbers of an attribute value, keeping a single separator where one is needed. In path data, the
+/// commands separate the numbers and the two flags of the arcs are read as single digits, as they may be written
+/// without a separator.
+fn minify_numbers(value: &str, precision: Option<usize>, path_data: bool) -> String {
+    let bytes = value.as_bytes();
+    let mut output = String::with_capacity(value.len());
+    let mut command: u8 = 0;
+    let mut argument = 0;
+    let mut separated = false;
+    let mut position = 0;
+
+    while position < bytes.len() {
+        let byte = bytes[position];
+        if byte.is_ascii_whitespace() || byte == b',' {
+            separated = true;
+            position += 1;
+            continue;
+        }
+
+        let is_flag =
+            path_data && command.eq_ignore_ascii_case(&b'a') && matches!(argument % 7, 3 | 4) && b"01".contains(&byte);
+        let number_end = match is_flag {
+            true => Some(position + 1),
+            false => number_end(bytes, position),
+        };
+        match number_end {
+            Some(end) => {
+                let token = &value[position..end];
+                let number = match is_flag {
+                    true => token.to_string(),
+                    false => format_number(token, precision),
+                };
+                // A number can follow another one without a separator only when its sign ends the previous one
+                let follows_number = output.ends_with(|c: char| c.is_ascii_digit() || c == '.');
+                if (follows_number && !number.starts_with('-'))
+                    || (!path_data && separated && !output.is_empty() && !output.ends_with('('))
+                {
+                    output.push(' ');
+                }
+                output.push_str(&number);
+                argument += 1;
+                position = end;
+            }
+            None => {
+                // A command, or the name of a transform, a parenthesis or a unit
+                let character = value[position..].chars().next().unwrap_or_default();
+                if path_data && byte.is_ascii_alphabetic() {
+                    command = byte;
+                    argument = 0;
+                } else if !path_data && separated && !output.is_empty() && character != ')' && !output.ends_with('(') {
+                    output.push(' ');
+                }
+                output.push(character);
+                position += character.len_utf8();
+            }
+        }
+        separated = false;
+    }
+
+    output
+}
+
+/// End of the number starting at `start`, if any
+fn number_end(bytes: &[u8], start: usize) -> Option<usize> {
+    let digits_end = |from: usize| from + bytes[from..].iter().take_while(|byte| byte.is_ascii_digit()).count();
+    let mut position = start;
+    if matches!(bytes.get(position), Some(b'-' | b'+')) {
+        position += 1;
+    }
+    let mut end = digits_end(position);
+    if bytes.get(end) == Some(&b'.') && bytes.get(end + 1).is_some_and(u8::is_ascii_digit) {
+        end = digits_end(end + 1);
+    }
+    if end == position {
+        return None;
+    }
+    // Only an exponent with digits, `1em` being a length
+    if matches!(bytes.get(end), Some(b'e' | b'E')) {
+        let exponent = match bytes.get(end + 1) {
+            Some(b'-' | b'+') => end + 2,
+            _ => end + 1,
+        };
+        if exponent < bytes.len() && digits_end(exponent) > exponent {
+            end = digits_end(exponent);
+        }
+    }
+
+    Some(end)
+}
+
+/// The shortest writing of the number rounded to `precision` decimals, or the original one when it isn't longer
+fn format_number(token: &str, precision: Option<usize>) -> String {
+    let digits = token.trim_start_matches(['-', '+']);
+    // Leading zeros come from arc flags written without separators, in a value that isn't parsed as path data
+    let has_leading_zero = digits.len() > 1 && digits.starts_with('0') && digits.as_bytes()[1].is_ascii_digit();
+    let Ok(value) = token.parse::<f64>() else {
+        return token.to_string();
+    };
+    if has_leading_zero || !value.is_finite() {
+        return token.to_string();
+    }
+
+    let mut number = match precision {
+        Some(precision) => format!("{value:.precision$}"),
+        None => value.to_string(),
+    };
+    if number.contains('.') {
+        number.truncate(number.trim_end_matches('0').trim_end_matches('.').len());
+    }
+    if number == "-0" {
+        number = "0".to_string();
+    }
+    if let Some(fraction) = number.strip_prefix("0.") {
+        number = format!(".{fraction}");
+    } else if let Some(fraction) = number.strip_prefix("-0.") {
+        number = format!("-.{fraction}");
+    }
+
+    match number.len() <= token.len() {
+        true => number,
+        false => token.to_string(),
+    }
+}
+
+/// Length of a `<!DOCTYPE ...>` declaration, including its internal subset in brackets
+fn declaration_length(buffer: &[u8]) -> Option<usize> {
+    let mut depth = 0;
+    for (position, byte) in buffer.iter().enumerate() {
+        match byte {
+            b'[' => depth += 1,
+            b']' => depth -= 1,
+            b'>' if depth == 0 => return Some(position + 1),
+            _ => {}
+        }
+    }
+
+    None
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn minify(source: &str, precision: Option<usize>) -> String {
+        String::from_utf8(minify_svg(source.as_bytes(), precision, false).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_is_svg() {
+        assert!(is_svg(b"<svg xmlns=\"http://www.w3.org/2000/svg\"/>"));
+        assert!(is_svg(
+            b"\xEF\xBB\xBF<?xml version=\"1.0\"?>\n<!-- Generator: editor -->\n<!DOCTYPE svg PUBLIC \"-//W3C//DTD SVG \
+              1.1//EN\" \"http://www.w3.org/Graphics/SVG/1.1/DTD/svg11.dtd\" [<!ENTITY a \"b\">]>\n<svg>"
+        ));
+        assert!(!is_svg(b"<svgs>"));
+        assert!(!is_svg(b"<?xml version=\"1.0\"?><html>"));
+        assert!(!is_svg(b"<!-- <svg> in a comment"));
+        assert!(!is_svg(&std::fs::read("samples/p0.png").unwrap()));
+    }
+
+    #[test]
+    fn test_minify_svg() {
+        let source = r##"<?xml version="1.0" encoding="UTF-8"?>
+<!-- Created with an editor -->
+<svg xmlns="http://www.w3.org/2000/svg" xmlns:inkscape="http://www.inkscape.org/namespaces/inkscape"
+     width="100" height="50.000" viewBox="0, 0, 100, 50" inkscape:version="1.3">
+  <sodipodi:namedview id="view" pagecolor="#ffffff"><inkscape:grid/></sodipodi:namedview>
+  <metadata><rdf:RDF/></metadata>
+  <g fill-opacity="1" opacity="1" class="">
+    <path d="M 10.123456 20.5 L 30,40.0009 a 5 5 0 1 0 10.25 -0.00004 Z" stroke-linecap="butt"/>
+    <rect x="0.5" y="-0.25" width="10" height="10" transform="translate( 1.00001 , 2 ) rotate(45)"/>
+  </g>
+  <text x="10" y="45">Hello <tspan> world</tspan> </text>
+</svg>
+"##;
+        assert_eq!(
+            minify(source, Some(3)),
+            concat!(
+                r#"<?xml version="1.0" encoding="UTF-8"?>"#,
+                r#"<svg xmlns="http://www.w3.org/2000/svg" width="100" height="50" viewBox="0 0 100 50">"#,
+                r#"<g><path d="M10.123 20.5L30 40.001a5 5 0 1 0 10.25 0Z"/>"#,
+                r#"<rect x=".5" y="-.25" width="10" height="10" transform="translate(1 2) rotate(45)"/></g>"#,
+                r#"<text x="10" y="45">Hello <tspan> world</tspan> </text></svg>"#
+            )
+        );
+
+        // The metadata is kept on demand
+        let output = String::from_utf8(minify_svg(source.as_bytes(), Some(3), true).unwrap()).unwrap();
+        assert!(output.contains("<metadata><rdf:RDF/></metadata>"));
+    }
+
+    #[test]
+    fn test_minify_svg_inherited_defaults() {
+        // An inherited value reset to its default changes the drawing
+        let source = r#"<svg><g fill-rule="evenodd"><path fill-rule="nonzero" d="M0 0"/></g></svg>"#;
+        assert_eq!(minify(source, None), source);
+        let source = r#"<svg><g style="fill-rule:evenodd"><path fill-rule="nonzero" d="M0 0"/></g></svg>"#;
+        assert_eq!(minify(source, None), source);
+        let source = r#"<svg><g><path fill-rule="nonzero" d="M0 0"/></g></svg>"#;
+        assert_eq!(minify(source, None), r#"<svg><g><path d="M0 0"/></g></svg>"#);
+    }
+
+    #[test]
+    fn test_minify_svg_whitespace() {
+        let source = "<svg>\n  <text xml:space=\"preserve\">  a  </text>\n  <g xml:space=\"preserve\"> <rect/> </g>\n\
+                      <style>\n  rect { fill: red; }\n</style>\n</svg>";
+        assert_eq!(
+            minify(source, None),
+            "<svg><text xml:space=\"preserve\">  a  </text><g xml:space=\"preserve\"> <rect/> </g>\
+             <style>\n  rect { fill: red; }\n</style></svg>"
+        );
+    }
+
+    #[test]
+    fn test_minify_numbers() {
+        // Lossless, only the writing of the numbers changes
+        assert_eq!(minify_numbers("M 0.50 , -0.0 L 1e2 +3", None, true), "M.5 0L100 3");
+        assert_eq!(minify_numbers("M1-0.0001 2", Some(3), true), "M1 0 2");
+        assert_eq!(minify_numbers("M1.0.5", Some(3), true), "M1 .5");
+        // Arc flags written without separators
+        assert_eq!(minify_numbers("a1 1 0 10.05.5", Some(1), true), "a1 1 0 1 0 .1 .5");
+        assert_eq!(
+            minify_numbers("M0 0A10 10 0 0110 10", Some(3), true),
+            "M0 0A10 10 0 0 1 10 10"
+        );
+        // Units, exponents and names are kept
+        assert_eq!(minify_numbers("1.50em", Some(3), false), "1.5em");
+        assert_eq!(minify_numbers("2.5e-1", None, false), ".25");
+        assert_eq!(
+            minify_numbers("matrix(1 0 0 1 0.333333 -2)", Some(2), false),
+            "matrix(1 0 0 1 .33 -2)"
+        );
+        assert_eq!(minify_numbers("100%", Some(2), false), "100%");
+    }
+
+    #[test]
+    fn test_minify_svg_errors() {
+        assert!(minify_svg(b"<svg><!-- unterminated</svg>", None, false).is_err());
+        assert!(minify_svg(b"<svg width=10></svg>", None, false).is_err());
+        assert!(minify_svg(b"<svg \xFF></svg>", None, false).is_err());
+    }
+
+    #[test]
+    fn test_svg_precision() {
+        assert_eq!(svg_precision(0), 1);
+        assert_eq!(svg_precision(80), 4);
+        assert_eq!(svg_precision(100), 5);
+    }
+}
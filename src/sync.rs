@@ -0,0 +1,303 @@
+use crate::scan_files::{is_valid_file, ScanSettings};
+use caesiumclt::compressor::plan_outputs;
+use caesiumclt::{CompressionOptions, CompressionResult};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    /// Orphaned files deleted, or that would be deleted on a dry run
+    pub removed: Vec<PathBuf>,
+    pub errors: Vec<String>,
+}
+
+/// Refuses an output folder that is, or contains, the folder of one of `inputs`, as `--sync` would delete the files
+/// around them. An output folder that doesn't exist yet has nothing to delete.
+pub fn check_sync_output_folder(output_folder: &Path, inputs: &[String]) -> Result<(), String> {
+    let Ok(output_folder) = fs::canonicalize(output_folder) else {
+        return Ok(());
+    };
+    for input in inputs {
+        let Ok(input_path) = fs::canonicalize(input) else {
+            continue;
+        };
+        let input_root = match input_path.is_dir() {
+            true => input_path.as_path(),
+            false => input_path.parent().unwrap_or(&input_path),
+        };
+        if input_root.starts_with(&output_folder) {
+            return Err(format!(
+                "--sync can't be used with the output folder {}, it contains the input {input}",
+                output_folder.display()
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Deletes the files of `output_folder` that are not the output of any of the current inputs, making the output a
+/// one-way mirror of the inputs. Subfolders are only visited if `recursive`. Only the files the scan would pick up
+/// with `scan_settings` are deleted, other files are left alone.
+/// Input files are never deleted, even when they live in the output folder. The planned output of every input is kept
+/// too, so that the outputs of files aborted or timed out in this run, which have no output path, survive.
+pub fn sync_output_folder(
+    output_folder: &Path,
+    results: &[CompressionResult],
+    input_files: &[PathBuf],
+    options: &CompressionOptions,
+    scan_settings: &ScanSettings,
+    recursive: bool,
+    dry_run: bool,
+) -> SyncReport {
+    let planned_outputs = plan_outputs(input_files, options)
+        .into_iter()
+        .filter_map(|planned_output| planned_output.output_path);
+    let expected_outputs: HashSet<PathBuf> = results
+        .iter()
+        .filter(|r| !r.output_path.is_empty())
+        .map(|r| PathBuf::from(&r.output_path))
+        .chain(planned_outputs)
+        .collect();
+    let inputs: HashSet<PathBuf> = input_files.iter().filter_map(|f| fs::canonicalize(f).ok()).collect();
+
+    let mut report = SyncReport::default();
+    let mut orphans = vec![];
+    find_orphans(
+        output_folder,
+        &expected_outputs,
+        &inputs,
+        scan_settings,
+        recursive,
+        &mut orphans,
+    );
+    orphans.sort();
+
+    for orphan in orphans {
+        if !dry_run {
+            if let Err(e) = fs::remove_file(&orphan) {
+                report.errors.push(format!("Error deleting {}: {e}", orphan.display()));
+                continue;
+            }
+        }
+        report.removed.push(orphan);
+    }
+
+    report
+}
+
+fn find_orphans(
+    directory: &Path,
+    expected_outputs: &HashSet<PathBuf>,
+    inputs: &HashSet<PathBuf>,
+    scan_settings: &ScanSettings,
+    recursive: bool,
+    orphans: &mut Vec<PathBuf>,
+) {
+    let Ok(entries) = fs::read_dir(directory) else {
+        return;
+    };
+
+    for entry in entries.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+
+        // Symlinks are left alone, they may point outside of the output folder
+        if file_type.is_dir() {
+            if recursive {
+                find_orphans(&path, expected_outputs, inputs, scan_settings, recursive, orphans);
+            }
+        } else if file_type.is_file()
+            && !expected_outputs.contains(&path)
+            && !fs::canonicalize(&path).is_ok_and(|p| inputs.contains(&p))
+            && is_valid_file(&path, scan_settings)
+        {
+            orphans.push(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use caesiumclt::compressor::start_compression;
+    use caesiumclt::CompressionStatus;
+    use std::path::absolute;
+    use tempfile::tempdir;
+
+    fn success_result(input: &Path, output: &Path) -> CompressionResult {
+        CompressionResult {
+            original_path: input.display().to_string(),
+            output_path: output.display().to_string(),
+            original_size: 100,
+            compressed_size: 50,
+            status: CompressionStatus::Success,
+            error_kind: None,
+            message: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_sync_output_folder() {
+        let temp_dir = tempdir().unwrap();
+        let output_folder = temp_dir.path().join("output");
+        fs::create_dir_all(output_folder.join("nested")).unwrap();
+
+        let input_file = temp_dir.path().join("image.jpg");
+        let output_file = output_folder.join("image.jpg");
+        let orphan = output_folder.join("deleted.jpg");
+        let nested_orphan = output_folder.join("nested/deleted.png");
+        // An input living in the output folder must survive
+        let input_in_output = output_folder.join("input.jpg");
+        // Files the scan would leave out are not outputs either
+        let document = output_folder.join("notes.txt");
+        for file in [
+            &input_file,
+            &output_file,
+            &orphan,
+            &nested_orphan,
+            &input_in_output,
+            &document,
+        ] {
+            fs::write(file, b"data").unwrap();
+        }
+
+        let results = vec![success_result(&input_file, &output_file)];
+        let input_files = vec![input_file.clone(), input_in_output.clone()];
+
+        let options = CompressionOptions {
+            output_folder: Some(output_folder.clone()),
+            base_path: temp_dir.path().to_path_buf(),
+            ..CompressionOptions::default()
+        };
+        let scan_settings = ScanSettings {
+            check_extension_only: true,
+            ..Default::default()
+        };
+
+        let report = sync_output_folder(
+            &output_folder,
+            &results,
+            &input_files,
+            &options,
+            &scan_settings,
+            true,
+            true,
+        );
+        assert_eq!(report.removed, vec![orphan.clone(), nested_orphan.clone()]);
+        assert!(orphan.exists());
+        assert!(nested_orphan.exists());
+
+        let report = sync_output_folder(
+            &output_folder,
+            &results,
+            &input_files,
+            &options,
+            &scan_settings,
+            false,
+            false,
+        );
+        assert_eq!(report.removed, vec![orphan.clone()]);
+        assert!(report.errors.is_empty());
+        assert!(!orphan.exists());
+        assert!(nested_orphan.exists());
+
+        let report = sync_output_folder(
+            &output_folder,
+            &results,
+            &input_files,
+            &options,
+            &scan_settings,
+            true,
+            false,
+        );
+        assert_eq!(report.removed, vec![nested_orphan.clone()]);
+        assert!(!nested_orphan.exists());
+        assert!(output_file.exists());
+        assert!(input_in_output.exists());
+        assert!(input_file.exists());
+        assert!(document.exists());
+
+        // Without trusting the extensions, only real images are deleted
+        let report = sync_output_folder(
+            &output_folder,
+            &[],
+            &input_files,
+            &options,
+            &ScanSettings::default(),
+            true,
+            true,
+        );
+        assert!(report.removed.is_empty());
+    }
+
+    #[test]
+    fn test_check_sync_output_folder() {
+        let temp_dir = tempdir().unwrap();
+        let photos = temp_dir.path().join("photos");
+        let output = photos.join("compressed");
+        fs::create_dir_all(&output).unwrap();
+        fs::write(photos.join("photo.jpg"), b"data").unwrap();
+        let inputs = |paths: &[&Path]| paths.iter().map(|p| p.display().to_string()).collect::<Vec<_>>();
+
+        // The output folder is the input folder, or one of its ancestors
+        assert!(check_sync_output_folder(&photos, &inputs(&[&photos])).is_err());
+        assert!(check_sync_output_folder(temp_dir.path(), &inputs(&[&photos])).is_err());
+        assert!(check_sync_output_folder(&photos, &inputs(&[&photos.join("photo.jpg")])).is_err());
+
+        // An output folder nested in the input folder, or apart from it, is scoped to the outputs
+        assert!(check_sync_output_folder(&output, &inputs(&[&photos])).is_ok());
+        assert!(check_sync_output_folder(&output, &inputs(&[&photos.join("photo.jpg")])).is_ok());
+        assert!(check_sync_output_folder(&temp_dir.path().join("missing"), &inputs(&[&photos])).is_ok());
+    }
+
+    #[test]
+    fn test_sync_keeps_the_outputs_of_aborted_files() {
+        let temp_dir = tempdir().unwrap();
+        let output_folder = temp_dir.path().join("output");
+        fs::create_dir_all(&output_folder).unwrap();
+        let input_files = vec![
+            absolute(PathBuf::from("samples/j0.JPG")).unwrap(),
+            absolute(PathBuf::from("samples/missing.jpg")).unwrap(),
+            absolute(PathBuf::from("samples/p0.png")).unwrap(),
+        ];
+        // Written by an earlier run
+        let previous_output = output_folder.join("p0.png");
+        let orphan = output_folder.join("deleted.jpg");
+        fs::write(&previous_output, b"data").unwrap();
+        fs::write(&orphan, b"data").unwrap();
+
+        let options = CompressionOptions {
+            quality: Some(80),
+            output_folder: Some(output_folder.clone()),
+            base_path: absolute(PathBuf::from("samples")).unwrap(),
+            fail_fast: true,
+            ..CompressionOptions::default()
+        };
+        let scan_settings = ScanSettings {
+            check_extension_only: true,
+            ..Default::default()
+        };
+        let thread_pool = rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        let results = thread_pool.install(|| start_compression(&input_files, &options, false, None));
+        assert!(matches!(results[2].status, CompressionStatus::Skipped));
+        assert!(results[2].output_path.is_empty());
+
+        let report = sync_output_folder(
+            &output_folder,
+            &results,
+            &input_files,
+            &options,
+            &scan_settings,
+            false,
+            false,
+        );
+        assert_eq!(report.removed, vec![orphan.clone()]);
+        assert!(previous_output.exists());
+        assert!(Path::new(&results[0].output_path).exists());
+        assert!(!orphan.exists());
+    }
+}
@@ -0,0 +1,194 @@
+//! Multi-page TIFF files, such as the output of document scanners. libcaesium only keeps their first page, so each
+//! page is decoded and written again as a page of the output instead, with the selected compression. Metadata is not
+//! kept.
+
+use crate::options::TiffCompression;
+use std::collections::HashSet;
+use std::io::Cursor;
+use tiff::decoder::{Decoder, DecodingResult};
+use tiff::encoder::colortype::{self, ColorType as EncoderColorType};
+use tiff::encoder::compression::{Compression, Deflate, Lzw, Uncompressed};
+use tiff::encoder::{TiffEncoder, TiffValue};
+use tiff::ColorType;
+
+/// Number of pages of a TIFF, from the chain of its IFDs. 0 when `buffer` is not a TIFF.
+pub fn tiff_page_count(buffer: &[u8]) -> usize {
+    let little_endian = match buffer.get(..4) {
+        Some(b"II*\0") => true,
+        Some(b"MM\0*") => false,
+        _ => return 0,
+    };
+    let read_u16 = |offset: usize| {
+        let bytes = [*buffer.get(offset)?, *buffer.get(offset + 1)?];
+        Some(match little_endian {
+            true => u16::from_le_bytes(bytes),
+            false => u16::from_be_bytes(bytes),
+        })
+    };
+    let read_u32 = |offset: usize| {
+        let bytes: [u8; 4] = buffer.get(offset..offset + 4)?.try_into().ok()?;
+        Some(match little_endian {
+            true => u32::from_le_bytes(bytes),
+            false => u32::from_be_bytes(bytes),
+        })
+    };
+
+    let mut pages = 0;
+    let mut visited = HashSet::new();
+    let mut offset = read_u32(4).unwrap_or(0) as usize;
+    // An IFD pointing back to a previous one would loop forever
+    while offset != 0 && visited.insert(offset) {
+        let Some(entry_count) = read_u16(offset) else {
+            break;
+        };
+        pages += 1;
+        offset = read_u32(offset + 2 + entry_count as usize * 12).unwrap_or(0) as usize;
+    }
+
+    pages
+}
+
+/// Decodes every page of a TIFF and writes them again with `compression`, in the same order
+pub fn recompress_tiff_pages(buffer: &[u8], compression: TiffCompression) -> Result<Vec<u8>, String> {
+    let mut decoder = Decoder::new(Cursor::new(buffer)).map_err(|e| e.to_string())?;
+    let mut output = Vec::new();
+    let mut encoder = TiffEncoder::new(Cursor::new(&mut output)).map_err(|e| e.to_string())?;
+    for page in 1.. {
+        let (width, height) = decoder.dimensions().map_err(|e| e.to_string())?;
+        let color_type = decoder.colortype().map_err(|e| e.to_string())?;
+        let pixels = decoder.read_image().map_err(|e| format!("Page {page}: {e}"))?;
+        let written = match (color_type, pixels) {
+            (ColorType::Gray(8), DecodingResult::U8(pixels)) => {
+                write_page::<colortype::Gray8>(&mut encoder, width, height, &pixels, compression)
+            }
+            (ColorType::Gray(16), DecodingResult::U16(pixels)) => {
+                write_page::<colortype::Gray16>(&mut encoder, width, height, &pixels, compression)
+            }
+            (ColorType::RGB(8), DecodingResult::U8(pixels)) => {
+                write_page::<colortype::RGB8>(&mut encoder, width, height, &pixels, compression)
+            }
+            (ColorType::RGB(16), DecodingResult::U16(pixels)) => {
+                write_page::<colortype::RGB16>(&mut encoder, width, height, &pixels, compression)
+            }
+            (ColorType::RGBA(8), DecodingResult::U8(pixels)) => {
+                write_page::<colortype::RGBA8>(&mut encoder, width, height, &pixels, compression)
+            }
+            (ColorType::RGBA(16), DecodingResult::U16(pixels)) => {
+                write_page::<colortype::RGBA16>(&mut encoder, width, height, &pixels, compression)
+            }
+            (ColorType::CMYK(8), DecodingResult::U8(pixels)) => {
+                write_page::<colortype::CMYK8>(&mut encoder, width, height, &pixels, compression)
+            }
+            (color_type, _) => return Err(format!("Page {page}: unsupported color type {color_type:?}")),
+        };
+        written.map_err(|e| format!("Page {page}: {e}"))?;
+
+        if !decoder.more_images() {
+            break;
+        }
+        decoder.next_image().map_err(|e| e.to_string())?;
+    }
+
+    Ok(output)
+}
+
+fn write_page<C: EncoderColorType>(
+    encoder: &mut TiffEncoder<Cursor<&mut Vec<u8>>>,
+    width: u32,
+    height: u32,
+    pixels: &[C::Inner],
+    compression: TiffCompression,
+) -> Result<(), String>
+where
+    [C::Inner]: TiffValue,
+{
+    fn write<C: EncoderColorType, D: Compression>(
+        encoder: &mut TiffEncoder<Cursor<&mut Vec<u8>>>,
+        width: u32,
+        height: u32,
+        pixels: &[C::Inner],
+        compression: D,
+    ) -> Result<(), String>
+    where
+        [C::Inner]: TiffValue,
+    {
+        encoder
+            .write_image_with_compression::<C, D>(width, height, compression, pixels)
+            .map_err(|e| e.to_string())
+    }
+
+    match compression {
+        TiffCompression::None => write::<C, _>(encoder, width, height, pixels, Uncompressed),
+        TiffCompression::Lzw => write::<C, _>(encoder, width, height, pixels, Lzw),
+        TiffCompression::Deflate => write::<C, _>(encoder, width, height, pixels, Deflate::default()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A TIFF of one gray and two RGB pages, each filled with one value
+    fn write_pages() -> Vec<u8> {
+        let mut output = Vec::new();
+        let mut encoder = TiffEncoder::new(Cursor::new(&mut output)).unwrap();
+        encoder.write_image::<colortype::Gray8>(8, 4, &[40; 8 * 4]).unwrap();
+        encoder.write_image::<colortype::RGB8>(6, 2, &[80; 6 * 2 * 3]).unwrap();
+        encoder.write_image::<colortype::RGB8>(4, 4, &[120; 4 * 4 * 3]).unwrap();
+        output
+    }
+
+    #[test]
+    fn test_tiff_page_count() {
+        assert_eq!(tiff_page_count(&write_pages()), 3);
+        let mut single_page = Vec::new();
+        TiffEncoder::new(Cursor::new(&mut single_page))
+            .unwrap()
+            .write_image::<colortype::Gray8>(2, 2, &[0; 4])
+            .unwrap();
+        assert_eq!(tiff_page_count(&single_page), 1);
+        assert_eq!(tiff_page_count(&std::fs::read("samples/j0.JPG").unwrap()), 0);
+
+        // An IFD pointing to itself
+        let mut looping = b"MM\0*".to_vec();
+        looping.extend_from_slice(&8u32.to_be_bytes());
+        looping.extend_from_slice(&0u16.to_be_bytes());
+        looping.extend_from_slice(&8u32.to_be_bytes());
+        assert_eq!(tiff_page_count(&looping), 1);
+    }
+
+    #[test]
+    fn test_recompress_tiff_pages() {
+        let input = write_pages();
+        let output = recompress_tiff_pages(&input, TiffCompression::Deflate).unwrap();
+        assert_eq!(tiff_page_count(&output), 3);
+
+        let mut decoder = Decoder::new(Cursor::new(&output)).unwrap();
+        let mut pages = Vec::new();
+        loop {
+            let pixels = match decoder.read_image().unwrap() {
+                DecodingResult::U8(pixels) => pixels,
+                _ => panic!("Unexpected sample type"),
+            };
+            pages.push((decoder.dimensions().unwrap(), decoder.colortype().unwrap(), pixels[0]));
+            if !decoder.more_images() {
+                break;
+            }
+            decoder.next_image().unwrap();
+        }
+        assert_eq!(
+            pages,
+            [
+                ((8, 4), ColorType::Gray(8), 40),
+                ((6, 2), ColorType::RGB(8), 80),
+                ((4, 4), ColorType::RGB(8), 120)
+            ]
+        );
+
+        assert!(recompress_tiff_pages(b"II*\0", TiffCompression::Deflate).is_err());
+        // Each page is written with the selected compression
+        let uncompressed = recompress_tiff_pages(&input, TiffCompression::None).unwrap();
+        assert!(uncompressed.len() > output.len());
+        assert_eq!(tiff_page_count(&uncompressed), 3);
+    }
+}
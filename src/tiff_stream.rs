@@ -0,0 +1,285 @@
+//! Recompression of TIFF files band by band, for images too big to be decoded at once.
+//!
+//! The strips or tiles of the input are read one row of chunks at a time and written as strips of the same height,
+//! with the selected compression, so only one band of pixels is held in memory. The output is streamed to a writer, so
+//! the recompressed image is not held either. Metadata is not kept, apart from the resolution set with `--dpi`.
+
+use crate::options::TiffCompression;
+use std::fs::File;
+use std::io::{BufReader, Seek, Write};
+use std::path::Path;
+use tiff::decoder::{Decoder, DecodingResult};
+use tiff::encoder::colortype::{self, ColorType as EncoderColorType};
+use tiff::encoder::compression::{Compression, Deflate, Lzw, Uncompressed};
+use tiff::encoder::{Rational, TiffEncoder, TiffValue};
+use tiff::tags::{ResolutionUnit, Tag};
+use tiff::ColorType;
+
+/// Layout of a TIFF that can be recompressed band by band
+#[derive(Debug, Clone, PartialEq)]
+pub struct TiffStream {
+    width: u32,
+    height: u32,
+    color_type: ColorType,
+    chunk_width: u32,
+    chunk_height: u32,
+    tiled: bool,
+}
+
+impl TiffStream {
+    /// Reads the layout of `path`. `None` when the whole image is a single band, or when it is not a single page of
+    /// 8 or 16 bits gray, RGB or RGBA pixels stored contiguously, which have to be fully decoded instead.
+    pub fn open(path: &Path) -> Option<Self> {
+        let mut decoder = Decoder::new(BufReader::new(File::open(path).ok()?)).ok()?;
+        let (width, height) = decoder.dimensions().ok()?;
+        let color_type = decoder.colortype().ok()?;
+        let planar_configuration = decoder
+            .find_tag_unsigned::<u16>(Tag::PlanarConfiguration)
+            .ok()?
+            .unwrap_or(1);
+        let (chunk_width, chunk_height) = decoder.chunk_dimensions();
+        let stream = Self {
+            width,
+            height,
+            color_type,
+            chunk_width,
+            chunk_height,
+            tiled: chunk_width < width,
+        };
+
+        let supported = matches!(
+            color_type,
+            ColorType::Gray(8 | 16) | ColorType::RGB(8 | 16) | ColorType::RGBA(8 | 16)
+        );
+        (supported
+            && planar_configuration == 1
+            && !decoder.more_images()
+            && chunk_width > 0
+            && chunk_height > 0
+            && stream.bands() > 1)
+            .then_some(stream)
+    }
+
+    /// Memory held while recompressing: a band of decoded pixels, the tile being copied into it, and the band once
+    /// compressed by the encoder, which is at most about as large as the pixels
+    pub fn band_memory(&self) -> u64 {
+        let (samples, bytes) = sample_layout(self.color_type);
+        let band = self.width as u64 * self.chunk_height as u64;
+        let chunk = match self.tiled {
+            true => self.chunk_width as u64 * self.chunk_height as u64,
+            false => 0,
+        };
+        (2 * band + chunk) * samples as u64 * bytes as u64
+    }
+
+    /// How the input has been read, e.g. `64 strips`
+    pub fn describe(&self) -> String {
+        if self.tiled {
+            format!("{} tiles", self.chunks_across() * self.bands())
+        } else {
+            format!("{} strips", self.bands())
+        }
+    }
+
+    /// Decodes `path` band by band and writes each band to `output` as a strip compressed with `compression`, with
+    /// the resolution `dpi` if set
+    pub fn recompress<W: Write + Seek>(
+        &self,
+        path: &Path,
+        compression: TiffCompression,
+        dpi: Option<u32>,
+        output: &mut W,
+    ) -> Result<(), String> {
+        let file = File::open(path).map_err(|e| e.to_string())?;
+        let mut decoder = Decoder::new(BufReader::new(file)).map_err(|e| e.to_string())?;
+        match self.color_type {
+            ColorType::Gray(8) => {
+                self.encode::<colortype::Gray8, _>(&mut decoder, u8_samples, compression, dpi, output)
+            }
+            ColorType::Gray(16) => {
+                self.encode::<colortype::Gray16, _>(&mut decoder, u16_samples, compression, dpi, output)
+            }
+            ColorType::RGB(8) => self.encode::<colortype::RGB8, _>(&mut decoder, u8_samples, compression, dpi, output),
+            ColorType::RGB(16) => {
+                self.encode::<colortype::RGB16, _>(&mut decoder, u16_samples, compression, dpi, output)
+            }
+            ColorType::RGBA(8) => {
+                self.encode::<colortype::RGBA8, _>(&mut decoder, u8_samples, compression, dpi, output)
+            }
+            ColorType::RGBA(16) => {
+                self.encode::<colortype::RGBA16, _>(&mut decoder, u16_samples, compression, dpi, output)
+            }
+            color_type => Err(format!("Unsupported TIFF color type {color_type:?}")),
+        }
+    }
+
+    fn encode<C: EncoderColorType, W: Write + Seek>(
+        &self,
+        decoder: &mut Decoder<BufReader<File>>,
+        samples: fn(DecodingResult) -> Option<Vec<C::Inner>>,
+        compression: TiffCompression,
+        dpi: Option<u32>,
+        output: &mut W,
+    ) -> Result<(), String>
+    where
+        C::Inner: Copy + Default,
+        [C::Inner]: TiffValue,
+    {
+        match compression {
+            TiffCompression::None => self.encode_with::<C, _, W>(decoder, samples, Uncompressed, dpi, output),
+            TiffCompression::Lzw => self.encode_with::<C, _, W>(decoder, samples, Lzw, dpi, output),
+            TiffCompression::Deflate => self.encode_with::<C, _, W>(decoder, samples, Deflate::default(), dpi, output),
+        }
+    }
+
+    fn encode_with<C: EncoderColorType, D: Compression, W: Write + Seek>(
+        &self,
+        decoder: &mut Decoder<BufReader<File>>,
+        samples: fn(DecodingResult) -> Option<Vec<C::Inner>>,
+        compression: D,
+        dpi: Option<u32>,
+        output: &mut W,
+    ) -> Result<(), String>
+    where
+        C::Inner: Copy + Default,
+        [C::Inner]: TiffValue,
+    {
+        let samples_per_pixel = sample_layout(self.color_type).0 as usize;
+        let width = self.width as usize;
+        let mut encoder = TiffEncoder::new(output).map_err(|e| e.to_string())?;
+        let mut image = encoder
+            .new_image_with_compression::<C, _>(self.width, self.height, compression)
+            .map_err(|e| e.to_string())?;
+        image.rows_per_strip(self.chunk_height).map_err(|e| e.to_string())?;
+        if let Some(dpi) = dpi {
+            image.resolution(ResolutionUnit::Inch, Rational { n: dpi, d: 1 });
+        }
+
+        for band_index in 0..self.bands() {
+            let first_chunk = band_index * self.chunks_across();
+            let read_chunk = |decoder: &mut Decoder<BufReader<File>>, chunk_index: u32| {
+                decoder
+                    .read_chunk(chunk_index)
+                    .map_err(|e| e.to_string())
+                    .and_then(|chunk| samples(chunk).ok_or_else(|| "Unexpected TIFF sample type".to_string()))
+            };
+
+            let band = if self.tiled {
+                let band_height = decoder.chunk_data_dimensions(first_chunk).1 as usize;
+                let mut band = vec![C::Inner::default(); width * band_height * samples_per_pixel];
+                for column in 0..self.chunks_across() {
+                    let chunk_index = first_chunk + column;
+                    let chunk_width = decoder.chunk_data_dimensions(chunk_index).0 as usize * samples_per_pixel;
+                    let chunk = read_chunk(decoder, chunk_index)?;
+                    let x = (column * self.chunk_width) as usize * samples_per_pixel;
+                    for (row, chunk_row) in chunk.chunks_exact(chunk_width).take(band_height).enumerate() {
+                        let start = row * width * samples_per_pixel + x;
+                        band[start..start + chunk_width].copy_from_slice(chunk_row);
+                    }
+                }
+                band
+            } else {
+                read_chunk(decoder, first_chunk)?
+            };
+            image.write_strip(&band).map_err(|e| e.to_string())?;
+        }
+
+        image.finish().map_err(|e| e.to_string())
+    }
+
+    fn chunks_across(&self) -> u32 {
+        self.width.div_ceil(self.chunk_width)
+    }
+
+    fn bands(&self) -> u32 {
+        self.height.div_ceil(self.chunk_height)
+    }
+}
+
+/// Samples per pixel and bytes per sample
+fn sample_layout(color_type: ColorType) -> (u8, u8) {
+    match color_type {
+        ColorType::Gray(bits) => (1, bits.div_ceil(8)),
+        ColorType::RGB(bits) => (3, bits.div_ceil(8)),
+        ColorType::RGBA(bits) => (4, bits.div_ceil(8)),
+        _ => (4, 1),
+    }
+}
+
+fn u8_samples(result: DecodingResult) -> Option<Vec<u8>> {
+    match result {
+        DecodingResult::U8(samples) => Some(samples),
+        _ => None,
+    }
+}
+
+fn u16_samples(result: DecodingResult) -> Option<Vec<u16>> {
+    match result {
+        DecodingResult::U16(samples) => Some(samples),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use tempfile::tempdir;
+
+    /// Writes an RGB TIFF in strips of 4 rows and returns its pixels
+    fn write_striped_tiff(path: &Path, width: u32, height: u32) -> Vec<u8> {
+        let pixels: Vec<u8> = (0..height)
+            .flat_map(|y| (0..width).flat_map(move |x| [x as u8, y as u8, (x ^ y) as u8]))
+            .collect();
+        let mut output = Vec::new();
+        let mut encoder = TiffEncoder::new(Cursor::new(&mut output)).unwrap();
+        let mut image = encoder.new_image::<colortype::RGB8>(width, height).unwrap();
+        image.rows_per_strip(4).unwrap();
+        image.write_data(&pixels).unwrap();
+        std::fs::write(path, output).unwrap();
+        pixels
+    }
+
+    fn decode(buffer: &[u8]) -> Vec<u8> {
+        let mut decoder = Decoder::new(Cursor::new(buffer)).unwrap();
+        u8_samples(decoder.read_image().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_recompress_strips() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("striped.tif");
+        let pixels = write_striped_tiff(&path, 50, 30);
+
+        let stream = TiffStream::open(&path).unwrap();
+        assert_eq!(stream.describe(), "8 strips");
+        assert_eq!(stream.band_memory(), 2 * 50 * 4 * 3);
+        for compression in [TiffCompression::None, TiffCompression::Lzw, TiffCompression::Deflate] {
+            let mut output = Cursor::new(Vec::new());
+            stream.recompress(&path, compression, None, &mut output).unwrap();
+            assert_eq!(decode(output.get_ref()), pixels);
+        }
+
+        let mut output = Cursor::new(Vec::new());
+        stream
+            .recompress(&path, TiffCompression::Lzw, Some(300), &mut output)
+            .unwrap();
+        let mut decoder = Decoder::new(Cursor::new(output.get_ref())).unwrap();
+        assert_eq!(decoder.find_tag_unsigned::<u16>(Tag::ResolutionUnit).unwrap(), Some(2));
+    }
+
+    #[test]
+    fn test_open_single_band() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("single.tif");
+        let mut output = Vec::new();
+        let mut encoder = TiffEncoder::new(Cursor::new(&mut output)).unwrap();
+        let mut image = encoder.new_image::<colortype::Gray8>(10, 10).unwrap();
+        image.rows_per_strip(10).unwrap();
+        image.write_data(&[0; 100]).unwrap();
+        std::fs::write(&path, output).unwrap();
+
+        assert_eq!(TiffStream::open(&path), None);
+        assert_eq!(TiffStream::open(Path::new("samples/j0.JPG")), None);
+    }
+}
@@ -0,0 +1,50 @@
+use caesiumclt::{compress_files, CompressionOptions, CompressionStatus};
+use std::fs;
+use std::path::PathBuf;
+use tempfile::tempdir;
+
+#[test]
+fn test_compress_files() {
+    let input_files = vec![
+        PathBuf::from("samples/j0.JPG"),
+        PathBuf::from("samples/p0.png"),
+        PathBuf::from("samples/w0.webp"),
+    ];
+    let output_folder = tempdir().unwrap();
+    let options = CompressionOptions {
+        quality: Some(80),
+        output_folder: Some(output_folder.path().to_path_buf()),
+        base_path: PathBuf::from("samples"),
+        ..Default::default()
+    };
+
+    let results = compress_files(&input_files, &options, None);
+
+    assert_eq!(results.len(), input_files.len());
+    for (input_file, result) in input_files.iter().zip(&results) {
+        assert!(matches!(result.status, CompressionStatus::Success));
+        assert_eq!(result.original_path, input_file.display().to_string());
+        assert_eq!(
+            PathBuf::from(&result.output_path),
+            output_folder
+                .path()
+                .join(input_file.file_name().unwrap().to_ascii_lowercase())
+        );
+        assert_eq!(fs::metadata(&result.output_path).unwrap().len(), result.compressed_size);
+    }
+}
+
+#[test]
+fn test_compress_files_reports_errors() {
+    let output_folder = tempdir().unwrap();
+    let options = CompressionOptions {
+        lossless: true,
+        output_folder: Some(output_folder.path().to_path_buf()),
+        ..Default::default()
+    };
+
+    let results = compress_files(&[PathBuf::from("samples/missing.jpg")], &options, None);
+
+    assert_eq!(results.len(), 1);
+    assert!(matches!(results[0].status, CompressionStatus::Error));
+}
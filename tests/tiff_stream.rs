@@ -0,0 +1,103 @@
+//! Peak memory of the band by band TIFF recompression, measured by counting the allocations of this test binary
+
+use caesiumclt::memory_budget::MemoryBudget;
+use caesiumclt::options::TiffCompression;
+use caesiumclt::{compress_files, CompressionOptions, CompressionStatus};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::fs::File;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tempfile::tempdir;
+use tiff::encoder::{colortype, TiffEncoder};
+
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let allocated = ALLOCATED.fetch_add(layout.size(), Ordering::SeqCst) + layout.size();
+            PEAK.fetch_max(allocated, Ordering::SeqCst);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::SeqCst);
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+const WIDTH: u32 = 2048;
+const HEIGHT: u32 = 2048;
+const ROWS_PER_STRIP: u32 = 16;
+
+/// Noise that no compression can shrink, so that the output is as large as the decoded image
+fn pixel(x: u32, y: u32) -> [u8; 3] {
+    let mut hash = (y * WIDTH + x).wrapping_mul(0x9E37_79B9);
+    hash ^= hash >> 15;
+    hash = hash.wrapping_mul(0x85EB_CA6B);
+    hash ^= hash >> 13;
+    let [red, green, blue, _] = hash.to_le_bytes();
+    [red, green, blue]
+}
+
+/// Writes the fixture strip by strip, so that it is never held in memory at once either
+fn write_striped_tiff(path: &Path) {
+    let mut encoder = TiffEncoder::new(File::create(path).unwrap()).unwrap();
+    let mut image = encoder.new_image::<colortype::RGB8>(WIDTH, HEIGHT).unwrap();
+    image.rows_per_strip(ROWS_PER_STRIP).unwrap();
+    for strip in 0..HEIGHT / ROWS_PER_STRIP {
+        let rows = strip * ROWS_PER_STRIP..(strip + 1) * ROWS_PER_STRIP;
+        let samples: Vec<u8> = rows.flat_map(|y| (0..WIDTH).flat_map(move |x| pixel(x, y))).collect();
+        image.write_strip(&samples).unwrap();
+    }
+    image.finish().unwrap();
+}
+
+#[test]
+fn test_striped_tiff_is_recompressed_with_bounded_memory() {
+    let temp_dir = tempdir().unwrap();
+    let input_file = temp_dir.path().join("large.tif");
+    write_striped_tiff(&input_file);
+    let output_folder = temp_dir.path().join("out");
+    let options = CompressionOptions {
+        lossless: true,
+        tiff_compression: TiffCompression::None,
+        output_folder: Some(output_folder.clone()),
+        base_path: temp_dir.path().to_path_buf(),
+        memory_budget: Some(Arc::new(MemoryBudget::new(1_000_000))),
+        ..Default::default()
+    };
+
+    let baseline = ALLOCATED.load(Ordering::SeqCst);
+    PEAK.store(baseline, Ordering::SeqCst);
+    let results = compress_files(&[input_file], &options, None);
+    let peak = PEAK.load(Ordering::SeqCst) - baseline;
+
+    assert!(
+        matches!(results[0].status, CompressionStatus::Success),
+        "{}",
+        results[0].message
+    );
+    assert_eq!(results[0].message, "Recompressed band by band (128 strips)");
+    let decoded_size = (WIDTH * HEIGHT * 3) as usize;
+    assert!(results[0].compressed_size as usize >= decoded_size);
+    assert!(
+        peak < decoded_size / 4,
+        "peak allocation of {peak} bytes, the decoded image is {decoded_size} bytes"
+    );
+
+    let output = image::open(output_folder.join("large.tif")).unwrap().to_rgb8();
+    assert_eq!(output.dimensions(), (WIDTH, HEIGHT));
+    assert!(output
+        .enumerate_pixels()
+        .all(|(x, y, output_pixel)| output_pixel.0 == pixel(x, y)));
+}